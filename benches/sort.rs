@@ -0,0 +1,37 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ssb_causal_sort::corpus::{generate, Shape};
+use ssb_causal_sort::{causal_sort, Backend, SortBuilder};
+
+const SIZES: &[usize] = &[1_000, 10_000, 100_000];
+
+fn shapes() -> Vec<(&'static str, Shape)> {
+    vec![
+        ("chain", Shape::Chain),
+        ("fan_out", Shape::FanOut),
+        ("threads", Shape::Threads { replies: 20 }),
+    ]
+}
+
+fn sort(c: &mut Criterion) {
+    for (name, shape) in shapes() {
+        let mut group = c.benchmark_group(format!("sort/{}", name));
+        group.sample_size(10);
+
+        for size in SIZES {
+            let msgs = generate(shape, *size, 42);
+            group.throughput(Throughput::Elements(*size as u64));
+
+            group.bench_with_input(BenchmarkId::new("daggy", size), &msgs, |b, msgs| {
+                b.iter(|| causal_sort(msgs))
+            });
+            let csr = SortBuilder::new().backend(Backend::Csr);
+            group.bench_with_input(BenchmarkId::new("csr", size), &msgs, |b, msgs| {
+                b.iter(|| csr.sort(msgs))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, sort);
+criterion_main!(benches);
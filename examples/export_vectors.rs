@@ -0,0 +1,83 @@
+//! Regenerate the conformance vectors in `vectors/`.
+//!
+//! ```sh
+//! cargo run --example export_vectors --features conformance,corpus,testing
+//! ```
+use serde_json::json;
+use ssb_causal_sort::conformance::TestVector;
+use ssb_causal_sort::corpus::{generate, Shape};
+use ssb_causal_sort::testing::TangleGenerator;
+use ssb_multiformats::multihash::Multihash;
+use std::fs;
+
+fn hash(legacy: &str) -> Multihash {
+    Multihash::from_legacy(legacy.as_bytes()).unwrap().0
+}
+
+fn main() {
+    let root = "%rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256";
+    let reply1 = "%reply1K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256";
+    let reply2 = "%reply2K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256";
+    let orphan = "%orphanK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256";
+
+    let thread = [
+        (hash(reply1), 2, json!({ "root": root }).to_string()),
+        (hash(root), 1, json!({}).to_string()),
+        (
+            hash(reply2),
+            3,
+            json!({ "root": root, "previous": reply1 }).to_string(),
+        ),
+    ];
+    let orphans = [
+        (hash(reply1), 2, json!({ "root": root }).to_string()),
+        (hash(orphan), 3, json!({}).to_string()),
+        (
+            hash(root),
+            1,
+            json!({
+                "previous": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+                "nested": { "arry": ["&3AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"] }
+            })
+            .to_string(),
+        ),
+    ];
+    let concurrent = TangleGenerator::new(7)
+        .depth(3)
+        .branching(2)
+        .concurrency(2)
+        .missing(0.2)
+        .generate();
+
+    let vectors = [
+        (
+            "thread",
+            TestVector::export("a root with two replies, out of order", &thread),
+        ),
+        (
+            "orphans",
+            TestVector::export(
+                "a message nothing links to, and links to messages that aren't in the set",
+                &orphans,
+            ),
+        ),
+        (
+            "chain",
+            TestVector::export(
+                "a chain of 20 messages, each linking to the one before",
+                &generate(Shape::Chain, 20, 1),
+            ),
+        ),
+        (
+            "concurrent",
+            TestVector::export(
+                "a thread with concurrent replies and missing messages",
+                &concurrent.msgs,
+            ),
+        ),
+    ];
+
+    for (name, vector) in vectors.iter() {
+        fs::write(format!("vectors/{}.json", name), vector.to_json() + "\n").unwrap();
+    }
+}
@@ -0,0 +1,12 @@
+//! Arbitrary bytes, as message bodies, must never make link extraction panic.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ssb_causal_sort::fuzzing::extract_links;
+
+fuzz_target!(|data: &[u8]| {
+    let links = extract_links(data);
+    // Anything that isn't JSON can't have links.
+    if serde_json::from_slice::<serde_json::Value>(data).is_err() {
+        assert!(links.is_empty());
+    }
+});
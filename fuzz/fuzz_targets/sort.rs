@@ -0,0 +1,56 @@
+//! Arbitrary sets of messages must never make sorting panic, and must always sort into a valid
+//! causal order.
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use serde_json::json;
+use ssb_causal_sort::fuzzing::extract_links;
+use ssb_causal_sort::{causal_sort, causal_sort_bytes, verify_causal_order};
+use ssb_multiformats::multihash::Multihash;
+
+#[derive(Arbitrary, Debug)]
+struct Message {
+    key: u8,
+    /// Links to other keys. They're only followed to smaller keys, so there are never cycles.
+    links: Vec<u8>,
+    /// Arbitrary extra content for the message, which may or may not be JSON, or have links.
+    junk: Vec<u8>,
+}
+
+fn hash(n: u8) -> Multihash {
+    Multihash::Message([n; 32])
+}
+
+fuzz_target!(|msgs: Vec<Message>| {
+    let keyed: Vec<_> = msgs
+        .iter()
+        .enumerate()
+        .map(|(index, msg)| {
+            let links: Vec<_> = msg
+                .links
+                .iter()
+                .filter(|link| **link < msg.key)
+                .map(|link| hash(*link))
+                .collect();
+            // The prefix stops the junk from being a link itself.
+            let junk = format!("junk:{}", String::from_utf8_lossy(&msg.junk));
+            let body = json!({ "links": links, "junk": junk }).to_string();
+            (hash(msg.key), index, body)
+        })
+        .collect();
+    let sorted = causal_sort(&keyed);
+    verify_causal_order(&keyed, &sorted).unwrap();
+
+    // Bodies that are nothing but junk, skipping any that would make a cycle.
+    let junk: Vec<_> = msgs
+        .iter()
+        .enumerate()
+        .filter(|(_, msg)| {
+            extract_links(&msg.junk)
+                .iter()
+                .all(|link| (msg.key..=u8::MAX).all(|key| *link != hash(key)))
+        })
+        .map(|(index, msg)| (hash(msg.key), index, &msg.junk))
+        .collect();
+    causal_sort_bytes(&junk);
+});
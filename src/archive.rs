@@ -0,0 +1,200 @@
+//! Archiving a thread, eg. to back it up or publish it.
+use crate::error::Error;
+use crate::ndjson::write_ndjson;
+use crate::tangles::{branches, content_and_root, tangle_completeness};
+use crate::try_causal_sort;
+use serde_json::{json, Value};
+use ssb_multiformats::multihash::Multihash;
+use std::collections::HashSet;
+use std::io::Write;
+
+/// What's in an archive written by [`archive_thread`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadArchive {
+    /// The key of the thread's root message.
+    pub root: Multihash,
+    /// The keys of the archived messages, newest first, in the order they're written.
+    pub keys: Vec<Multihash>,
+    /// The archived messages that no other archived message links to in its `branch`, newest
+    /// first. Replies to the thread should link to these.
+    pub heads: Vec<Multihash>,
+    /// The messages the thread links to that aren't archived, as in a
+    /// [`TangleReport`](crate::TangleReport). The root is first if it's missing.
+    pub missing: Vec<Multihash>,
+}
+
+/// Write the thread of the message `root` from `msgs` to `messages`, newest first, as
+/// [`write_ndjson`] writes them, and an index of it to `index`.
+///
+/// The thread is `root` and the messages whose `root` links to it, as for
+/// [`tangle_completeness`], so an archive can be read back with
+/// [`read_ndjson`](crate::read_ndjson) and sorted on its own. The index is one line of JSON with
+/// the fields of the returned [`ThreadArchive`], its hashes in their legacy form, so a reader
+/// can find the heads and what's missing without sorting the messages. Later messages with a
+/// key that's already been seen are left out.
+///
+/// Fails with `Error::Cycle` if the thread's links form a cycle, `Error::Parse`, with its index
+/// in `msgs`, if the root message isn't JSON, and `Error::Write` if writing fails. `messages` is
+/// written to a line at a time, so should be buffered.
+pub fn archive_thread<W: Write, I: Write, T: AsRef<str>, K>(
+    messages: W,
+    mut index: I,
+    root: &Multihash,
+    msgs: &[(Multihash, K, T)],
+) -> Result<ThreadArchive, Error> {
+    let mut seen = HashSet::new();
+    let mut linked = HashSet::new();
+    let mut thread = Vec::new();
+    for (msg_index, (key, _, msg)) in msgs.iter().enumerate() {
+        let msg = msg.as_ref();
+        let value: Option<Value> = serde_json::from_str(msg).ok();
+        let reply = value
+            .as_ref()
+            .and_then(|value| content_and_root(value))
+            .filter(|(_, reply_to)| reply_to == root);
+        if (key != root && reply.is_none()) || !seen.insert(key) {
+            continue;
+        }
+        if let Some((content, _)) = reply {
+            linked.insert(root.clone());
+            linked.extend(branches(content));
+        }
+        thread.push((key.clone(), msg_index, msg));
+    }
+
+    let sorted = try_causal_sort(&thread)?;
+    let keys: Vec<Multihash> = sorted.iter().map(|i| msgs[*i].0.clone()).collect();
+    let heads = keys
+        .iter()
+        .filter(|key| !linked.contains(*key))
+        .cloned()
+        .collect();
+    let missing = tangle_completeness(&thread)
+        .into_iter()
+        .find(|report| report.root == *root)
+        .map(|report| report.missing)
+        .unwrap_or_else(|| {
+            if seen.contains(root) {
+                vec![]
+            } else {
+                vec![root.clone()]
+            }
+        });
+    let archive = ThreadArchive {
+        root: root.clone(),
+        keys,
+        heads,
+        missing,
+    };
+
+    write_ndjson(messages, &sorted, &thread).map_err(|error| match error {
+        Error::Parse { index, source } => Error::Parse {
+            index: thread[index].1,
+            source,
+        },
+        error => error,
+    })?;
+    let legacy = |hashes: &[Multihash]| -> Vec<String> {
+        hashes.iter().map(Multihash::to_legacy_string).collect()
+    };
+    let line = json!({
+        "root": root.to_legacy_string(),
+        "keys": legacy(&archive.keys),
+        "heads": legacy(&archive.heads),
+        "missing": legacy(&archive.missing),
+    });
+    writeln!(index, "{}", line)
+        .and_then(|()| index.flush())
+        .map_err(|source| Error::Write { source })?;
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::archive_thread;
+    use crate::test_utils::numbered;
+    use crate::{read_ndjson, Error, SortBuilder};
+    use serde_json::{json, Value};
+    use std::io::Cursor;
+
+    #[test]
+    fn threads_are_archived_with_an_index() {
+        let root = numbered(1);
+        let msgs = vec![
+            (
+                numbered(5),
+                5,
+                json!({ "content": { "type": "post" } }).to_string(),
+            ),
+            (
+                numbered(3),
+                3,
+                json!({ "content": { "root": root, "branch": [numbered(2), numbered(9)] } })
+                    .to_string(),
+            ),
+            (
+                root.clone(),
+                1,
+                json!({ "content": { "type": "post" } }).to_string(),
+            ),
+            (
+                numbered(2),
+                2,
+                json!({ "content": { "root": root, "branch": root } }).to_string(),
+            ),
+            (
+                numbered(4),
+                4,
+                json!({ "content": { "root": root, "branch": numbered(2) } }).to_string(),
+            ),
+            (numbered(6), 6, "{\"not\": json".to_owned()),
+        ];
+
+        let (mut messages, mut index) = (Vec::new(), Vec::new());
+        let archive = archive_thread(&mut messages, &mut index, &root, &msgs).unwrap();
+        assert_eq!(archive.keys.len(), 4);
+        assert_eq!(archive.keys[3], root);
+        let mut heads = archive.heads.clone();
+        heads.sort();
+        let mut expected = vec![numbered(3), numbered(4)];
+        expected.sort();
+        assert_eq!(heads, expected);
+        assert_eq!(archive.missing, [numbered(9)]);
+
+        let read: Vec<_> = read_ndjson(Cursor::new(messages))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let keys: Vec<_> = read.iter().map(|(key, _, _)| key.clone()).collect();
+        assert_eq!(keys, archive.keys);
+        let sorted = SortBuilder::new().sort(&read);
+        assert_eq!(sorted.len(), 4);
+        assert_eq!(sorted[3], 3);
+
+        let index: Value = serde_json::from_slice(&index).unwrap();
+        assert_eq!(index["root"], root.to_legacy_string());
+        assert_eq!(index["keys"].as_array().unwrap().len(), 4);
+        assert_eq!(index["missing"], json!([numbered(9).to_legacy_string()]));
+    }
+
+    #[test]
+    fn missing_roots_are_listed() {
+        let msgs = [(
+            numbered(2),
+            2,
+            json!({ "content": { "root": numbered(1) } }).to_string(),
+        )];
+        let archive = archive_thread(Vec::new(), Vec::new(), &numbered(1), &msgs).unwrap();
+        assert_eq!(archive.keys, [numbered(2)]);
+        assert_eq!(archive.missing, [numbered(1)]);
+
+        let archive = archive_thread(Vec::new(), Vec::new(), &numbered(7), &msgs).unwrap();
+        assert!(archive.keys.is_empty());
+        assert_eq!(archive.missing, [numbered(7)]);
+
+        let broken = [msgs[0].clone(), (numbered(7), 7, "{".to_owned())];
+        assert!(matches!(
+            archive_thread(Vec::new(), Vec::new(), &numbered(7), &broken),
+            Err(Error::Parse { index: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,197 @@
+//! Parsing messages into a [bumpalo](https://docs.rs/bumpalo) arena.
+//!
+//! Parsing a message into a `serde_json::Value` allocates every string, array and object in it
+//! separately, only to free them all again once the links have been found. Here the message is
+//! parsed into a tree that lives in a bump arena instead. Allocating is a pointer bump, and the
+//! whole tree is freed in one go by resetting the arena before the next message. The arena keeps
+//! its memory between messages, so after the first few messages parsing stops allocating at all.
+//!
+//! The links found must be the same, in the same order, as when parsing into a `Value`.
+//! `serde_json::Map` iterates in key order, keeping the last of any repeated keys, so the objects
+//! here are sorted and deduplicated the same way before they're searched.
+use crate::extract::{Found, LinkOptions, Part, Scalar};
+use bumpalo::collections::Vec as BumpVec;
+use bumpalo::Bump;
+use serde::de::{DeserializeSeed, Deserializer, Error, MapAccess, SeqAccess, Visitor};
+use ssb_multiformats::multihash::Multihash;
+use std::fmt;
+
+enum Node<'b> {
+    String(&'b str),
+    Uint(u64),
+    Float(f64),
+    Array(&'b [Node<'b>]),
+    Object(&'b [(&'b str, Node<'b>)]),
+    Other,
+}
+
+pub(crate) fn extract_refs_into(
+    bump: &Bump,
+    msg: &[u8],
+    options: &LinkOptions,
+    refs: &mut Vec<Multihash>,
+) -> serde_json::Result<Found> {
+    let mut deserializer = serde_json::Deserializer::from_slice(msg);
+    let parsed = NodeSeed(bump)
+        .deserialize(&mut deserializer)
+        .and_then(|node| deserializer.end().map(|_| node));
+
+    let parsed = parsed?;
+    let found = options.find(|path| parsed.scalar_at(path));
+    if found.kept {
+        match options.part(|path| parsed.node_at(path).is_some()) {
+            Part::Whole => find_all_links(&parsed, options, refs),
+            Part::Nothing => (),
+            Part::At(path) => parsed
+                .node_at(path)
+                .into_iter()
+                .for_each(|part| find_all_links(part, options, refs)),
+        }
+    }
+    Ok(found)
+}
+
+impl<'b> Node<'b> {
+    fn node_at(&self, path: &[&str]) -> Option<&Node<'b>> {
+        path.iter().try_fold(self, |node, field| match node {
+            Node::Object(kv) => {
+                let index = kv.binary_search_by_key(field, |(key, _)| key).ok()?;
+                Some(&kv[index].1)
+            }
+            _ => None,
+        })
+    }
+
+    fn scalar_at(&self, path: &[&str]) -> Option<Scalar<'b>> {
+        match self.node_at(path)? {
+            Node::String(st) => Some(Scalar::Str(st)),
+            Node::Uint(n) => Some(Scalar::Uint(*n)),
+            Node::Float(n) => Some(Scalar::Float(*n)),
+            _ => None,
+        }
+    }
+}
+
+fn find_all_links(node: &Node, options: &LinkOptions, keys: &mut Vec<Multihash>) {
+    match node {
+        Node::String(st) => {
+            keys.extend(options.link(st));
+        }
+        Node::Array(arr) => arr
+            .iter()
+            .for_each(|val| find_all_links(val, options, keys)),
+        Node::Object(kv) => kv
+            .iter()
+            .filter(|(key, _)| !options.skips(key))
+            .for_each(|(_, val)| find_all_links(val, options, keys)),
+        Node::Uint(_) | Node::Float(_) | Node::Other => (),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct NodeSeed<'b>(&'b Bump);
+
+impl<'de, 'b> DeserializeSeed<'de> for NodeSeed<'b> {
+    type Value = Node<'b>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Node<'b>, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'b> Visitor<'de> for NodeSeed<'b> {
+    type Value = Node<'b>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any valid JSON value")
+    }
+
+    fn visit_bool<E: Error>(self, _: bool) -> Result<Node<'b>, E> {
+        Ok(Node::Other)
+    }
+
+    fn visit_i64<E: Error>(self, v: i64) -> Result<Node<'b>, E> {
+        // serde_json only visits negative integers as i64s.
+        Ok(Node::Float(v as f64))
+    }
+
+    fn visit_u64<E: Error>(self, v: u64) -> Result<Node<'b>, E> {
+        Ok(Node::Uint(v))
+    }
+
+    fn visit_f64<E: Error>(self, v: f64) -> Result<Node<'b>, E> {
+        Ok(Node::Float(v))
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Node<'b>, E> {
+        Ok(Node::Other)
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Node<'b>, E> {
+        Ok(Node::String(self.0.alloc_str(v)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Node<'b>, A::Error> {
+        let mut items = BumpVec::new_in(self.0);
+        while let Some(item) = seq.next_element_seed(self)? {
+            items.push(item);
+        }
+        Ok(Node::Array(items.into_bump_slice()))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Node<'b>, A::Error> {
+        let mut entries = BumpVec::new_in(self.0);
+        while let Some(key) = map.next_key_seed(KeySeed(self.0))? {
+            let value = map.next_value_seed(self)?;
+            entries.push((key, value));
+        }
+
+        // Match serde_json::Map: in key order, and the last value wins for repeated keys.
+        entries.sort_by_key(|(key, _)| *key);
+        let mut deduped: BumpVec<(&str, Node)> = BumpVec::with_capacity_in(entries.len(), self.0);
+        entries
+            .into_iter()
+            .for_each(|(key, value)| match deduped.last_mut() {
+                Some(last) if last.0 == key => last.1 = value,
+                _ => deduped.push((key, value)),
+            });
+        Ok(Node::Object(deduped.into_bump_slice()))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct KeySeed<'b>(&'b Bump);
+
+impl<'de, 'b> DeserializeSeed<'de> for KeySeed<'b> {
+    type Value = &'b str;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<&'b str, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de, 'b> Visitor<'de> for KeySeed<'b> {
+    type Value = &'b str;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string key")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<&'b str, E> {
+        Ok(self.0.alloc_str(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_refs_into;
+    use crate::test_utils::assert_extractor_matches_every_case;
+    use bumpalo::Bump;
+
+    #[test]
+    fn matches_serde_json() {
+        assert_extractor_matches_every_case(|msg, options, refs| {
+            extract_refs_into(&Bump::new(), msg, options, refs)
+        });
+    }
+}
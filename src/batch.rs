@@ -0,0 +1,176 @@
+//! Indexing a log one batch of messages at a time.
+use crate::dag::CausalDag;
+use crate::error::Error;
+use crate::sorted::SortedMessages;
+use ssb_multiformats::multihash::Multihash;
+
+/// What a [`BatchIndexer`] keeps between batches. Persist it to carry on indexing later with
+/// [`BatchIndexer::resume`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchState {
+    /// The keys of the messages so far that no message so far links to, in order.
+    pub heads: Vec<Multihash>,
+    /// The message hashes that messages so far link to but that weren't among them, in order.
+    pub missing: Vec<Multihash>,
+}
+
+/// One batch of messages, from [`BatchIndexer::push`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedBatch<K> {
+    /// The batch's key ids, newest first, as [`causal_sort`](crate::causal_sort) sorts them.
+    pub sorted: SortedMessages<K>,
+    /// The key ids of messages in the batch that earlier batches link to. These are older than
+    /// messages already indexed, so an index that relies on later batches being newer has to
+    /// move them.
+    pub late: Vec<K>,
+}
+
+/// Causally sorts a log in batches, remembering only the heads and the missing hashes between
+/// them rather than every message.
+///
+/// Each batch is sorted on its own. As long as no batch has messages that earlier batches link
+/// to, putting each batch's order before the last one's gives a causal order of the whole log.
+/// Messages that are linked to before they arrive are reported as late.
+#[derive(Clone, Debug, Default)]
+pub struct BatchIndexer {
+    state: BatchState,
+}
+
+impl BatchIndexer {
+    pub fn new() -> BatchIndexer {
+        BatchIndexer::default()
+    }
+
+    /// Carry on from the state after an earlier batch.
+    pub fn resume(state: BatchState) -> BatchIndexer {
+        BatchIndexer { state }
+    }
+
+    pub fn state(&self) -> &BatchState {
+        &self.state
+    }
+
+    pub fn into_state(self) -> BatchState {
+        self.state
+    }
+
+    /// Sort the next `batch` of messages.
+    ///
+    /// `indexed` says whether a message from an earlier batch has a given key, eg. by looking it
+    /// up in the index being written. It's only asked about the hashes that the batch links to
+    /// but doesn't have, so that the state doesn't have to hold every key. The state is left as
+    /// it was if this fails.
+    pub fn push<T, K, F>(
+        &mut self,
+        batch: &[(Multihash, K, T)],
+        indexed: F,
+    ) -> Result<IndexedBatch<K>, Error>
+    where
+        T: AsRef<str>,
+        K: Clone,
+        F: Fn(&Multihash) -> bool,
+    {
+        let dag = CausalDag::from_msgs(batch)?;
+        let BatchState { heads, missing } = &mut self.state;
+
+        let mut late = Vec::new();
+        let mut new_heads = Vec::new();
+        let mut linked = Vec::new();
+        for node in dag.nodes() {
+            let hash = match dag.hash(node) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            match dag.key_id(node) {
+                Some(key_id) => {
+                    if let Ok(index) = missing.binary_search(hash) {
+                        missing.remove(index);
+                        late.push(key_id.clone());
+                    } else if dag.linked_from(node).next().is_none() {
+                        new_heads.push(hash.clone());
+                    }
+                }
+                None => linked.push(hash),
+            }
+        }
+
+        linked.sort_unstable();
+        heads.retain(|head| linked.binary_search(&head).is_err());
+        for hash in linked {
+            let is_message = matches!(hash, Multihash::Message(_));
+            if is_message && heads.binary_search(hash).is_err() && !indexed(hash) {
+                if let Err(index) = missing.binary_search(hash) {
+                    missing.insert(index, hash.clone());
+                }
+            }
+        }
+        heads.extend(new_heads);
+        heads.sort_unstable();
+
+        Ok(IndexedBatch {
+            sorted: dag.sorted(),
+            late,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchIndexer, BatchState};
+    use crate::test_utils::{branching, numbered};
+    use crate::Error;
+    use ssb_multiformats::multihash::Multihash;
+    use std::collections::HashSet;
+
+    #[test]
+    fn sorts_batch_by_batch() {
+        let mut indexed = HashSet::new();
+        let mut indexer = BatchIndexer::new();
+        let mut push = |indexer: &mut BatchIndexer, batch: &[(Multihash, usize, String)]| {
+            let pushed = indexer.push(batch, |hash| indexed.contains(hash)).unwrap();
+            indexed.extend(batch.iter().map(|(key, _, _)| key.clone()));
+            pushed
+        };
+
+        // 3 links to 9, which isn't there yet.
+        let first = push(
+            &mut indexer,
+            &[branching(2, &[1]), branching(1, &[]), branching(3, &[2, 9])],
+        );
+        assert_eq!(first.sorted, [3, 2, 1]);
+        assert!(first.late.is_empty());
+        let state = indexer.state().clone();
+        assert_eq!(
+            state,
+            BatchState {
+                heads: vec![numbered(3)],
+                missing: vec![numbered(9)],
+            }
+        );
+
+        // Resume from the saved state. 4 links back to 1, which isn't a head any more.
+        let mut indexer = BatchIndexer::resume(state);
+        let second = push(&mut indexer, &[branching(5, &[4]), branching(4, &[3, 1])]);
+        assert_eq!(second.sorted, [5, 4]);
+        assert_eq!(indexer.state().heads, [numbered(5)]);
+        assert_eq!(indexer.state().missing, [numbered(9)]);
+
+        let third = push(&mut indexer, &[branching(9, &[]), branching(6, &[5])]);
+        assert_eq!(third.late, [9]);
+        assert_eq!(indexer.state().heads, [numbered(6)]);
+        assert!(indexer.state().missing.is_empty());
+    }
+
+    #[test]
+    fn failed_batches_leave_the_state() {
+        let mut indexer = BatchIndexer::new();
+        indexer.push(&[branching(1, &[])], |_| false).unwrap();
+        let before = indexer.state().clone();
+        let cycle = [branching(2, &[3]), branching(3, &[2])];
+        assert!(matches!(
+            indexer.push(&cycle, |_| false),
+            Err(Error::Cycle { .. })
+        ));
+        assert_eq!(*indexer.state(), before);
+    }
+}
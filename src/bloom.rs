@@ -0,0 +1,163 @@
+//! A [Bloom filter] over the hashes a set of messages links to.
+//!
+//! Exchanging one with a peer answers "might you have anything I link to?" without sending every
+//! hash. Hashes are placed in the filter by mixing the bytes of their digests with splitmix64's
+//! finaliser rather than with a `Hasher`, so the filter is the same on every platform and can be
+//! sent as bytes and queried on the other end.
+//!
+//! [Bloom filter]: https://en.wikipedia.org/wiki/Bloom_filter
+use crate::extract::Extractor;
+use ssb_multiformats::multihash::Multihash;
+use std::convert::TryInto;
+
+/// The most bits a hash sets, however low the false positive rate.
+const MAX_HASHES: u32 = 32;
+
+/// splitmix64's increment, used to tell blobs apart from messages with the same digest.
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// splitmix64's finaliser.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A set of hashes that can say for sure a hash isn't in it, but only that one might be.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `items` hashes, so that once they're inserted, about
+    /// `false_positive_rate` of the hashes not in it would be said to be.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `false_positive_rate` isn't between 0 and 1.
+    pub fn with_rate(items: usize, false_positive_rate: f64) -> BloomFilter {
+        assert!(
+            false_positive_rate > 0.0 && false_positive_rate < 1.0,
+            "false_positive_rate must be between 0 and 1"
+        );
+        let ln_2 = std::f64::consts::LN_2;
+        let bits = (-(items.max(1) as f64) * false_positive_rate.ln() / (ln_2 * ln_2)).ceil();
+        let words = ((bits / 64.0).ceil() as usize).max(1);
+        let hashes = (bits / items.max(1) as f64 * ln_2).round() as u32;
+        BloomFilter {
+            bits: vec![0; words],
+            hashes: hashes.clamp(1, MAX_HASHES),
+        }
+    }
+
+    pub fn insert(&mut self, hash: &Multihash) {
+        for bit in self.bits_of(hash) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether `hash` might have been inserted. If not, it definitely wasn't.
+    pub fn might_contain(&self, hash: &Multihash) -> bool {
+        self.bits_of(hash)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The filter as bytes, to send to a peer: the number of hashes as a little endian `u32`,
+    /// then the bits as little endian `u64`s.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.bits.len() * 8);
+        bytes.extend_from_slice(&self.hashes.to_le_bytes());
+        self.bits
+            .iter()
+            .for_each(|word| bytes.extend_from_slice(&word.to_le_bytes()));
+        bytes
+    }
+
+    /// Read a filter written by [`to_bytes`](BloomFilter::to_bytes). `None` if `bytes` isn't
+    /// one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<BloomFilter> {
+        if bytes.len() < 4 + 8 || !(bytes.len() - 4).is_multiple_of(8) {
+            return None;
+        }
+        let hashes = u32::from_le_bytes(bytes[..4].try_into().ok()?);
+        if hashes == 0 || hashes > MAX_HASHES {
+            return None;
+        }
+        let bits = bytes[4..]
+            .chunks(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Some(BloomFilter { bits, hashes })
+    }
+
+    /// The bits `hash` sets, by double hashing with two words mixed from its digest.
+    fn bits_of(&self, hash: &Multihash) -> impl Iterator<Item = usize> {
+        let (bytes, kind) = match hash {
+            Multihash::Message(bytes) => (bytes, 0),
+            Multihash::Blob(bytes) => (bytes, GOLDEN_GAMMA),
+        };
+        let first = bytes.chunks(8).fold(kind, |mixed, word| {
+            mix(mixed ^ u64::from_le_bytes(word.try_into().unwrap()))
+        });
+        let step = mix(first ^ GOLDEN_GAMMA) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+}
+
+/// A filter over every hash `msgs` link to, with about `false_positive_rate` false positives.
+/// Messages that aren't valid JSON have no links.
+///
+/// # Panics
+///
+/// Panics if `false_positive_rate` isn't between 0 and 1.
+pub fn referenced_hashes_filter<T: AsRef<str>, K>(
+    msgs: &[(Multihash, K, T)],
+    false_positive_rate: f64,
+) -> BloomFilter {
+    let mut extractor = Extractor::new();
+    let mut referenced = Vec::new();
+    for (_, _, msg) in msgs {
+        referenced.extend_from_slice(
+            extractor
+                .extract(msg.as_ref().as_bytes())
+                .unwrap_or_default(),
+        );
+    }
+    let mut filter = BloomFilter::with_rate(referenced.len(), false_positive_rate);
+    referenced.iter().for_each(|hash| filter.insert(hash));
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{referenced_hashes_filter, BloomFilter};
+    use crate::test_utils::{numbered, thread};
+
+    #[test]
+    fn contains_every_referenced_hash() {
+        let msgs = thread();
+        let filter = referenced_hashes_filter(&msgs, 0.01);
+        // The root and the first reply.
+        assert!(filter.might_contain(&msgs[1].0) && filter.might_contain(&msgs[0].0));
+
+        let bytes = filter.to_bytes();
+        assert_eq!(BloomFilter::from_bytes(&bytes), Some(filter));
+        assert_eq!(BloomFilter::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(BloomFilter::from_bytes(&[0; 12]), None);
+    }
+
+    #[test]
+    fn false_positives_are_about_the_rate() {
+        let mut filter = BloomFilter::with_rate(1000, 0.01);
+        (0..1000).for_each(|i| filter.insert(&numbered(i)));
+        assert!((0..1000).all(|i| filter.might_contain(&numbered(i))));
+        let false_positives = (1000..11000)
+            .filter(|i| filter.might_contain(&numbered(*i)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,176 @@
+//! Sorting within a time budget.
+//!
+//! Building the dag is the expensive part of a sort, and it happens one message at a time. That
+//! makes it easy to stop part way through, hand back what we've got so far, and pick up where we
+//! left off later.
+use crate::error::{self, Error};
+use crate::extract::Extractor;
+use crate::graph::{BuildGraph, CausalGraph, Checks};
+use crate::sorted::SortedMessages;
+use crate::trace::span;
+use ssb_multiformats::multihash::Multihash;
+use std::time::{Duration, Instant};
+
+/// The result of a budgeted sort.
+// Boxing the partial sort would save little next to the cost of the sort that returns it.
+#[allow(clippy::large_enum_variant)]
+pub enum Budgeted<'a, K, T> {
+    /// Every message was sorted within the budget.
+    Complete(SortedMessages<K>),
+    /// The budget ran out before every message was added to the dag.
+    Partial(PartialSort<'a, K, T>),
+}
+
+/// A sort that ran out of time. Use it to look at the order of the messages processed so far, or
+/// `resume` it with a fresh budget.
+pub struct PartialSort<'a, K, T> {
+    msgs: &'a [(Multihash, K, T)],
+    next: usize,
+    graph: CausalGraph<K>,
+    extractor: Extractor,
+}
+
+/// Like [`causal_sort`](crate::causal_sort), but stops adding messages to the dag once `budget`
+/// has elapsed.
+///
+/// At least one message is processed per call, so repeatedly resuming a `PartialSort` always
+/// makes progress. The final topological sort is not interruptible and may run over the budget.
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort_within`] to handle this as an
+/// error.
+pub fn causal_sort_within<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    budget: Duration,
+) -> Budgeted<'_, K, T> {
+    error::unwrap(try_causal_sort_within(msgs, budget))
+}
+
+/// Like [`causal_sort_within`], but returns an error rather than panicking.
+pub fn try_causal_sort_within<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    budget: Duration,
+) -> Result<Budgeted<'_, K, T>, Error> {
+    PartialSort {
+        msgs,
+        next: 0,
+        graph: CausalGraph::new(),
+        extractor: Extractor::new(),
+    }
+    .try_resume(budget)
+}
+
+impl<'a, K: Clone, T: AsRef<str>> PartialSort<'a, K, T> {
+    /// Continue sorting for up to another `budget`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the messages' links form a cycle. Use [`try_resume`](PartialSort::try_resume)
+    /// to handle this as an error.
+    pub fn resume(self, budget: Duration) -> Budgeted<'a, K, T> {
+        error::unwrap(self.try_resume(budget))
+    }
+
+    /// Like [`resume`](PartialSort::resume), but returns an error rather than panicking. The
+    /// messages are checked as [`try_causal_sort`](crate::try_causal_sort) checks them.
+    pub fn try_resume(mut self, budget: Duration) -> Result<Budgeted<'a, K, T>, Error> {
+        let deadline = Instant::now() + budget;
+
+        {
+            let span = span!(
+                DEBUG,
+                "build",
+                messages = tracing::field::Empty,
+                links = tracing::field::Empty,
+                nodes = tracing::field::Empty,
+            );
+            let _entered = span.enter();
+
+            let start = self.next;
+            while let Some((key, key_id, msg)) = self.msgs.get(self.next) {
+                let refs = self
+                    .extractor
+                    .extract(msg.as_ref().as_bytes())
+                    .unwrap_or_default();
+                self.graph
+                    .add(key, key_id.clone(), refs, Checks::default())?;
+                self.next += 1;
+
+                if Instant::now() >= deadline {
+                    break;
+                }
+            }
+
+            span.record("messages", self.next - start)
+                .record("links", self.graph.edge_count())
+                .record("nodes", self.graph.node_count());
+        }
+
+        Ok(match self.remaining() {
+            0 => Budgeted::Complete(self.graph.sorted()),
+            _ => Budgeted::Partial(self),
+        })
+    }
+
+    /// The causal order of the messages processed so far, newest first.
+    pub fn order(&self) -> SortedMessages<K> {
+        self.graph.sorted()
+    }
+
+    /// How many of the input messages have been added to the dag.
+    pub fn processed(&self) -> usize {
+        self.next
+    }
+
+    /// How many of the input messages are still to be added to the dag.
+    pub fn remaining(&self) -> usize {
+        self.msgs.len() - self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{numbered, thread};
+    use crate::{causal_sort, causal_sort_within, try_causal_sort_within, Budgeted, Error};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn resuming_with_no_budget_matches_causal_sort() {
+        let unsorted = thread();
+
+        let mut partials = 0;
+        let mut result = causal_sort_within(&unsorted[..], Duration::from_secs(0));
+        let sorted = loop {
+            match result {
+                Budgeted::Complete(sorted) => break sorted,
+                Budgeted::Partial(partial) => {
+                    partials += 1;
+                    assert_eq!(partial.processed(), partials);
+                    assert_eq!(partial.order().len(), partials);
+                    result = partial.resume(Duration::from_secs(0));
+                }
+            }
+        };
+
+        assert_eq!(partials, 2);
+        assert_eq!(sorted, causal_sort(&unsorted[..]));
+    }
+
+    #[test]
+    fn cycles_are_errors_when_resuming() {
+        let msgs = [
+            (numbered(1), 1, json!({ "branch": numbered(2) }).to_string()),
+            (numbered(2), 2, json!({ "branch": numbered(1) }).to_string()),
+        ];
+        let partial = match try_causal_sort_within(&msgs[..], Duration::from_secs(0)) {
+            Ok(Budgeted::Partial(partial)) => partial,
+            _ => panic!("expected a partial sort"),
+        };
+        assert!(matches!(
+            partial.try_resume(Duration::from_secs(60)),
+            Err(Error::Cycle { .. })
+        ));
+    }
+}
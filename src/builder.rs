@@ -0,0 +1,1667 @@
+//! Configuring how a sort is done.
+#[cfg(feature = "futures")]
+use crate::channel::{channel, SortSink, SortStream};
+use crate::csr::CsrBuilder;
+use crate::error::{self, Error};
+#[cfg(feature = "json")]
+use crate::extract::{count_message_links, envelope_key, LinkOptions, TrustLinks};
+use crate::graph::{BuildGraph, Built, CausalGraph, Checks, Threads};
+#[cfg(feature = "json")]
+use crate::hashes::{LinkFormat, ReadUnknown, UnknownHash};
+use crate::metrics::{Metrics, SortStats};
+#[cfg(feature = "rayon")]
+use crate::parallel::par_build;
+use crate::sorted::SortedMessages;
+#[cfg(feature = "json")]
+use crate::validate::{InvalidMessages, Validate};
+use ssb_multiformats::multihash::Multihash;
+use std::collections::HashSet;
+use std::fmt;
+#[cfg(feature = "json")]
+use std::ops::ControlFlow;
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Instant;
+#[cfg(feature = "tokio")]
+use tokio::task::JoinHandle;
+
+/// Which graph representation to build the dag in.
+///
+/// Every backend gives exactly the same results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// A [daggy](https://docs.rs/daggy) dag. The default. Its nodes and edges are numbered with
+    /// `u32`s, so it holds at most `u32::MAX - 1` hashes and as many links.
+    #[default]
+    Daggy,
+    /// A compact [compressed sparse row] graph, built in one pass once every message's links are
+    /// known. Uses a fraction of the memory per edge, but holds at most `u32::MAX` nodes.
+    ///
+    /// [compressed sparse row]: https://en.wikipedia.org/wiki/Sparse_matrix#Compressed_sparse_row_(CSR,_CRS_or_Yale_format)
+    Csr,
+}
+
+/// What to do with the links in `fork` fields.
+///
+/// A `fork` links a reply to the message in a thread that it starts a new thread from. Some
+/// clients show it as the start of a new thread rather than as following on from that message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Forks {
+    /// Sort replies after the message they fork from, like any other link. The default.
+    #[default]
+    Follow,
+    /// Leave out the links in `fork` fields, so the sort matches clients that show forks as new
+    /// threads.
+    Ignore,
+}
+
+/// Which classes of links to sort message values by.
+///
+/// A message value links back to the message before it in its author's feed with its `previous`
+/// field, and to other messages from its `content`, eg. to the root of a thread it replies to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Edges {
+    /// Every link anywhere in a message. The default.
+    #[default]
+    All,
+    /// Only `previous` links, to replicate feeds in order. Messages that are only content have
+    /// no links.
+    Feed,
+    /// Only the links in each message's content, to render threads. Every link in a message
+    /// that's only content is in its content.
+    Content,
+}
+
+/// How to order concurrent messages, which their links leave in no particular order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Whatever order the walk of the dag finds them in, which depends on the order the messages
+    /// are given in. The default, and the fastest.
+    #[default]
+    Input,
+    /// Whenever more than one message could come next, the one whose key is least: messages
+    /// before blobs, then by the bytes of their digests. Links to messages that aren't being
+    /// sorted are ordered the same way.
+    ///
+    /// The order depends only on the messages' keys and links, so every peer with the same
+    /// messages computes the same order, on any platform and whatever order they arrived in. This
+    /// is the total order for CRDTs to merge in.
+    Canonical,
+    /// In layers: first the messages nothing links to, then the ones only they link to, and so
+    /// on, each layer in the order its messages and links were first seen in the input.
+    ///
+    /// With the `rayon` feature, large graphs are sorted a layer at a time on many threads, in
+    /// the same order as on one. For graphs with millions of messages this is the fastest.
+    Layers,
+}
+
+/// Which messages a [time window](SortBuilder::time_window) sorts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Window {
+    /// The messages claimed to be from within the window, eg. to show a day of activity.
+    Inside,
+    /// The messages claimed to be from outside it, eg. to set the ones with implausible
+    /// timestamps aside.
+    Outside,
+}
+
+/// A preset combination of [`SortBuilder`] options, see [`SortBuilder::profile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// For phones, eg. clients built with React Native or Tauri: the compact `Backend::Csr`,
+    /// which numbers each hash with a `u32`, and at most
+    /// [`LOW_MEMORY_MAX_LINKS`](Profile::LOW_MEMORY_MAX_LINKS) links read from each message. The
+    /// sort runs on the calling thread only, as with
+    /// [`single_thread`](SortBuilder::single_thread).
+    ///
+    /// Add a [memory budget](SortBuilder::memory_budget) to fail before the OS kills the app.
+    LowMemory,
+    /// Pick `Backend::Csr` for sorts of at least [`AUTO_CSR_SIZE`](Profile::AUTO_CSR_SIZE)
+    /// messages and links, and `Backend::Daggy` for smaller ones. Links are counted by scanning a
+    /// sample of the messages, or exactly for [`sort_links`](SortBuilder::sort_links).
+    ///
+    /// Smaller sorts also leave out what only pays off for large ones: their hashes are kept in
+    /// one map rather than split into the shards the parallel build fills, and they run on the
+    /// calling thread, with `par_sort` building the dag as `sort` does. The other options, eg. the
+    /// tie-break, stay as they're set. The results are the same either way, so this only changes
+    /// how fast a sort is and how much memory it takes.
+    Auto,
+}
+
+impl Profile {
+    /// The most links `Profile::LowMemory` reads from one message. Genuine messages have far
+    /// fewer.
+    pub const LOW_MEMORY_MAX_LINKS: usize = 256;
+
+    /// The number of messages plus links from which `Profile::Auto` sorts with `Backend::Csr`.
+    pub const AUTO_CSR_SIZE: usize = 1 << 14;
+}
+
+/// How many messages `Profile::Auto` reads to guess how many links a sort's messages hold.
+#[cfg(feature = "json")]
+const AUTO_SAMPLES: usize = 64;
+
+/// Builds a configured sort. `SortBuilder::new().sort(msgs)` is the same as `causal_sort(msgs)`.
+#[derive(Clone, Default)]
+pub struct SortBuilder {
+    backend: Backend,
+    auto: bool,
+    forks: Forks,
+    edges: Edges,
+    exclude_types: Vec<String>,
+    exclude_keys: Option<Arc<HashSet<Multihash>>>,
+    exclude_authors: Vec<String>,
+    sequence_edges: bool,
+    time_window: Option<(Range<u64>, Window)>,
+    tie_break: TieBreak,
+    metrics: Option<Arc<dyn Metrics>>,
+    strict: bool,
+    cancelled: Option<Arc<AtomicBool>>,
+    max_links: Option<usize>,
+    memory_budget: Option<usize>,
+    internal_links_only: bool,
+    known_links: Option<Arc<[(Multihash, Multihash)]>>,
+    #[cfg(feature = "json")]
+    link_formats: Option<Arc<[LinkFormat]>>,
+    #[cfg(feature = "json")]
+    unknown_hashes: Option<ReadUnknown>,
+    #[cfg(feature = "json")]
+    envelopes: bool,
+    #[cfg(feature = "json")]
+    trust_links: Option<TrustLinks>,
+    #[cfg(feature = "json")]
+    validate: Option<(Arc<dyn Validate>, InvalidMessages)>,
+    #[cfg(feature = "verify-keys")]
+    verify_keys: bool,
+    single_thread: bool,
+    #[cfg(feature = "rayon")]
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl fmt::Debug for SortBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("SortBuilder");
+        debug
+            .field("backend", &self.backend)
+            .field("auto", &self.auto)
+            .field("forks", &self.forks)
+            .field("edges", &self.edges)
+            .field("exclude_types", &self.exclude_types)
+            .field(
+                "exclude_keys",
+                &self.exclude_keys.as_ref().map(|keys| keys.len()),
+            )
+            .field("exclude_authors", &self.exclude_authors)
+            .field("sequence_edges", &self.sequence_edges)
+            .field("time_window", &self.time_window)
+            .field("tie_break", &self.tie_break)
+            .field("metrics", &self.metrics.is_some())
+            .field("strict", &self.strict)
+            .field("cancelled", &self.cancelled)
+            .field("max_links", &self.max_links)
+            .field("memory_budget", &self.memory_budget)
+            .field("internal_links_only", &self.internal_links_only)
+            .field(
+                "known_links",
+                &self.known_links.as_ref().map(|links| links.len()),
+            );
+        #[cfg(feature = "json")]
+        debug.field("link_formats", &self.link_formats);
+        #[cfg(feature = "json")]
+        debug.field("unknown_hashes", &self.unknown_hashes.is_some());
+        #[cfg(feature = "json")]
+        debug.field("envelopes", &self.envelopes);
+        #[cfg(feature = "json")]
+        debug.field("trust_links", &self.trust_links.is_some());
+        #[cfg(feature = "json")]
+        debug.field(
+            "validate",
+            &self.validate.as_ref().map(|(_, invalid)| invalid),
+        );
+        #[cfg(feature = "verify-keys")]
+        debug.field("verify_keys", &self.verify_keys);
+        debug.field("single_thread", &self.single_thread);
+        #[cfg(feature = "rayon")]
+        debug.field("thread_pool", &self.thread_pool);
+        debug.finish()
+    }
+}
+
+impl SortBuilder {
+    pub fn new() -> SortBuilder {
+        SortBuilder::default()
+    }
+
+    /// Set the options that `profile` stands for. Options set afterwards override them.
+    pub fn profile(self, profile: Profile) -> SortBuilder {
+        match profile {
+            Profile::LowMemory => self
+                .backend(Backend::Csr)
+                .max_links(Profile::LOW_MEMORY_MAX_LINKS)
+                .single_thread(true),
+            Profile::Auto => SortBuilder { auto: true, ..self },
+        }
+    }
+
+    /// Choose the graph representation. Defaults to `Backend::Daggy`.
+    pub fn backend(mut self, backend: Backend) -> SortBuilder {
+        self.backend = backend;
+        self.auto = false;
+        self
+    }
+
+    /// Choose what to do with the links in `fork` fields. Defaults to `Forks::Follow`. Doesn't
+    /// affect messages with precomputed links.
+    pub fn forks(mut self, forks: Forks) -> SortBuilder {
+        self.forks = forks;
+        self
+    }
+
+    /// Choose which classes of links to sort by. Defaults to `Edges::All`.
+    ///
+    /// Messages with a `content` field, or a `value.content` field for messages with their keys,
+    /// are message values. Any other message is taken to be content. Doesn't affect messages with
+    /// precomputed links.
+    pub fn edges(mut self, edges: Edges) -> SortBuilder {
+        self.edges = edges;
+        self
+    }
+
+    /// Leave out messages of any of `types`, eg. `&["vote"]` to keep reactions from being sorted
+    /// in among replies. They aren't in the result, and their links are ignored.
+    ///
+    /// A message's type is its `type` field, or that of its `content` for message values, or of
+    /// its `value.content` for messages with their keys. Doesn't affect messages with precomputed
+    /// links, which can be filtered before sorting.
+    pub fn exclude_types(mut self, types: &[&str]) -> SortBuilder {
+        self.exclude_types = types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    /// Leave out the messages with any of `keys`, eg. messages a user has blocked. They aren't in
+    /// the result, and their links are ignored, though links to them from other messages still
+    /// order those messages. This applies to messages with precomputed links too.
+    pub fn exclude_keys(mut self, keys: &[Multihash]) -> SortBuilder {
+        self.exclude_keys = Some(Arc::new(keys.iter().cloned().collect()));
+        self
+    }
+
+    /// Leave out the messages by any of `authors`, eg. `&["@<base64>.ed25519"]` for a blocked
+    /// feed. They aren't in the result, and their links are ignored, as with
+    /// [`exclude_keys`](SortBuilder::exclude_keys).
+    ///
+    /// A message's author is its `author` field, or that of its `value` for messages with their
+    /// keys. Doesn't affect messages with precomputed links, whose authors aren't known, and
+    /// which can be excluded by key instead.
+    pub fn exclude_authors(mut self, authors: &[&str]) -> SortBuilder {
+        self.exclude_authors = authors.iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    /// Sort each author's messages by their sequence numbers, as if every message linked to the
+    /// one before it in its author's feed. Off by default.
+    ///
+    /// A message's author and sequence number are its `author` and `sequence` fields, or those of
+    /// its `value` for messages with their keys. Messages without both are sorted by their links
+    /// alone, as are messages with precomputed links. A message is only ordered after the one
+    /// before it in its feed if both are being sorted.
+    pub fn sequence_edges(mut self, sequence_edges: bool) -> SortBuilder {
+        self.sequence_edges = sequence_edges;
+        self
+    }
+
+    /// Only sort the messages whose claimed timestamps are on the `keep` side of `range`, in
+    /// milliseconds since the epoch. The other messages aren't in the result, but their links
+    /// still order the messages that are, so a message sorted after one outside the window is
+    /// still sorted after whatever that one links to.
+    ///
+    /// A message's timestamp is its `value.timestamp` field, or its `timestamp` for message
+    /// values. Messages without a numeric timestamp are inside any window, and messages with
+    /// precomputed links aren't affected.
+    pub fn time_window(mut self, range: Range<u64>, keep: Window) -> SortBuilder {
+        self.time_window = Some((range, keep));
+        self
+    }
+
+    /// Choose how to order concurrent messages. Defaults to `TieBreak::Input`.
+    pub fn tie_break(mut self, tie_break: TieBreak) -> SortBuilder {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Report what each sort does to `metrics`.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> SortBuilder {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Fail with `Error::Parse`, `Error::TooDeep` or `Error::DuplicateKey` rather than sorting
+    /// messages that aren't valid JSON, or that repeat the key of an earlier message. Off by
+    /// default, when invalid messages are sorted as having no links and only the first message
+    /// with each key is sorted. Messages with precomputed links are only checked for repeated
+    /// keys.
+    pub fn strict(mut self, strict: bool) -> SortBuilder {
+        self.strict = strict;
+        self
+    }
+
+    /// Stop sorting with `Error::Cancelled` once `cancelled` is set, eg. from another thread.
+    /// It's checked before each message is added to the dag.
+    pub fn cancellation(mut self, cancelled: Arc<AtomicBool>) -> SortBuilder {
+        self.cancelled = Some(cancelled);
+        self
+    }
+
+    /// Sort each message by only the first `max` hashes it links to, ignoring the rest, to bound
+    /// the memory a message crafted with huge numbers of links can take. Unlimited by default.
+    /// How many messages had links ignored is reported to the metrics.
+    ///
+    /// A hash linked to more than once only counts once. This applies to messages with
+    /// precomputed links too, and to the links of messages outside a time window.
+    pub fn max_links(mut self, max: usize) -> SortBuilder {
+        self.max_links = Some(max);
+        self
+    }
+
+    /// Fail with [`Error::OverBudget`] rather than let the graph grow past `bytes`, eg. to stay
+    /// clear of the memory limits of a phone. Unlimited by default.
+    ///
+    /// The graph's memory is estimated from the space its nodes, links and hashes have been given
+    /// as it's built. Sorting it takes some more on top, about as much again for `Backend::Csr`.
+    /// [`max_links`](SortBuilder::max_links) bounds how much a single message can add.
+    pub fn memory_budget(mut self, bytes: usize) -> SortBuilder {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Leave out links to hashes that aren't the key of one of the messages being sorted, rather
+    /// than giving each of them a node. Defaults to `false`.
+    ///
+    /// Messages are only ordered by the paths of links between them, and no path goes through a
+    /// message that isn't being sorted, so the sort is still causal. Concurrent messages may come
+    /// in a different order, except with `TieBreak::Canonical`. When most links are to messages
+    /// that haven't been replicated, this makes the graph far smaller, for the cost of a set of
+    /// the keys.
+    pub fn internal_links_only(mut self, internal_links_only: bool) -> SortBuilder {
+        self.internal_links_only = internal_links_only;
+        self
+    }
+
+    /// Sort as if each `(from, to)` in `links` were a link from the message with key `from` to
+    /// the one with key `to`, on top of the messages' own links, eg. for links known from
+    /// elsewhere between messages that haven't been replicated. None by default.
+    ///
+    /// A message that links to a missing message is then sorted after the messages that one is
+    /// known to link to, and so on through any others that are missing. Links from messages being
+    /// sorted count too, and a known link that closes a cycle is an `Error::Cycle` like any other.
+    /// With [`internal_links_only`](SortBuilder::internal_links_only), links to the `from` of a
+    /// known link are kept, so the order goes through them. This applies to messages with
+    /// precomputed links too.
+    pub fn known_links(mut self, links: &[(Multihash, Multihash)]) -> SortBuilder {
+        self.known_links = Some(links.into());
+        self
+    }
+
+    /// Read links in message bodies only in `formats`, rather than in the legacy
+    /// [`LinkFormat::legacy`] forms, eg. for a fork of the protocol with its own sigils or
+    /// suffixes. Pass the legacy forms too to read them as well. SSB URIs are read either way.
+    /// Doesn't affect messages with precomputed links, or how their keys are given.
+    #[cfg(feature = "json")]
+    pub fn link_formats(mut self, formats: &[LinkFormat]) -> SortBuilder {
+        self.link_formats = Some(formats.into());
+        self
+    }
+
+    /// Read links with hashes this crate can't, eg. of an algorithm newer than it, with `read`.
+    ///
+    /// Links are normally `sha256` hashes, in the legacy form or as SSB URIs. Any other string
+    /// shaped like a link to a message or blob is handed to `read`, which returns the hash it
+    /// stands for, or `None` if it isn't a link after all. Give the keys of messages with such
+    /// hashes as the same `Multihash`s `read` returns, eg. by hashing the link down to 32 bytes,
+    /// and they sort like any other. Without this, those strings are never links. Doesn't affect
+    /// messages with precomputed links.
+    #[cfg(feature = "json")]
+    pub fn unknown_hashes<F>(mut self, read: F) -> SortBuilder
+    where
+        F: Fn(&UnknownHash) -> Option<Multihash> + Send + Sync + 'static,
+    {
+        self.unknown_hashes = Some(ReadUnknown(Arc::new(read)));
+        self
+    }
+
+    /// Take messages to be `{ "key", "value", "timestamp" }` envelopes, as `createHistoryStream`
+    /// sends them, and find links only in their `value`s, rather than also taking a message's
+    /// own key as a link. Messages without a `value` are searched whole. Defaults to `false`.
+    /// [`sort_envelopes`](SortBuilder::sort_envelopes) also reads the keys from the envelopes.
+    #[cfg(feature = "json")]
+    pub fn envelopes(mut self, envelopes: bool) -> SortBuilder {
+        self.envelopes = envelopes;
+        self
+    }
+
+    /// Only sort by the links of messages `trusted` accepts, given each message's author and
+    /// type, eg. to keep a flood of spam from reshaping the order of the threads it replies to.
+    /// Every message's links are trusted by default.
+    ///
+    /// A message's author is its `author` field, or that of its `value` for messages with their
+    /// keys, and its type is as for [`exclude_types`](SortBuilder::exclude_types). Messages whose
+    /// links aren't trusted are still sorted, as if they linked to nothing and weren't in their
+    /// author's feed for [`sequence_edges`](SortBuilder::sequence_edges), so they're only
+    /// ordered by the trusted messages that link to them. Doesn't affect messages with
+    /// precomputed links, which can be filtered before sorting.
+    #[cfg(feature = "json")]
+    pub fn trust_links<F>(mut self, trusted: F) -> SortBuilder
+    where
+        F: Fn(Option<&str>, Option<&str>) -> bool + Send + Sync + 'static,
+    {
+        self.trust_links = Some(TrustLinks(Arc::new(trusted)));
+        self
+    }
+
+    /// Check each message with `validate` before its links are added, and deal with the invalid
+    /// ones as `invalid` says. Messages of excluded types are checked too. Doesn't affect
+    /// messages with precomputed links, which have no values to check.
+    #[cfg(feature = "json")]
+    pub fn validate(
+        mut self,
+        validate: Arc<dyn Validate>,
+        invalid: InvalidMessages,
+    ) -> SortBuilder {
+        self.validate = Some((validate, invalid));
+        self
+    }
+
+    /// Fail with `Error::KeyMismatch` on a message whose key isn't the hash of its value, as
+    /// [`message_key`](crate::message_key) computes it. Defaults to `false`.
+    ///
+    /// A forged message can claim any key, and so take the place of a real message in the
+    /// order, or link to messages published after it. Checking costs a hash of every message,
+    /// and needs each to be given exactly as it was signed. Doesn't affect messages with
+    /// precomputed links, which have no values to hash.
+    #[cfg(feature = "verify-keys")]
+    pub fn verify_keys(mut self, verify_keys: bool) -> SortBuilder {
+        self.verify_keys = verify_keys;
+        self
+    }
+
+    /// Sort on the calling thread only, even where the sort would otherwise use many: large dags
+    /// with `TieBreak::Layers`, and `par_sort`, which then builds the dag as `sort` does. For
+    /// devices with few cores to spare, eg. phones. Off by default. Without the `rayon` feature,
+    /// every sort runs on the calling thread anyway.
+    pub fn single_thread(mut self, single_thread: bool) -> SortBuilder {
+        self.single_thread = single_thread;
+        self
+    }
+
+    /// Run the parts of the sort that use many threads on `pool`, rather than rayon's global pool:
+    /// the sort of large dags with `TieBreak::Layers`, and the build of the dag with
+    /// [`par_sort`](SortBuilder::par_sort). The rest runs on the calling thread.
+    #[cfg(feature = "rayon")]
+    pub fn thread_pool(mut self, pool: Arc<rayon::ThreadPool>) -> SortBuilder {
+        self.thread_pool = Some(pool);
+        self
+    }
+
+    /// Causally sort `msgs`, returning their key ids newest first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the messages' links form a cycle, or for any of the other errors
+    /// [`try_sort`](SortBuilder::try_sort) returns.
+    #[cfg(feature = "json")]
+    pub fn sort<T: AsRef<str>, K: Clone>(&self, msgs: &[(Multihash, K, T)]) -> SortedMessages<K> {
+        error::unwrap(self.try_sort(msgs))
+    }
+
+    /// Like [`sort`](SortBuilder::sort), but returns an error rather than panicking. This never
+    /// panics, whatever the messages contain.
+    #[cfg(feature = "json")]
+    pub fn try_sort<T: AsRef<str>, K: Clone>(
+        &self,
+        msgs: &[(Multihash, K, T)],
+    ) -> Result<SortedMessages<K>, Error> {
+        let mut sorted = Vec::new();
+        self.try_sort_into(msgs, |key_id| {
+            sorted.push(key_id);
+            ControlFlow::Continue(())
+        })?;
+        Ok(sorted.into())
+    }
+
+    /// Like [`sort`](SortBuilder::sort), but build the dag on many threads, as
+    /// [`par_causal_sort`](crate::par_causal_sort) does, on the builder's
+    /// [`thread_pool`](SortBuilder::thread_pool) if it has one.
+    ///
+    /// The result is always the same as `sort`'s. Only a dag of every link in the messages, as
+    /// `par_causal_sort` finds them, can be built in parallel, so with any option that changes
+    /// which messages or links are sorted, checks them, or measures the sort, eg.
+    /// [`strict`](SortBuilder::strict), [`max_links`](SortBuilder::max_links) or
+    /// [`metrics`](SortBuilder::metrics), or with [`single_thread`](SortBuilder::single_thread),
+    /// the dag is built as `sort` builds it, on the calling thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the messages' links form a cycle. Use [`try_par_sort`](SortBuilder::try_par_sort)
+    /// to handle this as an error.
+    #[cfg(feature = "rayon")]
+    pub fn par_sort<T, K>(&self, msgs: &[(Multihash, K, T)]) -> SortedMessages<K>
+    where
+        T: AsRef<str> + Sync,
+        K: Clone + Send + Sync,
+    {
+        error::unwrap(self.try_par_sort(msgs))
+    }
+
+    /// Like [`par_sort`](SortBuilder::par_sort), but returns an error rather than panicking.
+    #[cfg(feature = "rayon")]
+    pub fn try_par_sort<T, K>(&self, msgs: &[(Multihash, K, T)]) -> Result<SortedMessages<K>, Error>
+    where
+        T: AsRef<str> + Sync,
+        K: Clone + Send + Sync,
+    {
+        let small =
+            self.auto && self.backend_for(msgs.len(), || sampled_links(msgs)) == Backend::Daggy;
+        if small || self.single_thread || !self.builds_every_link() {
+            return self.try_sort(msgs);
+        }
+        let graph = match &self.thread_pool {
+            Some(pool) => pool.install(|| par_build(msgs))?,
+            None => par_build(msgs)?,
+        };
+        Ok(graph.sorted_with(self.tie_break, self.threads()))
+    }
+
+    /// Whether every message and every link in it is sorted, unchecked and unmeasured, so that
+    /// the dag `par_build` builds is the one `sort` would.
+    #[cfg(feature = "rayon")]
+    fn builds_every_link(&self) -> bool {
+        #[cfg(feature = "verify-keys")]
+        if self.verify_keys {
+            return false;
+        }
+        self.forks == Forks::default()
+            && self.edges == Edges::default()
+            && self.exclude_types.is_empty()
+            && self.exclude_keys.is_none()
+            && self.exclude_authors.is_empty()
+            && !self.sequence_edges
+            && self.time_window.is_none()
+            && self.metrics.is_none()
+            && !self.strict
+            && self.cancelled.is_none()
+            && self.max_links.is_none()
+            && self.memory_budget.is_none()
+            && !self.internal_links_only
+            && self.known_links.is_none()
+            && self.link_formats.is_none()
+            && self.unknown_hashes.is_none()
+            && !self.envelopes
+            && self.trust_links.is_none()
+            && self.validate.is_none()
+    }
+
+    /// Like [`sort`](SortBuilder::sort), but give each `K` to `sink` in order as it's sorted,
+    /// rather than collecting them, eg. to send them down a channel or write them out. Sorting
+    /// stops as soon as `sink` returns `ControlFlow::Break`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the messages' links form a cycle, or for any of the other errors
+    /// [`try_sort_into`](SortBuilder::try_sort_into) returns.
+    #[cfg(feature = "json")]
+    pub fn sort_into<T: AsRef<str>, K: Clone, F: FnMut(K) -> ControlFlow<()>>(
+        &self,
+        msgs: &[(Multihash, K, T)],
+        sink: F,
+    ) {
+        error::unwrap(self.try_sort_into(msgs, sink))
+    }
+
+    /// Like [`sort_into`](SortBuilder::sort_into), but returns an error rather than panicking.
+    /// With [`Backend::Csr`] and [`TieBreak::Input`], a cycle is only found after `sink` has been
+    /// given every message that sorts before it.
+    #[cfg(feature = "json")]
+    pub fn try_sort_into<T: AsRef<str>, K: Clone, F: FnMut(K) -> ControlFlow<()>>(
+        &self,
+        msgs: &[(Multihash, K, T)],
+        sink: F,
+    ) -> Result<(), Error> {
+        let started = Instant::now();
+        let keys = self.keys(msgs);
+        let checks = self.checks(keys.as_ref());
+        let extracted = msgs
+            .iter()
+            .map(|(key, key_id, msg)| (key, key_id.clone(), msg.as_ref().as_bytes()));
+
+        let sorted = match self.backend_for(msgs.len(), || sampled_links(msgs)) {
+            Backend::Daggy => {
+                let (mut graph, threads) = self.daggy();
+                graph
+                    .extend(extracted, checks, self.link_options())
+                    .map(|built| {
+                        let built_at = Instant::now();
+                        graph.sort_into(self.tie_break, threads, sink);
+                        ((), built, built_at)
+                    })
+            }
+            Backend::Csr => {
+                let mut graph = CsrBuilder::new();
+                graph
+                    .extend(extracted, checks, self.link_options())
+                    .and_then(|built| {
+                        let sorted = graph.finish();
+                        let built_at = Instant::now();
+                        sorted.sort_into(self.tie_break, self.threads(), sink)?;
+                        Ok(((), built, built_at))
+                    })
+            }
+        };
+        self.report(msgs.len(), started, sorted)
+    }
+
+    /// Like [`try_sort`](SortBuilder::try_sort), but on tokio's blocking threads, so that async
+    /// code can wait for a sort without holding up its runtime, as with
+    /// [`spawn_sort`](crate::spawn_sort).
+    ///
+    /// Dropping or aborting the `JoinHandle` doesn't stop a sort that's begun. To stop it, set
+    /// the builder's [`cancellation`](SortBuilder::cancellation), and it ends with
+    /// `Error::Cancelled`. A panic in one of the builder's hooks, eg. its
+    /// [`validate`](SortBuilder::validate), isn't re-raised: the handle gives it as a `JoinError`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_sort<T, K>(
+        &self,
+        msgs: Vec<(Multihash, K, T)>,
+    ) -> JoinHandle<Result<SortedMessages<K>, Error>>
+    where
+        T: AsRef<str> + Send + 'static,
+        K: Clone + Send + 'static,
+    {
+        let builder = self.clone();
+        tokio::task::spawn_blocking(move || builder.try_sort(&msgs))
+    }
+
+    /// A [`SortSink`] to send messages into and a [`SortStream`] their key ids come out of, sorted
+    /// with these options, for putting a sort in the middle of a pipeline, eg. with
+    /// `messages.forward(sink)`.
+    ///
+    /// Messages are sorted in batches of up to `capacity`. A batch is sorted when it's full or
+    /// the sink is closed, and its key ids are given newest first. Until the stream has given
+    /// every key id of one batch, the sink won't take the message that starts the next one, so
+    /// at most `capacity` messages and one batch of key ids are held at once. Each batch is
+    /// sorted on its own, as [`BatchIndexer`](crate::BatchIndexer) sorts them, so key ids only
+    /// come out in a causal order of every message if the sender sends messages before the
+    /// messages that link to them, eg. in feed order, or if they all fit in one batch.
+    ///
+    /// A batch that can't be sorted is one `Err` from the stream in place of its key ids. The
+    /// stream ends once the sink's closed and every batch has been given. If the sink is dropped
+    /// without being closed, the batch it was sending is lost and the stream ends with
+    /// `Error::Cancelled`. If the stream is dropped, the sink fails with `Error::Cancelled`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    #[cfg(feature = "futures")]
+    pub fn sort_channel<K: Clone, T: AsRef<str>>(
+        &self,
+        capacity: usize,
+    ) -> (SortSink<K, T>, SortStream<K, T>) {
+        channel(self.clone(), capacity)
+    }
+
+    /// Like [`sort`](SortBuilder::sort), but for `{ "key", "value", "timestamp" }` envelopes, as
+    /// `createHistoryStream` sends them, reading each message's key from its envelope and its
+    /// links from its `value`, as with [`envelopes`](SortBuilder::envelopes).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the messages' links form a cycle, or for any of the other errors
+    /// [`try_sort_envelopes`](SortBuilder::try_sort_envelopes) returns.
+    #[cfg(feature = "json")]
+    pub fn sort_envelopes<T: AsRef<str>, K: Clone>(&self, msgs: &[(K, T)]) -> SortedMessages<K> {
+        error::unwrap(self.try_sort_envelopes(msgs))
+    }
+
+    /// Like [`sort_envelopes`](SortBuilder::sort_envelopes), but returns an error rather than
+    /// panicking. Envelopes without a message key are left out, or with
+    /// [`strict`](SortBuilder::strict) are an `Error::Parse`.
+    #[cfg(feature = "json")]
+    pub fn try_sort_envelopes<T: AsRef<str>, K: Clone>(
+        &self,
+        msgs: &[(K, T)],
+    ) -> Result<SortedMessages<K>, Error> {
+        let mut keyed = Vec::with_capacity(msgs.len());
+        for (index, (key_id, msg)) in msgs.iter().enumerate() {
+            match envelope_key(msg.as_ref()) {
+                Some(key) => keyed.push((key, key_id.clone(), msg.as_ref())),
+                None if self.strict => {
+                    return Err(Error::Parse {
+                        index,
+                        source: "the message has no key".into(),
+                    })
+                }
+                None => (),
+            }
+        }
+        self.clone().envelopes(true).try_sort(&keyed)
+    }
+
+    /// Like [`sort`](SortBuilder::sort), but for messages whose links have already been found, as
+    /// with [`causal_sort_links`](crate::causal_sort_links).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the messages' links form a cycle, or for any of the other errors
+    /// [`try_sort_links`](SortBuilder::try_sort_links) returns.
+    pub fn sort_links<L: AsRef<[Multihash]>, K: Clone>(
+        &self,
+        msgs: &[(Multihash, K, L)],
+    ) -> SortedMessages<K> {
+        error::unwrap(self.try_sort_links(msgs))
+    }
+
+    /// Like [`sort_links`](SortBuilder::sort_links), but returns an error rather than panicking.
+    pub fn try_sort_links<L: AsRef<[Multihash]>, K: Clone>(
+        &self,
+        msgs: &[(Multihash, K, L)],
+    ) -> Result<SortedMessages<K>, Error> {
+        let started = Instant::now();
+        let keys = self.keys(msgs);
+        let checks = self.checks(keys.as_ref());
+        let links = msgs
+            .iter()
+            .map(|(key, key_id, links)| (key, key_id.clone(), links.as_ref()));
+
+        let links_count = || msgs.iter().map(|(_, _, links)| links.as_ref().len()).sum();
+        let sorted = match self.backend_for(msgs.len(), links_count) {
+            Backend::Daggy => {
+                let (mut graph, threads) = self.daggy();
+                graph.extend_links(links, checks).map(|built| {
+                    let built_at = Instant::now();
+                    let sorted = graph.sorted_with(self.tie_break, threads);
+                    (sorted, built, built_at)
+                })
+            }
+            Backend::Csr => {
+                let mut graph = CsrBuilder::new();
+                graph.extend_links(links, checks).and_then(|built| {
+                    let graph = graph.finish();
+                    let built_at = Instant::now();
+                    let sorted = graph.sorted_with(self.tie_break, self.threads())?;
+                    Ok((sorted, built, built_at))
+                })
+            }
+        };
+        self.report(msgs.len(), started, sorted)
+    }
+
+    /// The graph to build and the threads to sort it on for a sort with `Backend::Daggy`.
+    /// `Profile::Auto` only picks it for small sorts, which don't gain from an interner split
+    /// into shards or from sorting on many threads.
+    fn daggy<K: Clone>(&self) -> (CausalGraph<K>, Threads<'_>) {
+        match self.auto {
+            true => (CausalGraph::unsharded(), Threads::single()),
+            false => (CausalGraph::new(), self.threads()),
+        }
+    }
+
+    /// The backend to sort `count` messages with, which hold about `links()` links.
+    fn backend_for(&self, count: usize, links: impl FnOnce() -> usize) -> Backend {
+        match self.auto {
+            true if count.saturating_add(links()) >= Profile::AUTO_CSR_SIZE => Backend::Csr,
+            true => Backend::Daggy,
+            false => self.backend,
+        }
+    }
+
+    #[cfg(feature = "json")]
+    fn link_options(&self) -> LinkOptions {
+        LinkOptions {
+            ignore_forks: self.forks == Forks::Ignore,
+            exclude_types: self.exclude_types.clone(),
+            exclude_authors: self.exclude_authors.clone(),
+            sequence_edges: self.sequence_edges,
+            edges: self.edges,
+            window: self.time_window.clone(),
+            link_formats: self.link_formats.clone(),
+            unknown_hashes: self.unknown_hashes.clone(),
+            envelopes: self.envelopes,
+            types: self.metrics.is_some(),
+            trust: self.trust_links.clone(),
+        }
+    }
+
+    /// The keys of `msgs`, and the hashes known links are from, if only the links between them
+    /// are to be added.
+    fn keys<'m, K, T>(&'m self, msgs: &'m [(Multihash, K, T)]) -> Option<HashSet<&'m Multihash>> {
+        let known = self.known_links.iter().flat_map(|links| links.iter());
+        match self.internal_links_only {
+            true => Some(
+                msgs.iter()
+                    .map(|(key, _, _)| key)
+                    .chain(known.map(|(from, _)| from))
+                    .collect(),
+            ),
+            false => None,
+        }
+    }
+
+    fn checks<'a>(&'a self, keys: Option<&'a HashSet<&'a Multihash>>) -> Checks<'a> {
+        Checks {
+            strict: self.strict,
+            cancelled: self.cancelled.as_deref(),
+            max_links: self.max_links,
+            memory_budget: self.memory_budget,
+            keys,
+            excluded_keys: self.exclude_keys.as_deref(),
+            known_links: self.known_links.as_deref().unwrap_or_default(),
+            #[cfg(feature = "json")]
+            validate: self
+                .validate
+                .as_ref()
+                .map(|(validate, invalid)| (&**validate, *invalid)),
+            #[cfg(feature = "verify-keys")]
+            verify_keys: self.verify_keys,
+            #[cfg(feature = "json")]
+            timed: self.metrics.is_some(),
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    fn threads(&self) -> Threads<'_> {
+        Threads {
+            pool: self.thread_pool.as_deref(),
+            single: self.single_thread,
+        }
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    fn threads(&self) -> Threads<'_> {
+        Threads::default()
+    }
+
+    /// Tell the metrics how a sort of `messages` that began at `started`, and finished building
+    /// its dag at `built_at`, went.
+    fn report<S>(
+        &self,
+        messages: usize,
+        started: Instant,
+        sorted: Result<(S, Built, Instant), Error>,
+    ) -> Result<S, Error> {
+        if let Some(metrics) = &self.metrics {
+            match &sorted {
+                Ok((_, built, built_at)) => {
+                    let total = started.elapsed();
+                    metrics.messages_sorted(messages);
+                    metrics.parse_failures(built.parse_failures);
+                    metrics.links_capped(built.capped);
+                    metrics.sort_latency(total);
+                    let building = built_at.duration_since(started);
+                    metrics.sort_stats(&SortStats {
+                        messages,
+                        parse_failures: built.parse_failures,
+                        links_capped: built.capped,
+                        extract: built.extract,
+                        build: building.saturating_sub(built.extract),
+                        sort: total.saturating_sub(building),
+                        total,
+                        types: built.types.clone(),
+                    });
+                }
+                Err(Error::Cycle { .. }) => metrics.cycle(),
+                Err(_) => (),
+            }
+        }
+        sorted.map(|(sorted, _, _)| sorted)
+    }
+}
+
+/// Roughly how many message links `msgs` hold, from the links counted in a sample of them spread
+/// through the input.
+#[cfg(feature = "json")]
+fn sampled_links<K, T: AsRef<str>>(msgs: &[(Multihash, K, T)]) -> usize {
+    let step = (msgs.len() / AUTO_SAMPLES).max(1);
+    let (sampled, links) = msgs
+        .iter()
+        .step_by(step)
+        .map(|(_, _, msg)| count_message_links(msg.as_ref().as_bytes()))
+        .fold((0, 0), |(sampled, links), found| {
+            (sampled + 1, links + found)
+        });
+    match sampled {
+        0 => 0,
+        _ => links.saturating_mul(msgs.len()) / sampled,
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::{sampled_links, Backend, Edges, Forks, Profile, SortBuilder, TieBreak, Window};
+    use crate::test_utils::{branching, feed, numbered, thread};
+    #[cfg(feature = "rayon")]
+    use crate::{causal_sort, SortedMessages};
+    use crate::{causal_sort_links, Error};
+    use serde_json::{json, Value};
+    #[cfg(feature = "rayon")]
+    use ssb_multiformats::multihash::Multihash;
+    #[cfg(feature = "rayon")]
+    use std::collections::HashSet;
+    use std::ops::ControlFlow;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn excluded_types_arent_sorted() {
+        let mut msgs = thread();
+        let root = msgs[1].0.clone();
+        let vote = json!({ "type": "vote", "vote": { "link": root } });
+        msgs.insert(0, (numbered(4), 4, vote.to_string()));
+        let value = json!({ "author": "@someone", "content": vote });
+        msgs.insert(2, (numbered(5), 5, value.to_string()));
+        let post = json!({ "type": "post", "root": numbered(5) });
+        msgs.push((numbered(6), 6, post.to_string()));
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert_eq!(builder.sort(&msgs).len(), 6);
+            assert_eq!(builder.exclude_types(&["vote"]).sort(&msgs), [6, 3, 2, 1]);
+        });
+    }
+
+    #[test]
+    fn blocked_keys_and_authors_arent_sorted() {
+        // 4 is by a blocked author and 5 is blocked by key. Each claims to link to 3.
+        let mut msgs = thread();
+        let reply = msgs[2].0.clone();
+        let blocked = json!({ "author": "@blocked", "content": { "branch": reply } });
+        msgs.insert(0, (numbered(4), 4, json!({ "value": blocked }).to_string()));
+        let spam = json!({ "author": "@someone", "content": { "branch": [reply] } });
+        msgs.insert(1, (numbered(5), 5, spam.to_string()));
+        // 6 replies to 4, which is then missing.
+        msgs.push((numbered(6), 6, json!({ "branch": numbered(4) }).to_string()));
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert_eq!(builder.sort(&msgs).len(), 6);
+            let blocking = builder
+                .exclude_authors(&["@blocked"])
+                .exclude_keys(&[numbered(5)]);
+            let sorted = blocking.sort(&msgs);
+            let thread: Vec<_> = sorted.iter().copied().filter(|id| *id != 6).collect();
+            assert_eq!((sorted.len(), thread), (4, vec![3, 2, 1]));
+
+            let links = [
+                (numbered(5), 5, vec![reply.clone()]),
+                (reply.clone(), 3, vec![]),
+            ];
+            assert_eq!(blocking.sort_links(&links), [3]);
+        });
+    }
+
+    #[test]
+    fn ignored_forks_arent_links() {
+        let msgs: Vec<_> = (1..40)
+            .map(|i| {
+                let msg = json!({
+                    "root": numbered(0),
+                    "branch": numbered(i / 3),
+                    "fork": numbered(i - 1),
+                });
+                (numbered(i), i, msg.to_string())
+            })
+            .rev()
+            .collect();
+        let without_forks: Vec<_> = (1..40)
+            .map(|i| (numbered(i), i, vec![numbered(i / 3), numbered(0)]))
+            .rev()
+            .collect();
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert_eq!(
+                builder.clone().forks(Forks::Ignore).sort(&msgs),
+                causal_sort_links(&without_forks)
+            );
+            assert_eq!(
+                builder.sort(&msgs).as_slice(),
+                (1..40).rev().collect::<Vec<_>>().as_slice()
+            );
+        });
+    }
+
+    #[test]
+    fn sequence_edges_order_each_feed() {
+        let msg = |author: &str, sequence: u32| json!({ "author": author, "sequence": sequence });
+        let value = |author: &str, sequence: u32| json!({ "value": msg(author, sequence) });
+        // 4 forks the feed at 3.
+        let positions = [
+            (5, msg("@a", 4)),
+            (1, msg("@a", 1)),
+            (7, value("@b", 2)),
+            (3, msg("@a", 3)),
+            (8, json!({ "author": "@a" })),
+            (2, msg("@a", 2)),
+            (6, value("@b", 1)),
+            (4, msg("@a", 3)),
+        ];
+        let msgs: Vec<_> = positions
+            .iter()
+            .map(|(i, msg)| (numbered(*i), *i, msg.to_string()))
+            .collect();
+        let previous = |i: usize| match i {
+            2 => vec![numbered(1)],
+            3 | 4 => vec![numbered(2)],
+            5 => vec![numbered(4)],
+            7 => vec![numbered(6)],
+            _ => vec![],
+        };
+        let links: Vec<_> = positions
+            .iter()
+            .map(|(i, _)| (numbered(*i), *i, previous(*i)))
+            .collect();
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert_eq!(
+                builder.clone().sequence_edges(true).sort(&msgs),
+                causal_sort_links(&links)
+            );
+            assert_eq!(
+                builder.sort(&msgs),
+                positions.iter().rev().map(|(i, _)| *i).collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn forked_feeds_sort_canonically_in_any_order() {
+        // 2 and 3 fork the feed at 2, and 4 follows them.
+        let msg = |i: usize, sequence: u32| {
+            let msg = json!({ "author": "@a", "sequence": sequence });
+            (numbered(i), i, msg.to_string())
+        };
+        let msgs = [msg(1, 1), msg(2, 2), msg(3, 2), msg(4, 3), msg(5, 4)];
+        let builder = SortBuilder::new()
+            .sequence_edges(true)
+            .tie_break(TieBreak::Canonical);
+        let sorted = builder.sort(&msgs);
+        for rotation in 0..msgs.len() {
+            let mut shuffled = msgs.clone();
+            shuffled.rotate_left(rotation);
+            assert_eq!(builder.sort(&shuffled), sorted);
+            shuffled.reverse();
+            assert_eq!(builder.sort(&shuffled), sorted);
+        }
+    }
+
+    #[test]
+    fn edge_classes_are_chosen_separately() {
+        let root = numbered(1);
+        let value = |i: usize, root: Option<_>| {
+            let content = json!({ "type": "post", "root": root });
+            let value = json!({ "previous": numbered(i - 1), "content": content });
+            (
+                numbered(i),
+                i,
+                json!({ "key": numbered(i), "value": value }).to_string(),
+            )
+        };
+        let root_msg = json!({ "value": { "previous": null, "content": { "type": "post" } } });
+        let mut msgs = vec![(root.clone(), 1, root_msg.to_string())];
+        // 2 is in another thread, and 3 follows it in the feed.
+        msgs.push(value(2, None));
+        msgs.push(value(3, Some(root.clone())));
+        msgs.push((numbered(4), 4, json!({ "root": root }).to_string()));
+        msgs.reverse();
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            let feed = builder.clone().edges(Edges::Feed).sort(&msgs);
+            assert_eq!(
+                feed,
+                causal_sort_links(&[
+                    (numbered(1), 1, vec![]),
+                    (numbered(4), 4, vec![]),
+                    (numbered(2), 2, vec![numbered(1)]),
+                    (numbered(3), 3, vec![numbered(2)]),
+                ])
+            );
+            let content = builder.edges(Edges::Content).sort(&msgs);
+            assert_eq!(
+                content,
+                causal_sort_links(&[
+                    (numbered(4), 4, vec![root.clone()]),
+                    (numbered(3), 3, vec![root.clone()]),
+                    (numbered(2), 2, vec![]),
+                    (numbered(1), 1, vec![]),
+                ])
+            );
+        });
+    }
+
+    #[test]
+    fn sinks_are_given_keys_as_theyre_sorted() {
+        let msgs: Vec<_> = (1..50)
+            .map(|i| {
+                let msg = json!({ "root": numbered(i / 7), "branch": numbered(i - 1) });
+                (numbered(i), i, msg.to_string())
+            })
+            .collect();
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let tie_breaks = [TieBreak::Input, TieBreak::Canonical, TieBreak::Layers];
+            tie_breaks.iter().for_each(|tie_break| {
+                let builder = SortBuilder::new().backend(*backend).tie_break(*tie_break);
+                let sorted = builder.sort(&msgs);
+                let mut given = Vec::new();
+                builder.sort_into(&msgs, |key_id| {
+                    given.push(key_id);
+                    ControlFlow::Continue(())
+                });
+                assert_eq!(sorted, given);
+
+                given.clear();
+                builder.sort_into(&msgs, |key_id| {
+                    given.push(key_id);
+                    match given.len() {
+                        3 => ControlFlow::Break(()),
+                        _ => ControlFlow::Continue(()),
+                    }
+                });
+                assert_eq!(given, sorted[..3]);
+            });
+        });
+
+        let cycle = vec![
+            (
+                numbered(1),
+                1,
+                json!({ "previous": numbered(2) }).to_string(),
+            ),
+            (
+                numbered(2),
+                2,
+                json!({ "previous": numbered(1) }).to_string(),
+            ),
+        ];
+        let result = SortBuilder::new().try_sort_into(&cycle, |_| ControlFlow::Continue(()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn envelopes_are_sorted_by_their_values() {
+        let envelope = |i: usize, value| {
+            let envelope = json!({ "key": numbered(i), "value": value, "timestamp": i });
+            (i, envelope.to_string())
+        };
+        let msgs = vec![
+            envelope(
+                3,
+                json!({ "previous": numbered(2), "content": { "root": numbered(1) } }),
+            ),
+            envelope(
+                1,
+                json!({ "previous": null, "content": { "type": "post" } }),
+            ),
+            envelope(2, json!({ "previous": numbered(1), "content": {} })),
+        ];
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert_eq!(builder.sort_envelopes(&msgs), [3, 2, 1]);
+            assert_eq!(crate::causal_sort_envelopes(&msgs), [3, 2, 1]);
+
+            // Searched whole, each envelope links to itself with its key.
+            let keyed: Vec<_> = msgs
+                .iter()
+                .map(|(i, msg)| (numbered(*i), *i, msg.clone()))
+                .collect();
+            assert!(builder.try_sort(&keyed).is_err());
+            assert_eq!(builder.clone().envelopes(true).sort(&keyed), [3, 2, 1]);
+        });
+
+        let mut msgs = msgs;
+        msgs.push((4, json!({ "value": {} }).to_string()));
+        assert_eq!(SortBuilder::new().sort_envelopes(&msgs), [3, 2, 1]);
+        assert!(matches!(
+            SortBuilder::new().strict(true).try_sort_envelopes(&msgs),
+            Err(crate::Error::Parse { index: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn time_windows_keep_the_links_outside_them() {
+        let msg = |i: usize, timestamp: f64, previous: Option<usize>| {
+            let value = json!({ "previous": previous.map(numbered), "timestamp": timestamp });
+            (numbered(i), i, json!({ "value": value }).to_string())
+        };
+        // 2 claims to be from long before 1, which it links to, and 3 links to 2.
+        let msgs = vec![
+            msg(3, 1700.5, Some(2)),
+            msg(2, 20.0, Some(1)),
+            msg(1, 1500.0, None),
+            (numbered(4), 4, json!({ "type": "post" }).to_string()),
+        ];
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            let inside = builder.clone().time_window(1000..2000, Window::Inside);
+            assert_eq!(inside.sort(&msgs), [4, 3, 1]);
+            let outside = builder.time_window(1000..2000, Window::Outside);
+            assert_eq!(outside.sort(&msgs), [2]);
+        });
+    }
+
+    #[test]
+    fn links_past_the_cap_are_ignored() {
+        // 1's second link would make a cycle.
+        let msgs = vec![
+            (
+                numbered(1),
+                1,
+                json!({ "branch": [numbered(9), numbered(2)] }).to_string(),
+            ),
+            (numbered(2), 2, json!({ "root": numbered(1) }).to_string()),
+        ];
+        let links = vec![
+            (numbered(1), 1, vec![numbered(9), numbered(9), numbered(2)]),
+            (numbered(2), 2, vec![numbered(1)]),
+        ];
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert!(builder.clone().try_sort(&msgs).is_err());
+            assert_eq!(builder.clone().max_links(1).sort(&msgs), [2, 1]);
+            // A repeated link doesn't count towards the cap.
+            assert!(builder.clone().max_links(1).try_sort_links(&links).is_ok());
+            assert!(builder.max_links(2).try_sort_links(&links).is_err());
+        });
+    }
+
+    #[test]
+    fn low_memory_profile_caps_links() {
+        let builder = SortBuilder::new().profile(Profile::LowMemory);
+        assert_eq!(builder.backend, Backend::Csr);
+        assert_eq!(builder.sort(&thread()), SortBuilder::new().sort(&thread()));
+
+        // Only the last link, which is past the cap, makes a cycle.
+        let branch: Vec<_> = (1..=Profile::LOW_MEMORY_MAX_LINKS + 1)
+            .map(numbered)
+            .collect();
+        let msgs = vec![
+            (numbered(0), 0, json!({ "branch": branch }).to_string()),
+            (
+                branch[branch.len() - 1].clone(),
+                1,
+                json!({ "root": numbered(0) }).to_string(),
+            ),
+        ];
+        assert!(SortBuilder::new().try_sort(&msgs).is_err());
+        assert_eq!(builder.sort(&msgs), [1, 0]);
+    }
+
+    /// Sort `msgs` with `builder` and `par_sort` both, on a thread pool whose only thread is
+    /// kept busy, so that a sort that waits on the pool can't finish.
+    #[cfg(feature = "rayon")]
+    fn sort_beside_a_busy_pool(
+        builder: SortBuilder,
+        msgs: Vec<(Multihash, usize, String)>,
+    ) -> SortedMessages<usize> {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let pool = Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(1)
+                .build()
+                .unwrap(),
+        );
+        let (release, busy) = mpsc::channel::<()>();
+        pool.spawn(move || {
+            let _ = busy.recv();
+        });
+        let builder = builder.thread_pool(pool);
+        let (done, sorted) = mpsc::channel();
+        std::thread::spawn(move || {
+            let sorted = (builder.sort(&msgs), builder.par_sort(&msgs));
+            let _ = done.send(sorted);
+        });
+        let sorted = sorted.recv_timeout(Duration::from_secs(60));
+        release.send(()).unwrap();
+        let (sorted, par_sorted) = sorted.expect("the sort waited on the pool");
+        assert_eq!(sorted, par_sorted);
+        sorted
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn low_memory_profile_stays_on_one_thread() {
+        let builder = SortBuilder::new()
+            .tie_break(TieBreak::Layers)
+            .profile(Profile::LowMemory);
+        assert_eq!(sort_beside_a_busy_pool(builder, feed(70_000)).len(), 70_000);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn auto_profile_sorts_small_inputs_on_one_thread() {
+        let builder = SortBuilder::new().profile(Profile::Auto);
+        let sorted = sort_beside_a_busy_pool(builder, feed(100));
+        assert_eq!(sorted, causal_sort(&feed(100)));
+    }
+
+    #[test]
+    fn auto_profile_picks_a_backend_by_size() {
+        let auto = SortBuilder::new().profile(Profile::Auto);
+        let small = thread();
+        assert_eq!(sampled_links(&small), 3);
+        assert_eq!(auto.backend_for(3, || 3), Backend::Daggy);
+        assert_eq!(auto.sort(&small), SortBuilder::new().sort(&small));
+
+        let large = feed(Profile::AUTO_CSR_SIZE / 2 + 1);
+        assert_eq!(
+            auto.backend_for(large.len(), || sampled_links(&large)),
+            Backend::Csr
+        );
+        assert_eq!(auto.sort(&large), SortBuilder::new().sort(&large));
+
+        let links: Vec<_> = (0..Profile::AUTO_CSR_SIZE)
+            .map(|i| (numbered(i), i, vec![numbered(i + 1)]))
+            .collect();
+        assert_eq!(auto.sort_links(&links), causal_sort_links(&links));
+
+        // Choosing a backend afterwards overrides the profile's choice.
+        let daggy = auto.backend(Backend::Daggy);
+        assert_eq!(daggy.backend_for(usize::MAX, || 0), Backend::Daggy);
+    }
+
+    #[test]
+    fn internal_links_only_leave_out_the_rest() {
+        // Replies to a thread, with most of it yet to be replicated.
+        let msgs: Vec<_> = (1..100)
+            .map(|i| {
+                let branch: Vec<_> = (i * 100..i * 100 + 20).map(numbered).collect();
+                let value = json!({ "branch": branch, "previous": numbered(i - 1) });
+                (numbered(i), i, json!({ "value": value }).to_string())
+            })
+            .collect();
+        let causal: Vec<_> = (1..100).rev().collect();
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend).memory_budget(20_000);
+            assert!(builder.try_sort(&msgs).is_err());
+            let internal = builder.internal_links_only(true);
+            assert_eq!(internal.sort(&msgs), causal);
+        });
+
+        let links = [
+            (numbered(1), 1, vec![numbered(9)]),
+            (numbered(2), 2, vec![numbered(9), numbered(1)]),
+        ];
+        let canonical = SortBuilder::new().tie_break(TieBreak::Canonical);
+        assert_eq!(
+            canonical
+                .clone()
+                .internal_links_only(true)
+                .sort_links(&links),
+            canonical.sort_links(&links)
+        );
+    }
+
+    #[test]
+    fn untrusted_links_are_left_out() {
+        let root = numbered(1);
+        let msgs = |spam: Value, vote: Value| {
+            [
+                (numbered(3), 3, json!({ "value": spam }).to_string()),
+                (numbered(4), 4, vote.to_string()),
+                (
+                    root.clone(),
+                    1,
+                    json!({ "author": "@alice", "content": { "type": "post" } }).to_string(),
+                ),
+                (
+                    numbered(2),
+                    2,
+                    json!({ "author": "@bob", "content": { "type": "post", "root": root } })
+                        .to_string(),
+                ),
+            ]
+        };
+        let linked = msgs(
+            json!({ "author": "@spam", "content": { "branch": [numbered(2)] } }),
+            json!({ "author": "@alice", "content": { "type": "vote", "link": numbered(2) } }),
+        );
+        let unlinked = msgs(
+            json!({ "author": "@spam", "content": {} }),
+            json!({ "author": "@alice", "content": { "type": "vote" } }),
+        );
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let trusted = {
+            let seen = seen.clone();
+            move |author: Option<&str>, msg_type: Option<&str>| {
+                let mut seen = seen.lock().unwrap();
+                seen.push((author.map(str::to_owned), msg_type.map(str::to_owned)));
+                author != Some("@spam") && msg_type != Some("vote")
+            }
+        };
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            let trusting = builder.clone().trust_links(trusted.clone());
+            assert_eq!(trusting.sort(&linked), builder.sort(&unlinked));
+            let everyone = builder.clone().trust_links(|_, _| true);
+            assert_eq!(everyone.sort(&linked), builder.sort(&linked));
+        });
+        let seen = seen.lock().unwrap();
+        let author = |author: &str| Some(author.to_owned());
+        assert_eq!(
+            seen[..4],
+            [
+                (author("@spam"), None),
+                (author("@alice"), Some("vote".to_owned())),
+                (author("@alice"), Some("post".to_owned())),
+                (author("@bob"), Some("post".to_owned())),
+            ]
+        );
+
+        // Sequence edges from untrusted messages are left out too.
+        let feed = [
+            (
+                numbered(6),
+                6,
+                json!({ "author": "@spam", "sequence": 2 }).to_string(),
+            ),
+            (
+                numbered(5),
+                5,
+                json!({ "author": "@spam", "sequence": 1 }).to_string(),
+            ),
+        ];
+        let sequenced = SortBuilder::new().sequence_edges(true);
+        assert_eq!(sequenced.sort(&feed), [6, 5]);
+        let untrusted = sequenced.trust_links(|author, _| author != Some("@spam"));
+        assert_eq!(untrusted.sort(&feed), [5, 6]);
+    }
+
+    #[test]
+    fn known_links_order_through_missing_messages() {
+        // 3 links to 2, which isn't here, but is known to link to 9, which links to 1.
+        let msgs = [
+            (numbered(1), 1, json!({}).to_string()),
+            (
+                numbered(3),
+                3,
+                json!({ "previous": numbered(2) }).to_string(),
+            ),
+        ];
+        let known = [(numbered(2), numbered(9)), (numbered(9), numbered(1))];
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            [false, true].iter().for_each(|internal| {
+                let builder = builder.clone().internal_links_only(*internal);
+                let mut reversed = msgs.clone();
+                reversed.reverse();
+                assert_ne!(builder.sort(&msgs), builder.sort(&reversed));
+
+                let known = builder.known_links(&known);
+                assert_eq!(known.sort(&msgs), [3, 1]);
+                assert_eq!(known.sort(&reversed), [3, 1]);
+                let links = [
+                    (numbered(1), 1, vec![]),
+                    (numbered(3), 3, vec![numbered(2)]),
+                ];
+                assert_eq!(known.sort_links(&links), [3, 1]);
+            });
+
+            let cycle = builder.known_links(&[(numbered(2), numbered(3))]);
+            assert!(matches!(cycle.try_sort(&msgs), Err(Error::Cycle { .. })));
+        });
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn layers_sort_on_the_given_pool() {
+        // Enough messages to sort on many threads, each a reply to an earlier one.
+        let links: Vec<_> = (1..70_000)
+            .map(|i| (numbered(i), i, vec![numbered(i / 3)]))
+            .collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let layers = SortBuilder::new().tie_break(TieBreak::Layers);
+        let sorted = layers.clone().thread_pool(pool.into()).sort_links(&links);
+        assert_eq!(sorted, layers.sort_links(&links));
+        assert_eq!(sorted.len(), links.len());
+    }
+
+    /// A message that records the name of each thread that reads it.
+    #[cfg(feature = "rayon")]
+    struct Recorded(String, Arc<Mutex<HashSet<Option<String>>>>);
+
+    #[cfg(feature = "rayon")]
+    impl AsRef<str> for Recorded {
+        fn as_ref(&self) -> &str {
+            let thread = std::thread::current().name().map(str::to_owned);
+            self.1.lock().unwrap().insert(thread);
+            &self.0
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_builds_run_on_the_given_pool() {
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+        let msgs: Vec<_> = feed(3_000)
+            .into_iter()
+            .map(|(key, key_id, msg)| (key, key_id, Recorded(msg, threads.clone())))
+            .collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .thread_name(|index| format!("sort-pool-{}", index))
+            .build()
+            .unwrap();
+        let builder = SortBuilder::new().thread_pool(pool.into());
+        let sorted = builder.par_sort(&msgs);
+        assert_eq!(sorted, builder.sort(&feed(3_000)));
+        let expected: HashSet<_> = std::iter::once(Some("sort-pool-0".to_owned())).collect();
+        assert_eq!(*threads.lock().unwrap(), expected);
+
+        let cycle = [
+            (
+                numbered(1),
+                1,
+                json!({ "previous": numbered(2) }).to_string(),
+            ),
+            (
+                numbered(2),
+                2,
+                json!({ "previous": numbered(1) }).to_string(),
+            ),
+        ];
+        assert!(matches!(
+            builder.try_par_sort(&cycle),
+            Err(Error::Cycle { .. })
+        ));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_sorts_apply_every_option() {
+        let mut msgs = vec![
+            branching(1, &[]),
+            branching(2, &[1]),
+            branching(3, &[1, 2]),
+            (
+                numbered(4),
+                4,
+                json!({ "type": "vote", "root": numbered(3) }).to_string(),
+            ),
+        ];
+        let builders = [
+            SortBuilder::new().exclude_types(&["vote"]),
+            SortBuilder::new().max_links(1),
+            SortBuilder::new()
+                .forks(Forks::Ignore)
+                .tie_break(TieBreak::Canonical),
+        ];
+        for builder in &builders {
+            assert_eq!(builder.par_sort(&msgs), builder.sort(&msgs));
+        }
+
+        msgs.push(branching(2, &[]));
+        let strict = SortBuilder::new().strict(true);
+        assert!(matches!(
+            strict.try_par_sort(&msgs),
+            Err(Error::DuplicateKey { .. })
+        ));
+    }
+
+    #[test]
+    fn canonical_ties_dont_depend_on_input_order() {
+        // Four replies to a root, two of them replied to in turn, and a link to a missing message.
+        let msgs = vec![
+            branching(1, &[]),
+            branching(2, &[1]),
+            branching(3, &[1]),
+            branching(4, &[1, 9]),
+            branching(5, &[1]),
+            branching(6, &[2, 5]),
+            branching(7, &[3]),
+        ];
+        // numbered(n) starts with the nth character of the base64 alphabet, so concurrent
+        // messages come lowest numbered first. Written out, so that a platform that disagrees
+        // fails.
+        let canonical = [4, 6, 2, 5, 7, 3, 1];
+
+        let mut reordered = msgs.clone();
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new()
+                .backend(*backend)
+                .tie_break(TieBreak::Canonical);
+            for _ in 0..msgs.len() {
+                assert_eq!(builder.sort(&reordered), canonical);
+                reordered.rotate_left(3);
+                reordered.reverse();
+            }
+        });
+
+        let layers = SortBuilder::new().tie_break(TieBreak::Layers);
+        assert_eq!(layers.sort(&msgs), [4, 6, 7, 2, 3, 5, 1]);
+        assert_eq!(
+            layers.clone().backend(Backend::Csr).sort(&msgs),
+            layers.sort(&msgs)
+        );
+
+        let links: Vec<_> = msgs
+            .iter()
+            .map(|(key, i, _)| (key.clone(), *i, vec![]))
+            .collect();
+        let unlinked = SortBuilder::new()
+            .tie_break(TieBreak::Canonical)
+            .sort_links(&links);
+        assert_eq!(unlinked, [1, 2, 3, 4, 5, 6, 7]);
+    }
+}
@@ -0,0 +1,286 @@
+//! Sorting in the middle of a futures pipeline, with a [`Sink`] that messages are sent into and
+//! a [`Stream`] that their sorted keys come out of.
+use crate::builder::SortBuilder;
+use crate::error::Error;
+use futures_core::Stream;
+use futures_sink::Sink;
+use ssb_multiformats::multihash::Multihash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+/// Sort the messages sent into a [`SortSink`], giving their key ids out of a [`SortStream`], as
+/// [`SortBuilder::sort_channel`] does with the default options.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+pub fn sort_channel<K: Clone, T: AsRef<str>>(
+    capacity: usize,
+) -> (SortSink<K, T>, SortStream<K, T>) {
+    SortBuilder::new().sort_channel(capacity)
+}
+
+/// What a [`SortSink`] and its [`SortStream`] share.
+struct Shared<K, T> {
+    builder: SortBuilder,
+    capacity: usize,
+    /// The messages of the batch being sent.
+    batch: Vec<(Multihash, K, T)>,
+    /// The key ids of the last batch sorted that the stream hasn't given yet.
+    sorted: std::vec::IntoIter<K>,
+    /// Why the last batch couldn't be sorted, until the stream gives it.
+    error: Option<Error>,
+    /// Whether the sink has sorted its last batch, or been dropped without closing.
+    closed: bool,
+    /// Whether the stream's been dropped, so there's no point sorting.
+    stream_dropped: bool,
+    sink_waker: Option<Waker>,
+    stream_waker: Option<Waker>,
+}
+
+impl<K: Clone, T: AsRef<str>> Shared<K, T> {
+    /// Whether the stream has given every key id sorted so far, so another batch can be sorted.
+    fn drained(&self) -> bool {
+        self.sorted.len() == 0 && self.error.is_none()
+    }
+
+    /// Sort the batch for the stream to give.
+    fn sort_batch(&mut self) {
+        match self.builder.try_sort(&self.batch) {
+            Ok(sorted) => self.sorted = sorted.into_vec().into_iter(),
+            Err(error) => self.error = Some(error),
+        }
+        self.batch.clear();
+        if let Some(waker) = self.stream_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn lock<K, T>(shared: &Mutex<Shared<K, T>>) -> MutexGuard<'_, Shared<K, T>> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// See [`SortBuilder::sort_channel`].
+pub(crate) fn channel<K, T>(
+    builder: SortBuilder,
+    capacity: usize,
+) -> (SortSink<K, T>, SortStream<K, T>) {
+    assert!(capacity > 0, "A sort channel needs room for a message");
+    let shared = Arc::new(Mutex::new(Shared {
+        builder,
+        capacity,
+        batch: Vec::new(),
+        sorted: Vec::new().into_iter(),
+        error: None,
+        closed: false,
+        stream_dropped: false,
+        sink_waker: None,
+        stream_waker: None,
+    }));
+    (
+        SortSink {
+            shared: shared.clone(),
+        },
+        SortStream { shared },
+    )
+}
+
+/// Takes messages to sort, from [`SortBuilder::sort_channel`].
+pub struct SortSink<K, T> {
+    shared: Arc<Mutex<Shared<K, T>>>,
+}
+
+impl<K: Clone, T: AsRef<str>> Sink<(Multihash, K, T)> for SortSink<K, T> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let mut shared = lock(&self.shared);
+        if shared.stream_dropped {
+            return Poll::Ready(Err(Error::Cancelled));
+        }
+        if shared.batch.len() < shared.capacity {
+            return Poll::Ready(Ok(()));
+        }
+        if shared.drained() {
+            shared.sort_batch();
+            return Poll::Ready(Ok(()));
+        }
+        shared.sink_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: (Multihash, K, T)) -> Result<(), Error> {
+        lock(&self.shared).batch.push(msg);
+        Ok(())
+    }
+
+    /// Messages are held until their batch is full or the sink is closed, so there's nothing to
+    /// flush.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let mut shared = lock(&self.shared);
+        if shared.closed || shared.stream_dropped {
+            return Poll::Ready(Ok(()));
+        }
+        if !shared.drained() {
+            shared.sink_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if !shared.batch.is_empty() {
+            shared.sort_batch();
+        }
+        shared.closed = true;
+        if let Some(waker) = shared.stream_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<K, T> Drop for SortSink<K, T> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        if !shared.closed {
+            shared.closed = true;
+            shared.batch.clear();
+            if shared.error.is_none() {
+                shared.error = Some(Error::Cancelled);
+            }
+        }
+        if let Some(waker) = shared.stream_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Gives the key ids of the messages sent to its [`SortSink`], from
+/// [`SortBuilder::sort_channel`].
+pub struct SortStream<K, T> {
+    shared: Arc<Mutex<Shared<K, T>>>,
+}
+
+impl<K: Clone, T: AsRef<str>> Stream for SortStream<K, T> {
+    type Item = Result<K, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<K, Error>>> {
+        let mut shared = lock(&self.shared);
+        let next = match shared.sorted.next() {
+            Some(key_id) => Some(Ok(key_id)),
+            None => shared.error.take().map(Err),
+        };
+        match next {
+            Some(next) => {
+                if shared.drained() {
+                    if let Some(waker) = shared.sink_waker.take() {
+                        waker.wake();
+                    }
+                }
+                Poll::Ready(Some(next))
+            }
+            None if shared.closed => Poll::Ready(None),
+            None => {
+                shared.stream_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<K, T> Drop for SortStream<K, T> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.stream_dropped = true;
+        if let Some(waker) = shared.sink_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sort_channel;
+    use crate::test_utils::{feed, numbered, thread};
+    use crate::{causal_sort, Error};
+    use futures::executor::block_on;
+    use futures::{future, poll, stream, SinkExt, StreamExt};
+    use serde_json::json;
+    use std::task::Poll;
+
+    #[test]
+    fn messages_sent_in_come_out_sorted() {
+        let (sink, sorted) = sort_channel(100);
+        let sent = stream::iter(thread().into_iter().map(Ok)).forward(sink);
+        let (sent, sorted) = block_on(future::join(sent, sorted.collect::<Vec<_>>()));
+        assert!(sent.is_ok());
+        let sorted: Vec<_> = sorted.into_iter().map(Result::unwrap).collect();
+        assert_eq!(causal_sort(&thread()), sorted);
+
+        let (sink, sorted) = sort_channel(2);
+        let sent = stream::iter(feed(5).into_iter().map(Ok)).forward(sink);
+        let (_, sorted) = block_on(future::join(sent, sorted.collect::<Vec<_>>()));
+        let sorted: Vec<_> = sorted.into_iter().map(Result::unwrap).collect();
+        assert_eq!(sorted, [2, 1, 4, 3, 5]);
+    }
+
+    #[test]
+    fn senders_wait_for_each_batch_to_be_read() {
+        block_on(async {
+            let (mut sink, mut sorted) = sort_channel(2);
+            let mut msgs = feed(5).into_iter();
+            for _ in 0..4 {
+                sink.feed(msgs.next().unwrap()).await.unwrap();
+            }
+            let fifth = msgs.next().unwrap();
+            assert!(poll!(sink.feed(fifth.clone())).is_pending());
+            assert!(matches!(sorted.next().await, Some(Ok(2))));
+            assert!(poll!(sink.feed(fifth.clone())).is_pending());
+            assert!(matches!(sorted.next().await, Some(Ok(1))));
+            assert!(matches!(poll!(sink.feed(fifth)), Poll::Ready(Ok(()))));
+        });
+    }
+
+    #[test]
+    fn failures_end_the_channel() {
+        block_on(async {
+            let (mut sink, sorted) = sort_channel(10);
+            let cycle = vec![
+                (
+                    numbered(1),
+                    1,
+                    json!({ "previous": numbered(2) }).to_string(),
+                ),
+                (
+                    numbered(2),
+                    2,
+                    json!({ "previous": numbered(1) }).to_string(),
+                ),
+            ];
+            sink.send_all(&mut stream::iter(cycle.into_iter().map(Ok)))
+                .await
+                .unwrap();
+            sink.close().await.unwrap();
+            let sorted: Vec<_> = sorted.collect().await;
+            assert!(matches!(sorted[..], [Err(Error::Cycle { .. })]));
+
+            let (mut sink, sorted) = sort_channel(10);
+            sink.feed(thread().remove(0)).await.unwrap();
+            drop(sink);
+            let sorted: Vec<_> = sorted.collect().await;
+            assert!(matches!(sorted[..], [Err(Error::Cancelled)]));
+
+            let (mut sink, sorted) = sort_channel::<u32, String>(10);
+            drop(sorted);
+            assert!(matches!(
+                sink.send(thread().remove(0)).await,
+                Err(Error::Cancelled)
+            ));
+        });
+    }
+}
@@ -0,0 +1,258 @@
+//! Tangle clocks: the analogue of an epidemic broadcast tree's feed clock for tangles.
+//!
+//! An EBT peer sends the latest sequence number it has of each feed it replicates, so the other
+//! end knows which messages to send. Tangles have no sequence numbers, so a tangle clock sends the
+//! heads of each tangle instead: its messages that no other message in the tangle links to. That
+//! is the tangle's frontier, and stands for everything before it.
+use crate::extract::find_hashes;
+use crate::tangles::content_and_root;
+use serde_json::Value;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+
+/// The heads of each tangle in a set of messages.
+#[derive(Clone, Debug, Default)]
+pub struct TangleClock {
+    /// Each tangle's root, and its heads in order.
+    tangles: BTreeMap<Multihash, Vec<Multihash>>,
+    /// The links from each message the clock was built from, for working out what a peer is
+    /// missing. These aren't sent, so are empty for clocks read from bytes.
+    links: HashMap<Multihash, Vec<Multihash>>,
+}
+
+/// Which tangles to send to a peer and which to ask it for, from [`TangleClock::exchange`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Exchange {
+    /// The roots of the tangles with messages the peer hasn't seen.
+    pub send: Vec<Multihash>,
+    /// The roots of the tangles with messages the peer has that we don't.
+    pub receive: Vec<Multihash>,
+}
+
+impl TangleClock {
+    /// The clock of the tangles in `msgs`, found as [`tangle_completeness`] finds them. A tangle's
+    /// root message, if it's in `msgs`, is in the tangle too.
+    ///
+    /// [`tangle_completeness`]: crate::tangle_completeness
+    pub fn from_msgs<T: AsRef<str>, K>(msgs: &[(Multihash, K, T)]) -> TangleClock {
+        let mut links = HashMap::new();
+        let mut members: BTreeMap<Multihash, Vec<Multihash>> = BTreeMap::new();
+        for (key, _, msg) in msgs {
+            let value: Value = serde_json::from_str(msg.as_ref()).unwrap_or_default();
+            if let Some((_, root)) = content_and_root(&value) {
+                members.entry(root).or_default().push(key.clone());
+            }
+            let mut refs = Vec::new();
+            find_hashes(&value, &mut refs);
+            links.entry(key.clone()).or_insert(refs);
+        }
+
+        let tangles = members
+            .into_iter()
+            .map(|(root, mut members)| {
+                if links.contains_key(&root) {
+                    members.push(root.clone());
+                }
+                let linked: HashSet<&Multihash> =
+                    members.iter().flat_map(|member| &links[member]).collect();
+                let mut heads: Vec<_> = members
+                    .iter()
+                    .filter(|member| !linked.contains(member))
+                    .cloned()
+                    .collect();
+                heads.sort_unstable();
+                heads.dedup();
+                (root, heads)
+            })
+            .collect();
+        TangleClock { tangles, links }
+    }
+
+    /// The roots of the tangles in the clock, in order.
+    pub fn roots(&self) -> impl Iterator<Item = &Multihash> {
+        self.tangles.keys()
+    }
+
+    /// The heads of the tangle with `root`, in order, if it's in the clock.
+    pub fn heads(&self, root: &Multihash) -> Option<&[Multihash]> {
+        self.tangles.get(root).map(Vec::as_slice)
+    }
+
+    /// Which tangles to send to the peer with clock `theirs`, and which to ask it for.
+    ///
+    /// Only the tangles in both clocks are compared, as in EBT only the feeds both peers replicate
+    /// are. We want a tangle if the peer has heads we haven't seen. The peer wants it if we have
+    /// messages outside the history of its heads. That history can only be followed through
+    /// messages we've seen, so when the peer is ahead of us, or each has messages the other
+    /// doesn't, the tangle is both sent and received.
+    pub fn exchange(&self, theirs: &TangleClock) -> Exchange {
+        let mut exchange = Exchange::default();
+        for (root, their_heads) in &theirs.tangles {
+            let our_heads = match self.tangles.get(root) {
+                Some(heads) => heads,
+                None => continue,
+            };
+            if their_heads
+                .iter()
+                .any(|head| !self.links.contains_key(head))
+            {
+                exchange.receive.push(root.clone());
+            }
+            let their_history = self.history(theirs, their_heads);
+            if our_heads.iter().any(|head| !their_history.contains(head)) {
+                exchange.send.push(root.clone());
+            }
+        }
+        exchange
+    }
+
+    /// `heads` and every message either clock knows they link to, directly or not.
+    fn history<'a>(
+        &'a self,
+        theirs: &'a TangleClock,
+        heads: &'a [Multihash],
+    ) -> HashSet<&'a Multihash> {
+        let mut seen = HashSet::new();
+        let mut to_visit: Vec<&Multihash> = heads.iter().collect();
+        while let Some(hash) = to_visit.pop() {
+            if seen.insert(hash) {
+                let links = self.links.get(hash).or_else(|| theirs.links.get(hash));
+                to_visit.extend(links.into_iter().flatten());
+            }
+        }
+        seen
+    }
+
+    /// The clock as bytes, to send to a peer: the number of tangles, then each tangle's root, its
+    /// number of heads and its heads. Numbers are little endian `u32`s, and each hash is a byte
+    /// for its kind, 0 for a message and 1 for a blob, then its 32 bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.tangles.len() as u32).to_le_bytes());
+        for (root, heads) in &self.tangles {
+            write_hash(&mut bytes, root);
+            bytes.extend_from_slice(&(heads.len() as u32).to_le_bytes());
+            heads.iter().for_each(|head| write_hash(&mut bytes, head));
+        }
+        bytes
+    }
+
+    /// Read a clock written by [`to_bytes`](TangleClock::to_bytes). `None` if `bytes` isn't one.
+    pub fn from_bytes(mut bytes: &[u8]) -> Option<TangleClock> {
+        let mut tangles = BTreeMap::new();
+        for _ in 0..read_u32(&mut bytes)? {
+            let root = read_hash(&mut bytes)?;
+            let heads = (0..read_u32(&mut bytes)?)
+                .map(|_| read_hash(&mut bytes))
+                .collect::<Option<_>>()?;
+            tangles.insert(root, heads);
+        }
+        if !bytes.is_empty() {
+            return None;
+        }
+        Some(TangleClock {
+            tangles,
+            links: HashMap::new(),
+        })
+    }
+}
+
+fn write_hash(bytes: &mut Vec<u8>, hash: &Multihash) {
+    let (kind, digest) = match hash {
+        Multihash::Message(digest) => (0, digest),
+        Multihash::Blob(digest) => (1, digest),
+    };
+    bytes.push(kind);
+    bytes.extend_from_slice(digest);
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Option<u32> {
+    let (number, rest) = split(bytes, 4)?;
+    *bytes = rest;
+    Some(u32::from_le_bytes(number.try_into().ok()?))
+}
+
+fn read_hash(bytes: &mut &[u8]) -> Option<Multihash> {
+    let (hash, rest) = split(bytes, 33)?;
+    *bytes = rest;
+    let digest = hash[1..].try_into().ok()?;
+    match hash[0] {
+        0 => Some(Multihash::Message(digest)),
+        1 => Some(Multihash::Blob(digest)),
+        _ => None,
+    }
+}
+
+fn split(bytes: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    if bytes.len() < len {
+        return None;
+    }
+    Some(bytes.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Exchange, TangleClock};
+    use crate::test_utils::numbered;
+    use serde_json::json;
+
+    /// A root, two concurrent replies to it, and a reply to the first of those.
+    fn tangle() -> Vec<(ssb_multiformats::multihash::Multihash, u32, String)> {
+        let reply = |i: usize, branch: usize| {
+            let msg = json!({ "root": numbered(1), "branch": numbered(branch) });
+            (numbered(i), i as u32, msg.to_string())
+        };
+        vec![
+            (numbered(1), 1, json!({ "type": "post" }).to_string()),
+            reply(2, 1),
+            reply(3, 1),
+            reply(4, 2),
+        ]
+    }
+
+    #[test]
+    fn heads_are_the_tangles_frontier() {
+        let clock = TangleClock::from_msgs(&tangle());
+        assert_eq!(clock.roots().collect::<Vec<_>>(), [&numbered(1)]);
+        let mut heads = vec![numbered(3), numbered(4)];
+        heads.sort_unstable();
+        assert_eq!(clock.heads(&numbered(1)), Some(heads.as_slice()));
+        assert_eq!(clock.heads(&numbered(2)), None);
+
+        let read = TangleClock::from_bytes(&clock.to_bytes()).unwrap();
+        assert_eq!(read.tangles, clock.tangles);
+        assert!(TangleClock::from_bytes(&clock.to_bytes()[1..]).is_none());
+    }
+
+    #[test]
+    fn exchanges_what_each_side_is_missing() {
+        let msgs = tangle();
+        let ours = TangleClock::from_msgs(&msgs);
+        let behind = TangleClock::from_msgs(&msgs[..3]);
+        let send = Exchange {
+            send: vec![numbered(1)],
+            receive: vec![],
+        };
+        let receive = Exchange {
+            send: vec![],
+            receive: vec![numbered(1)],
+        };
+        assert_eq!(ours.exchange(&ours), Exchange::default());
+        assert_eq!(ours.exchange(&behind), send);
+        assert_eq!(behind.exchange(&ours), receive);
+
+        // Only the heads are sent, so we can't tell that a peer that's ahead has everything we
+        // do.
+        let theirs = TangleClock::from_bytes(&behind.to_bytes()).unwrap();
+        assert_eq!(ours.exchange(&theirs), send);
+        let theirs = TangleClock::from_bytes(&ours.to_bytes()).unwrap();
+        assert_eq!(
+            behind.exchange(&theirs),
+            Exchange {
+                send: vec![numbered(1)],
+                receive: vec![numbered(1)],
+            }
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Machine readable test vectors, for checking this crate and other implementations (eg. the
+//! javascript `ssb-sort`) agree.
+//!
+//! A vector is a JSON object holding a set of messages and the order this crate sorts them in:
+//!
+//! ```json
+//! {
+//!   "description": "a root with two replies",
+//!   "messages": [{ "key": "%...=.sha256", "value": { "root": "%...=.sha256" } }],
+//!   "sorted": ["%...=.sha256"]
+//! }
+//! ```
+//!
+//! `sorted` is newest first. Concurrent messages can be validly ordered in more than one way, so an
+//! implementation passes a vector if its order is accepted by [`TestVector::check`], whether or
+//! not it matches `sorted` exactly. The vectors shipped in the crate's `vectors/` directory are
+//! available from [`shipped`].
+use crate::{causal_sort, verify_causal_order, OrderError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ssb_multiformats::multihash::Multihash;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TestVector {
+    pub description: String,
+    pub messages: Vec<VectorMessage>,
+    /// The keys of `messages` in the order `causal_sort` gives, newest first.
+    pub sorted: Vec<Multihash>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VectorMessage {
+    pub key: Multihash,
+    pub value: Value,
+}
+
+impl TestVector {
+    /// Make a new vector from any set of messages, recording the order this crate sorts them in.
+    ///
+    /// Message bodies that aren't valid JSON are recorded as `null`, which has the same links as
+    /// they do: none.
+    pub fn export<T: AsRef<str>, K>(description: &str, msgs: &[(Multihash, K, T)]) -> TestVector {
+        let messages: Vec<VectorMessage> = msgs
+            .iter()
+            .map(|(key, _, msg)| VectorMessage {
+                key: key.clone(),
+                value: serde_json::from_str(msg.as_ref()).unwrap_or(Value::Null),
+            })
+            .collect();
+        let mut vector = TestVector {
+            description: description.to_owned(),
+            messages,
+            sorted: Vec::new(),
+        };
+        vector.sorted = causal_sort(&vector.msgs()).into_vec();
+        vector
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<TestVector> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a vector is always valid JSON")
+    }
+
+    /// The messages, ready to sort. Each message's key is also its key id.
+    pub fn msgs(&self) -> Vec<(Multihash, Multihash, String)> {
+        self.messages
+            .iter()
+            .map(|msg| (msg.key.clone(), msg.key.clone(), msg.value.to_string()))
+            .collect()
+    }
+
+    /// Check that `order`, a list of keys newest first, is a valid causal order of the messages.
+    pub fn check(&self, order: &[Multihash]) -> Result<(), OrderError<Multihash>> {
+        verify_causal_order(&self.msgs(), order)
+    }
+}
+
+/// The vectors shipped with the crate.
+pub fn shipped() -> Vec<TestVector> {
+    [
+        include_str!("../vectors/thread.json"),
+        include_str!("../vectors/orphans.json"),
+        include_str!("../vectors/chain.json"),
+        include_str!("../vectors/concurrent.json"),
+    ]
+    .iter()
+    .map(|json| TestVector::from_json(json).expect("shipped vectors are valid"))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shipped, TestVector};
+    use crate::causal_sort;
+    use crate::test_utils::thread;
+
+    #[test]
+    fn shipped_vectors_match_causal_sort() {
+        let vectors = shipped();
+        assert_eq!(vectors.len(), 4);
+        vectors.iter().for_each(|vector| {
+            assert_eq!(causal_sort(&vector.msgs()), vector.sorted);
+            assert_eq!(vector.check(&vector.sorted), Ok(()));
+        });
+    }
+
+    #[test]
+    fn exported_vectors_round_trip() {
+        let vector = TestVector::export("a root with two replies", &thread());
+        assert_eq!(vector.messages.len(), 3);
+        assert_eq!(TestVector::from_json(&vector.to_json()).unwrap(), vector);
+
+        let mut reversed = vector.sorted.clone();
+        reversed.reverse();
+        assert!(vector.check(&reversed).is_err());
+    }
+}
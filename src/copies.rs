@@ -0,0 +1,151 @@
+//! Finding messages that are copies of each other's content.
+use crate::extract::CONTENT_PATHS;
+use serde_json::Value;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// The groups of messages in `msgs` with identical content, eg. content that peers republished
+/// under other keys, alongside their order `sorted`, newest first, eg. as a sort returned it.
+///
+/// A message's content is its `content`, or its value's `content` for a message with its key,
+/// or the whole message if it has neither, as the sorts take it. Contents are compared as
+/// parsed JSON, so whitespace, escapes and the order of object fields don't tell them apart.
+/// Each group holds two or more key ids in the order of `sorted`, and the groups are in the
+/// order their newest messages are sorted in. Messages that aren't valid JSON or aren't in
+/// `sorted` are left out, as are later messages with a key that's already been seen.
+pub fn content_copies<T, K>(msgs: &[(Multihash, K, T)], sorted: &[K]) -> Vec<Vec<K>>
+where
+    T: AsRef<str>,
+    K: Clone + Hash + Eq,
+{
+    let mut seen = HashSet::with_capacity(msgs.len());
+    let mut contents: HashMap<K, String> = HashMap::with_capacity(msgs.len());
+    for (key, key_id, msg) in msgs {
+        if !seen.insert(key) {
+            continue;
+        }
+        if let Some(content) = content(msg.as_ref()) {
+            contents.entry(key_id.clone()).or_insert(content);
+        }
+    }
+
+    let mut groups: HashMap<&str, usize> = HashMap::new();
+    let mut copies: Vec<Vec<K>> = Vec::new();
+    for key_id in sorted {
+        if let Some(content) = contents.get(key_id) {
+            let next = copies.len();
+            let group = *groups.entry(content).or_insert(next);
+            if group == next {
+                copies.push(Vec::new());
+            }
+            copies[group].push(key_id.clone());
+        }
+    }
+    copies.retain(|group| group.len() > 1);
+    copies
+}
+
+/// The content of `msg`, written out with its objects' fields sorted.
+fn content(msg: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(msg).ok()?;
+    let content = CONTENT_PATHS
+        .iter()
+        .find_map(|path| {
+            path.iter()
+                .try_fold(&value, |value, field| value.as_object()?.get(*field))
+        })
+        .unwrap_or(&value);
+    let mut written = String::new();
+    write_sorted(content, &mut written);
+    Some(written)
+}
+
+/// Write `value` to `out` as JSON, with each object's fields sorted by name whether or not
+/// `serde_json` keeps them in order.
+fn write_sorted(value: &Value, out: &mut String) {
+    match value {
+        Value::Array(values) => {
+            out.push('[');
+            for (index, value) in values.iter().enumerate() {
+                if index > 0 {
+                    out.push(',');
+                }
+                write_sorted(value, out);
+            }
+            out.push(']');
+        }
+        Value::Object(fields) => {
+            let mut fields: Vec<_> = fields.iter().collect();
+            fields.sort_unstable_by_key(|(field, _)| *field);
+            out.push('{');
+            for (index, (field, value)) in fields.into_iter().enumerate() {
+                if index > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::from(field.as_str()).to_string());
+                out.push(':');
+                write_sorted(value, out);
+            }
+            out.push('}');
+        }
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::content_copies;
+    use crate::causal_sort;
+    use crate::test_utils::numbered;
+    use serde_json::json;
+
+    #[test]
+    fn copies_are_grouped_in_order() {
+        let post = json!({ "type": "post", "text": "hi" });
+        let msgs = vec![
+            (numbered(1), 1, json!({ "content": post }).to_string()),
+            (
+                numbered(2),
+                2,
+                json!({ "previous": numbered(1), "content": post }).to_string(),
+            ),
+            (
+                numbered(3),
+                3,
+                "{\"content\": {\"text\": \"\\u0068i\", \"type\": \"post\"}}".to_owned(),
+            ),
+            (
+                numbered(4),
+                4,
+                json!({ "content": { "type": "vote" } }).to_string(),
+            ),
+            (
+                numbered(5),
+                5,
+                json!({ "key": numbered(9), "value": { "content": { "type": "vote" } } })
+                    .to_string(),
+            ),
+            (
+                numbered(6),
+                6,
+                json!({ "content": "c2VjcmV0.box" }).to_string(),
+            ),
+            (numbered(6), 7, json!({ "content": post }).to_string()),
+            (numbered(8), 8, "{\"not\": json".to_owned()),
+        ];
+        let sorted = causal_sort(&msgs);
+        let copies = content_copies(&msgs, &sorted);
+        assert_eq!(copies.len(), 2);
+        let position = |key_id| sorted.iter().position(|k| *k == key_id).unwrap();
+        copies.iter().for_each(|group| {
+            assert!(group.windows(2).all(|w| position(w[0]) < position(w[1])));
+        });
+        let mut groups: Vec<Vec<_>> = copies.clone();
+        groups.iter_mut().for_each(|group| group.sort_unstable());
+        groups.sort();
+        assert_eq!(groups, [vec![1, 2, 3], vec![4, 5]]);
+
+        assert!(content_copies(&msgs, &[1]).is_empty());
+    }
+}
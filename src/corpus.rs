@@ -0,0 +1,96 @@
+//! Generated message sets with realistic tangle shapes, for benchmarks and tests.
+//!
+//! Every corpus is generated from a seed, so the same arguments always give the same messages.
+//! The messages look like ssb `post` contents, with `root` and `branch` links, and are returned
+//! shuffled so that sorting them has real work to do. Key ids are the message's position in
+//! publish order, oldest first.
+use crate::rng::Rng;
+use serde_json::json;
+use ssb_multiformats::multihash::Multihash;
+
+/// The shape of the tangle to generate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shape {
+    /// One long chain, each message linking to the one before it.
+    Chain,
+    /// One root with every other message replying directly to it.
+    FanOut,
+    /// Many threads of `replies + 1` messages each. Replies link to their thread's root and to a
+    /// random earlier message in the thread, and sometimes mention a message from another thread.
+    Threads { replies: usize },
+}
+
+/// Generate `size` messages in the given shape.
+pub fn generate(shape: Shape, size: usize, seed: u64) -> Vec<(Multihash, usize, String)> {
+    let mut rng = Rng::new(seed);
+    let keys: Vec<Multihash> = (0..size).map(|_| rng.hash()).collect();
+
+    let mut msgs: Vec<_> = (0..size)
+        .map(|index| {
+            let content = match shape {
+                Shape::Chain => match index {
+                    0 => json!({ "type": "post", "text": rng.text() }),
+                    _ => json!({ "type": "post", "text": rng.text(), "previous": keys[index - 1] }),
+                },
+                Shape::FanOut => match index {
+                    0 => json!({ "type": "post", "text": rng.text() }),
+                    _ => json!({
+                        "type": "post",
+                        "text": rng.text(),
+                        "root": keys[0],
+                        "branch": [keys[0]],
+                    }),
+                },
+                Shape::Threads { replies } => {
+                    let root = index - index % (replies + 1);
+                    if root == index {
+                        json!({ "type": "post", "text": rng.text() })
+                    } else {
+                        let branch = rng.below(index - root) + root;
+                        let mut content = json!({
+                            "type": "post",
+                            "text": rng.text(),
+                            "root": keys[root],
+                            "branch": [keys[branch]],
+                        });
+                        if root > 0 && rng.below(10) == 0 {
+                            content["mentions"] = json!([{ "link": keys[rng.below(root)] }]);
+                        }
+                        content
+                    }
+                }
+            };
+            (keys[index].clone(), index, content.to_string())
+        })
+        .collect();
+
+    rng.shuffle(&mut msgs);
+    msgs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, Shape};
+    use crate::causal_sort;
+
+    #[test]
+    fn generated_shapes_sort_into_publish_order() {
+        let chain = generate(Shape::Chain, 100, 1);
+        assert_eq!(causal_sort(&chain), (0..100).rev().collect::<Vec<_>>());
+
+        let fan_out = causal_sort(&generate(Shape::FanOut, 100, 2));
+        assert_eq!(fan_out.len(), 100);
+        assert_eq!(fan_out.last(), Some(&0));
+
+        let threads = causal_sort(&generate(Shape::Threads { replies: 9 }, 100, 3));
+        let position = |id| threads.iter().position(|i| *i == id).unwrap();
+        (0..10).for_each(|root| assert!(position(root * 10) > position(root * 10 + 1)));
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        let shape = Shape::Threads { replies: 4 };
+        assert_eq!(generate(shape, 50, 7), generate(shape, 50, 7));
+        assert_ne!(generate(shape, 50, 7), generate(shape, 50, 8));
+    }
+}
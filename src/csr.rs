@@ -0,0 +1,322 @@
+//! A compact, read only graph in [compressed sparse row] form.
+//!
+//! daggy stores every edge as a petgraph `Edge`: a weight and four `usize` indices. Here an edge
+//! is a single `u32` in one big array, with each node's edges found by slicing that array between
+//! two offsets. The graph is laid out in one pass once every message's links are known, and can't
+//! be changed afterwards.
+//!
+//! [compressed sparse row]: https://en.wikipedia.org/wiki/Sparse_matrix#Compressed_sparse_row_(CSR,_CRS_or_Yale_format)
+use crate::builder::TieBreak;
+use crate::error::Error;
+use crate::graph::{canonical_order, find_cycle, layered_order, map_bytes, BuildGraph, Threads};
+use crate::sorted::SortedMessages;
+use crate::trace::span;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::ops::ControlFlow;
+
+pub(crate) struct CsrGraph<K> {
+    /// The edges out of node `n` are `targets[offsets[n]..offsets[n + 1]]`, in the order they were
+    /// added.
+    offsets: Vec<usize>,
+    targets: Vec<u32>,
+    node_to_key_id: Vec<Option<K>>,
+    /// The hash of each node, for reporting cycles.
+    hashes: Vec<Multihash>,
+}
+
+/// Collects the nodes and edges of a `CsrGraph` as messages are added.
+pub(crate) struct CsrBuilder<K> {
+    hash_to_node: HashMap<Multihash, u32>,
+    node_to_key_id: Vec<Option<K>>,
+    edges: Vec<(u32, u32)>,
+}
+
+impl<K: Clone> CsrBuilder<K> {
+    pub(crate) fn new() -> CsrBuilder<K> {
+        CsrBuilder {
+            hash_to_node: HashMap::new(),
+            node_to_key_id: Vec::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    fn intern(&mut self, hash: &Multihash) -> Result<u32, Error> {
+        if let Some(node) = self.hash_to_node.get(hash) {
+            return Ok(*node);
+        }
+        let next = u32::try_from(self.hash_to_node.len()).map_err(|_| Error::TooManyNodes)?;
+        self.hash_to_node.insert(hash.clone(), next);
+        Ok(next)
+    }
+
+    /// Lay the edges out in their final form. The hashes are only kept in a list, for reporting
+    /// cycles, rather than a map.
+    pub(crate) fn finish(self) -> CsrGraph<K> {
+        let CsrBuilder {
+            hash_to_node,
+            mut node_to_key_id,
+            edges,
+        } = self;
+        let node_count = hash_to_node.len();
+        let mut hashes: Vec<(u32, Multihash)> = hash_to_node
+            .into_iter()
+            .map(|(hash, node)| (node, hash))
+            .collect();
+        hashes.sort_unstable_by_key(|(node, _)| *node);
+        let hashes = hashes.into_iter().map(|(_, hash)| hash).collect();
+        node_to_key_id.resize(node_count, None);
+
+        // Count the edges out of each node, then place each edge after its node's earlier ones.
+        let mut offsets = vec![0; node_count + 1];
+        edges
+            .iter()
+            .for_each(|(source, _)| offsets[*source as usize + 1] += 1);
+        (0..node_count).for_each(|node| offsets[node + 1] += offsets[node]);
+
+        let mut next = offsets.clone();
+        let mut targets = vec![0; edges.len()];
+        edges.iter().for_each(|(source, target)| {
+            targets[next[*source as usize]] = *target;
+            next[*source as usize] += 1;
+        });
+
+        CsrGraph {
+            offsets,
+            targets,
+            node_to_key_id,
+            hashes,
+        }
+    }
+}
+
+impl<K: Clone> BuildGraph<K> for CsrBuilder<K> {
+    fn insert(&mut self, key: &Multihash, key_id: K, refs: &[Multihash]) -> Result<bool, Error> {
+        let key_node = self.intern(key)?;
+        if self.node_to_key_id.len() <= key_node as usize {
+            self.node_to_key_id.resize(key_node as usize + 1, None);
+        }
+        let first = self.node_to_key_id[key_node as usize].is_none();
+        self.node_to_key_id[key_node as usize].get_or_insert(key_id);
+        for reference in refs {
+            let ref_node = self.intern(reference)?;
+            self.edges.push((key_node, ref_node));
+        }
+        Ok(first)
+    }
+
+    fn link(&mut self, from: &Multihash, to: &Multihash) -> Result<(), Error> {
+        let from_node = self.intern(from)?;
+        let to_node = self.intern(to)?;
+        self.edges.push((from_node, to_node));
+        Ok(())
+    }
+
+    fn node_count(&self) -> usize {
+        self.hash_to_node.len()
+    }
+
+    fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    fn bytes_used(&self) -> usize {
+        map_bytes(&self.hash_to_node)
+            + self.node_to_key_id.capacity() * size_of::<Option<K>>()
+            + self.edges.capacity() * size_of::<(u32, u32)>()
+    }
+}
+
+impl<K: Clone> CsrGraph<K> {
+    fn children(&self, node: usize) -> &[u32] {
+        &self.targets[self.offsets[node]..self.offsets[node + 1]]
+    }
+
+    /// Topologically sort the graph, newest first, breaking ties between concurrent messages
+    /// with `tie_break`.
+    pub(crate) fn sorted_with(
+        &self,
+        tie_break: TieBreak,
+        threads: Threads,
+    ) -> Result<SortedMessages<K>, Error> {
+        let mut sorted = Vec::new();
+        self.sort_into(tie_break, threads, |key_id| {
+            sorted.push(key_id);
+            ControlFlow::Continue(())
+        })?;
+        Ok(sorted.into())
+    }
+
+    /// Like `sorted_with`, but give each key id to `sink` as it's sorted until `sink` breaks.
+    ///
+    /// By default this visits nodes in exactly the same order as petgraph's `Topo` does on the
+    /// equivalent daggy graph, so both backends give the same result. `Topo` starts from a stack
+    /// of the nodes nothing points at, and walks each node's edges newest first. A cycle is only
+    /// found once every node it doesn't hold up has been given to `sink`.
+    pub(crate) fn sort_into<F: FnMut(K) -> ControlFlow<()>>(
+        &self,
+        tie_break: TieBreak,
+        threads: Threads,
+        mut sink: F,
+    ) -> Result<(), Error> {
+        let node_count = self.node_to_key_id.len();
+        let span = span!(
+            DEBUG,
+            "topo_sort",
+            nodes = node_count,
+            sorted = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+
+        let order = match tie_break {
+            TieBreak::Input => {
+                span.record("sorted", self.topo_into(sink)?);
+                return Ok(());
+            }
+            TieBreak::Canonical => canonical_order(&self.hashes, |node| {
+                self.children(node).iter().map(|child| *child as usize)
+            }),
+            TieBreak::Layers => {
+                let (offsets, targets) = (&self.offsets, &self.targets);
+                layered_order(
+                    node_count,
+                    |node| {
+                        targets[offsets[node]..offsets[node + 1]]
+                            .iter()
+                            .map(|child| *child as usize)
+                    },
+                    threads,
+                )
+            }
+        };
+        if order.len() < node_count {
+            let mut sorted = vec![false; node_count];
+            order.iter().for_each(|node| sorted[*node] = true);
+            return Err(self.cycle((0..node_count).filter(|node| !sorted[*node])));
+        }
+        let mut sorted = 0;
+        let _ = order
+            .into_iter()
+            .filter_map(|node| self.node_to_key_id[node].clone())
+            .try_for_each(|key_id| {
+                sorted += 1;
+                sink(key_id)
+            });
+        span.record("sorted", sorted);
+        Ok(())
+    }
+
+    /// Sort as `Topo` does, giving each key id to `sink` as it's visited, and count the key ids
+    /// given.
+    fn topo_into<F: FnMut(K) -> ControlFlow<()>>(&self, mut sink: F) -> Result<usize, Error> {
+        let node_count = self.node_to_key_id.len();
+        let mut in_degree = vec![0_u32; node_count];
+        self.targets
+            .iter()
+            .for_each(|target| in_degree[*target as usize] += 1);
+
+        let mut to_visit: Vec<usize> = (0..node_count).filter(|n| in_degree[*n] == 0).collect();
+        let mut visited = 0;
+        let mut sorted = 0;
+        while let Some(node) = to_visit.pop() {
+            visited += 1;
+            if let Some(key_id) = &self.node_to_key_id[node] {
+                sorted += 1;
+                if sink(key_id.clone()).is_break() {
+                    return Ok(sorted);
+                }
+            }
+            self.children(node).iter().rev().for_each(|child| {
+                let child = *child as usize;
+                in_degree[child] -= 1;
+                if in_degree[child] == 0 {
+                    to_visit.push(child);
+                }
+            });
+        }
+        if visited < node_count {
+            // Every node left over is on a cycle or after one.
+            let left_over = (0..node_count).filter(|node| in_degree[*node] > 0);
+            return Err(self.cycle(left_over));
+        }
+        Ok(sorted)
+    }
+
+    /// The error for a cycle among `left_over`, the nodes a sort couldn't reach.
+    fn cycle<I: Iterator<Item = usize>>(&self, left_over: I) -> Error {
+        let nodes = find_cycle(self.node_to_key_id.len(), left_over, |node| {
+            self.children(node).iter().map(|child| *child as usize)
+        });
+        Error::Cycle {
+            keys: nodes
+                .unwrap_or_default()
+                .into_iter()
+                .map(|node| self.hashes[node].clone())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use crate::test_utils::{numbered, thread};
+    use crate::{causal_sort, Backend, Error, SortBuilder, TieBreak};
+    use serde_json::json;
+
+    #[test]
+    fn matches_daggy_backend() {
+        let csr = SortBuilder::new().backend(Backend::Csr);
+        assert_eq!(csr.sort(&thread()), causal_sort(&thread()));
+
+        // Concurrent messages, parallel edges and shared external references all affect the
+        // order nodes are visited in.
+        let unsorted: Vec<_> = (1..300)
+            .map(|i| {
+                let msg = json!({
+                    "root": numbered(i / 10),
+                    "branch": [numbered(i / 2), numbered(i / 2), numbered(i - 1)],
+                    "mentions": numbered(i % 7 + 1000),
+                });
+                (numbered(i), i, msg.to_string())
+            })
+            .rev()
+            .collect();
+        assert_eq!(csr.sort(&unsorted), causal_sort(&unsorted));
+    }
+
+    #[test]
+    #[should_panic]
+    fn panics_on_cycles() {
+        let a = numbered(1);
+        let b = numbered(2);
+        let unsorted = [
+            (a.clone(), 1, json!({ "previous": b }).to_string()),
+            (b, 2, json!({ "previous": a }).to_string()),
+        ];
+        SortBuilder::new().backend(Backend::Csr).sort(&unsorted);
+    }
+
+    #[test]
+    fn canonical_sorts_find_cycles() {
+        let a = numbered(1);
+        let b = numbered(2);
+        let unsorted = [
+            (numbered(3), 3, json!({ "previous": a }).to_string()),
+            (a.clone(), 1, json!({ "previous": b }).to_string()),
+            (b.clone(), 2, json!({ "previous": a }).to_string()),
+        ];
+        let sorted = SortBuilder::new()
+            .backend(Backend::Csr)
+            .tie_break(TieBreak::Canonical)
+            .try_sort(&unsorted);
+        match sorted {
+            Err(Error::Cycle { mut keys }) => {
+                keys.sort_unstable();
+                assert_eq!(keys, [a, b]);
+            }
+            sorted => panic!("expected a cycle, got {:?}", sorted),
+        }
+    }
+}
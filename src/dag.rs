@@ -0,0 +1,1507 @@
+//! The dag of references between messages, for looking at rather than just sorting.
+//!
+//! The dag is built with [daggy](https://docs.rs/daggy), but none of its types, or petgraph's,
+//! appear here, so that they can change without breaking this API. The `unstable-graph` feature
+//! adds conversions to petgraph's types, which change whenever petgraph does.
+use crate::error::Error;
+#[cfg(feature = "json")]
+use crate::extract::LinkOptions;
+use crate::graph::{BuildGraph, CausalGraph, Checks, Ix};
+use crate::sorted::SortedMessages;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::{EdgeRef, Topo, Visitable, Walker};
+use petgraph::Direction;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::OnceLock;
+
+/// A node in a [`CausalDag`]: a hash that is either a message's key or referenced by a message.
+///
+/// `NodeId`s are positions in one dag, so the same hash can have another in a dag built again,
+/// eg. from messages in another order. To keep what's found out about a node between builds,
+/// key it by its [`StableId`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// The position of the node in [`CausalDag::nodes`].
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A node's id from its hash, the same in every [`CausalDag`] the hash is in, eg. to cache the
+/// results of queries between builds. The queries that take a hash, eg.
+/// [`CausalDag::descendants`], take one of these as well, and the node of one in a dag is found
+/// with [`CausalDag::stable_node`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StableId(Multihash);
+
+impl StableId {
+    /// The id of the node of `hash`.
+    pub fn of(hash: &Multihash) -> StableId {
+        StableId(hash.clone())
+    }
+
+    /// The hash this is the id of.
+    pub fn hash(&self) -> &Multihash {
+        &self.0
+    }
+}
+
+impl From<Multihash> for StableId {
+    fn from(hash: Multihash) -> StableId {
+        StableId(hash)
+    }
+}
+
+impl From<&Multihash> for StableId {
+    fn from(hash: &Multihash) -> StableId {
+        StableId::of(hash)
+    }
+}
+
+impl From<&StableId> for StableId {
+    fn from(id: &StableId) -> StableId {
+        id.clone()
+    }
+}
+
+/// A link in a [`CausalDag`], from a message to a hash it references.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EdgeId(usize);
+
+impl EdgeId {
+    /// The position of the edge, counting from 0 in the order the edges were added. Removing a
+    /// message with [`CausalDag::remove`] can move other edges to the positions its edges leave.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A weakly connected component of a [`CausalDag`]: messages joined by links in either
+/// direction, eg. one thread. Messages that link to the same hash are in the same component,
+/// even if it has no message in the dag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ComponentId(usize);
+
+impl ComponentId {
+    /// Components are numbered from 0 in the order their newest messages come in the sort.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Where a message is in a [`CausalDag`], by whether it links to and is linked to by other
+/// messages in the dag. Links to hashes with no message in the dag don't count. See
+/// [`CausalDag::roles`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Nothing links to it, but it links to other messages: one of the newest.
+    Head,
+    /// It links to other messages, and others link to it.
+    Interior,
+    /// It links to no other messages, but others link to it: one of the oldest.
+    Root,
+    /// It neither links to nor is linked to by other messages, so the sort puts it anywhere.
+    /// Clients may want to show these apart rather than as the newest messages.
+    Orphan,
+}
+
+/// The dag of references between messages that the sorts build.
+///
+/// There is a node for every hash seen, whether as a message's key or as a link, and an edge from
+/// each message to every hash it links to, ie. from newer to older.
+#[derive(Clone)]
+pub struct CausalDag<K> {
+    graph: CausalGraph<K>,
+    /// The hash of each node.
+    hashes: Vec<Multihash>,
+    /// The walk the sorts make, for paging, found the first time a page is taken and forgotten
+    /// whenever the dag changes.
+    walk: OnceLock<Walk>,
+}
+
+/// Every node in the order the sorts walk them, newest first, and each node's position in it.
+#[derive(Clone)]
+struct Walk {
+    nodes: Vec<usize>,
+    positions: Vec<usize>,
+}
+
+impl<K: Clone> CausalDag<K> {
+    /// Build the dag of `msgs`, finding the links in their JSON bodies as
+    /// [`causal_sort`](crate::causal_sort) does.
+    #[cfg(feature = "json")]
+    pub fn from_msgs<T: AsRef<str>>(msgs: &[(Multihash, K, T)]) -> Result<CausalDag<K>, Error> {
+        let mut graph = CausalGraph::new();
+        let extracted = msgs
+            .iter()
+            .map(|(key, key_id, msg)| (key, key_id.clone(), msg.as_ref().as_bytes()));
+        graph.extend(extracted, Checks::default(), LinkOptions::default())?;
+        Ok(CausalDag::new(graph))
+    }
+
+    /// Build the dag of messages whose links have already been found, as
+    /// [`causal_sort_links`](crate::causal_sort_links) does.
+    pub fn from_links<L: AsRef<[Multihash]>>(
+        msgs: &[(Multihash, K, L)],
+    ) -> Result<CausalDag<K>, Error> {
+        let mut graph = CausalGraph::new();
+        let links = msgs
+            .iter()
+            .map(|(key, key_id, links)| (key, key_id.clone(), links.as_ref()));
+        graph.extend_links(links, Checks::default())?;
+        Ok(CausalDag::new(graph))
+    }
+
+    pub(crate) fn new(graph: CausalGraph<K>) -> CausalDag<K> {
+        let hashes = graph.interner().hashes(graph.node_count());
+        CausalDag {
+            graph,
+            hashes,
+            walk: OnceLock::new(),
+        }
+    }
+
+    fn walk(&self) -> &Walk {
+        self.walk.get_or_init(|| {
+            let graph = self.graph.dag().graph();
+            let nodes: Vec<usize> = Topo::new(graph)
+                .iter(graph)
+                .map(|node| node.index())
+                .collect();
+            let mut positions = vec![0; nodes.len()];
+            for (position, node) in nodes.iter().enumerate() {
+                positions[*node] = position;
+            }
+            Walk { nodes, positions }
+        })
+    }
+
+    /// Where the node of `key` is in the walk.
+    fn walk_position(&self, key: &StableId) -> Option<usize> {
+        let node = self.stable_node(key)?;
+        Some(self.walk().positions[node.index()])
+    }
+
+    /// The key ids of the messages, newest first, exactly as the sorts return them.
+    pub fn sorted(&self) -> SortedMessages<K> {
+        self.graph.sorted()
+    }
+
+    /// The same key ids as [`sorted`](CausalDag::sorted), found one at a time as the dag is
+    /// walked. The walk only goes newest first, so to take from both ends, iterate over `sorted`.
+    pub fn iter(&self) -> Sorted<'_, K> {
+        Sorted {
+            graph: &self.graph,
+            topo: Topo::new(self.graph.dag().graph()),
+        }
+    }
+
+    /// Call `visit` with each message's key id and the key ids of the messages it links to,
+    /// oldest first, so that every message is visited after all of the messages it links to.
+    /// Linked hashes with no message in the dag are left out of the parents.
+    ///
+    /// This is for building up state in causal order, eg. a reduction over a thread, without
+    /// collecting the sorted messages first.
+    pub fn walk_oldest_first<F>(&self, mut visit: F)
+    where
+        F: FnMut(&K, Parents<'_, K>),
+    {
+        let graph = self.graph.dag().graph();
+        let mut unvisited_links: Vec<usize> = (0..graph.node_count())
+            .map(|node| graph.neighbors(NodeIndex::new(node)).count())
+            .collect();
+        let mut to_visit: Vec<NodeIndex<Ix>> = graph
+            .node_indices()
+            .filter(|node| unvisited_links[node.index()] == 0)
+            .collect();
+        while let Some(node) = to_visit.pop() {
+            if let Some(key_id) = self.graph.key_id(node.index()) {
+                visit(
+                    key_id,
+                    Parents {
+                        graph: &self.graph,
+                        links: graph.neighbors(node),
+                    },
+                );
+            }
+            graph
+                .neighbors_directed(node, Direction::Incoming)
+                .for_each(|child| {
+                    unvisited_links[child.index()] -= 1;
+                    if unvisited_links[child.index()] == 0 {
+                        to_visit.push(child);
+                    }
+                });
+        }
+    }
+
+    /// Bound when each message was published from `anchors`, the trusted timestamps of some
+    /// hashes, eg. of one's own messages: a message was published after every anchor it links
+    /// to, directly or through other messages, and before every anchor that links to it.
+    ///
+    /// Anchors can be for hashes with no message in the dag, which still bound the messages
+    /// linking to them, and a hash anchored more than once is bound by each of its anchors.
+    /// Anchors for hashes that aren't in the dag are ignored. A message's claimed timestamp isn't
+    /// an anchor unless it's given as one, so bounds that a claimed timestamp falls outside of
+    /// point to a forged or wrong clock.
+    pub fn time_bounds(&self, anchors: &[(Multihash, u64)]) -> HashMap<K, TimeBounds>
+    where
+        K: Hash + Eq,
+    {
+        let graph = self.graph.dag().graph();
+        let mut bounds = vec![TimeBounds::default(); self.node_count()];
+        for (hash, time) in anchors {
+            if let Some(node) = self.node(hash) {
+                let bound = &mut bounds[node.index()];
+                bound.earliest = bound.earliest.max(Some(*time));
+                bound.latest = Some(bound.latest.map_or(*time, |latest| latest.min(*time)));
+            }
+        }
+
+        let newest_first: Vec<NodeIndex<Ix>> = Topo::new(graph).iter(graph).collect();
+        for node in &newest_first {
+            let latest = graph
+                .neighbors_directed(*node, Direction::Incoming)
+                .filter_map(|parent| bounds[parent.index()].latest)
+                .chain(bounds[node.index()].latest)
+                .min();
+            bounds[node.index()].latest = latest;
+        }
+        for node in newest_first.iter().rev() {
+            let earliest = graph
+                .neighbors(*node)
+                .filter_map(|child| bounds[child.index()].earliest)
+                .chain(bounds[node.index()].earliest)
+                .max();
+            bounds[node.index()].earliest = earliest;
+        }
+
+        self.nodes()
+            .filter_map(|node| Some((self.key_id(node)?.clone(), bounds[node.index()])))
+            .collect()
+    }
+
+    /// Each message's depth: 0 if it links to no message in the dag, otherwise one more than the
+    /// deepest message it links to.
+    ///
+    /// Messages sort oldest first by depth, with concurrent messages sharing depths. Unlike a
+    /// position in the order, a message's depth stays the same when newer messages are added,
+    /// so it can be stored in an index and need only be found for the new messages. Break ties
+    /// with something else stable, eg. the key, for a total order.
+    pub fn depths(&self) -> HashMap<K, u64>
+    where
+        K: Hash + Eq,
+    {
+        let mut depths = HashMap::new();
+        self.walk_oldest_first(|key_id, parents| {
+            let depth = parents.map(|parent| depths[parent] + 1).max().unwrap_or(0);
+            depths.entry(key_id.clone()).or_insert(depth);
+        });
+        depths
+    }
+
+    /// The key ids in the order [`sorted`](CausalDag::sorted) gives, each with its component, so
+    /// that unrelated threads can be grouped without finding their links again.
+    pub fn sorted_labeled(&self) -> Vec<(K, ComponentId)> {
+        let components = self.components();
+        let mut ids = HashMap::new();
+        let graph = self.graph.dag().graph();
+        Topo::new(graph)
+            .iter(graph)
+            .filter_map(|node| {
+                let key_id = self.graph.key_id(node.index())?.clone();
+                let next = ids.len();
+                let id = *ids.entry(components[node.index()]).or_insert(next);
+                Some((key_id, ComponentId(id)))
+            })
+            .collect()
+    }
+
+    /// The messages as a forest: each weakly connected component as a tree, with the hash of its
+    /// root and its key ids newest first in the order [`sorted`](CausalDag::sorted) gives. Trees
+    /// are in the order their newest messages are sorted in, as with
+    /// [`sorted_labeled`](CausalDag::sorted_labeled), eg. to store an index per thread straight
+    /// from one sort.
+    ///
+    /// A tree's root is the oldest of its hashes in the order, so it can be only a link, when
+    /// the message a thread starts from isn't in the dag. When a tree has more than one root, eg.
+    /// when a message links two threads together, it's the one the order puts last. Hashes with
+    /// no message linked to them, eg. of a message that's been [removed](CausalDag::remove), are
+    /// in no tree.
+    pub fn sorted_forest(&self) -> Vec<(Multihash, SortedMessages<K>)> {
+        let components = self.components();
+        let mut ids = HashMap::new();
+        let mut trees: Vec<(usize, Vec<K>)> = Vec::new();
+        let graph = self.graph.dag().graph();
+        for node in Topo::new(graph).iter(graph) {
+            let next = ids.len();
+            let id = *ids.entry(components[node.index()]).or_insert(next);
+            if id == trees.len() {
+                trees.push((node.index(), Vec::new()));
+            }
+            let (root, key_ids) = &mut trees[id];
+            *root = node.index();
+            key_ids.extend(self.graph.key_id(node.index()).cloned());
+        }
+        trees
+            .into_iter()
+            .filter(|(_, key_ids)| !key_ids.is_empty())
+            .map(|(root, key_ids)| (self.hashes[root].clone(), key_ids.into()))
+            .collect()
+    }
+
+    /// The weakly connected component of each node, as the least node in it.
+    fn components(&self) -> Vec<usize> {
+        fn root(parent: &mut [usize], mut node: usize) -> usize {
+            while parent[node] != node {
+                parent[node] = parent[parent[node]];
+                node = parent[node];
+            }
+            node
+        }
+
+        // Union-find over the nodes, each root the least node of its component.
+        let mut parent: Vec<usize> = (0..self.node_count()).collect();
+        for node in self.nodes() {
+            for (_, linked) in self.links(node) {
+                let (a, b) = (root(&mut parent, node.0), root(&mut parent, linked.0));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+        (0..parent.len())
+            .map(|node| root(&mut parent, node))
+            .collect()
+    }
+
+    /// The [`Role`] of each message.
+    pub fn roles(&self) -> HashMap<K, Role>
+    where
+        K: Hash + Eq,
+    {
+        let is_message = |node: NodeId| self.key_id(node).is_some();
+        self.nodes()
+            .filter_map(|node| {
+                let key_id = self.key_id(node)?;
+                let links = self.links(node).any(|(_, linked)| is_message(linked));
+                let linked = self.linked_from(node).next().is_some();
+                let role = match (links, linked) {
+                    (true, false) => Role::Head,
+                    (true, true) => Role::Interior,
+                    (false, true) => Role::Root,
+                    (false, false) => Role::Orphan,
+                };
+                Some((key_id.clone(), role))
+            })
+            .collect()
+    }
+
+    /// The key ids of the messages at most `max_depth` links from a head, newest first in the
+    /// order [`sorted`](CausalDag::sorted) gives. A head is a message nothing links to, so
+    /// `max_depth` 0 leaves only the heads.
+    ///
+    /// This is for showing the latest of a long history, eg. a preview of a tangle, without
+    /// sorting all of it.
+    pub fn within_depth(&self, max_depth: usize) -> SortedMessages<K> {
+        let mut within = vec![false; self.node_count()];
+        let mut layer: Vec<NodeId> = self
+            .nodes()
+            .filter(|node| self.linked_from(*node).next().is_none())
+            .collect();
+        for depth in 0..=max_depth {
+            layer.retain(|node| !std::mem::replace(&mut within[node.index()], true));
+            if depth == max_depth || layer.is_empty() {
+                break;
+            }
+            layer = layer
+                .iter()
+                .flat_map(|node| self.links(*node).map(|(_, linked)| linked))
+                .collect();
+        }
+
+        self.sorted_within(&within)
+    }
+
+    /// The key ids of the nodes marked in `within`, in order.
+    fn sorted_within(&self, within: &[bool]) -> SortedMessages<K> {
+        let graph = self.graph.dag().graph();
+        Topo::new(graph)
+            .iter(graph)
+            .filter(|node| within[node.index()])
+            .filter_map(|node| self.graph.key_id(node.index()))
+            .cloned()
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// The key ids of the messages that link to `key`, directly or not, newest first in the
+    /// order [`sorted`](CausalDag::sorted) gives. With `inclusive`, the message with `key` is
+    /// last, if it's in the dag. Empty if nothing links to `key`.
+    ///
+    /// These are the messages causally after `key`, eg. to load the messages newer than the last
+    /// one shown.
+    pub fn descendants(&self, key: impl Into<StableId>, inclusive: bool) -> SortedMessages<K> {
+        let start = match self.stable_node(&key.into()) {
+            Some(start) => start,
+            None => return Vec::new().into(),
+        };
+        let mut after = self.after(start);
+        after[start.index()] = inclusive;
+        self.sorted_within(&after)
+    }
+
+    /// The key ids of the `n` messages just older than `key` in the order
+    /// [`sorted`](CausalDag::sorted) gives, newest first, eg. to load the next page of a thread
+    /// when the oldest message shown is `key`. Empty if `key` isn't in the dag. `key` can be only
+    /// a link, if its message wasn't sorted.
+    ///
+    /// Pages taken like this from the newest message fit together into exactly the sorted order,
+    /// so every message comes after the messages that link to it, without sorting again. The
+    /// order is walked once and kept until the dag changes, so each page after the first starts
+    /// from `key`'s place in it.
+    pub fn older_than(&self, key: impl Into<StableId>, n: usize) -> SortedMessages<K> {
+        let start = match self.walk_position(&key.into()) {
+            Some(start) => start,
+            None => return Vec::new().into(),
+        };
+        self.walk().nodes[start + 1..]
+            .iter()
+            .filter_map(|node| self.graph.key_id(*node))
+            .take(n)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// The key ids of the `n` messages just newer than `key` in the order
+    /// [`sorted`](CausalDag::sorted) gives, newest first, as [`older_than`](CausalDag::older_than)
+    /// does the other way, eg. to load the page above the newest message shown. Empty if `key`
+    /// isn't in the dag.
+    pub fn newer_than(&self, key: impl Into<StableId>, n: usize) -> SortedMessages<K> {
+        let start = match self.walk_position(&key.into()) {
+            Some(start) => start,
+            None => return Vec::new().into(),
+        };
+        let mut newer: Vec<K> = self.walk().nodes[..start]
+            .iter()
+            .rev()
+            .filter_map(|node| self.graph.key_id(*node))
+            .take(n)
+            .cloned()
+            .collect();
+        newer.reverse();
+        newer.into()
+    }
+
+    /// Another name for [`newer_than`](CausalDag::newer_than), to page up from the message with
+    /// `key`.
+    pub fn newer_than_key(&self, key: impl Into<StableId>, n: usize) -> SortedMessages<K> {
+        self.newer_than(key, n)
+    }
+
+    /// The key ids of the latest versions of `root`: the messages descended from it, or `root`
+    /// itself, that nothing links to. These are all concurrent, so there's more than one when
+    /// there are conflicting versions to merge. Newest first in the order
+    /// [`sorted`](CausalDag::sorted) gives, and empty if `root` isn't in the dag. `root` can be
+    /// only a link, if its message wasn't sorted.
+    pub fn concurrent_heads(&self, root: impl Into<StableId>) -> SortedMessages<K> {
+        let start = match self.stable_node(&root.into()) {
+            Some(start) => start,
+            None => return Vec::new().into(),
+        };
+        let mut heads = self.after(start);
+        heads[start.index()] = true;
+        for node in self.nodes() {
+            if heads[node.index()] && self.linked_from(node).next().is_some() {
+                heads[node.index()] = false;
+            }
+        }
+        self.sorted_within(&heads)
+    }
+
+    /// Which nodes link to `start`, directly or not.
+    fn after(&self, start: NodeId) -> Vec<bool> {
+        let mut after = vec![false; self.node_count()];
+        let mut to_visit = vec![start];
+        while let Some(node) = to_visit.pop() {
+            to_visit.extend(
+                self.linked_from(node)
+                    .map(|(_, child)| child)
+                    .filter(|child| !std::mem::replace(&mut after[child.index()], true)),
+            );
+        }
+        after
+    }
+
+    /// The key ids of the messages that every one of `keys` links to, directly or not, newest
+    /// first in the order [`sorted`](CausalDag::sorted) gives. A message counts as its own
+    /// ancestor, as in git, so if one of `keys` is an ancestor of the rest it's first. Empty if
+    /// `keys` is, or if any of them isn't in the dag.
+    ///
+    /// For two conflicting edits, these are the messages both were based on, and the first is
+    /// the nearest.
+    pub fn common_ancestors<I>(&self, keys: I) -> SortedMessages<K>
+    where
+        I: IntoIterator,
+        I::Item: Into<StableId>,
+    {
+        let mut starts = match keys
+            .into_iter()
+            .map(|key| self.stable_node(&key.into()))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(starts) if !starts.is_empty() => starts,
+            _ => return Vec::new().into(),
+        };
+        starts.sort_unstable();
+        starts.dedup();
+
+        let mut reached_by = vec![0; self.node_count()];
+        let mut seen = vec![false; self.node_count()];
+        for start in &starts {
+            seen.iter_mut().for_each(|seen| *seen = false);
+            let mut to_visit = vec![*start];
+            while let Some(node) = to_visit.pop() {
+                if !std::mem::replace(&mut seen[node.index()], true) {
+                    reached_by[node.index()] += 1;
+                    to_visit.extend(self.links(node).map(|(_, linked)| linked));
+                }
+            }
+        }
+        let common: Vec<bool> = reached_by
+            .iter()
+            .map(|reached_by| *reached_by == starts.len())
+            .collect();
+        self.sorted_within(&common)
+    }
+
+    /// Add the message with `key` to the dag, finding its links as
+    /// [`from_msgs`](CausalDag::from_msgs) does, eg. as it arrives from a peer. This looks through
+    /// every hash in the dag for the nodes it adds, so it's for adding a few messages to a built
+    /// dag, not for building one.
+    ///
+    /// If it fails, eg. with `Error::Cycle`, none of the message's links are added, and the
+    /// message is left out of the dag unless it was already in it, but the hashes it links to stay
+    /// as links.
+    #[cfg(feature = "json")]
+    pub fn insert(&mut self, key: &Multihash, key_id: K, msg: &str) -> Result<(), Error> {
+        let was_message = self.node(key).and_then(|node| self.key_id(node)).is_some();
+        let (nodes, edges) = (self.node_count(), self.graph.edge_count());
+        let msgs = std::iter::once((key, key_id, msg.as_bytes()));
+        let inserted = self
+            .graph
+            .extend(msgs, Checks::default(), LinkOptions::default());
+        if inserted.is_err() {
+            self.graph.truncate_edges(edges);
+            if let (false, Some(node)) = (was_message, self.graph.interner().get(key)) {
+                self.graph.detach(node.index());
+            }
+        }
+        let added: Vec<_> = (nodes..self.graph.node_count()).collect();
+        if !added.is_empty() {
+            self.hashes.extend(self.graph.interner().hashes_of(&added));
+        }
+        self.walk = OnceLock::new();
+        inserted.map(|_| ())
+    }
+
+    /// Remove the message with `key`, eg. after a peer deletes it, along with its links. Its node
+    /// stays as a link from the messages that link to it, so every `NodeId` is unchanged.
+    ///
+    /// Returns the key ids of the messages that link to it, which now link to a message that
+    /// isn't in the dag, newest first in the order [`sorted`](CausalDag::sorted) gives. `None` if
+    /// no message in the dag has `key`.
+    pub fn remove(&mut self, key: &Multihash) -> Option<SortedMessages<K>> {
+        let node = self.node(key)?;
+        self.graph.detach(node.index())?;
+        self.walk = OnceLock::new();
+        let mut dangling = vec![false; self.node_count()];
+        self.linked_from(node)
+            .for_each(|(_, from)| dangling[from.index()] = true);
+        Some(self.sorted_within(&dangling))
+    }
+
+    /// Drop the nodes of hashes that no message in the dag has as its key, eg. messages that
+    /// weren't part of the sort, or blobs, to save memory when keeping a dag around for queries.
+    ///
+    /// Such nodes have no links of their own, so no path from one message to another goes through
+    /// them, and every message still links directly to the messages it did. The dag sorts the
+    /// same way, but the messages' nodes are numbered again from 0, in the same order, so
+    /// `NodeId`s and `EdgeId`s from before don't stay the same. Queries for a dropped hash, eg.
+    /// [`descendants`](CausalDag::descendants), then find nothing, and a message inserted later
+    /// that links to it adds it back.
+    pub fn shrink(&mut self) {
+        let hashes = std::mem::take(&mut self.hashes);
+        self.hashes = hashes
+            .into_iter()
+            .enumerate()
+            .filter(|(node, _)| self.graph.key_id(*node).is_some())
+            .map(|(_, hash)| hash)
+            .collect();
+        self.graph.shrink();
+        self.walk = OnceLock::new();
+    }
+
+    /// The key ids in causal waves, oldest first: first the messages that link to no other
+    /// message in the dag, then the ones that only link to those, and so on. Each wave is in the
+    /// order the messages' keys were first seen, as keys or as links.
+    ///
+    /// Every message in a wave only depends on earlier waves, so a wave's messages can be
+    /// processed in parallel once the waves before it are done.
+    pub fn generations(&self) -> Generations<'_, K> {
+        let is_message = |node: NodeId| self.key_id(node).is_some();
+        let mut remaining = vec![0; self.node_count()];
+        for node in self.nodes().filter(|node| is_message(*node)) {
+            remaining[node.index()] = self
+                .links(node)
+                .filter(|(_, linked)| is_message(*linked))
+                .count();
+        }
+        let ready = self
+            .nodes()
+            .filter(|node| is_message(*node) && remaining[node.index()] == 0)
+            .collect();
+        Generations {
+            dag: self,
+            remaining,
+            ready,
+        }
+    }
+
+    /// The key ids in order, in `Vec`s of `size` except the last. Each chunk is found as it's
+    /// needed, eg. to write it to an index before walking further.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn chunks(&self, size: usize) -> Chunks<'_, K> {
+        assert!(size > 0, "chunk size must be non-zero");
+        Chunks {
+            sorted: self.iter(),
+            size,
+        }
+    }
+
+    /// The happens-before relation between the messages: the transitive closure of their links,
+    /// as a bit matrix over their key ids, eg. for formal analysis of an order.
+    ///
+    /// Rows are kept only for the messages before each message in the sort, so `n` messages take
+    /// about `n * n / 16` bytes. If that's more than `budget` bytes, this is `Error::OverBudget`
+    /// before anything is allocated.
+    pub fn relation(&self, budget: usize) -> Result<Relation<K>, Error> {
+        let graph = self.graph.dag().graph();
+        let mut oldest_first: Vec<NodeIndex<Ix>> = Topo::new(graph)
+            .iter(graph)
+            .filter(|node| self.graph.key_id(node.index()).is_some())
+            .collect();
+        oldest_first.reverse();
+
+        let mut offsets = Vec::with_capacity(oldest_first.len() + 1);
+        let mut words = 0_usize;
+        for row in 0..=oldest_first.len() {
+            offsets.push(words);
+            words = words.saturating_add(row.div_ceil(64));
+        }
+        let bytes = words.saturating_mul(std::mem::size_of::<u64>());
+        if bytes > budget {
+            return Err(Error::OverBudget { budget });
+        }
+
+        let mut index = vec![usize::MAX; self.node_count()];
+        oldest_first
+            .iter()
+            .enumerate()
+            .for_each(|(row, node)| index[node.index()] = row);
+        let mut bits = vec![0_u64; words];
+        for (row, node) in oldest_first.iter().enumerate() {
+            let (before, rest) = bits.split_at_mut(offsets[row]);
+            let this = &mut rest[..offsets[row + 1] - offsets[row]];
+            for linked in graph.neighbors(*node) {
+                let linked = index[linked.index()];
+                if linked == usize::MAX {
+                    continue;
+                }
+                this[linked / 64] |= 1 << (linked % 64);
+                let linked_row = &before[offsets[linked]..offsets[linked + 1]];
+                this.iter_mut()
+                    .zip(linked_row)
+                    .for_each(|(word, linked)| *word |= linked);
+            }
+        }
+
+        let keys = oldest_first
+            .iter()
+            .filter_map(|node| self.graph.key_id(node.index()))
+            .cloned()
+            .collect();
+        Ok(Relation {
+            keys,
+            offsets,
+            bits,
+        })
+    }
+}
+
+impl<K> CausalDag<K> {
+    pub fn node_count(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.graph.dag().edge_count()
+    }
+
+    /// Every node, in the order their hashes were first seen.
+    pub fn nodes(&self) -> impl Iterator<Item = NodeId> {
+        (0..self.node_count()).map(NodeId)
+    }
+
+    /// The node for `hash`, if it is a message's key or is referenced by a message.
+    pub fn node(&self, hash: &Multihash) -> Option<NodeId> {
+        self.graph
+            .interner()
+            .get(hash)
+            .map(|node| NodeId(node.index()))
+    }
+
+    /// The hash of `node`, or `None` if it isn't in this dag.
+    pub fn hash(&self, node: NodeId) -> Option<&Multihash> {
+        self.hashes.get(node.0)
+    }
+
+    /// The [`StableId`] of `node`, or `None` if it isn't in this dag.
+    pub fn stable_id(&self, node: NodeId) -> Option<StableId> {
+        self.hash(node).map(StableId::of)
+    }
+
+    /// The node with the [`StableId`] `id`, if its hash is in this dag.
+    pub fn stable_node(&self, id: &StableId) -> Option<NodeId> {
+        self.node(id.hash())
+    }
+
+    /// The key id of the message with `node` as its key. `None` for hashes that are only ever
+    /// referenced, eg. messages that weren't part of the sort, or blobs.
+    pub fn key_id(&self, node: NodeId) -> Option<&K> {
+        self.graph.key_id(node.0)
+    }
+
+    /// The edges out of `node`, to the hashes its message links to. In no particular order. A
+    /// message has one edge to each hash, however many times it links to it.
+    pub fn links(&self, node: NodeId) -> impl Iterator<Item = (EdgeId, NodeId)> + '_ {
+        self.edges(node, Direction::Outgoing)
+    }
+
+    /// The edges into `node`, from the messages that link to it. In no particular order.
+    pub fn linked_from(&self, node: NodeId) -> impl Iterator<Item = (EdgeId, NodeId)> + '_ {
+        self.edges(node, Direction::Incoming)
+    }
+
+    /// The nodes an edge goes from and to, or `None` if it isn't in this dag.
+    pub fn edge(&self, edge: EdgeId) -> Option<(NodeId, NodeId)> {
+        let (from, to) = self
+            .graph
+            .dag()
+            .graph()
+            .edge_endpoints(petgraph::graph::EdgeIndex::new(edge.0))?;
+        Some((NodeId(from.index()), NodeId(to.index())))
+    }
+
+    fn edges(
+        &self,
+        node: NodeId,
+        direction: Direction,
+    ) -> impl Iterator<Item = (EdgeId, NodeId)> + '_ {
+        let graph = self.graph.dag().graph();
+        // petgraph panics for nodes that aren't in the graph.
+        let node = Some(node.0)
+            .filter(|node| *node < graph.node_count())
+            .map(petgraph::graph::NodeIndex::new);
+        node.into_iter()
+            .flat_map(move |node| graph.edges_directed(node, direction))
+            .map(move |edge| {
+                let other = match direction {
+                    Direction::Outgoing => edge.target(),
+                    Direction::Incoming => edge.source(),
+                };
+                (EdgeId(edge.id().index()), NodeId(other.index()))
+            })
+    }
+}
+
+/// The key ids of a [`CausalDag`]'s messages, newest first. See [`CausalDag::iter`].
+pub struct Sorted<'a, K> {
+    graph: &'a CausalGraph<K>,
+    topo: Topo<NodeIndex<Ix>, <DiGraph<u32, u32, Ix> as Visitable>::Map>,
+}
+
+impl<K: Clone> Iterator for Sorted<'_, K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        let graph = self.graph.dag().graph();
+        loop {
+            let node = self.topo.next(graph)?;
+            if let Some(key_id) = self.graph.key_id(node.index()) {
+                return Some(key_id.clone());
+            }
+        }
+    }
+}
+
+/// The key ids of the messages a message links to, once for each link. See
+/// [`CausalDag::walk_oldest_first`].
+pub struct Parents<'a, K> {
+    graph: &'a CausalGraph<K>,
+    links: petgraph::graph::Neighbors<'a, u32, Ix>,
+}
+
+impl<'a, K> Iterator for Parents<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        let graph = self.graph;
+        self.links
+            .by_ref()
+            .find_map(|node| graph.key_id(node.index()))
+    }
+}
+
+/// Waves of a [`CausalDag`]'s key ids, oldest first. See [`CausalDag::generations`].
+pub struct Generations<'a, K> {
+    dag: &'a CausalDag<K>,
+    /// How many messages each message links to that haven't been in a wave yet.
+    remaining: Vec<usize>,
+    ready: Vec<NodeId>,
+}
+
+impl<K: Clone> Iterator for Generations<'_, K> {
+    type Item = Vec<K>;
+
+    fn next(&mut self) -> Option<Vec<K>> {
+        if self.ready.is_empty() {
+            return None;
+        }
+        let dag = self.dag;
+        let mut next = Vec::new();
+        for node in &self.ready {
+            for (_, child) in dag.linked_from(*node) {
+                self.remaining[child.index()] -= 1;
+                if self.remaining[child.index()] == 0 {
+                    next.push(child);
+                }
+            }
+        }
+        next.sort_unstable();
+        let ready = std::mem::replace(&mut self.ready, next);
+        Some(
+            ready
+                .into_iter()
+                .filter_map(|node| dag.key_id(node))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// The times a message must have been published between, from [`CausalDag::time_bounds`], in
+/// whatever unit the anchors' timestamps are in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TimeBounds {
+    /// The latest anchor the message links to, directly or through other messages, or is. `None`
+    /// if there's none.
+    pub earliest: Option<u64>,
+    /// The earliest anchor that links to the message, directly or through other messages, or
+    /// is. `None` if there's none.
+    pub latest: Option<u64>,
+}
+
+impl TimeBounds {
+    /// Whether the anchors agree about the message, ie. it isn't bound to be published both
+    /// after and before some time. A message that isn't is evidence that an anchor is wrong, or
+    /// that a feed linked to messages before they were published.
+    pub fn is_consistent(&self) -> bool {
+        match (self.earliest, self.latest) {
+            (Some(earliest), Some(latest)) => earliest <= latest,
+            _ => true,
+        }
+    }
+}
+
+/// The happens-before relation between a [`CausalDag`]'s messages, as a bit matrix. See
+/// [`CausalDag::relation`].
+///
+/// Messages are numbered oldest first, so a message only ever happens before messages with
+/// greater indices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relation<K> {
+    /// The key ids, oldest first.
+    keys: Vec<K>,
+    /// Row `n`, the messages before message `n`, is `bits[offsets[n]..offsets[n + 1]]`, with a
+    /// bit for each message before `n` in the order.
+    offsets: Vec<usize>,
+    bits: Vec<u64>,
+}
+
+impl<K> Relation<K> {
+    /// The key ids, oldest first in the reverse of the order [`CausalDag::sorted`] gives. A
+    /// message's index here is its row and column in the matrix.
+    pub fn keys(&self) -> &[K] {
+        &self.keys
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Whether the message at index `before` happens before the one at `after`: whether `after`
+    /// links to it, directly or not. False for out of range indices.
+    pub fn happens_before(&self, before: usize, after: usize) -> bool {
+        before < after
+            && after < self.len()
+            && self.bits[self.offsets[after] + before / 64] & (1 << (before % 64)) != 0
+    }
+
+    /// Every pair of key ids where the first happens before the second, by the second's index
+    /// and then the first's.
+    pub fn pairs(&self) -> impl Iterator<Item = (&K, &K)> + '_ {
+        (0..self.len()).flat_map(move |after| {
+            (0..after)
+                .filter(move |before| self.happens_before(*before, after))
+                .map(move |before| (&self.keys[before], &self.keys[after]))
+        })
+    }
+}
+
+/// Chunks of a [`CausalDag`]'s key ids, newest first. See [`CausalDag::chunks`].
+pub struct Chunks<'a, K> {
+    sorted: Sorted<'a, K>,
+    size: usize,
+}
+
+impl<K: Clone> Iterator for Chunks<'_, K> {
+    type Item = Vec<K>;
+
+    fn next(&mut self) -> Option<Vec<K>> {
+        let chunk: Vec<K> = self.sorted.by_ref().take(self.size).collect();
+        Some(chunk).filter(|chunk| !chunk.is_empty())
+    }
+}
+
+#[cfg(feature = "unstable-graph")]
+mod unstable {
+    use super::{CausalDag, EdgeId, NodeId};
+    use petgraph::graph::{EdgeIndex, Graph, NodeIndex};
+    use ssb_multiformats::multihash::Multihash;
+
+    impl<K> CausalDag<K> {
+        /// Copy the dag into a petgraph `Graph`, weighted with each node's hash. Its indices are
+        /// the same as the `NodeId`s and `EdgeId`s here.
+        ///
+        /// This is only semver stable for as long as petgraph's `Graph` is.
+        pub fn to_petgraph(&self) -> Graph<Multihash, (), petgraph::Directed, u32> {
+            self.graph
+                .dag()
+                .graph()
+                .map(|node, _| self.hashes[node.index()].clone(), |_, _| ())
+        }
+    }
+
+    impl From<NodeId> for NodeIndex<u32> {
+        fn from(node: NodeId) -> NodeIndex<u32> {
+            NodeIndex::new(node.0)
+        }
+    }
+
+    impl From<NodeIndex<u32>> for NodeId {
+        fn from(node: NodeIndex<u32>) -> NodeId {
+            NodeId(node.index())
+        }
+    }
+
+    impl From<EdgeId> for EdgeIndex<u32> {
+        fn from(edge: EdgeId) -> EdgeIndex<u32> {
+            EdgeIndex::new(edge.0)
+        }
+    }
+
+    impl From<EdgeIndex<u32>> for EdgeId {
+        fn from(edge: EdgeIndex<u32>) -> EdgeId {
+            EdgeId(edge.index())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::{CausalDag, NodeId, Role, StableId, TimeBounds};
+    use crate::test_utils::{branching, hash, numbered, thread};
+    use crate::{causal_sort, Error};
+    use serde_json::json;
+
+    #[test]
+    fn dag_matches_messages() {
+        let dag = CausalDag::from_msgs(&thread()).unwrap();
+        assert_eq!(dag.sorted(), causal_sort(&thread()));
+        assert_eq!((dag.node_count(), dag.edge_count()), (3, 3));
+
+        let root = dag
+            .node(&hash(
+                "%rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+            ))
+            .unwrap();
+        let reply = dag
+            .node(&hash(
+                "%reply2K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+            ))
+            .unwrap();
+        assert_eq!(dag.key_id(root), Some(&1));
+        assert_eq!(
+            dag.hash(root),
+            dag.nodes().nth(root.index()).and_then(|n| dag.hash(n))
+        );
+        assert_eq!(dag.links(root).count(), 0);
+        assert_eq!(dag.linked_from(root).count(), 2);
+
+        let mut links: Vec<_> = dag.links(reply).map(|(_, to)| dag.key_id(to)).collect();
+        links.sort();
+        assert_eq!(links, [Some(&1), Some(&2)]);
+        dag.links(reply)
+            .for_each(|(edge, to)| assert_eq!(dag.edge(edge), Some((reply, to))));
+
+        let missing = NodeId(dag.node_count());
+        assert_eq!((dag.hash(missing), dag.key_id(missing)), (None, None));
+        assert_eq!(dag.links(missing).count(), 0);
+    }
+
+    #[test]
+    fn repeated_links_are_one_edge() {
+        // 3 links to 1 as its root and again among its mentions.
+        let mentions = json!([{ "link": numbered(1) }, { "link": numbered(2) }, numbered(1)]);
+        let msgs = [
+            (
+                numbered(3),
+                3,
+                json!({ "root": numbered(1), "mentions": mentions }).to_string(),
+            ),
+            (numbered(2), 2, json!({ "root": numbered(1) }).to_string()),
+            (numbered(1), 1, json!({}).to_string()),
+        ];
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        assert_eq!(dag.edge_count(), 3);
+        assert_eq!(dag.sorted(), [3, 2, 1]);
+
+        let many: Vec<_> = (0..40).map(|i| numbered(i % 20)).collect();
+        let dag = CausalDag::from_links(&[(numbered(99), 99, many)]).unwrap();
+        assert_eq!(dag.edge_count(), 20);
+    }
+
+    #[test]
+    fn inserted_messages_sort_as_if_built_with_the_dag() {
+        let msgs = thread();
+        let mut dag = CausalDag::from_msgs(&msgs[..1]).unwrap();
+        msgs[1..]
+            .iter()
+            .for_each(|(key, key_id, msg)| dag.insert(key, *key_id, msg).unwrap());
+        let built = CausalDag::from_msgs(&msgs).unwrap();
+        assert_eq!(dag.sorted(), built.sorted());
+        assert!(built.nodes().all(|node| dag.hash(node) == built.hash(node)
+            && dag.node(built.hash(node).unwrap()) == Some(node)));
+    }
+
+    #[test]
+    fn failed_inserts_add_no_links() {
+        let msgs = [branching(1, &[]), branching(2, &[1]), branching(3, &[2])];
+        let mut dag = CausalDag::from_msgs(&msgs).unwrap();
+        let sorted = dag.sorted();
+
+        // Linking 1 to 4, then to 3, makes a cycle only once the link to 4 is in.
+        let cycle = json!({ "a": numbered(4), "b": numbered(3) }).to_string();
+        assert!(dag.insert(&numbered(1), 1, &cycle).is_err());
+        assert_eq!(dag.edge_count(), 2);
+        assert_eq!(dag.sorted(), sorted);
+        assert!(dag.key_id(dag.node(&numbered(4)).unwrap()).is_none());
+
+        let cycle = json!({ "a": numbered(4), "b": numbered(5) }).to_string();
+        assert!(dag.insert(&numbered(5), 5, &cycle).is_err());
+        assert_eq!(dag.edge_count(), 2);
+        assert!(dag.key_id(dag.node(&numbered(5)).unwrap()).is_none());
+    }
+
+    #[test]
+    fn removed_messages_leave_dangling_links() {
+        let mut dag = CausalDag::from_msgs(&thread()).unwrap();
+        let root = hash("%rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256");
+        let reply = hash("%reply1K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256");
+        let nodes = dag.node_count();
+
+        assert_eq!(dag.remove(&reply).unwrap(), [3]);
+        assert_eq!(dag.sorted(), [3, 1]);
+        assert_eq!(dag.node_count(), nodes);
+        assert_eq!(dag.edge_count(), 2);
+        assert!(dag.key_id(dag.node(&reply).unwrap()).is_none());
+        assert!(dag.remove(&reply).is_none());
+
+        assert_eq!(dag.remove(&root).unwrap(), [3]);
+        assert_eq!(dag.sorted(), [3]);
+        assert!(dag.remove(&numbered(9)).is_none());
+    }
+
+    #[test]
+    fn shrinking_drops_only_links() {
+        // 4 and 5 link to 8 and 9, which aren't messages, and 2 is removed.
+        let msgs = [
+            branching(4, &[8, 2, 3]),
+            branching(3, &[1]),
+            branching(5, &[9, 4]),
+            branching(2, &[1]),
+            branching(1, &[]),
+            branching(6, &[4, 1]),
+        ];
+        let mut dag = CausalDag::from_msgs(&msgs).unwrap();
+        dag.remove(&numbered(2));
+        let (sorted, generations) = (dag.sorted(), dag.generations().count());
+        let descendants = dag.descendants(numbered(1), false);
+
+        dag.shrink();
+        assert_eq!((dag.node_count(), dag.edge_count()), (5, 5));
+        assert_eq!(dag.sorted(), sorted);
+        assert_eq!(dag.generations().count(), generations);
+        assert!(dag
+            .nodes()
+            .all(|node| dag.key_id(node).is_some()
+                && dag.node(dag.hash(node).unwrap()) == Some(node)));
+        assert_eq!(dag.node(&numbered(8)), None);
+        assert_eq!(dag.descendants(numbered(1), false), descendants);
+
+        let reply = json!({ "branch": [numbered(5), numbered(9)] }).to_string();
+        dag.insert(&numbered(7), 7, &reply).unwrap();
+        assert_eq!(dag.node_count(), 7);
+        assert_eq!(dag.sorted()[0], 7);
+    }
+
+    #[test]
+    fn pages_fit_together_into_the_order() {
+        // A chain of 10 messages, with 11 linking to 5 and to 20, which is missing.
+        let mut msgs: Vec<_> = (1..=10).map(|i| branching(i, &[i - 1])).collect();
+        msgs.push(branching(11, &[5, 20]));
+        let mut dag = CausalDag::from_msgs(&msgs).unwrap();
+        let sorted = dag.sorted();
+
+        let mut pages = vec![sorted[0]];
+        while let Some(oldest) = pages.last() {
+            let page = dag.older_than(numbered(*oldest), 3);
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 3);
+            pages.extend(page.iter());
+        }
+        assert_eq!(pages, sorted[..]);
+
+        assert_eq!(dag.newer_than(numbered(sorted[4]), 3), sorted[1..4]);
+        assert_eq!(dag.newer_than(numbered(sorted[1]), 3), sorted[..1]);
+        assert_eq!(dag.older_than(numbered(sorted[9]), 3), sorted[10..]);
+        let missing = dag.older_than(numbered(20), 20);
+        assert!(!missing.is_empty() && missing.len() < sorted.len());
+        assert!(dag.older_than(numbered(30), 3).is_empty());
+        assert!(dag.newer_than(numbered(sorted[4]), 0).is_empty());
+        assert_eq!(dag.newer_than_key(numbered(sorted[4]), 3), sorted[1..4]);
+
+        // The walk is taken again once the dag changes.
+        let newest = branching(12, &[sorted[0]]).2;
+        dag.insert(&numbered(12), 12, &newest).unwrap();
+        assert_eq!(dag.newer_than(numbered(sorted[0]), 3), [12]);
+    }
+
+    #[test]
+    fn stable_ids_outlast_rebuilds() {
+        let msgs = thread();
+        let mut reversed = msgs.clone();
+        reversed.reverse();
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        let mut rebuilt = CausalDag::from_msgs(&reversed).unwrap();
+        rebuilt.shrink();
+
+        let reply = msgs[0].0.clone();
+        let id = dag.stable_id(dag.node(&reply).unwrap()).unwrap();
+        assert_eq!(id, StableId::of(&reply));
+        assert_eq!(*id.hash(), reply);
+        assert_ne!(dag.stable_node(&id), rebuilt.stable_node(&id));
+        let node = rebuilt.stable_node(&id).unwrap();
+        assert_eq!(
+            rebuilt.key_id(node),
+            dag.key_id(dag.stable_node(&id).unwrap())
+        );
+
+        // Queries take ids as well as hashes.
+        let root = StableId::from(msgs[1].0.clone());
+        assert_eq!(
+            rebuilt.descendants(&root, false),
+            dag.descendants(&msgs[1].0, false)
+        );
+        assert_eq!(rebuilt.concurrent_heads(&root), [3]);
+        assert_eq!(rebuilt.common_ancestors([root, id]), [1]);
+
+        let blob = hash("&rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256");
+        assert_ne!(StableId::of(&blob), StableId::of(&msgs[1].0));
+        assert_eq!(*StableId::of(&blob).hash(), blob);
+        assert_eq!(dag.stable_node(&StableId::of(&blob)), None);
+        assert_eq!(dag.stable_id(NodeId(dag.node_count())), None);
+    }
+
+    #[test]
+    fn time_bounds_come_from_anchors() {
+        // 3 and 2 reply to 1, 4 replies to 3 and 9, which is missing, and 5 replies to 4.
+        let msgs = [
+            branching(1, &[]),
+            branching(2, &[1]),
+            branching(3, &[1]),
+            branching(4, &[3, 9]),
+            branching(5, &[4]),
+        ];
+        let anchors = [
+            (numbered(1), 100),
+            (numbered(9), 300),
+            (numbered(5), 500),
+            (numbered(20), 0),
+        ];
+        let bounds = CausalDag::from_msgs(&msgs).unwrap().time_bounds(&anchors);
+        let bound = |i| (bounds[&i].earliest, bounds[&i].latest);
+        assert_eq!(bounds.len(), 5);
+        assert_eq!(bound(1), (Some(100), Some(100)));
+        assert_eq!(bound(2), (Some(100), None));
+        assert_eq!(bound(3), (Some(100), Some(500)));
+        assert_eq!(bound(4), (Some(300), Some(500)));
+        assert_eq!(bound(5), (Some(500), Some(500)));
+        assert!(bounds.values().all(TimeBounds::is_consistent));
+
+        // 3 can't be both after 400 and before 200.
+        let wrong = [(numbered(3), 400), (numbered(4), 200)];
+        let bounds = CausalDag::from_msgs(&msgs).unwrap().time_bounds(&wrong);
+        assert!(!bounds[&3].is_consistent());
+        assert!(bounds[&1].is_consistent());
+        assert_eq!((bounds[&1].earliest, bounds[&1].latest), (None, Some(200)));
+    }
+
+    #[test]
+    fn generations_come_in_causal_waves() {
+        // 4 links to 2 and 3, which both link to 1, and 5 only links to a missing message.
+        let msgs = [
+            branching(4, &[2, 3]),
+            branching(3, &[1]),
+            branching(5, &[9]),
+            branching(2, &[1]),
+            branching(1, &[]),
+            branching(6, &[4, 1]),
+        ];
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        let generations: Vec<_> = dag.generations().collect();
+        assert_eq!(generations, [vec![1, 5], vec![2, 3], vec![4], vec![6]]);
+        let empty = CausalDag::<usize>::from_links::<Vec<_>>(&[]).unwrap();
+        assert_eq!(empty.generations().count(), 0);
+    }
+
+    #[test]
+    fn roles_tell_orphans_apart() {
+        let mut msgs = thread();
+        msgs.push((numbered(4), 4, json!({ "root": numbered(9) }).to_string()));
+        let roles = CausalDag::from_msgs(&msgs).unwrap().roles();
+        assert_eq!(roles.len(), 4);
+        assert_eq!(roles[&3], Role::Head);
+        assert_eq!(roles[&2], Role::Interior);
+        assert_eq!(roles[&1], Role::Root);
+        assert_eq!(roles[&4], Role::Orphan);
+    }
+
+    #[test]
+    fn components_group_threads() {
+        // Two threads, one joined only by links to a missing root, and a message on its own.
+        let msgs = [
+            (numbered(1), 1, json!({}).to_string()),
+            (numbered(2), 2, json!({ "root": numbered(1) }).to_string()),
+            (numbered(3), 3, json!({ "root": numbered(9) }).to_string()),
+            (numbered(4), 4, json!({ "root": numbered(9) }).to_string()),
+            (numbered(5), 5, json!({ "type": "about" }).to_string()),
+        ];
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        let labeled = dag.sorted_labeled();
+        let key_ids: Vec<_> = labeled.iter().map(|(key_id, _)| *key_id).collect();
+        assert_eq!(key_ids, dag.sorted());
+
+        let component = |key_id: usize| labeled.iter().find(|(k, _)| *k == key_id).unwrap().1;
+        assert_eq!(component(1), component(2));
+        assert_eq!(component(3), component(4));
+        assert_ne!(component(1), component(3));
+        assert_ne!(component(5), component(1));
+        assert_ne!(component(5), component(3));
+        // Numbered in the order each component first comes.
+        let mut next = 0;
+        for (_, component) in &labeled {
+            assert!(component.index() <= next);
+            next = next.max(component.index() + 1);
+        }
+        assert_eq!(next, 3);
+    }
+
+    #[test]
+    fn forests_have_a_tree_per_thread() {
+        // Two threads, one with its root missing, a message on its own, and a message that links
+        // the second thread to another root.
+        let msgs = [
+            (numbered(1), 1, json!({}).to_string()),
+            (numbered(2), 2, json!({ "root": numbered(1) }).to_string()),
+            (numbered(3), 3, json!({ "root": numbered(9) }).to_string()),
+            (numbered(4), 4, json!({ "root": numbered(9) }).to_string()),
+            (numbered(5), 5, json!({ "type": "about" }).to_string()),
+            (
+                numbered(6),
+                6,
+                json!({ "root": numbered(9), "branch": numbered(8) }).to_string(),
+            ),
+        ];
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        let forest = dag.sorted_forest();
+        let labeled = dag.sorted_labeled();
+        assert_eq!(forest.len(), 3);
+        forest.iter().enumerate().for_each(|(id, (_, key_ids))| {
+            let in_tree: Vec<_> = labeled
+                .iter()
+                .filter(|(_, component)| component.index() == id)
+                .map(|(key_id, _)| *key_id)
+                .collect();
+            assert_eq!(*key_ids, in_tree);
+        });
+
+        let tree = |key_id| forest.iter().find(|(_, tree)| tree.contains(&key_id));
+        assert_eq!(tree(2).unwrap().0, numbered(1));
+        assert_eq!(tree(2).unwrap().1.last(), Some(&1));
+        assert_eq!(tree(5).unwrap(), &(numbered(5), vec![5].into()));
+        let (root, key_ids) = tree(3).unwrap();
+        assert_eq!(key_ids.len(), 3);
+        assert!([numbered(8), numbered(9)].contains(root));
+    }
+
+    #[test]
+    fn forests_leave_out_removed_messages() {
+        let mut dag = CausalDag::from_msgs(&[branching(1, &[]), branching(2, &[1])]).unwrap();
+        dag.remove(&numbered(2)).unwrap();
+        assert_eq!(dag.sorted_forest(), [(numbered(1), vec![1].into())]);
+        assert_eq!(dag.sorted_labeled()[0].1.index(), 0);
+    }
+
+    #[test]
+    fn chunks_walk_the_sorted_order() {
+        let unsorted: Vec<_> = (1..=7)
+            .map(|i| {
+                (
+                    numbered(i),
+                    i,
+                    json!({ "previous": numbered(i - 1) }).to_string(),
+                )
+            })
+            .collect();
+        let dag = CausalDag::from_msgs(&unsorted).unwrap();
+        assert_eq!(dag.iter().collect::<Vec<_>>(), dag.sorted());
+
+        let chunks: Vec<_> = dag.chunks(3).collect();
+        assert_eq!(chunks, [vec![7, 6, 5], vec![4, 3, 2], vec![1]]);
+        assert_eq!(dag.chunks(7).count(), 1);
+    }
+
+    #[test]
+    fn walk_visits_parents_first() {
+        let dag = CausalDag::from_msgs(&thread()).unwrap();
+        let mut visited = Vec::new();
+        dag.walk_oldest_first(|key_id, parents| {
+            let mut parents: Vec<_> = parents.copied().collect();
+            parents.sort();
+            parents
+                .iter()
+                .for_each(|parent| assert!(visited.contains(parent)));
+            visited.push(*key_id);
+            if *key_id == 3 {
+                assert_eq!(parents, [1, 2]);
+            }
+        });
+        assert_eq!(visited, [1, 2, 3]);
+
+        // Links to hashes that aren't messages aren't parents.
+        let msgs = [(numbered(1), 1, vec![numbered(0)])];
+        let mut walked = Vec::new();
+        CausalDag::from_links(&msgs)
+            .unwrap()
+            .walk_oldest_first(|key_id, parents| walked.push((*key_id, parents.count())));
+        assert_eq!(walked, [(1, 0)]);
+    }
+
+    #[test]
+    fn referenced_hashes_have_no_key_id() {
+        let msgs = [(
+            hash("%reply1K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"),
+            1,
+            vec![hash("%rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256")],
+        )];
+        let dag = CausalDag::from_links(&msgs).unwrap();
+        let root = dag.node(&msgs[0].2[0]).unwrap();
+        assert_eq!(dag.key_id(root), None);
+        assert_eq!(dag.sorted(), [1]);
+    }
+
+    #[test]
+    fn relation_is_the_transitive_closure() {
+        let dag = CausalDag::from_msgs(&thread()).unwrap();
+        let relation = dag.relation(usize::MAX).unwrap();
+        assert_eq!(relation.keys(), [1, 2, 3]);
+        let pairs: Vec<_> = relation.pairs().map(|(a, b)| (*a, *b)).collect();
+        assert_eq!(pairs, [(1, 2), (1, 3), (2, 3)]);
+        assert!(!relation.happens_before(1, 0));
+        assert!(!relation.happens_before(0, 3));
+
+        // A chain, across many words of each row, through a message that's only a link.
+        let mut msgs: Vec<_> = (1..=100)
+            .map(|i| (numbered(i), i, vec![numbered(i - 1)]))
+            .collect();
+        msgs.remove(50);
+        let relation = CausalDag::from_links(&msgs)
+            .unwrap()
+            .relation(2000)
+            .unwrap();
+        assert_eq!(relation.len(), 99);
+        assert_eq!(relation.pairs().count(), 49 * 48 / 2 + 50 * 49 / 2);
+        let index = |key| relation.keys().iter().position(|k| *k == key).unwrap();
+        assert!(relation.happens_before(index(1), index(50)));
+        assert!(!relation.happens_before(index(50), index(52)));
+        assert!(!relation.happens_before(index(52), index(50)));
+        assert!(relation.happens_before(index(52), index(100)));
+
+        let dag = CausalDag::from_links(&msgs).unwrap();
+        assert!(matches!(
+            dag.relation(1000),
+            Err(Error::OverBudget { budget: 1000 })
+        ));
+    }
+
+    #[cfg(feature = "unstable-graph")]
+    #[test]
+    fn petgraph_indices_match() {
+        use petgraph::graph::NodeIndex;
+
+        let dag = CausalDag::from_msgs(&thread()).unwrap();
+        let graph = dag.to_petgraph();
+        assert_eq!(graph.edge_count(), dag.edge_count());
+        dag.nodes().for_each(|node| {
+            assert_eq!(dag.hash(node), Some(&graph[NodeIndex::from(node)]));
+            assert_eq!(
+                graph.neighbors(node.into()).count(),
+                dag.links(node).count()
+            );
+        });
+    }
+}
@@ -0,0 +1,309 @@
+//! Reading messages from ssb-db2's log, an
+//! [async-append-only-log](https://github.com/ssbc/async-append-only-log) of
+//! [BIPF](https://github.com/ssbc/bipf) records.
+use crate::error::Error;
+use crate::extract::RECURSION_LIMIT;
+use crate::keys::Json;
+use crate::ndjson::read_json;
+use ssb_multiformats::multihash::Multihash;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read};
+
+/// The size of the log's blocks. Records never cross from one block to the next.
+const BLOCK: usize = 64 * 1024;
+
+/// Read each record of ssb-db2's log, eg. `~/.ssb/db2/log.bipf`, as a message to sort, so that
+/// sorting the messages gives their offsets in the log:
+/// `causal_sort(&read_db2_log(file).collect::<Result<Vec<_>, _>>()?)`.
+///
+/// Each record is a BIPF `{ key, value, timestamp }`, and is keyed and given as
+/// [`read_ndjson`] gives an envelope: its value in its legacy encoding. Records that have been
+/// deleted, ie. overwritten with zeros, are skipped.
+///
+/// A record that isn't a message is an `Error::Parse`, and reading carries on past it. A log
+/// that can't be read, or that's corrupt, is an `Error::Read`, and ends the records.
+///
+/// [`read_ndjson`]: crate::read_ndjson
+pub fn read_db2_log<R: Read>(
+    log: R,
+) -> impl Iterator<Item = Result<(Multihash, usize, String), Error>> {
+    read_records(log)
+        .filter(|record| !matches!(record, Ok((_, record)) if record.iter().all(|byte| *byte == 0)))
+        .map(|record| {
+            let (offset, record) = record?;
+            read_record(&record)
+                .map(|(key, msg)| (key, offset, msg))
+                .map_err(|source| Error::Parse {
+                    index: offset,
+                    source,
+                })
+        })
+}
+
+/// Each record of the log with its offset. In each block, a record is its length, a little
+/// endian `u16`, then its bytes, and a length of 0 ends the block.
+fn read_records<R: Read>(log: R) -> impl Iterator<Item = Result<(usize, Vec<u8>), Error>> {
+    let mut log = Some(log);
+    let mut block = Vec::with_capacity(BLOCK);
+    let (mut start, mut pos) = (0, 0);
+    std::iter::from_fn(move || loop {
+        if pos + 2 > block.len() || block[pos..pos + 2] == [0, 0] {
+            start += block.len();
+            pos = 0;
+            block.clear();
+            let read = log.as_mut()?.take(BLOCK as u64).read_to_end(&mut block);
+            match read {
+                Ok(0) => return None,
+                Ok(_) => continue,
+                Err(source) => {
+                    log = None;
+                    return Some(Err(Error::Read { source }));
+                }
+            }
+        }
+        let len = usize::from(u16::from_le_bytes([block[pos], block[pos + 1]]));
+        let offset = start + pos;
+        match block.get(pos + 2..pos + 2 + len) {
+            Some(record) => {
+                pos += 2 + len;
+                return Some(Ok((offset, record.to_vec())));
+            }
+            None => {
+                log = None;
+                block.clear();
+                let source = io::Error::new(ErrorKind::InvalidData, "record overruns its block");
+                return Some(Err(Error::Read { source }));
+            }
+        }
+    })
+}
+
+fn read_record(
+    record: &[u8],
+) -> Result<(Multihash, String), Box<dyn std::error::Error + Send + Sync>> {
+    match Bipf(record).value(0)? {
+        (json @ Json::Object(_), []) => read_json(json),
+        (Json::Object(_), _) => Err("the record has bytes after its value".into()),
+        _ => Err("the record isn't an object".into()),
+    }
+}
+
+/// BIPF bytes being decoded.
+struct Bipf<'a>(&'a [u8]);
+
+const STRING: u8 = 0;
+const BUFFER: u8 = 1;
+const INT: u8 = 2;
+const DOUBLE: u8 = 3;
+const ARRAY: u8 = 4;
+const OBJECT: u8 = 5;
+const BOOLNULL: u8 = 6;
+
+impl<'a> Bipf<'a> {
+    /// Decode one value, `depth` arrays and objects deep, and give the bytes after it.
+    fn value(mut self, depth: usize) -> Result<(Json, &'a [u8]), &'static str> {
+        let tag = self.varint()?;
+        let len = usize::try_from(tag >> 3).map_err(|_| "a value is too long")?;
+        if len > self.0.len() {
+            return Err("a value is cut short");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        let kind = (tag & 7) as u8;
+        if (kind == ARRAY || kind == OBJECT) && depth >= RECURSION_LIMIT {
+            return Err("nested too deeply");
+        }
+        let value = match kind {
+            STRING => Json::String(string(bytes)?),
+            INT => match <[u8; 4]>::try_from(bytes) {
+                Ok(bytes) => Json::Number(f64::from(i32::from_le_bytes(bytes))),
+                Err(_) => return Err("an int isn't 4 bytes long"),
+            },
+            DOUBLE => match <[u8; 8]>::try_from(bytes) {
+                Ok(bytes) => Json::Number(f64::from_le_bytes(bytes)),
+                Err(_) => return Err("a double isn't 8 bytes long"),
+            },
+            ARRAY => {
+                let (mut values, mut bytes) = (Vec::new(), bytes);
+                while !bytes.is_empty() {
+                    let (value, rest) = Bipf(bytes).value(depth + 1)?;
+                    values.push(value);
+                    bytes = rest;
+                }
+                Json::Array(values)
+            }
+            OBJECT => {
+                let mut fields: Vec<(String, Json)> = Vec::new();
+                let mut bytes = bytes;
+                while !bytes.is_empty() {
+                    let (field, rest) = match Bipf(bytes).value(depth + 1)? {
+                        (Json::String(field), rest) => (field, rest),
+                        _ => return Err("a field name isn't a string"),
+                    };
+                    let (value, rest) = Bipf(rest).value(depth + 1)?;
+                    match fields.iter_mut().find(|(seen, _)| *seen == field) {
+                        Some((_, seen)) => *seen = value,
+                        None => fields.push((field, value)),
+                    }
+                    bytes = rest;
+                }
+                Json::Object(fields)
+            }
+            BOOLNULL => match bytes {
+                [] => Json::Null,
+                [b] => Json::Bool(*b != 0),
+                _ => return Err("a boolean is more than a byte"),
+            },
+            BUFFER => return Err("a buffer isn't JSON"),
+            _ => return Err("a value has a reserved type"),
+        };
+        Ok((value, rest))
+    }
+
+    /// Decode an unsigned LEB128 varint.
+    fn varint(&mut self) -> Result<u64, &'static str> {
+        let mut n = 0;
+        for (index, byte) in self.0.iter().enumerate().take(10) {
+            n |= u64::from(byte & 0x7f) << (7 * index);
+            if byte & 0x80 == 0 {
+                self.0 = &self.0[index + 1..];
+                return Ok(n);
+            }
+        }
+        Err("a varint is cut short")
+    }
+}
+
+fn string(bytes: &[u8]) -> Result<String, &'static str> {
+    match std::str::from_utf8(bytes) {
+        Ok(st) => Ok(st.to_owned()),
+        Err(_) => Err("a string isn't UTF-8"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_db2_log, BLOCK};
+    use crate::extract::RECURSION_LIMIT;
+    use crate::keys::message_key;
+    use crate::test_utils::numbered;
+    use crate::{Error, SortBuilder};
+    use serde_json::{json, Value};
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+
+    /// `value` in BIPF.
+    fn bipf(value: &Value) -> Vec<u8> {
+        let (kind, bytes) = match value {
+            Value::Null => (6, vec![]),
+            Value::Bool(b) => (6, vec![*b as u8]),
+            Value::Number(n) => match n.as_i64().map(i32::try_from) {
+                Some(Ok(n)) => (2, n.to_le_bytes().to_vec()),
+                _ => (3, n.as_f64().unwrap().to_le_bytes().to_vec()),
+            },
+            Value::String(st) => (0, st.as_bytes().to_vec()),
+            Value::Array(values) => (4, values.iter().flat_map(bipf).collect()),
+            Value::Object(fields) => {
+                let fields = fields.iter();
+                let bytes = fields.flat_map(|(field, value)| {
+                    let mut bytes = bipf(&Value::String(field.clone()));
+                    bytes.extend(bipf(value));
+                    bytes
+                });
+                (5, bytes.collect())
+            }
+        };
+        encode(kind, bytes)
+    }
+
+    /// A value of BIPF type `kind` with the encoded `bytes`.
+    fn encode(kind: u64, bytes: Vec<u8>) -> Vec<u8> {
+        let mut tag = (bytes.len() as u64) << 3 | kind;
+        let mut encoded = Vec::new();
+        while tag >= 0x80 {
+            encoded.push(tag as u8 | 0x80);
+            tag >>= 7;
+        }
+        encoded.push(tag as u8);
+        encoded.extend(bytes);
+        encoded
+    }
+
+    /// Append `record` to `log`, in the block it's in.
+    fn append(log: &mut Vec<u8>, record: &[u8]) {
+        log.extend_from_slice(&(record.len() as u16).to_le_bytes());
+        log.extend_from_slice(record);
+    }
+
+    /// Pad `log` out to the end of its block.
+    fn end_block(log: &mut Vec<u8>) {
+        log.resize(log.len() + BLOCK - log.len() % BLOCK, 0);
+    }
+
+    #[test]
+    fn records_are_read_at_their_offsets() {
+        let content = json!({ "type": "post", "n": [1, 1.5, -3000000000i64, null, true] });
+        let root = json!({ "key": numbered(1), "value": { "content": content }, "timestamp": 1 });
+        let reply = json!({ "previous": numbered(1), "content": { "type": "post" } });
+        let reply_key = message_key(&reply.to_string());
+        let reply = json!({ "key": reply_key, "value": reply, "timestamp": 2 });
+
+        let mut log = Vec::new();
+        append(&mut log, &bipf(&root));
+        append(&mut log, &[0; 20]);
+        append(&mut log, &bipf(&json!("not a message")));
+        end_block(&mut log);
+        append(&mut log, &bipf(&reply));
+        end_block(&mut log);
+
+        let read: Vec<_> = read_db2_log(Cursor::new(log)).collect();
+        assert_eq!(read.len(), 3);
+        let (key, offset, msg) = read[0].as_ref().unwrap();
+        assert_eq!((key, *offset), (&numbered(1), 0));
+        assert!(msg.contains("\"n\": [\n      1,\n      1.5,\n      -3000000000,\n"));
+        let garbage = 2 + bipf(&root).len() + 22;
+        assert!(matches!(read[1], Err(Error::Parse { index, .. }) if index == garbage));
+        let (key, offset, _) = read[2].as_ref().unwrap();
+        assert_eq!((key, *offset), (&reply_key, BLOCK));
+
+        let msgs: Vec<_> = read.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(SortBuilder::new().try_sort(&msgs).unwrap(), [BLOCK, 0]);
+    }
+
+    #[test]
+    fn corrupt_logs_end_with_an_error() {
+        let mut log = Vec::new();
+        append(&mut log, &bipf(&json!({ "key": numbered(1), "value": {} })));
+        log.extend_from_slice(&u16::MAX.to_le_bytes());
+        end_block(&mut log);
+        append(&mut log, &bipf(&json!({ "key": numbered(2), "value": {} })));
+
+        let read: Vec<_> = read_db2_log(Cursor::new(log)).collect();
+        assert!(matches!(read[..], [Ok(_), Err(Error::Read { .. })]));
+        assert_eq!(read_db2_log(Cursor::new(vec![])).count(), 0);
+    }
+
+    #[test]
+    fn deep_records_are_errors() {
+        // Built from the inside out, since `bipf` would recurse as deep.
+        let record = |depth| {
+            let nested = (0..depth).fold(encode(4, vec![]), |inner, _| encode(4, inner));
+            let mut value = bipf(&json!("content"));
+            value.extend(nested);
+            let mut fields = bipf(&json!("key"));
+            fields.extend(bipf(&json!(numbered(1))));
+            fields.extend(bipf(&json!("value")));
+            fields.extend(encode(5, value));
+            encode(5, fields)
+        };
+        let mut log = Vec::new();
+        append(&mut log, &record(RECURSION_LIMIT - 3));
+        append(&mut log, &record(15_000));
+
+        let read: Vec<_> = read_db2_log(Cursor::new(log)).collect();
+        assert!(read[0].is_ok());
+        match &read[1] {
+            Err(Error::Parse { source, .. }) => assert_eq!(source.to_string(), "nested too deeply"),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+}
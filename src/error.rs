@@ -0,0 +1,255 @@
+//! Why a sort failed.
+use crate::graph::CYCLE;
+use ssb_multiformats::multihash::Multihash;
+use std::error;
+
+/// Why the `try_` sorts couldn't sort a set of messages.
+///
+/// Only `Cycle` and `TooManyNodes` stop a sort by default. The other failures need to be asked
+/// for, eg. with [`SortBuilder::strict`](crate::SortBuilder::strict).
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The messages' links form a cycle, so they have no causal order. `keys` are the messages on
+    /// the cycle, each linking to the next and the last linking back to the first.
+    ///
+    /// A message's key is the hash of its contents, so genuine messages can't do this, but made up
+    /// or mislabelled ones can.
+    #[error("the links of {} messages form a cycle", keys.len())]
+    Cycle { keys: Vec<Multihash> },
+    /// More than one message has `key`.
+    #[error("more than one message has the key {key:?}")]
+    DuplicateKey { key: Multihash },
+    /// The message at `index` isn't valid JSON.
+    #[error("message {index} isn't valid JSON")]
+    Parse {
+        index: usize,
+        #[source]
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+    /// The message at `index` has arrays or objects nested too deep to parse.
+    #[error("message {index} is nested too deep")]
+    TooDeep { index: usize },
+    /// The sort was cancelled before it finished.
+    #[error("the sort was cancelled")]
+    Cancelled,
+    /// There are more distinct hashes, keys and links together, than the graph backend can hold.
+    #[error("there are too many hashes for the graph backend")]
+    TooManyNodes,
+    /// The graph grew past the [memory budget](crate::SortBuilder::memory_budget) of `budget`
+    /// bytes.
+    #[error("the graph needs more than its budget of {budget} bytes")]
+    OverBudget { budget: usize },
+    /// `key` isn't the hash of the value of the message at `index`, so the message is forged or
+    /// mislabelled. Only checked for with `SortBuilder::verify_keys`.
+    #[error("message {index} doesn't hash to its key {key:?}")]
+    KeyMismatch { index: usize, key: Multihash },
+    /// Messages couldn't be read, eg. by [`read_ndjson`](crate::read_ndjson).
+    #[error("couldn't read the messages")]
+    Read {
+        #[source]
+        source: std::io::Error,
+    },
+    /// Sorted messages couldn't be written, eg. by [`write_ndjson`](crate::write_ndjson).
+    #[error("couldn't write the messages")]
+    Write {
+        #[source]
+        source: std::io::Error,
+    },
+    /// The sort's [`Validate`](crate::Validate) found the message at `index` invalid, and was
+    /// set to fail with [`InvalidMessages::Error`](crate::InvalidMessages::Error).
+    #[error("message {index} is invalid")]
+    Invalid {
+        index: usize,
+        #[source]
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+}
+
+/// Unwrap the result of a `try_` sort for the sorts that panic instead.
+pub(crate) fn unwrap<T>(result: Result<T, Error>) -> T {
+    result.unwrap_or_else(|error| match error {
+        Error::Cycle { .. } => panic!("{}", CYCLE),
+        error => panic!("{}", error),
+    })
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::Error;
+    use crate::test_utils::{numbered, thread};
+    use crate::{try_causal_sort, try_causal_sort_bytes, Backend, SortBuilder, SortedMessages};
+    use proptest::prelude::*;
+    use serde_json::json;
+    use ssb_multiformats::multihash::Multihash;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn try_every_sort(
+        msgs: &[(Multihash, usize, Vec<u8>)],
+    ) -> Vec<Result<SortedMessages<usize>, Error>> {
+        let text: Vec<_> = msgs
+            .iter()
+            .map(|(key, id, msg)| (key.clone(), *id, String::from_utf8_lossy(msg).into_owned()))
+            .collect();
+        #[cfg_attr(not(feature = "rayon"), allow(unused_mut))]
+        let mut results = vec![
+            try_causal_sort_bytes(msgs),
+            try_causal_sort(&text),
+            SortBuilder::new().try_sort(&text),
+            SortBuilder::new().backend(Backend::Csr).try_sort(&text),
+        ];
+        #[cfg(feature = "rayon")]
+        results.push(crate::try_par_causal_sort(&text));
+        results
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let a = numbered(1);
+        let b = numbered(2);
+        let cycle = [
+            (
+                a.clone(),
+                1,
+                json!({ "previous": b }).to_string().into_bytes(),
+            ),
+            (
+                b.clone(),
+                2,
+                json!({ "previous": a }).to_string().into_bytes(),
+            ),
+        ];
+        let loop_ = [(
+            a.clone(),
+            1,
+            json!({ "previous": a }).to_string().into_bytes(),
+        )];
+
+        try_every_sort(&cycle)
+            .into_iter()
+            .for_each(|result| match result {
+                Err(Error::Cycle { mut keys }) => {
+                    keys.sort();
+                    assert_eq!(keys, [a.clone(), b.clone()]);
+                }
+                result => panic!("expected a cycle, got {:?}", result),
+            });
+        try_every_sort(&loop_).into_iter().for_each(|result| {
+            assert!(matches!(result, Err(Error::Cycle { keys }) if keys == [a.clone()]));
+        });
+    }
+
+    #[test]
+    fn strict_sorts_reject_invalid_messages() {
+        let strict = SortBuilder::new().strict(true);
+        let mut msgs = thread();
+        assert_eq!(strict.try_sort(&msgs).unwrap(), [3, 2, 1]);
+
+        msgs.push((numbered(4), 4, "{\"not\": json".to_owned()));
+        assert!(matches!(
+            strict.try_sort(&msgs),
+            Err(Error::Parse { index: 3, .. })
+        ));
+
+        msgs[3].2 = format!("{}1{}", "[".repeat(200), "]".repeat(200));
+        assert!(matches!(
+            strict.try_sort(&msgs),
+            Err(Error::TooDeep { index: 3 })
+        ));
+        assert_eq!(SortBuilder::new().try_sort(&msgs).unwrap(), [4, 3, 2, 1]);
+
+        msgs[3] = msgs[0].clone();
+        assert!(
+            matches!(strict.try_sort(&msgs), Err(Error::DuplicateKey { key }) if key == msgs[0].0)
+        );
+    }
+
+    #[test]
+    fn cancelled_sorts_stop() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let builder = SortBuilder::new().cancellation(cancelled.clone());
+        assert!(builder.try_sort(&thread()).is_ok());
+
+        cancelled.store(true, Ordering::Relaxed);
+        assert!(matches!(builder.try_sort(&thread()), Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn sorts_stop_at_the_memory_budget() {
+        let msgs: Vec<_> = (1..1000)
+            .map(|i| {
+                (
+                    numbered(i),
+                    i,
+                    json!({ "previous": numbered(i - 1) }).to_string(),
+                )
+            })
+            .collect();
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert!(builder
+                .clone()
+                .memory_budget(1 << 20)
+                .try_sort(&msgs)
+                .is_ok());
+            assert!(matches!(
+                builder.memory_budget(10_000).try_sort(&msgs),
+                Err(Error::OverBudget { budget: 10_000 })
+            ));
+        });
+    }
+
+    #[test]
+    fn adversarial_messages_sort() {
+        let link = "\"%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256\"";
+        let bodies: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"\xff\xfe{\"root\": \x80}".to_vec(),
+            format!("{}{}{}", "[".repeat(100_000), link, "]".repeat(100_000)).into_bytes(),
+            format!("{}{}", "{\"a\":".repeat(10_000), link).into_bytes(),
+            b"{\"a\": 1e99999999, \"b\": -0, \"c\": 123456789012345678901234567890}".to_vec(),
+            b"\"%\" \"&.sha256\" \"%====.sha256\" \"%AAAA=.sha256\"".to_vec(),
+            json!(["%.sha256", "%AAAA=.sha256", "&%=.sha256", "%\u{0}=.sha256"])
+                .to_string()
+                .into_bytes(),
+            json!({ "a": link, "a": link }).to_string().into_bytes(),
+        ];
+        let msgs: Vec<_> = bodies
+            .into_iter()
+            .enumerate()
+            .map(|(i, body)| (numbered(i % 3 + 10), i, body))
+            .collect();
+
+        try_every_sort(&msgs)
+            .into_iter()
+            .for_each(|result| assert_eq!(result.map(|sorted| sorted.len()).ok(), Some(3)));
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_messages_never_panic(
+            msgs in prop::collection::vec(
+                (0..20_usize, prop::collection::vec(0..20_usize, 0..4), any::<Vec<u8>>()),
+                0..40,
+            )
+        ) {
+            let msgs: Vec<_> = msgs
+                .into_iter()
+                .enumerate()
+                .map(|(i, (key, links, junk))| {
+                    let links: Vec<_> = links.into_iter().map(numbered).collect();
+                    let body = match i % 3 {
+                        0 => junk,
+                        _ => json!({ "branch": links, "junk": junk }).to_string().into_bytes(),
+                    };
+                    (numbered(key), i, body)
+                })
+                .collect();
+
+            let results = try_every_sort(&msgs);
+            let first = results[0].as_ref().ok();
+            prop_assert!(results.iter().all(|result| result.as_ref().ok() == first));
+        }
+    }
+}
@@ -0,0 +1,727 @@
+//! Finding the links in a message.
+use crate::builder::{Edges, Window};
+use crate::hashes::{from_uri, parse_link, LinkFormat, ReadUnknown, UnknownHash};
+use crate::trace::span;
+use serde::Deserialize;
+use serde_json::Value;
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::Multikey;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// serde_json refuses to parse messages nested this deep, and the other parsers do the same.
+pub(crate) const RECURSION_LIMIT: usize = 128;
+
+/// Why the links couldn't be found in a message.
+#[derive(Debug)]
+pub(crate) enum Failure {
+    /// The message isn't valid JSON.
+    Parse(Box<dyn Error + Send + Sync>),
+    /// The message is nested `RECURSION_LIMIT` or more deep.
+    TooDeep,
+}
+
+impl Failure {
+    /// Work out why `msg` couldn't be parsed.
+    pub(crate) fn of<E: Into<Box<dyn Error + Send + Sync>>>(msg: &[u8], error: E) -> Failure {
+        if too_deep(msg) {
+            Failure::TooDeep
+        } else {
+            Failure::Parse(error.into())
+        }
+    }
+}
+
+/// Whether the arrays and objects in `msg` are nested `RECURSION_LIMIT` or more deep. This only
+/// looks at brackets outside of strings, so works whether or not the rest of `msg` is valid.
+pub(crate) fn too_deep(msg: &[u8]) -> bool {
+    let mut depth = 0_usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for byte in msg {
+        match (in_string, escaped, byte) {
+            (true, true, _) => escaped = false,
+            (true, false, b'\\') => escaped = true,
+            (true, false, b'"') => in_string = false,
+            (true, false, _) => (),
+            (false, _, b'"') => in_string = true,
+            (false, _, b'[') | (false, _, b'{') => {
+                depth += 1;
+                if depth >= RECURSION_LIMIT {
+                    return true;
+                }
+            }
+            (false, _, b']') | (false, _, b'}') => depth = depth.saturating_sub(1),
+            (false, _, _) => (),
+        }
+    }
+    false
+}
+
+/// Roughly how many links to messages `msg` has, counted without parsing it: the strings that
+/// start like a link, with `%`, with `%` escaped as `\u0025`, or with `ssb:message/`. Like
+/// `too_deep` this tracks strings and escapes rather than looking for sigils anywhere, so the
+/// count is the same whatever the whitespace around the strings or the escapes in them.
+pub(crate) fn count_message_links(msg: &[u8]) -> usize {
+    let starts_link = |rest: &[u8]| {
+        [&b"%"[..], b"\\u0025", b"ssb:message/"]
+            .iter()
+            .any(|start| rest.starts_with(start))
+    };
+    let mut count = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, byte) in msg.iter().enumerate() {
+        match (in_string, escaped, byte) {
+            (true, true, _) => escaped = false,
+            (true, false, b'\\') => escaped = true,
+            (true, false, b'"') => in_string = false,
+            (true, false, _) => (),
+            (false, _, b'"') => {
+                in_string = true;
+                count += starts_link(&msg[index + 1..]) as usize;
+            }
+            (false, _, _) => (),
+        }
+    }
+    count
+}
+
+/// Where a message's type can be, depending on whether it's the content of a message, a message
+/// value or a message with its key.
+const TYPE_PATHS: [&[&str]; 3] = [
+    &["type"],
+    &["content", "type"],
+    &["value", "content", "type"],
+];
+
+/// Where a message's content and `previous` link can be, for message values and messages with
+/// their keys. Messages with neither are taken to be content.
+pub(crate) const CONTENT_PATHS: [&[&str]; 2] = [&["content"], &["value", "content"]];
+const PREVIOUS_PATHS: [&[&str]; 2] = [&["previous"], &["value", "previous"]];
+
+/// The key of a message with its key, eg. from `createHistoryStream`.
+pub(crate) fn envelope_key(msg: &str) -> Option<Multihash> {
+    #[derive(Deserialize)]
+    struct Envelope<'a> {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+    }
+    match parse_link(&serde_json::from_str::<Envelope>(msg).ok()?.key)? {
+        key @ Multihash::Message(_) => Some(key),
+        Multihash::Blob(_) => None,
+    }
+}
+
+/// Which part of a message to find links in.
+pub(crate) enum Part {
+    Whole,
+    Nothing,
+    /// The value at this path of object fields, if there is one.
+    At(&'static [&'static str]),
+}
+
+/// Where a message's author and sequence number can be, depending on whether it's a message value
+/// or a message with its key.
+const POSITION_PATHS: [(&[&str], &[&str]); 2] = [
+    (&["author"], &["sequence"]),
+    (&["value", "author"], &["value", "sequence"]),
+];
+
+/// Where a message's claimed timestamp can be: in its value, or at the top for message values.
+const TIMESTAMP_PATHS: [&[&str]; 2] = [&["value", "timestamp"], &["timestamp"]];
+
+/// A string or a number found in a message. Non-negative integers are `Uint`s, any other number
+/// a `Float`.
+pub(crate) enum Scalar<'v> {
+    Str(&'v str),
+    Uint(u64),
+    Float(f64),
+}
+
+/// Where a message is in its author's feed.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct FeedPosition {
+    pub(crate) author: String,
+    pub(crate) sequence: u64,
+}
+
+/// What's found in a message besides its links.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Found {
+    /// Whether the message is kept, rather than excluded by its type or author.
+    pub(crate) kept: bool,
+    /// Whether the message is excluded by its author.
+    pub(crate) excluded_author: bool,
+    /// Where the message is in its author's feed, if that's wanted and the message says.
+    pub(crate) position: Option<FeedPosition>,
+    /// Whether the message goes in the order, rather than only lending it its links because its
+    /// timestamp is on the wrong side of the time window.
+    pub(crate) sorted: bool,
+    /// The message's type, if `LinkOptions::types` and it has one.
+    pub(crate) msg_type: Option<String>,
+    /// Whether the message's links are trusted to order it.
+    pub(crate) trusted: bool,
+}
+
+/// Decides from a message's author and type whether its links are trusted.
+type Trust = dyn Fn(Option<&str>, Option<&str>) -> bool + Send + Sync;
+
+#[derive(Clone)]
+pub(crate) struct TrustLinks(pub(crate) Arc<Trust>);
+
+impl fmt::Debug for TrustLinks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TrustLinks")
+    }
+}
+
+/// Which messages, and which of their links, to find.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LinkOptions {
+    /// Leave out the links in `fork` fields.
+    pub(crate) ignore_forks: bool,
+    /// Leave out messages of these types altogether.
+    pub(crate) exclude_types: Vec<String>,
+    /// Leave out messages by these authors altogether.
+    pub(crate) exclude_authors: Vec<String>,
+    /// Find where each message is in its author's feed.
+    pub(crate) sequence_edges: bool,
+    /// Which classes of links to find.
+    pub(crate) edges: Edges,
+    /// Only sort the messages with claimed timestamps on one side of this window, in
+    /// milliseconds.
+    pub(crate) window: Option<(Range<u64>, Window)>,
+    /// Read links in these forms rather than the legacy ones.
+    pub(crate) link_formats: Option<Arc<[LinkFormat]>>,
+    /// Read links with hashes this crate can't.
+    pub(crate) unknown_hashes: Option<ReadUnknown>,
+    /// Find links only in the `value` of messages with their keys.
+    pub(crate) envelopes: bool,
+    /// Find each message's type, to count messages and links by type.
+    pub(crate) types: bool,
+    /// Only find the links of messages this trusts.
+    pub(crate) trust: Option<TrustLinks>,
+}
+
+impl LinkOptions {
+    /// The hash that the string `st` links to, if it's a link.
+    pub(crate) fn link(&self, st: &str) -> Option<Multihash> {
+        let known = match (&self.link_formats, st.strip_prefix("ssb:")) {
+            (None, _) => parse_link(st),
+            (Some(_), Some(uri)) => from_uri(uri),
+            (Some(formats), None) => formats.iter().find_map(|format| format.parse(st)),
+        };
+        known.or_else(|| {
+            let read = self.unknown_hashes.as_ref()?;
+            (read.0)(&UnknownHash::parse(st)?)
+        })
+    }
+
+    /// Whether to leave out the links in the object field `key`.
+    pub(crate) fn skips(&self, key: &str) -> bool {
+        self.ignore_forks && key == "fork"
+    }
+
+    /// Which part of a message to find links in, given a way to tell whether there's a value at a
+    /// path of object fields in it.
+    pub(crate) fn part<F: Fn(&[&str]) -> bool>(&self, has: F) -> Part {
+        let value = CONTENT_PATHS.iter().position(|path| has(path));
+        match (self.edges, value) {
+            (Edges::All, _) if self.envelopes && has(&["value"]) => Part::At(&["value"]),
+            (Edges::All, _) | (Edges::Content, None) => Part::Whole,
+            (Edges::Feed, None) => Part::Nothing,
+            (Edges::Feed, Some(index)) => Part::At(PREVIOUS_PATHS[index]),
+            (Edges::Content, Some(index)) => Part::At(CONTENT_PATHS[index]),
+        }
+    }
+
+    /// Look through a message, given a way to look up the scalar at a path of object fields in
+    /// it.
+    pub(crate) fn find<'v, F>(&self, scalar_at: F) -> Found
+    where
+        F: Fn(&[&str]) -> Option<Scalar<'v>>,
+    {
+        let string_at = |path: &[&str]| match scalar_at(path)? {
+            Scalar::Str(st) => Some(st),
+            _ => None,
+        };
+        let msg_type = match self.exclude_types.is_empty() && !self.types && self.trust.is_none() {
+            true => None,
+            false => TYPE_PATHS.iter().find_map(|path| string_at(path)),
+        };
+        let author = match self.exclude_authors.is_empty() && self.trust.is_none() {
+            true => None,
+            false => POSITION_PATHS
+                .iter()
+                .find_map(|(author, _)| string_at(author)),
+        };
+        let excluded = |excluded: &[String], found: Option<&str>| {
+            found.is_some_and(|found| excluded.iter().any(|excluded| excluded == found))
+        };
+        let excluded_author = excluded(&self.exclude_authors, author);
+        let kept = !excluded(&self.exclude_types, msg_type) && !excluded_author;
+        let position = if self.sequence_edges {
+            POSITION_PATHS.iter().find_map(|(author, sequence)| {
+                Some(FeedPosition {
+                    author: string_at(author)?.to_owned(),
+                    sequence: match scalar_at(sequence)? {
+                        Scalar::Uint(sequence) => sequence,
+                        _ => return None,
+                    },
+                })
+            })
+        } else {
+            None
+        };
+        let sorted = match &self.window {
+            Some((window, keep)) => {
+                let timestamp = TIMESTAMP_PATHS
+                    .iter()
+                    .find_map(|path| match scalar_at(path)? {
+                        Scalar::Uint(timestamp) => Some(timestamp as f64),
+                        Scalar::Float(timestamp) => Some(timestamp),
+                        Scalar::Str(_) => None,
+                    });
+                // Messages without a timestamp aren't outside the window.
+                let inside = timestamp.is_none_or(|timestamp| {
+                    window.start as f64 <= timestamp && timestamp < window.end as f64
+                });
+                inside == (*keep == Window::Inside)
+            }
+            None => true,
+        };
+        let trusted = self
+            .trust
+            .as_ref()
+            .is_none_or(|trust| (trust.0)(author, msg_type));
+        Found {
+            kept,
+            excluded_author,
+            position,
+            sorted,
+            msg_type: msg_type.filter(|_| self.types).map(str::to_owned),
+            trusted,
+        }
+    }
+}
+
+/// The links found in a message that's kept.
+pub(crate) struct Extracted<'a> {
+    pub(crate) refs: &'a [Multihash],
+    pub(crate) position: Option<FeedPosition>,
+    /// Whether the message goes in the order, or only its links do.
+    pub(crate) sorted: bool,
+    /// The message's type, if `LinkOptions::types` and it has one.
+    pub(crate) msg_type: Option<String>,
+}
+
+/// Finds the links in one message after another.
+///
+/// The buffers used along the way are kept between messages, rather than being allocated and
+/// freed again for every message.
+pub(crate) struct Extractor {
+    options: LinkOptions,
+    refs: Vec<Multihash>,
+    failures: usize,
+    /// Whether the last message was left out for its author.
+    excluded_author: bool,
+    #[cfg(all(feature = "bumpalo", not(feature = "simd-json")))]
+    bump: bumpalo::Bump,
+}
+
+impl Extractor {
+    pub(crate) fn new() -> Extractor {
+        Extractor::with_options(LinkOptions::default())
+    }
+
+    pub(crate) fn with_options(options: LinkOptions) -> Extractor {
+        Extractor {
+            options,
+            refs: Vec::new(),
+            failures: 0,
+            excluded_author: false,
+            #[cfg(all(feature = "bumpalo", not(feature = "simd-json")))]
+            bump: bumpalo::Bump::new(),
+        }
+    }
+
+    /// Parse a message and recursively search through the object for Multihashes, in the order
+    /// they're first found. A hash the message links to more than once is only found once.
+    ///
+    /// Messages that aren't valid JSON have no links, so callers that don't care why can use
+    /// `extract(msg).unwrap_or_default()`. Messages of excluded types have none either.
+    pub(crate) fn extract(&mut self, msg: &[u8]) -> Result<&[Multihash], Failure> {
+        let extracted = self.extract_kept(msg)?;
+        Ok(extracted.map_or(&[], |extracted| extracted.refs))
+    }
+
+    /// Like `extract`, but with where the message is in its feed, and `None` for messages of
+    /// excluded types.
+    pub(crate) fn extract_kept(&mut self, msg: &[u8]) -> Result<Option<Extracted<'_>>, Failure> {
+        let span = span!(
+            TRACE,
+            "extract",
+            bytes = msg.len(),
+            links = tracing::field::Empty
+        );
+        let _entered = span.enter();
+        self.refs.clear();
+
+        #[cfg(feature = "simd-json")]
+        let parsed = crate::simd::extract_refs_into(msg, &self.options, &mut self.refs);
+
+        #[cfg(all(feature = "bumpalo", not(feature = "simd-json")))]
+        let parsed = {
+            self.bump.reset();
+            crate::arena::extract_refs_into(&self.bump, msg, &self.options, &mut self.refs)
+                .map_err(|error| Failure::of(msg, error))
+        };
+
+        #[cfg(not(any(feature = "simd-json", feature = "bumpalo")))]
+        let parsed = crate::stream::extract_refs_into(msg, &self.options, &mut self.refs)
+            .map_err(|error| Failure::of(msg, error));
+
+        crate::graph::dedup_links(&mut self.refs);
+        span.record("links", self.refs.len());
+        match parsed {
+            Ok(Found {
+                kept: true,
+                position,
+                sorted,
+                msg_type,
+                trusted,
+                ..
+            }) => {
+                if !trusted {
+                    self.refs.clear();
+                }
+                Ok(Some(Extracted {
+                    refs: &self.refs,
+                    position: position.filter(|_| trusted),
+                    sorted,
+                    msg_type,
+                }))
+            }
+            Ok(Found {
+                excluded_author, ..
+            }) => {
+                self.excluded_author = excluded_author;
+                Ok(None)
+            }
+            Err(failure) => {
+                self.failures += 1;
+                Err(failure)
+            }
+        }
+    }
+
+    /// Whether the last message `extract_kept` left out was left out for its author, rather than
+    /// its type.
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub(crate) fn excluded_author(&self) -> bool {
+        self.excluded_author
+    }
+
+    /// How many of the messages so far weren't valid JSON.
+    pub(crate) fn failures(&self) -> usize {
+        self.failures
+    }
+}
+
+/// Find the links in `msg` by parsing it into a `Value`. The other backends are tested against
+/// this.
+#[cfg(test)]
+pub(crate) fn serde_extract_refs_into(
+    msg: &[u8],
+    options: &LinkOptions,
+    refs: &mut Vec<Multihash>,
+) -> serde_json::Result<Found> {
+    let value: Value = serde_json::from_slice(msg)?;
+    let value_at = |path: &[&str]| {
+        path.iter()
+            .try_fold(&value, |value, field| value.as_object()?.get(*field))
+    };
+    let found = options.find(|path| match value_at(path)? {
+        Value::String(st) => Some(Scalar::Str(st)),
+        Value::Number(number) => match number.as_u64() {
+            Some(number) => Some(Scalar::Uint(number)),
+            None => number.as_f64().map(Scalar::Float),
+        },
+        _ => None,
+    });
+    if found.kept {
+        match options.part(|path| value_at(path).is_some()) {
+            Part::Whole => find_links(&value, options, refs),
+            Part::Nothing => (),
+            Part::At(path) => value_at(path)
+                .into_iter()
+                .for_each(|part| find_links(part, options, refs)),
+        }
+    }
+    Ok(found)
+}
+
+/// Every message and blob hash in `obj`, as the sorts find them.
+pub(crate) fn find_hashes(obj: &Value, keys: &mut Vec<Multihash>) {
+    find_links(obj, &LinkOptions::default(), keys)
+}
+
+#[cfg_attr(any(feature = "simd-json", feature = "bumpalo"), allow(dead_code))]
+fn find_links(obj: &Value, options: &LinkOptions, keys: &mut Vec<Multihash>) {
+    for_each_string(obj, options, &mut |st| {
+        keys.extend(options.link(st));
+    });
+}
+
+/// Call `f` with every string in `obj`, depth first, with object values in key order. Walks a
+/// stack rather than recursing, so that however deep `obj` is it can't overflow.
+fn for_each_string<F: FnMut(&str)>(obj: &Value, options: &LinkOptions, f: &mut F) {
+    let mut to_visit = vec![obj];
+    while let Some(value) = to_visit.pop() {
+        match value {
+            Value::String(st) => f(st),
+            Value::Array(arr) => to_visit.extend(arr.iter().rev()),
+            Value::Object(kv) => to_visit.extend(
+                kv.iter()
+                    .rev()
+                    .filter(|(key, _)| !options.skips(key))
+                    .map(|(_, val)| val),
+            ),
+            _ => (),
+        }
+    }
+}
+
+/// Something a message links to, told apart by the sigil at the start of the link.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Link {
+    /// `%`: another message. These are the links the sorts follow.
+    Message(Multihash),
+    /// `@`: a feed, eg. a mention of another user.
+    Feed(Multikey),
+    /// `&`: a blob, eg. an attached image.
+    Blob(Multihash),
+}
+
+impl Link {
+    /// Parse a link in the legacy `<sigil><base64>.<suffix>` form, as it would appear in a
+    /// message.
+    pub fn from_legacy(link: &str) -> Option<Link> {
+        let link = link.as_bytes();
+        match link.first()? {
+            b'@' => Some(Link::Feed(Multikey::from_legacy(link).ok()?.0)),
+            _ => match Multihash::from_legacy(link).ok()?.0 {
+                hash @ Multihash::Message(_) => Some(Link::Message(hash)),
+                hash @ Multihash::Blob(_) => Some(Link::Blob(hash)),
+            },
+        }
+    }
+}
+
+/// Which sorts of link [`find_all_links`] finds, by their sigils.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sigils {
+    /// `%` links, to messages.
+    pub messages: bool,
+    /// `@` links, to feeds.
+    pub feeds: bool,
+    /// `&` links, to blobs.
+    pub blobs: bool,
+}
+
+impl Sigils {
+    /// Every sort of link.
+    pub const ALL: Sigils = Sigils {
+        messages: true,
+        feeds: true,
+        blobs: true,
+    };
+
+    /// Only links to messages, as the sorts follow.
+    pub const MESSAGES: Sigils = Sigils {
+        messages: true,
+        feeds: false,
+        blobs: false,
+    };
+
+    /// Whether `st` starts with one of the sigils, so is worth parsing.
+    fn start(&self, st: &str) -> bool {
+        match st.as_bytes().first() {
+            Some(b'%') => self.messages,
+            Some(b'@') => self.feeds,
+            Some(b'&') => self.blobs,
+            _ => false,
+        }
+    }
+}
+
+impl Default for Sigils {
+    fn default() -> Sigils {
+        Sigils::ALL
+    }
+}
+
+/// Every link in `value` with one of `sigils`, eg. to index backlinks or find mentions.
+///
+/// A link is any string, at any depth, that parses as a whole with
+/// [`Link::from_legacy`]. Object keys are never links. Links are found depth first, array
+/// elements in order and object values in the order of their keys, and a link is found again
+/// each time it appears. Unlike the sorts, this doesn't skip `fork` fields or look at message
+/// types. `value` can be nested as deep as it likes.
+pub fn find_all_links(value: &Value, sigils: Sigils) -> Vec<Link> {
+    let mut links = Vec::new();
+    for_each_string(value, &LinkOptions::default(), &mut |st| {
+        if sigils.start(st) {
+            links.extend(Link::from_legacy(st))
+        }
+    });
+    links
+}
+
+/// Every link in the JSON message `msg`, of any sort. As with [`find_all_links`], a link appears
+/// once for each time it's in `msg`, object values are searched in the order of their keys, and
+/// `fork` fields aren't skipped.
+///
+/// Messages that aren't valid JSON have no links.
+pub fn extract_links(msg: &[u8]) -> Vec<Link> {
+    match serde_json::from_slice(msg) {
+        Ok(value) => find_all_links(&value, Sigils::ALL),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        count_message_links, extract_links, find_all_links, serde_extract_refs_into, too_deep,
+        Extractor, Link, LinkOptions, Sigils, RECURSION_LIMIT,
+    };
+    use crate::causal_sort;
+    use crate::test_utils::numbered;
+    use serde_json::{json, Value};
+    use ssb_multiformats::multihash::Multihash;
+    use ssb_multiformats::multikey::Multikey;
+
+    #[test]
+    fn find_all_links_finds_chosen_sigils() {
+        let message = "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256";
+        let blob = "&2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256";
+        let feed = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+        let value = json!({ "b": [blob, { "fork": feed }], "a": message, message: 1 });
+        let hash = |link: &str| Multihash::from_legacy(link.as_bytes()).unwrap().0;
+        assert_eq!(
+            find_all_links(&value, Sigils::ALL),
+            [
+                Link::Message(hash(message)),
+                Link::Blob(hash(blob)),
+                Link::Feed(Multikey::from_legacy(feed.as_bytes()).unwrap().0),
+            ]
+        );
+        assert_eq!(
+            find_all_links(&value, Sigils::MESSAGES),
+            [Link::Message(hash(message))]
+        );
+        let sigils = Sigils {
+            blobs: false,
+            ..Sigils::default()
+        };
+        assert_eq!(find_all_links(&value, sigils).len(), 2);
+
+        // Deeper than any parser would allow.
+        let deep = (0..5000).fold(json!(message), |value, _| Value::Array(vec![value]));
+        assert_eq!(find_all_links(&deep, Sigils::MESSAGES).len(), 1);
+    }
+
+    #[test]
+    fn extract_links_finds_every_sigil() {
+        let feed = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+        let msg = format!(
+            r#"{{
+                "root": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+                "mentions": [{{ "link": "{}" }}, "&2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"],
+                "text": "@not a feed"
+            }}"#,
+            feed
+        );
+        let links = extract_links(msg.as_bytes());
+        let hash = |link: &str| Multihash::from_legacy(link.as_bytes()).unwrap().0;
+        assert_eq!(
+            links,
+            [
+                Link::Feed(Multikey::from_legacy(feed.as_bytes()).unwrap().0),
+                Link::Blob(hash("&2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256")),
+                Link::Message(hash("%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256")),
+            ]
+        );
+
+        let mut refs = Vec::new();
+        serde_extract_refs_into(msg.as_bytes(), &LinkOptions::default(), &mut refs).unwrap();
+        let hashes: Vec<_> = links
+            .into_iter()
+            .filter_map(|link| match link {
+                Link::Message(hash) | Link::Blob(hash) => Some(hash),
+                Link::Feed(_) => None,
+            })
+            .collect();
+        assert_eq!(hashes, refs);
+        assert!(extract_links(b"{\"not\": json").is_empty());
+    }
+
+    #[test]
+    fn too_deep_only_counts_brackets_outside_strings() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(!too_deep(nested(RECURSION_LIMIT - 1).as_bytes()));
+        assert!(too_deep(nested(RECURSION_LIMIT).as_bytes()));
+        assert!(too_deep("{\"a\":".repeat(RECURSION_LIMIT).as_bytes()));
+
+        let in_string = format!("[\"{}\\\"{}\"]", "[".repeat(200), "{".repeat(200));
+        assert!(!too_deep(in_string.as_bytes()));
+    }
+
+    #[test]
+    fn escapes_and_whitespace_dont_hide_links() {
+        let link = numbered(1).to_legacy_string();
+        let escaped = link
+            .replacen('%', "\\u0025", 1)
+            .replace('/', "\\/")
+            .replace('=', "\\u003d");
+        let variants = [
+            json!({ "type": "post", "root": link, "branch": [link] }).to_string(),
+            format!(
+                "{{\n  \"type\" : \"post\" ,\n\t\"root\":\r\n \"{}\",  \"branch\" : [ \"{}\" ]\n}}",
+                link, link
+            ),
+            format!(
+                "{{\"type\":\"p\\u006fst\",\"root\":\"{}\",\"branch\":[\"{}\"]}}",
+                escaped, escaped
+            ),
+            format!(
+                "{{\"type\":\"post\",\"root\":\"{}\",\"branch\":[\"{}\"],\"text\":\"\\\"%\"}}",
+                escaped, link
+            ),
+        ];
+        let mut extractor = Extractor::new();
+        variants.iter().for_each(|msg| {
+            assert_eq!(
+                extractor.extract(msg.as_bytes()).unwrap(),
+                [numbered(1)],
+                "{}",
+                msg
+            );
+            assert_eq!(count_message_links(msg.as_bytes()), 2, "{}", msg);
+            let msgs = vec![
+                (numbered(2), 2, msg.clone()),
+                (numbered(1), 1, json!({}).to_string()),
+            ];
+            assert_eq!(causal_sort(&msgs), [2, 1]);
+        });
+        assert_eq!(
+            count_message_links(b"{\"a\": \"ssb:message/classic/x\", \"b\": 1}"),
+            1
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Interleaving whole feeds into one order, and picking one feed out of an order.
+use crate::error::{self, Error};
+use crate::extract::LinkOptions;
+use crate::graph::{BuildGraph, CausalGraph, Checks};
+use crate::sorted::SortedMessages;
+use crate::try_causal_sort;
+use serde_json::Value;
+use ssb_multiformats::multihash::Multihash;
+
+/// One author's messages, oldest first, as `(key, key_id, message)`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Feed<K, T> {
+    pub messages: Vec<(Multihash, K, T)>,
+}
+
+impl<K, T> From<Vec<(Multihash, K, T)>> for Feed<K, T> {
+    fn from(messages: Vec<(Multihash, K, T)>) -> Feed<K, T> {
+        Feed { messages }
+    }
+}
+
+/// Interleave the messages of several `feeds` into one causal order, returning their key ids
+/// newest first.
+///
+/// Each message is sorted after the one before it in its feed, as well as after the messages it
+/// links to, so every feed keeps its own order. Messages from different feeds that don't link to
+/// each other are concurrent, and are sorted like concurrent messages passed to
+/// [`causal_sort`](crate::causal_sort) with every feed one after another.
+///
+/// # Panics
+///
+/// Panics if the feeds' orders and the messages' links form a cycle. Use
+/// [`try_interleave_feeds`] to handle this as an error.
+pub fn interleave_feeds<T: AsRef<str>, K: Clone>(feeds: &[Feed<K, T>]) -> SortedMessages<K> {
+    error::unwrap(try_interleave_feeds(feeds))
+}
+
+/// Like [`interleave_feeds`], but returns an error rather than panicking.
+pub fn try_interleave_feeds<T: AsRef<str>, K: Clone>(
+    feeds: &[Feed<K, T>],
+) -> Result<SortedMessages<K>, Error> {
+    let mut graph = CausalGraph::new();
+    let extracted: Vec<_> = feeds
+        .iter()
+        .flat_map(|feed| &feed.messages)
+        .map(|(key, key_id, msg)| (key, key_id.clone(), msg.as_ref().as_bytes()))
+        .collect();
+    graph.extend(
+        extracted.into_iter(),
+        Checks::default(),
+        LinkOptions::default(),
+    )?;
+    for feed in feeds {
+        for pair in feed.messages.windows(2) {
+            graph.link(&pair[1].0, &pair[0].0)?;
+        }
+    }
+
+    Ok(graph.sorted())
+}
+
+/// Causally sort `msgs`, keeping only the key ids of the messages by `author`, newest first.
+///
+/// All of `msgs` are sorted together, so one of the author's messages is after another if it
+/// links to it through other authors' messages too, eg. a reply to a reply to their post. A
+/// message's author is its `author` field, or that of its `value` for messages with their keys.
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_author_order`] to handle this as an
+/// error.
+pub fn author_order<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    author: &str,
+) -> SortedMessages<K> {
+    error::unwrap(try_author_order(msgs, author))
+}
+
+/// Like [`author_order`], but returns an error rather than panicking.
+pub fn try_author_order<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    author: &str,
+) -> Result<SortedMessages<K>, Error> {
+    let indexed: Vec<_> = msgs
+        .iter()
+        .enumerate()
+        .map(|(index, (key, _, msg))| (key.clone(), index, msg.as_ref()))
+        .collect();
+    let by_author = |msg: &str| {
+        let value: Value = match serde_json::from_str(msg) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        let found = value
+            .get("author")
+            .or_else(|| value.get("value")?.get("author"));
+        found.and_then(Value::as_str) == Some(author)
+    };
+    Ok(try_causal_sort(&indexed)?
+        .into_iter()
+        .filter(|index| by_author(msgs[*index].2.as_ref()))
+        .map(|index| msgs[index].1.clone())
+        .collect::<Vec<_>>()
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{author_order, interleave_feeds, try_interleave_feeds, Feed};
+    use crate::test_utils::numbered;
+    use crate::Error;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_each_feed_in_order() {
+        // a2 replies to b1, nothing else links across feeds.
+        let a = Feed::from(vec![
+            (numbered(1), "a1", json!({}).to_string()),
+            (
+                numbered(2),
+                "a2",
+                json!({ "root": numbered(11) }).to_string(),
+            ),
+            (numbered(3), "a3", json!({}).to_string()),
+        ]);
+        let b = Feed::from(vec![
+            (numbered(11), "b1", json!({}).to_string()),
+            (numbered(12), "b2", json!({}).to_string()),
+        ]);
+        let sorted = interleave_feeds(&[a, b]);
+        let before = |earlier, later| sorted.position_of(&later) < sorted.position_of(&earlier);
+        assert_eq!(sorted.len(), 5);
+        assert!(before("a1", "a2") && before("a2", "a3"));
+        assert!(before("b1", "b2"));
+        assert!(before("b1", "a2"));
+    }
+
+    #[test]
+    fn crossed_feeds_are_a_cycle() {
+        let a = Feed::from(vec![
+            (numbered(1), 1, json!({ "root": numbered(12) }).to_string()),
+            (numbered(2), 2, json!({}).to_string()),
+        ]);
+        let b = Feed::from(vec![
+            (numbered(11), 11, json!({ "root": numbered(2) }).to_string()),
+            (numbered(12), 12, json!({}).to_string()),
+        ]);
+        assert!(matches!(
+            try_interleave_feeds(&[a, b]),
+            Err(Error::Cycle { .. })
+        ));
+    }
+
+    #[test]
+    fn author_order_follows_other_authors_links() {
+        // a's 1 only links to a's 2 through b's 3.
+        let msgs = [
+            (
+                numbered(1),
+                1,
+                json!({ "author": "@a", "root": numbered(3) }).to_string(),
+            ),
+            (
+                numbered(3),
+                3,
+                json!({ "author": "@b", "root": numbered(2) }).to_string(),
+            ),
+            (
+                numbered(2),
+                2,
+                json!({ "value": { "author": "@a" } }).to_string(),
+            ),
+            (numbered(4), 4, json!({ "author": "@a" }).to_string()),
+            (numbered(5), 5, "not json".to_owned()),
+        ];
+        let sorted = author_order(&msgs, "@a");
+        assert_eq!(sorted.len(), 3);
+        assert!(sorted.position_of(&1) < sorted.position_of(&2));
+        assert_eq!(author_order(&msgs, "@b"), [3]);
+    }
+}
@@ -0,0 +1,151 @@
+//! Planning which missing messages to fetch.
+use crate::error::{self, Error};
+use crate::extract::{Extractor, LinkOptions};
+use crate::graph::{BuildGraph, CausalGraph, Checks};
+use daggy::NodeIndex;
+use petgraph::Direction;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// The hashes `msgs` link to that aren't among them, in the order to fetch them in.
+///
+/// Each message counts towards the missing hash nearest it in its history, the fewest links away,
+/// or the first linked to of those as near. Hashes more of `msgs` count towards come first, since
+/// fetching them fills in the nearest gap in the history of more messages. Hashes with as many
+/// come in the order they're first linked to.
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_fetch_plan`] to handle this as an error.
+pub fn fetch_plan<T: AsRef<str>, K: Clone>(msgs: &[(Multihash, K, T)]) -> Vec<Multihash> {
+    error::unwrap(try_fetch_plan(msgs))
+}
+
+/// Like [`fetch_plan`], but returns an error rather than panicking.
+pub fn try_fetch_plan<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<Vec<Multihash>, Error> {
+    let mut graph = CausalGraph::new();
+    let extracted = msgs
+        .iter()
+        .map(|(key, key_id, msg)| (key, key_id.clone(), msg.as_ref().as_bytes()));
+    graph.extend(extracted, Checks::default(), LinkOptions::default())?;
+
+    let dag = graph.dag().graph();
+    let node_count = dag.node_count();
+    // Walk back from every missing node at once, breadth first, so that each message is first
+    // reached from the missing node nearest it. `nearest` is that node, `usize::MAX` until then.
+    let mut nearest = vec![usize::MAX; node_count];
+    let mut dependents = vec![0; node_count];
+    let mut to_visit: VecDeque<usize> = (0..node_count)
+        .filter(|node| graph.key_id(*node).is_none())
+        .collect();
+    for missing in &to_visit {
+        nearest[*missing] = *missing;
+    }
+    let mut plan: Vec<usize> = to_visit.iter().copied().collect();
+    while let Some(node) = to_visit.pop_front() {
+        let missing = nearest[node];
+        dag.neighbors_directed(NodeIndex::new(node), Direction::Incoming)
+            .for_each(|parent| {
+                if nearest[parent.index()] == usize::MAX {
+                    nearest[parent.index()] = missing;
+                    dependents[missing] += 1;
+                    to_visit.push_back(parent.index());
+                }
+            });
+    }
+    plan.sort_by(|a, b| dependents[*b].cmp(&dependents[*a]).then(a.cmp(b)));
+
+    let hashes = graph.interner().hashes(node_count);
+    Ok(plan
+        .into_iter()
+        .map(|missing| hashes[missing].clone())
+        .collect())
+}
+
+/// The hashes `msgs` link to that aren't among them, each with the key ids of the messages that
+/// link to it, in the order they're given.
+///
+/// A message that links to the same hash more than once is only listed once for it. Messages that
+/// aren't valid JSON have no links.
+pub fn missing_referrers<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> HashMap<Multihash, Vec<K>> {
+    let present: HashSet<&Multihash> = msgs.iter().map(|(key, _, _)| key).collect();
+    let mut extractor = Extractor::new();
+    let mut referrers: HashMap<Multihash, Vec<K>> = HashMap::new();
+    for (_, key_id, msg) in msgs {
+        let refs = extractor
+            .extract(msg.as_ref().as_bytes())
+            .unwrap_or_default();
+        let mut linked = HashSet::new();
+        for reference in refs {
+            if !present.contains(reference) && linked.insert(reference) {
+                referrers
+                    .entry(reference.clone())
+                    .or_default()
+                    .push(key_id.clone());
+            }
+        }
+    }
+    referrers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch_plan, missing_referrers};
+    use crate::test_utils::{numbered, thread};
+    use serde_json::json;
+
+    #[test]
+    fn plans_the_most_depended_on_first() {
+        assert!(fetch_plan(&thread()).is_empty());
+
+        // 1, 2 and 3 are missing. 2 is linked to first, but 1 has more dependents.
+        let msgs = [
+            (numbered(10), 10, json!({ "root": numbered(2) }).to_string()),
+            (numbered(11), 11, json!({ "root": numbered(1) }).to_string()),
+            (
+                numbered(12),
+                12,
+                json!({ "branch": numbered(11) }).to_string(),
+            ),
+            (
+                numbered(13),
+                13,
+                json!({ "branch": numbered(3) }).to_string(),
+            ),
+        ];
+        assert_eq!(fetch_plan(&msgs), [numbered(1), numbered(2), numbered(3)]);
+
+        // 16 depends on 1 and 3, but counts only towards 3, which is nearer to it through 15.
+        let mut more = msgs.to_vec();
+        more.push((numbered(15), 15, json!({ "root": numbered(3) }).to_string()));
+        more.push((
+            numbered(16),
+            16,
+            json!({ "branch": [numbered(12), numbered(15)] }).to_string(),
+        ));
+        assert_eq!(fetch_plan(&more), [numbered(3), numbered(1), numbered(2)]);
+    }
+
+    #[test]
+    fn maps_missing_hashes_to_their_referrers() {
+        assert!(missing_referrers(&thread()).is_empty());
+
+        let msgs = [
+            (numbered(10), 10, json!({ "root": numbered(1) }).to_string()),
+            (
+                numbered(11),
+                11,
+                json!({ "root": numbered(1), "branch": [numbered(10), numbered(1)] }).to_string(),
+            ),
+            (numbered(12), 12, json!({ "root": numbered(2) }).to_string()),
+        ];
+        let referrers = missing_referrers(&msgs);
+        assert_eq!(referrers.len(), 2);
+        assert_eq!(referrers[&numbered(1)], [10, 11]);
+        assert_eq!(referrers[&numbered(2)], [12]);
+    }
+}
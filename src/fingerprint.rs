@@ -0,0 +1,82 @@
+//! A short hash of the order a set of messages sorts into.
+use crate::builder::{SortBuilder, TieBreak};
+use crate::error::{self, Error};
+use sha2::{Digest, Sha256};
+use ssb_multiformats::multihash::Multihash;
+
+/// The SHA-256 of the keys of `msgs` in their [canonical](TieBreak::Canonical) order, newest
+/// first, each written as a byte for its kind, 0 for a message and 1 for a blob, then its 32
+/// bytes.
+///
+/// Two peers with the same messages get the same fingerprint, whatever order the messages arrived
+/// in, so comparing fingerprints checks that their indexes agree without sending the whole order.
+/// Messages are sorted as [`causal_sort`](crate::causal_sort) sorts them, so only the first
+/// message with each key counts.
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_order_fingerprint`] to handle this as an
+/// error.
+pub fn order_fingerprint<T: AsRef<str>, K>(msgs: &[(Multihash, K, T)]) -> [u8; 32] {
+    error::unwrap(try_order_fingerprint(msgs))
+}
+
+/// Like [`order_fingerprint`], but returns an error rather than panicking.
+pub fn try_order_fingerprint<T: AsRef<str>, K>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<[u8; 32], Error> {
+    let indexed: Vec<_> = msgs
+        .iter()
+        .enumerate()
+        .map(|(index, (key, _, msg))| (key.clone(), index, msg.as_ref()))
+        .collect();
+    let sorted = SortBuilder::new()
+        .tie_break(TieBreak::Canonical)
+        .try_sort(&indexed)?;
+
+    let mut hasher = Sha256::new();
+    for index in sorted {
+        let (kind, digest) = match &msgs[index].0 {
+            Multihash::Message(digest) => (0, digest),
+            Multihash::Blob(digest) => (1, digest),
+        };
+        hasher.update([kind]);
+        hasher.update(digest);
+    }
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::order_fingerprint;
+    use crate::test_utils::{numbered, thread};
+    use serde_json::json;
+
+    #[test]
+    fn fingerprints_only_the_order() {
+        let msgs = thread();
+        let mut reordered = msgs.clone();
+        reordered.reverse();
+        assert_eq!(order_fingerprint(&msgs), order_fingerprint(&reordered));
+
+        // The key ids and message bodies don't matter, only the keys and their order.
+        let relabelled: Vec<_> = msgs
+            .iter()
+            .map(|(key, i, msg)| (key.clone(), i + 10, format!(" {}", msg)))
+            .collect();
+        assert_eq!(order_fingerprint(&msgs), order_fingerprint(&relabelled));
+
+        let mut more = msgs.clone();
+        more.push((numbered(4), 4, json!({}).to_string()));
+        assert_ne!(order_fingerprint(&msgs), order_fingerprint(&more));
+        let empty: [(_, u32, String); 0] = [];
+        assert_eq!(
+            order_fingerprint(&empty),
+            [
+                0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+                0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+                0x78, 0x52, 0xb8, 0x55,
+            ]
+        );
+    }
+}
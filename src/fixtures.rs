@@ -0,0 +1,163 @@
+//! Reading the logs [ssb-fixtures](https://github.com/ssb-ngi-pointer/ssb-fixtures) generates,
+//! for tests and benchmarks on realistic data.
+use crate::error::Error;
+use crate::ndjson::read_json;
+use ssb_multiformats::multihash::Multihash;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::Path;
+
+/// Read the messages of the fixtures ssb-fixtures wrote to `dir`, ready to sort, eg.
+/// `causal_sort(&read_fixtures("fixtures")?)`.
+///
+/// The messages are read from the log in `dir/flume/log.offset` with [`read_flume_log`], so
+/// they're numbered by their offsets in it. Fixtures are expected to be whole, so a message that
+/// can't be read is an error: `Error::Read` if the log can't be opened or read, or
+/// `Error::Parse` if a record isn't a message.
+pub fn read_fixtures<P: AsRef<Path>>(dir: P) -> Result<Vec<(Multihash, usize, String)>, Error> {
+    let path = dir.as_ref().join("flume").join("log.offset");
+    let log = File::open(path).map_err(|source| Error::Read { source })?;
+    read_flume_log(BufReader::new(log)).collect()
+}
+
+/// Read each record of a [flumelog-offset](https://github.com/flumedb/flumelog-offset) log, as
+/// ssb-db and ssb-fixtures keep it, as a message to sort, so that sorting the messages gives
+/// their offsets in the log.
+///
+/// Each record is a `{ key, value, timestamp }` envelope in JSON, and is keyed and given as
+/// [`read_ndjson`](crate::read_ndjson) gives an envelope: its value in its legacy encoding.
+/// Records that have been deleted, ie. overwritten with zeros, are skipped.
+///
+/// A record that isn't a message is an `Error::Parse`, and reading carries on past it. A log
+/// that can't be read, or that's corrupt, is an `Error::Read`, and ends the records.
+pub fn read_flume_log<R: Read>(
+    log: R,
+) -> impl Iterator<Item = Result<(Multihash, usize, String), Error>> {
+    read_records(log)
+        .filter(|record| !matches!(record, Ok((_, record)) if record.iter().all(|byte| *byte == 0)))
+        .map(|record| {
+            let (offset, record) = record?;
+            read_record(&record)
+                .map(|(key, msg)| (key, offset, msg))
+                .map_err(|source| Error::Parse {
+                    index: offset,
+                    source,
+                })
+        })
+}
+
+/// Each record of the log with its offset. A record is its length, a big endian `u32`, then its
+/// bytes, its length again, and the length of the log after it.
+fn read_records<R: Read>(log: R) -> impl Iterator<Item = Result<(usize, Vec<u8>), Error>> {
+    let mut log = Some(log);
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let reader = log.as_mut()?;
+        let record = match read_record_bytes(reader) {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(source) => {
+                log = None;
+                return Some(Err(Error::Read { source }));
+            }
+        };
+        let record_offset = offset;
+        offset += record.len() + 12;
+        Some(Ok((record_offset, record)))
+    })
+}
+
+/// Read one record's bytes, or `None` at the end of the log.
+fn read_record_bytes<R: Read>(log: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match log.read(&mut len[..1])? {
+        0 => return Ok(None),
+        _ => log.read_exact(&mut len[1..])?,
+    }
+    let len = usize::try_from(u32::from_be_bytes(len)).map_err(|_| corrupt())?;
+    let mut record = Vec::new();
+    log.take(len as u64).read_to_end(&mut record)?;
+    let mut trailer = [0; 8];
+    log.read_exact(&mut trailer)?;
+    if record.len() != len || trailer[..4] != (len as u32).to_be_bytes() {
+        return Err(corrupt());
+    }
+    Ok(Some(record))
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "a record's lengths don't match")
+}
+
+fn read_record(
+    record: &[u8],
+) -> Result<(Multihash, String), Box<dyn std::error::Error + Send + Sync>> {
+    read_json(serde_json::from_slice(record)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_fixtures, read_flume_log};
+    use crate::test_utils::numbered;
+    use crate::{causal_sort, Error};
+    use serde_json::{json, Value};
+    use std::io::{Cursor, ErrorKind};
+
+    /// A flumelog-offset log of `records`.
+    fn log(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut log = Vec::new();
+        for record in records {
+            let len = (record.len() as u32).to_be_bytes();
+            log.extend_from_slice(&len);
+            log.extend_from_slice(record);
+            log.extend_from_slice(&len);
+            log.extend_from_slice(&(log.len() as u32 + 4).to_be_bytes());
+        }
+        log
+    }
+
+    fn envelope(key: usize, value: Value) -> Vec<u8> {
+        json!({ "key": numbered(key), "value": value, "timestamp": 1 })
+            .to_string()
+            .into_bytes()
+    }
+
+    #[test]
+    fn records_are_read_by_offset() {
+        let root = envelope(1, json!({ "content": { "type": "post" } }));
+        let reply = envelope(2, json!({ "content": { "root": numbered(1) } }));
+        let deleted = vec![0; 10];
+        let offsets = [0, root.len() + 12, root.len() + deleted.len() + 24];
+        let log = log(&[root, deleted, reply, b"{".to_vec()]);
+
+        let read: Vec<_> = read_flume_log(Cursor::new(&log)).collect();
+        assert_eq!(read.len(), 3);
+        let (key, offset, _) = read[0].as_ref().unwrap();
+        assert_eq!((key, *offset), (&numbered(1), offsets[0]));
+        assert!(matches!(read[2], Err(Error::Parse { .. })));
+
+        let msgs: Vec<_> = read.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(causal_sort(&msgs), [offsets[2], offsets[0]]);
+
+        let cut = &log[..log.len() - 3];
+        let error = read_flume_log(Cursor::new(cut)).last().unwrap();
+        assert!(
+            matches!(error, Err(Error::Read { source }) if source.kind() == ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn fixtures_are_read_from_their_directory() {
+        let dir = std::env::temp_dir().join(format!("ssb-fixtures-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("flume")).unwrap();
+        let records = [envelope(1, json!({ "content": {} }))];
+        std::fs::write(dir.join("flume").join("log.offset"), log(&records)).unwrap();
+        let msgs = read_fixtures(&dir);
+        let empty = read_fixtures(dir.join("flume"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(msgs.unwrap().len(), 1);
+        assert!(matches!(empty, Err(Error::Read { .. })));
+    }
+}
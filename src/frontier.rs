@@ -0,0 +1,139 @@
+//! Comparing how much of a dag two peers have seen.
+use crate::dag::{CausalDag, NodeId};
+use ssb_multiformats::multihash::Multihash;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A set of heads in a [`CausalDag`], standing for them and everything they link to, directly or
+/// not.
+///
+/// Frontiers are ordered by what they stand for: one is less than another if everything it
+/// stands for is in the other's history. Two peers can exchange just their heads, and each build
+/// a frontier from them, to find out whether either is behind or whether they've each seen
+/// messages the other hasn't, in which case the frontiers are concurrent and `partial_cmp` is
+/// `None`. Frontiers in different dags are never comparable.
+pub struct Frontier<'a, K> {
+    dag: &'a CausalDag<K>,
+    heads: Vec<NodeId>,
+}
+
+impl<K> CausalDag<K> {
+    /// The frontier of the whole dag: every message that nothing links to. Hashes that are only
+    /// links, eg. of a message that's been [removed](CausalDag::remove), are never heads.
+    pub fn heads(&self) -> Frontier<'_, K> {
+        let heads = self
+            .nodes()
+            .filter(|node| self.key_id(*node).is_some() && self.linked_from(*node).next().is_none())
+            .collect();
+        Frontier { dag: self, heads }
+    }
+
+    /// The frontier with `heads` as its heads, or `None` if any of them isn't in this dag.
+    pub fn frontier(&self, heads: &[Multihash]) -> Option<Frontier<'_, K>> {
+        let mut heads = heads
+            .iter()
+            .map(|head| self.node(head))
+            .collect::<Option<Vec<_>>>()?;
+        heads.sort_unstable();
+        heads.dedup();
+        Some(Frontier { dag: self, heads })
+    }
+}
+
+impl<'a, K> Frontier<'a, K> {
+    /// The hashes of the heads, to send to a peer.
+    pub fn heads(&self) -> impl Iterator<Item = &'a Multihash> + '_ {
+        self.heads
+            .iter()
+            .filter_map(move |head| self.dag.hash(*head))
+    }
+
+    /// Whether everything `other` stands for is in the history of this frontier.
+    fn covers(&self, other: &Frontier<K>) -> bool {
+        let mut seen = vec![false; self.dag.node_count()];
+        let mut to_visit = self.heads.clone();
+        while let Some(node) = to_visit.pop() {
+            if !std::mem::replace(&mut seen[node.index()], true) {
+                to_visit.extend(self.dag.links(node).map(|(_, linked)| linked));
+            }
+        }
+        other.heads.iter().all(|head| seen[head.index()])
+    }
+}
+
+impl<K> Clone for Frontier<'_, K> {
+    fn clone(&self) -> Self {
+        Frontier {
+            dag: self.dag,
+            heads: self.heads.clone(),
+        }
+    }
+}
+
+impl<K> fmt::Debug for Frontier<'_, K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Frontier")
+            .field("heads", &self.heads)
+            .finish()
+    }
+}
+
+impl<K> PartialEq for Frontier<'_, K> {
+    fn eq(&self, other: &Frontier<K>) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl<K> PartialOrd for Frontier<'_, K> {
+    fn partial_cmp(&self, other: &Frontier<K>) -> Option<Ordering> {
+        if !std::ptr::eq(self.dag, other.dag) {
+            return None;
+        }
+        match (self.covers(other), other.covers(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use crate::test_utils::{branching, numbered, thread};
+    use crate::CausalDag;
+    use std::cmp::Ordering;
+    use std::slice;
+
+    #[test]
+    fn orders_frontiers_by_history() {
+        let msgs = thread();
+        let (reply1, root, reply2) = (&msgs[0].0, &msgs[1].0, &msgs[2].0);
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        let frontier = |heads: &[_]| dag.frontier(heads).unwrap();
+
+        assert_eq!(dag.heads().heads().collect::<Vec<_>>(), [reply2]);
+        assert!(frontier(slice::from_ref(root)) < frontier(slice::from_ref(reply1)));
+        assert!(dag.heads() > frontier(&[reply1.clone(), root.clone()]));
+        assert!(frontier(&[reply2.clone(), root.clone()]) == dag.heads());
+        assert!(dag.frontier(&[numbered(9)]).is_none());
+
+        let links = [
+            (numbered(1), 1, vec![]),
+            (numbered(2), 2, vec![numbered(1)]),
+            (numbered(3), 3, vec![numbered(1)]),
+        ];
+        let dag = CausalDag::from_links(&links).unwrap();
+        let two = dag.frontier(&[numbered(2)]).unwrap();
+        let three = dag.frontier(&[numbered(3)]).unwrap();
+        assert_eq!(two.partial_cmp(&three), None);
+        assert_eq!(dag.heads().partial_cmp(&three), Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn removed_messages_arent_heads() {
+        let mut dag = CausalDag::from_msgs(&[branching(1, &[]), branching(2, &[1])]).unwrap();
+        dag.remove(&numbered(2)).unwrap();
+        assert_eq!(dag.heads().heads().collect::<Vec<_>>(), [&numbered(1)]);
+    }
+}
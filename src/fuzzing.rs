@@ -0,0 +1,8 @@
+//! Internals exposed for the fuzz targets in `fuzz/`. Not part of the public API.
+use crate::extract::Extractor;
+use ssb_multiformats::multihash::Multihash;
+
+/// The links the sort finds in a message body.
+pub fn extract_links(msg: &[u8]) -> Vec<Multihash> {
+    Extractor::new().extract(msg).unwrap_or_default().to_vec()
+}
@@ -0,0 +1,941 @@
+use crate::builder::TieBreak;
+use crate::error::Error;
+#[cfg(feature = "json")]
+use crate::extract::{Extracted, Extractor, Failure, FeedPosition, LinkOptions};
+use crate::metrics::TypeStats;
+use crate::sorted::SortedMessages;
+#[cfg(any(feature = "json", feature = "tracing"))]
+use crate::trace::ignored;
+use crate::trace::span;
+#[cfg(feature = "json")]
+use crate::validate::{InvalidMessages, Validate};
+use daggy::{Dag, NodeIndex, Walker};
+use petgraph::visit::Topo;
+use ssb_multiformats::multihash::Multihash;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::mem::size_of;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+#[cfg(feature = "json")]
+use std::time::Instant;
+
+pub(crate) const CYCLE: &str = "The dag has a cycle. This is _VERY_ unexpected. Either the SHA256 hash function is broken, someone is a time traveller, or someone guessed a hash of a message before it was ever created. Most likely this module has a bug :)";
+
+/// The type of the dag's node and edge indices. On 64-bit platforms, `u32` indices make each edge
+/// half the size `usize` ones would, for at most `u32::MAX - 1` hashes and as many links.
+pub(crate) type Ix = u32;
+
+/// How many independent maps the interner splits hashes across.
+pub(crate) const SHARDS: usize = 64;
+
+/// Maps each hash we've seen to its node in the dag.
+///
+/// The hashes are split across `SHARDS` maps by their first byte, so that the parallel build can
+/// fill each shard on a different thread. Small graphs keep them in one map instead.
+#[derive(Clone)]
+pub(crate) struct Interner {
+    shards: Vec<HashMap<Multihash, NodeIndex<Ix>>>,
+}
+
+impl Interner {
+    pub(crate) fn new() -> Interner {
+        Interner::from_shards((0..SHARDS).map(|_| HashMap::new()).collect())
+    }
+
+    /// An interner with all of its hashes in one map, for small graphs that aren't built in
+    /// parallel.
+    pub(crate) fn unsharded() -> Interner {
+        Interner {
+            shards: vec![HashMap::new()],
+        }
+    }
+
+    pub(crate) fn from_shards(shards: Vec<HashMap<Multihash, NodeIndex<Ix>>>) -> Interner {
+        debug_assert_eq!(shards.len(), SHARDS);
+        Interner { shards }
+    }
+
+    pub(crate) fn shard_of(hash: &Multihash) -> usize {
+        match hash {
+            Multihash::Message(bytes) | Multihash::Blob(bytes) => bytes[0] as usize % SHARDS,
+        }
+    }
+
+    /// The map of this interner that `hash` is in.
+    fn shard(&self, hash: &Multihash) -> usize {
+        Interner::shard_of(hash) % self.shards.len()
+    }
+
+    pub(crate) fn get(&self, hash: &Multihash) -> Option<NodeIndex<Ix>> {
+        self.shards[self.shard(hash)].get(hash).copied()
+    }
+
+    /// The hashes of `nodes`, in the same order. This looks through every hash, so is only for
+    /// reporting errors.
+    pub(crate) fn hashes_of(&self, nodes: &[usize]) -> Vec<Multihash> {
+        let wanted: HashMap<usize, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (*node, index))
+            .collect();
+        let mut hashes = vec![None; nodes.len()];
+        self.shards.iter().flatten().for_each(|(hash, node)| {
+            if let Some(index) = wanted.get(&node.index()) {
+                hashes[*index] = Some(hash.clone());
+            }
+        });
+        hashes.into_iter().flatten().collect()
+    }
+
+    /// The hash of every one of `node_count` nodes, in node order.
+    pub(crate) fn hashes(&self, node_count: usize) -> Vec<Multihash> {
+        let mut hashes = vec![None; node_count];
+        self.shards.iter().flatten().for_each(|(hash, node)| {
+            hashes[node.index()] = Some(hash.clone());
+        });
+        hashes.into_iter().flatten().collect()
+    }
+
+    /// Keep only the hashes whose nodes have a new index in `renumbered`, giving them it.
+    pub(crate) fn renumber(&mut self, renumbered: &[Option<NodeIndex<Ix>>]) {
+        self.shards.iter_mut().for_each(|shard| {
+            shard.retain(|_, node| match renumbered[node.index()] {
+                Some(new) => {
+                    *node = new;
+                    true
+                }
+                None => false,
+            });
+            shard.shrink_to_fit();
+        });
+    }
+
+    /// Roughly how many bytes the maps have allocated.
+    pub(crate) fn bytes_used(&self) -> usize {
+        self.shards.iter().map(map_bytes).sum()
+    }
+
+    pub(crate) fn get_or_insert_with<F>(
+        &mut self,
+        hash: &Multihash,
+        node: F,
+    ) -> Result<NodeIndex<Ix>, Error>
+    where
+        F: FnOnce() -> Result<NodeIndex<Ix>, Error>,
+    {
+        let shard = self.shard(hash);
+        match self.shards[shard].entry(hash.clone()) {
+            Entry::Occupied(entry) => Ok(*entry.get()),
+            Entry::Vacant(entry) => Ok(*entry.insert(node()?)),
+        }
+    }
+}
+
+/// Roughly how many bytes `map` has allocated: a slot and a control byte for each entry it has
+/// room for.
+pub(crate) fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
+
+/// What to check for as a graph is built, on top of cycles.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Checks<'a> {
+    /// Fail on messages that can't be parsed and on repeated keys, rather than sorting them
+    /// anyway.
+    pub(crate) strict: bool,
+    /// Stop with `Error::Cancelled` once this is set.
+    pub(crate) cancelled: Option<&'a AtomicBool>,
+    /// Ignore any more than this many links from one message.
+    pub(crate) max_links: Option<usize>,
+    /// Stop with `Error::OverBudget` once the graph takes more than this many bytes.
+    pub(crate) memory_budget: Option<usize>,
+    /// Only add links to these hashes, the keys of the messages being sorted.
+    pub(crate) keys: Option<&'a HashSet<&'a Multihash>>,
+    /// Leave out the messages with these keys.
+    pub(crate) excluded_keys: Option<&'a HashSet<Multihash>>,
+    /// Links between hashes known from elsewhere, added once the messages are.
+    pub(crate) known_links: &'a [(Multihash, Multihash)],
+    /// Check each message with this, and deal with the invalid ones as it says.
+    #[cfg(feature = "json")]
+    pub(crate) validate: Option<(&'a dyn Validate, InvalidMessages)>,
+    /// Fail with `Error::KeyMismatch` on messages that don't hash to their keys.
+    #[cfg(feature = "verify-keys")]
+    pub(crate) verify_keys: bool,
+    /// Time how long finding the messages' links takes, for `Built::extract`.
+    #[cfg(feature = "json")]
+    pub(crate) timed: bool,
+}
+
+/// What happened while a graph was built.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Built {
+    /// How many messages weren't valid JSON.
+    pub(crate) parse_failures: usize,
+    /// How many messages had more links than `Checks::max_links`.
+    pub(crate) capped: usize,
+    /// How long finding the messages' links took, if `Checks::timed`.
+    pub(crate) extract: Duration,
+    /// The messages and links of each type, if `LinkOptions::types`.
+    pub(crate) types: BTreeMap<String, TypeStats>,
+}
+
+impl Checks<'_> {
+    /// Whether to leave out the message with `key`.
+    fn excludes(&self, key: &Multihash) -> bool {
+        self.excluded_keys.is_some_and(|keys| keys.contains(key))
+    }
+
+    /// Whether to add a link to `hash`.
+    fn keeps(&self, hash: &Multihash) -> bool {
+        self.keys.is_none_or(|keys| keys.contains(hash))
+    }
+
+    /// The links of a message to add, copied into `kept` if only some are to be kept, and whether
+    /// there were too many to add them all.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn kept<'r>(
+        &self,
+        key: &Multihash,
+        refs: &'r [Multihash],
+        kept: &'r mut Vec<Multihash>,
+    ) -> (&'r [Multihash], bool) {
+        let all = refs;
+        let refs = if refs.iter().all(|hash| self.keeps(hash)) {
+            refs
+        } else {
+            kept.extend(refs.iter().filter(|hash| self.keeps(hash)).cloned());
+            kept
+        };
+        let (refs, capped) = match self.max_links {
+            Some(max) if refs.len() > max => (&refs[..max], true),
+            _ => (refs, false),
+        };
+        #[cfg(feature = "tracing")]
+        if refs.len() < all.len() {
+            self.record_ignored(key, all);
+        }
+        (refs, capped)
+    }
+
+    /// Record each of the links of `key` that `kept` leaves out.
+    #[cfg(feature = "tracing")]
+    fn record_ignored(&self, key: &Multihash, refs: &[Multihash]) {
+        let mut kept = 0;
+        for link in refs {
+            if !self.keeps(link) {
+                ignored!(key, Some(link), None, "not a sorted message");
+            } else {
+                kept += 1;
+                if self.max_links.is_some_and(|max| kept > max) {
+                    ignored!(key, Some(link), None, "max_links");
+                }
+            }
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<(), Error> {
+        match self.cancelled {
+            Some(cancelled) if cancelled.load(Ordering::Relaxed) => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A graph that messages are added to one at a time.
+pub(crate) trait BuildGraph<K> {
+    /// Add a message and the references it makes. Returns whether this is the first message with
+    /// its key.
+    fn insert(&mut self, key: &Multihash, key_id: K, refs: &[Multihash]) -> Result<bool, Error>;
+
+    /// Add an edge from the message with key `from` to the one with key `to`.
+    fn link(&mut self, from: &Multihash, to: &Multihash) -> Result<(), Error>;
+
+    fn node_count(&self) -> usize;
+
+    fn edge_count(&self) -> usize;
+
+    /// Roughly how many bytes the graph has allocated.
+    fn bytes_used(&self) -> usize;
+
+    /// Fail if the graph has grown past the checks' memory budget.
+    fn check_budget(&self, checks: Checks) -> Result<(), Error> {
+        match checks.memory_budget {
+            Some(budget) if self.bytes_used() > budget => Err(Error::OverBudget { budget }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Add the checks' known links, after the messages.
+    fn link_known(&mut self, checks: Checks) -> Result<(), Error> {
+        for (from, to) in checks.known_links {
+            self.link(from, to)?;
+        }
+        self.check_budget(checks)
+    }
+
+    /// Add a message, failing on a repeated key if the checks are strict. Returns whether it had
+    /// too many links to add them all.
+    fn add(
+        &mut self,
+        key: &Multihash,
+        key_id: K,
+        refs: &[Multihash],
+        checks: Checks,
+    ) -> Result<bool, Error> {
+        let mut kept = Vec::new();
+        let (refs, capped) = checks.kept(key, refs, &mut kept);
+        if !self.insert(key, key_id, refs)? && checks.strict {
+            return Err(Error::DuplicateKey { key: key.clone() });
+        }
+        self.check_budget(checks)?;
+        Ok(capped)
+    }
+
+    /// Add each of `msgs`, finding their links with a shared `Extractor`. Messages of excluded
+    /// types are skipped, and only the links of messages outside the options' time window are
+    /// added. If the options ask for sequence edges, each message is linked to the one before it
+    /// in its author's feed.
+    #[cfg(feature = "json")]
+    fn extend<'m, I>(
+        &mut self,
+        msgs: I,
+        checks: Checks,
+        options: LinkOptions,
+    ) -> Result<Built, Error>
+    where
+        I: ExactSizeIterator<Item = (&'m Multihash, K, &'m [u8])>,
+        Self: Sized,
+    {
+        let span = span!(
+            DEBUG,
+            "build",
+            messages = msgs.len(),
+            links = tracing::field::Empty,
+            nodes = tracing::field::Empty,
+            parse_failures = tracing::field::Empty,
+            capped = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+
+        #[cfg(feature = "tracing")]
+        let link_options = options.clone();
+        let mut extractor = Extractor::with_options(options);
+        let mut positions = Vec::new();
+        let mut capped = 0;
+        let mut extract = Duration::ZERO;
+        let mut types = BTreeMap::new();
+        for (index, (key, key_id, msg)) in msgs.enumerate() {
+            checks.check_cancelled()?;
+            if checks.excludes(key) {
+                ignored!(key, None, None, "excluded key");
+                continue;
+            }
+            #[cfg(feature = "verify-keys")]
+            if checks.verify_keys && !crate::keys::key_matches(key, msg) {
+                return Err(Error::KeyMismatch {
+                    index,
+                    key: key.clone(),
+                });
+            }
+            if let Some((validate, invalid)) = checks.validate {
+                if let Err(source) = validate.validate(key, msg) {
+                    match invalid {
+                        InvalidMessages::Exclude => ignored!(key, None, None, "invalid"),
+                        InvalidMessages::Orphan => {
+                            capped += self.add(key, key_id, &[], checks)? as usize
+                        }
+                        InvalidMessages::Error => return Err(Error::Invalid { index, source }),
+                    }
+                    continue;
+                }
+            }
+            let extracting = checks.timed.then(Instant::now);
+            let extracted = extractor.extract_kept(msg);
+            if let Some(extracting) = extracting {
+                extract += extracting.elapsed();
+            }
+            let refs = match extracted {
+                Ok(Some(Extracted {
+                    refs,
+                    position,
+                    sorted,
+                    msg_type,
+                })) => {
+                    if let Some(msg_type) = msg_type {
+                        let stats: &mut TypeStats = types.entry(msg_type).or_default();
+                        stats.messages += 1;
+                        stats.links += refs.len();
+                    }
+                    #[cfg(feature = "tracing")]
+                    crate::trace::filtered_links(key, msg, refs, &link_options);
+                    positions.extend(position.map(|position| (position, key)));
+                    if !sorted {
+                        ignored!(key, None, None, "outside time window");
+                        // Keep the message's links, but not the message.
+                        let mut kept = Vec::new();
+                        let (refs, too_many) = checks.kept(key, refs, &mut kept);
+                        for reference in refs {
+                            self.link(key, reference)?;
+                        }
+                        self.check_budget(checks)?;
+                        capped += too_many as usize;
+                        continue;
+                    }
+                    refs
+                }
+                Ok(None) => {
+                    #[cfg(feature = "tracing")]
+                    let reason = match extractor.excluded_author() {
+                        true => "excluded author",
+                        false => "excluded type",
+                    };
+                    ignored!(key, None, None, reason);
+                    continue;
+                }
+                Err(Failure::Parse(source)) if checks.strict => {
+                    return Err(Error::Parse { index, source })
+                }
+                Err(Failure::TooDeep) if checks.strict => return Err(Error::TooDeep { index }),
+                Err(_) => &[],
+            };
+            capped += self.add(key, key_id, refs, checks)? as usize;
+        }
+        self.link_feeds(positions)?;
+        self.link_known(checks)?;
+
+        span.record("links", self.edge_count())
+            .record("nodes", self.node_count())
+            .record("parse_failures", extractor.failures())
+            .record("capped", capped);
+        Ok(Built {
+            parse_failures: extractor.failures(),
+            capped,
+            extract,
+            types,
+        })
+    }
+
+    /// Link each message to the one before it in its author's feed. Messages with the same
+    /// sequence number, eg. forks of a feed, each follow the one before them rather than each
+    /// other. Of those, the one with the greatest key is followed, whatever order they came in.
+    #[cfg(feature = "json")]
+    fn link_feeds(&mut self, mut positions: Vec<(FeedPosition, &Multihash)>) -> Result<(), Error> {
+        positions.sort_by(|(a, key_a), (b, key_b)| a.cmp(b).then_with(|| key_a.cmp(key_b)));
+        let mut earlier = None;
+        for (index, (position, key)) in positions.iter().enumerate() {
+            if let Some((previous, previous_key)) = index.checked_sub(1).map(|i| &positions[i]) {
+                if previous.author != position.author {
+                    earlier = None;
+                } else if previous.sequence < position.sequence {
+                    earlier = Some(*previous_key);
+                }
+            }
+            if let Some(earlier) = earlier {
+                self.link(key, earlier)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add each of `msgs` with the links already found in them.
+    fn extend_links<'m, I>(&mut self, msgs: I, checks: Checks) -> Result<Built, Error>
+    where
+        I: ExactSizeIterator<Item = (&'m Multihash, K, &'m [Multihash])>,
+        Self: Sized,
+    {
+        let span = span!(
+            DEBUG,
+            "build",
+            messages = msgs.len(),
+            links = tracing::field::Empty,
+            nodes = tracing::field::Empty,
+            capped = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+
+        let mut unique = Vec::new();
+        let mut capped = 0;
+        for (key, key_id, refs) in msgs {
+            checks.check_cancelled()?;
+            if checks.excludes(key) {
+                continue;
+            }
+            let refs = if has_repeats(refs) {
+                unique.clear();
+                unique.extend_from_slice(refs);
+                dedup_links(&mut unique);
+                &unique
+            } else {
+                refs
+            };
+            capped += self.add(key, key_id, refs, checks)? as usize;
+        }
+        self.link_known(checks)?;
+
+        span.record("links", self.edge_count())
+            .record("nodes", self.node_count())
+            .record("capped", capped);
+        Ok(Built {
+            parse_failures: 0,
+            capped,
+            extract: Duration::ZERO,
+            types: BTreeMap::new(),
+        })
+    }
+}
+
+/// Above this many links, look for repeats with a set rather than by comparing every pair.
+const FEW_LINKS: usize = 16;
+
+/// Whether any hash is in `links` more than once.
+pub(crate) fn has_repeats(links: &[Multihash]) -> bool {
+    if links.len() <= FEW_LINKS {
+        links
+            .iter()
+            .enumerate()
+            .any(|(i, link)| links[..i].contains(link))
+    } else {
+        let mut seen = HashSet::with_capacity(links.len());
+        !links.iter().all(|link| seen.insert(link))
+    }
+}
+
+/// Remove the repeats of each hash in `links`, keeping the first. A message has one edge to each
+/// hash it links to, however many times it links to it, and keeping the first link to each hash
+/// leaves the sort in the same order.
+pub(crate) fn dedup_links(links: &mut Vec<Multihash>) {
+    if !has_repeats(links) {
+        return;
+    }
+    let mut seen = HashSet::with_capacity(links.len());
+    let mut kept = Vec::with_capacity(links.len());
+    links.drain(..).for_each(|link| {
+        if !seen.contains(&link) {
+            seen.insert(link.clone());
+            kept.push(link);
+        }
+    });
+    *links = kept;
+}
+
+/// Find a cycle by walking depth first from each of `starts` in turn, returning its nodes in the
+/// order they link to each other.
+pub(crate) fn find_cycle<S, C, I>(node_count: usize, starts: S, children: C) -> Option<Vec<usize>>
+where
+    S: IntoIterator<Item = usize>,
+    C: Fn(usize) -> I,
+    I: Iterator<Item = usize>,
+{
+    const UNSEEN: u8 = 0;
+    const WALKING: u8 = 1;
+    const DONE: u8 = 2;
+
+    let mut state = vec![UNSEEN; node_count];
+    for start in starts {
+        if state[start] != UNSEEN {
+            continue;
+        }
+        state[start] = WALKING;
+        let mut walk = vec![(start, children(start))];
+        while let Some((node, remaining)) = walk.last_mut() {
+            let node = *node;
+            match remaining.next() {
+                Some(child) if state[child] == UNSEEN => {
+                    state[child] = WALKING;
+                    walk.push((child, children(child)));
+                }
+                Some(child) if state[child] == WALKING => {
+                    let from = walk.iter().position(|(walked, _)| *walked == child)?;
+                    return Some(walk[from..].iter().map(|(walked, _)| *walked).collect());
+                }
+                Some(_) => (),
+                None => {
+                    state[node] = DONE;
+                    walk.pop();
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The nodes of a graph in topological order, newest first, taking the node with the least hash
+/// whenever more than one could come next. Nodes on or after a cycle are left out.
+///
+/// The order only depends on the hashes and the edges between them, not on the order the nodes
+/// and edges were added in, so every peer with the same messages finds the same order.
+pub(crate) fn canonical_order<C, I>(hashes: &[Multihash], children: C) -> Vec<usize>
+where
+    C: Fn(usize) -> I,
+    I: Iterator<Item = usize>,
+{
+    let mut in_degree = vec![0_usize; hashes.len()];
+    (0..hashes.len()).for_each(|node| children(node).for_each(|child| in_degree[child] += 1));
+
+    let mut ready: BinaryHeap<Reverse<(&Multihash, usize)>> = (0..hashes.len())
+        .filter(|node| in_degree[*node] == 0)
+        .map(|node| Reverse((&hashes[node], node)))
+        .collect();
+    let mut order = Vec::with_capacity(hashes.len());
+    while let Some(Reverse((_, node))) = ready.pop() {
+        order.push(node);
+        children(node).for_each(|child| {
+            in_degree[child] -= 1;
+            if in_degree[child] == 0 {
+                ready.push(Reverse((&hashes[child], child)));
+            }
+        });
+    }
+    order
+}
+
+/// Above this many nodes, sort in layers on many threads, if there's a thread pool to use.
+#[cfg(feature = "rayon")]
+const PARALLEL_LAYERS: usize = 1 << 16;
+
+/// The thread pool to sort large graphs on, if not rayon's global one.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Threads<'a> {
+    #[cfg(feature = "rayon")]
+    pub(crate) pool: Option<&'a rayon::ThreadPool>,
+    /// Sort on the calling thread, however large the graph.
+    #[cfg(feature = "rayon")]
+    pub(crate) single: bool,
+    #[cfg(not(feature = "rayon"))]
+    pub(crate) pool: std::marker::PhantomData<&'a ()>,
+}
+
+impl Threads<'_> {
+    /// Sort on the calling thread only.
+    pub(crate) fn single() -> Self {
+        Threads {
+            #[cfg(feature = "rayon")]
+            single: true,
+            ..Threads::default()
+        }
+    }
+}
+
+/// The nodes of a graph in layers, newest first: first the nodes nothing links to, then the nodes
+/// only they link to, and so on, each layer in node order. Nodes on or after a cycle are left out.
+///
+/// Large graphs are sorted on many threads of `threads` with the `rayon` feature, in exactly the
+/// same order.
+#[cfg_attr(not(feature = "rayon"), allow(unused_variables))]
+pub(crate) fn layered_order<C, I>(node_count: usize, children: C, threads: Threads) -> Vec<usize>
+where
+    C: Fn(usize) -> I + Sync,
+    I: Iterator<Item = usize>,
+{
+    #[cfg(feature = "rayon")]
+    if node_count >= PARALLEL_LAYERS && !threads.single {
+        let children = &children;
+        let sort = || crate::parallel::par_layered_order(node_count, children);
+        return match threads.pool {
+            Some(pool) => pool.install(sort),
+            None => sort(),
+        };
+    }
+    sequential_layered_order(node_count, children)
+}
+
+/// `layered_order` on one thread.
+pub(crate) fn sequential_layered_order<C, I>(node_count: usize, children: C) -> Vec<usize>
+where
+    C: Fn(usize) -> I,
+    I: Iterator<Item = usize>,
+{
+    let mut in_degree = vec![0_usize; node_count];
+    (0..node_count).for_each(|node| children(node).for_each(|child| in_degree[child] += 1));
+
+    let mut layer: Vec<usize> = (0..node_count)
+        .filter(|node| in_degree[*node] == 0)
+        .collect();
+    let mut order = Vec::with_capacity(node_count);
+    while !layer.is_empty() {
+        order.extend_from_slice(&layer);
+        let mut next = Vec::new();
+        layer.iter().for_each(|node| {
+            children(*node).for_each(|child| {
+                in_degree[child] -= 1;
+                if in_degree[child] == 0 {
+                    next.push(child);
+                }
+            })
+        });
+        next.sort_unstable();
+        layer = next;
+    }
+    order
+}
+
+/// Add a node to `dag`, unless it already has as many as its indices can count. petgraph would
+/// panic instead, as its largest index marks the ends of its lists.
+fn add_node(dag: &mut Dag<u32, u32, Ix>) -> Result<NodeIndex<Ix>, Error> {
+    check_count(dag.node_count())?;
+    Ok(dag.add_node(1))
+}
+
+/// Fail unless there's room for another edge in `dag`.
+fn check_edge_count(dag: &Dag<u32, u32, Ix>) -> Result<(), Error> {
+    check_count(dag.edge_count())
+}
+
+/// Fail unless there's an index for another of `count` nodes or edges.
+pub(crate) fn check_count(count: usize) -> Result<(), Error> {
+    match count < Ix::MAX as usize {
+        true => Ok(()),
+        false => Err(Error::TooManyNodes),
+    }
+}
+
+/// The error for the cycle that an edge from `from` to `to` would close.
+fn cycle_error(
+    dag: &Dag<u32, u32, Ix>,
+    interner: &Interner,
+    from: NodeIndex<Ix>,
+    to: NodeIndex<Ix>,
+) -> Error {
+    let graph = dag.graph();
+    let nodes = find_cycle(graph.node_count(), Some(from.index()), |node| {
+        let closing = Some(to.index()).filter(|_| node == from.index());
+        graph
+            .neighbors(NodeIndex::new(node))
+            .map(|child| child.index())
+            .chain(closing)
+    });
+    Error::Cycle {
+        keys: interner.hashes_of(&nodes.unwrap_or_default()),
+    }
+}
+
+/// The dag of references between messages, built up one message at a time.
+///
+/// Nodes are created for every hash we see, either as a message key or as a reference. Only the
+/// nodes created for message keys map back to a key id, so only those get emitted by `sorted`.
+#[derive(Clone)]
+pub(crate) struct CausalGraph<K> {
+    dag: Dag<u32, u32, Ix>,
+    hash_to_node: Interner,
+    node_to_key_id: Vec<Option<K>>,
+}
+
+impl<K> CausalGraph<K> {
+    pub(crate) fn dag(&self) -> &Dag<u32, u32, Ix> {
+        &self.dag
+    }
+
+    pub(crate) fn interner(&self) -> &Interner {
+        &self.hash_to_node
+    }
+
+    /// The key id of the message with `node` as its key, if there is one.
+    pub(crate) fn key_id(&self, node: usize) -> Option<&K> {
+        self.node_to_key_id.get(node)?.as_ref()
+    }
+
+    /// Drop the nodes of hashes that are only linked to, along with the edges to them, keeping
+    /// the order of the other nodes and edges so the graph sorts the same way.
+    pub(crate) fn shrink(&mut self) {
+        let node_count = self.dag.node_count();
+        let mut renumbered = vec![None; node_count];
+        let mut kept = 0;
+        for (node, new) in renumbered.iter_mut().enumerate() {
+            if self.key_id(node).is_some() {
+                *new = Some(NodeIndex::new(kept));
+                kept += 1;
+            }
+        }
+        self.dag = self.dag.filter_map(
+            |node, weight| renumbered[node.index()].map(|_| *weight),
+            |_, weight| Some(*weight),
+        );
+        self.hash_to_node.renumber(&renumbered);
+        self.node_to_key_id.retain(Option::is_some);
+        self.node_to_key_id.shrink_to_fit();
+    }
+
+    /// Remove the message with `node` as its key, leaving the node only a link if anything still
+    /// links to it. Returns the message's key id, or `None` if `node` isn't a message's key.
+    pub(crate) fn detach(&mut self, node: usize) -> Option<K> {
+        let key_id = self.node_to_key_id.get_mut(node)?.take()?;
+        let node = NodeIndex::new(node);
+        while let Some(edge) = self.dag.graph().first_edge(node, petgraph::Outgoing) {
+            self.dag.remove_edge(edge);
+        }
+        Some(key_id)
+    }
+
+    /// Remove every edge but the first `edges`, eg. the links of a message that failed part way
+    /// through being added. Edges are numbered in the order they were added, and removing the
+    /// last leaves the others' numbers alone.
+    #[cfg(feature = "json")]
+    pub(crate) fn truncate_edges(&mut self, edges: usize) {
+        while self.dag.edge_count() > edges {
+            self.dag
+                .remove_edge(daggy::EdgeIndex::new(self.dag.edge_count() - 1));
+        }
+    }
+}
+
+impl<K: Clone> CausalGraph<K> {
+    pub(crate) fn new() -> CausalGraph<K> {
+        CausalGraph {
+            dag: Dag::new(),
+            hash_to_node: Interner::new(),
+            node_to_key_id: Vec::new(),
+        }
+    }
+
+    /// A graph for a small sort, with an [unsharded](Interner::unsharded) interner.
+    pub(crate) fn unsharded() -> CausalGraph<K> {
+        CausalGraph {
+            hash_to_node: Interner::unsharded(),
+            ..CausalGraph::new()
+        }
+    }
+
+    /// Assemble a graph that was built elsewhere, eg. in parallel.
+    #[cfg_attr(not(feature = "rayon"), allow(dead_code))]
+    pub(crate) fn from_parts(
+        dag: Dag<u32, u32, Ix>,
+        hash_to_node: Interner,
+        node_to_key_id: Vec<Option<K>>,
+    ) -> CausalGraph<K> {
+        CausalGraph {
+            dag,
+            hash_to_node,
+            node_to_key_id,
+        }
+    }
+
+    /// Topologically sort the dag, newest first.
+    pub(crate) fn sorted(&self) -> SortedMessages<K> {
+        self.sorted_with(TieBreak::Input, Threads::default())
+    }
+
+    /// Topologically sort the dag, newest first, breaking ties between concurrent messages with
+    /// `tie_break`.
+    pub(crate) fn sorted_with(&self, tie_break: TieBreak, threads: Threads) -> SortedMessages<K> {
+        let mut sorted = Vec::new();
+        self.sort_into(tie_break, threads, |key_id| {
+            sorted.push(key_id);
+            ControlFlow::Continue(())
+        });
+        sorted.into()
+    }
+
+    /// Like `sorted_with`, but give each key id to `sink` as it's sorted until `sink` breaks.
+    pub(crate) fn sort_into<F: FnMut(K) -> ControlFlow<()>>(
+        &self,
+        tie_break: TieBreak,
+        threads: Threads,
+        mut sink: F,
+    ) {
+        let span = span!(
+            DEBUG,
+            "topo_sort",
+            nodes = self.node_count(),
+            sorted = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+
+        let graph = self.dag.graph();
+        let mut sorted = 0;
+        let mut give = |node: usize| match self.key_id(node) {
+            Some(key_id) => {
+                sorted += 1;
+                sink(key_id.clone())
+            }
+            None => ControlFlow::Continue(()),
+        };
+        match tie_break {
+            // Topo visits the nodes as it goes, so nothing is sorted past where `sink` stops.
+            TieBreak::Input => {
+                let _ = Topo::new(graph)
+                    .iter(graph)
+                    .try_for_each(|node| give(node.index()));
+            }
+            TieBreak::Canonical => {
+                let hashes = self.hash_to_node.hashes(graph.node_count());
+                let order = canonical_order(&hashes, |node| {
+                    graph
+                        .neighbors(NodeIndex::new(node))
+                        .map(|child| child.index())
+                });
+                let _ = order.into_iter().try_for_each(give);
+            }
+            TieBreak::Layers => {
+                let order = layered_order(
+                    graph.node_count(),
+                    |node| {
+                        graph
+                            .neighbors(NodeIndex::new(node))
+                            .map(|child| child.index())
+                    },
+                    threads,
+                );
+                let _ = order.into_iter().try_for_each(give);
+            }
+        }
+        span.record("sorted", sorted);
+    }
+}
+
+impl<K: Clone> BuildGraph<K> for CausalGraph<K> {
+    fn insert(&mut self, key: &Multihash, key_id: K, refs: &[Multihash]) -> Result<bool, Error> {
+        let CausalGraph {
+            dag,
+            hash_to_node,
+            node_to_key_id,
+        } = self;
+
+        // Check if we've already created a node for key
+        let key_node = hash_to_node.get_or_insert_with(key, || add_node(dag))?;
+        node_to_key_id.resize(dag.node_count(), None);
+        let first = node_to_key_id[key_node.index()].is_none();
+        node_to_key_id[key_node.index()].get_or_insert(key_id);
+
+        for reference in refs {
+            let ref_node = hash_to_node.get_or_insert_with(reference, || add_node(dag))?;
+            check_edge_count(dag)?;
+            // daggy allows an edge from a node to itself, but then leaves the node out of the sort.
+            if ref_node == key_node || dag.add_edge(key_node, ref_node, 1).is_err() {
+                return Err(cycle_error(dag, hash_to_node, key_node, ref_node));
+            }
+        }
+        Ok(first)
+    }
+
+    fn link(&mut self, from: &Multihash, to: &Multihash) -> Result<(), Error> {
+        let CausalGraph {
+            dag, hash_to_node, ..
+        } = self;
+        let from_node = hash_to_node.get_or_insert_with(from, || add_node(dag))?;
+        let to_node = hash_to_node.get_or_insert_with(to, || add_node(dag))?;
+        check_edge_count(dag)?;
+        if from_node == to_node || dag.add_edge(from_node, to_node, 1).is_err() {
+            return Err(cycle_error(dag, hash_to_node, from_node, to_node));
+        }
+        Ok(())
+    }
+
+    fn node_count(&self) -> usize {
+        self.dag.node_count()
+    }
+
+    fn edge_count(&self) -> usize {
+        self.dag.edge_count()
+    }
+
+    fn bytes_used(&self) -> usize {
+        let (nodes, edges) = self.dag.graph().capacity();
+        nodes * size_of::<petgraph::graph::Node<u32, Ix>>()
+            + edges * size_of::<petgraph::graph::Edge<u32, Ix>>()
+            + self.hash_to_node.bytes_used()
+            + self.node_to_key_id.capacity() * size_of::<Option<K>>()
+    }
+}
@@ -0,0 +1,401 @@
+//! Reading hashes in each of the forms a message or blob can be referred to by, so that every
+//! form of one hash is the same node of the dag.
+use ssb_multiformats::multihash::Multihash;
+use std::convert::TryFrom;
+#[cfg(feature = "json")]
+use std::fmt;
+#[cfg(feature = "json")]
+use std::sync::Arc;
+
+/// The hash `link` refers to, in any of the forms a hash can take:
+///
+/// - the legacy `%<base64>.sha256` form for messages, and `&<base64>.sha256` for blobs, as they
+///   appear in classic message bodies.
+/// - an [SSB URI](https://github.com/ssbc/ssb-uri-spec), eg. `ssb:message/classic/<base64url>`
+///   or `ssb:blob/sha256/<base64url>`, with or without its padding.
+/// - the 32 bytes of the hash, as binary formats carry a message key.
+///
+/// Every form of a hash gives the same `Multihash`, so a message linked to in one form sorts
+/// before messages that link to it in another. Strings in message bodies are read the same way,
+/// but only as legacy links and URIs, never as bytes.
+pub fn normalize_hash(link: &[u8]) -> Option<Multihash> {
+    match <[u8; 32]>::try_from(link) {
+        Ok(bytes) => Some(Multihash::Message(bytes)),
+        Err(_) => parse_link(std::str::from_utf8(link).ok()?),
+    }
+}
+
+/// The hash a string in a message body links to, if it's a legacy link or an SSB URI.
+pub(crate) fn parse_link(st: &str) -> Option<Multihash> {
+    match st.strip_prefix("ssb:") {
+        Some(uri) => from_uri(uri),
+        None => Multihash::from_legacy(st.as_bytes()).ok().map(|(mh, _)| mh),
+    }
+}
+
+/// A link to a message or blob with a hash this crate can't read, eg. `%<base64>.blake3` or
+/// `ssb:message/buttwoo-v1/<base64url>`, for [`SortBuilder::unknown_hashes`] to read instead.
+///
+/// [`SortBuilder::unknown_hashes`]: crate::SortBuilder::unknown_hashes
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownHash<'a> {
+    /// The whole link, as it appears in the message.
+    pub link: &'a str,
+    /// Whether the link is to a blob rather than a message.
+    pub blob: bool,
+    /// The suffix of a legacy link, eg. `blake3`, or the format of a URI, eg. `buttwoo-v1`.
+    pub format: &'a str,
+    /// The hash as written: base64 in a legacy link, base64url in a URI.
+    pub data: &'a str,
+}
+
+#[cfg(feature = "json")]
+impl UnknownHash<'_> {
+    /// Split up `link`, if it has the shape of a link to a message or blob but not a known hash.
+    pub(crate) fn parse(link: &str) -> Option<UnknownHash<'_>> {
+        let (blob, format, data) = match link.strip_prefix("ssb:") {
+            Some(uri) => {
+                let mut parts = uri.splitn(3, '/');
+                let blob = match parts.next()? {
+                    "message" => false,
+                    "blob" => true,
+                    _ => return None,
+                };
+                (blob, parts.next()?, parts.next()?)
+            }
+            None => {
+                let blob = match link.as_bytes().first()? {
+                    b'%' => false,
+                    b'&' => true,
+                    _ => return None,
+                };
+                let (data, format) = link[1..].rsplit_once('.')?;
+                (blob, format, data)
+            }
+        };
+        let known = matches!(format, "sha256" | "classic");
+        let word = |st: &str| !st.is_empty() && !st.contains(|c: char| c.is_whitespace());
+        match !known && word(format) && word(data) {
+            true => Some(UnknownHash {
+                link,
+                blob,
+                format,
+                data,
+            }),
+            false => None,
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+type Read = dyn Fn(&UnknownHash) -> Option<Multihash> + Send + Sync;
+
+/// A form of link for the sorts to read, for [`SortBuilder::link_formats`]: a prefix, the base64
+/// of a 32 byte `sha256` hash, and a suffix, like the legacy `%<base64>.sha256`.
+///
+/// [`SortBuilder::link_formats`]: crate::SortBuilder::link_formats
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LinkFormat {
+    /// What the link starts with, eg. `%`.
+    pub prefix: String,
+    /// What the link ends with, eg. `.sha256`.
+    pub suffix: String,
+    /// Whether the link is to a blob rather than a message.
+    pub blob: bool,
+}
+
+#[cfg(feature = "json")]
+impl LinkFormat {
+    /// Links to messages that start with `prefix` and end with `suffix`.
+    pub fn message(prefix: &str, suffix: &str) -> LinkFormat {
+        LinkFormat {
+            prefix: prefix.to_owned(),
+            suffix: suffix.to_owned(),
+            blob: false,
+        }
+    }
+
+    /// Links to blobs that start with `prefix` and end with `suffix`.
+    pub fn blob(prefix: &str, suffix: &str) -> LinkFormat {
+        LinkFormat {
+            blob: true,
+            ..LinkFormat::message(prefix, suffix)
+        }
+    }
+
+    /// The legacy forms that are read by default: `%<base64>.sha256` for messages and
+    /// `&<base64>.sha256` for blobs.
+    pub fn legacy() -> [LinkFormat; 2] {
+        [
+            LinkFormat::message("%", ".sha256"),
+            LinkFormat::blob("&", ".sha256"),
+        ]
+    }
+
+    /// The hash `link` refers to, if it's in this form.
+    pub(crate) fn parse(&self, link: &str) -> Option<Multihash> {
+        let data = link
+            .strip_prefix(self.prefix.as_str())?
+            .strip_suffix(self.suffix.as_str())?;
+        if data.is_empty() {
+            return None;
+        }
+        let mut legacy = String::with_capacity(data.len() + 8);
+        legacy.push(if self.blob { '&' } else { '%' });
+        legacy.push_str(data);
+        legacy.push_str(".sha256");
+        match Multihash::from_legacy(legacy.as_bytes()) {
+            Ok((mh, [])) => Some(mh),
+            _ => None,
+        }
+    }
+}
+
+/// What reads [`UnknownHash`]es for a sort.
+#[cfg(feature = "json")]
+#[derive(Clone)]
+pub(crate) struct ReadUnknown(pub(crate) Arc<Read>);
+
+#[cfg(feature = "json")]
+impl fmt::Debug for ReadUnknown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ReadUnknown")
+    }
+}
+
+/// Parse the part of an SSB URI after `ssb:`, by rewriting it in the legacy form.
+pub(crate) fn from_uri(uri: &str) -> Option<Multihash> {
+    let mut parts = uri.splitn(3, '/');
+    let sigil = match parts.next()? {
+        "message" => '%',
+        "blob" => '&',
+        _ => return None,
+    };
+    match parts.next()? {
+        "classic" | "sha256" => (),
+        _ => return None,
+    }
+    let data = parts.next()?.replace("%3D", "=");
+    let data = data.trim_end_matches('=');
+    if data.len() % 4 == 1 {
+        return None;
+    }
+    let mut legacy = String::with_capacity(data.len() + 10);
+    legacy.push(sigil);
+    legacy.extend(data.chars().map(|c| match c {
+        '-' => '+',
+        '_' => '/',
+        c => c,
+    }));
+    (0..(4 - data.len() % 4) % 4).for_each(|_| legacy.push('='));
+    legacy.push_str(".sha256");
+    match Multihash::from_legacy(legacy.as_bytes()) {
+        Ok((mh, [])) => Some(mh),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_hash, parse_link};
+    use ssb_multiformats::multihash::Multihash;
+    #[cfg(feature = "json")]
+    use {
+        super::{LinkFormat, UnknownHash},
+        crate::test_utils::numbered,
+        crate::{causal_sort, Backend, CausalDag, SortBuilder},
+        serde_json::json,
+    };
+
+    const LEGACY: &str = "%g3hPVPDEO1Aj/uPl0+J2NlhFB2bbFLIHlty+YuqFZ3w=.sha256";
+
+    #[test]
+    fn every_form_of_a_hash_is_the_same() {
+        let hash = Multihash::from_legacy(LEGACY.as_bytes()).unwrap().0;
+        let bytes = match &hash {
+            Multihash::Message(bytes) => *bytes,
+            Multihash::Blob(_) => unreachable!(),
+        };
+        let forms = vec![
+            LEGACY,
+            "ssb:message/classic/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=",
+            "ssb:message/sha256/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w",
+            "ssb:message/classic/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w%3D",
+        ];
+        forms
+            .into_iter()
+            .for_each(|form| assert_eq!(parse_link(form), Some(hash.clone()), "{}", form));
+        assert_eq!(normalize_hash(&bytes), Some(hash.clone()));
+        assert_eq!(normalize_hash(LEGACY.as_bytes()), Some(hash));
+
+        let blob = parse_link("ssb:blob/classic/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=");
+        assert_eq!(blob, Some(Multihash::Blob(bytes)));
+    }
+
+    #[test]
+    fn other_strings_are_not_hashes() {
+        let strings = vec![
+            "ssb:feed/classic/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=",
+            "ssb:message/bendybutt-v1/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=",
+            "ssb:message/classic/g3hPVPDEO1Aj",
+            "ssb:message/classic/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=/more",
+            "ssb:message/classic",
+            "ssb:",
+            "it's 32 bytes long, but a string",
+        ];
+        strings
+            .into_iter()
+            .for_each(|st| assert_eq!(parse_link(st), None, "{}", st));
+        assert_eq!(normalize_hash(&[0; 31]), None);
+    }
+
+    /// `hash` as an `ssb:message/classic/` URI, without its padding if `padded` is false.
+    #[cfg(feature = "json")]
+    fn uri(hash: &Multihash, padded: bool) -> String {
+        let legacy = hash.to_legacy_string();
+        let base64 = &legacy[1..legacy.len() - ".sha256".len()];
+        let base64 = if padded {
+            base64
+        } else {
+            base64.trim_end_matches('=')
+        };
+        let base64: String = base64
+            .chars()
+            .map(|c| match c {
+                '+' => '-',
+                '/' => '_',
+                c => c,
+            })
+            .collect();
+        format!("ssb:message/classic/{}", base64)
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn links_in_mixed_forms_sort_as_one_message() {
+        let root = match numbered(1) {
+            Multihash::Message(bytes) => normalize_hash(&bytes).unwrap(),
+            Multihash::Blob(_) => unreachable!(),
+        };
+        let reply = numbered(2);
+        let msgs = vec![
+            (
+                reply.clone(),
+                2,
+                json!({ "root": uri(&root, true) }).to_string(),
+            ),
+            (
+                numbered(3),
+                3,
+                json!({ "root": root, "branch": uri(&reply, false) }).to_string(),
+            ),
+            (root, 1, json!({}).to_string()),
+        ];
+
+        assert_eq!(causal_sort(&msgs), [3, 2, 1]);
+        let sorted = SortBuilder::new().backend(Backend::Csr).sort(&msgs);
+        assert_eq!(sorted, [3, 2, 1]);
+        assert_eq!(CausalDag::from_msgs(&msgs).unwrap().node_count(), 3);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn links_are_read_in_the_formats_given() {
+        let fork = LinkFormat::message("!", ".fork");
+        let legacy = numbered(1).to_legacy_string();
+        let base64 = &legacy[1..legacy.len() - ".sha256".len()];
+        assert_eq!(fork.parse(&format!("!{}.fork", base64)), Some(numbered(1)));
+        let blob = LinkFormat::blob("!", ".fork").parse(&format!("!{}.fork", base64));
+        assert!(matches!(blob, Some(Multihash::Blob(_))));
+        let strings = vec![legacy.clone(), "!.fork".to_owned(), "!g3hP.fork".to_owned()];
+        strings
+            .into_iter()
+            .for_each(|st| assert_eq!(fork.parse(&st), None, "{}", st));
+
+        // The reply links to the root in the fork's form, and the root back to the reply in the
+        // legacy form.
+        let reply = numbered(2).to_legacy_string();
+        let msgs = vec![
+            (numbered(1), 1, json!({ "root": reply }).to_string()),
+            (
+                numbered(2),
+                2,
+                json!({ "root": format!("!{}.fork", base64) }).to_string(),
+            ),
+            (
+                numbered(3),
+                3,
+                json!({ "root": uri(&numbered(2), true) }).to_string(),
+            ),
+        ];
+        let mut both = LinkFormat::legacy().to_vec();
+        both.push(fork.clone());
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend);
+            assert_eq!(builder.sort(&msgs), [3, 1, 2]);
+            let forked = builder.clone().link_formats(std::slice::from_ref(&fork));
+            assert_eq!(forked.sort(&msgs), [3, 2, 1]);
+            assert!(builder.link_formats(&both).try_sort(&msgs).is_err());
+        });
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn unknown_hashes_are_split_up() {
+        let unknown = UnknownHash::parse("%g3hPVPDEO1Aj.blake3").unwrap();
+        assert_eq!(
+            (unknown.blob, unknown.format, unknown.data),
+            (false, "blake3", "g3hPVPDEO1Aj")
+        );
+        let unknown = UnknownHash::parse("ssb:blob/buttwoo-v1/g3h_PVP").unwrap();
+        assert_eq!(
+            (unknown.blob, unknown.format, unknown.data),
+            (true, "buttwoo-v1", "g3h_PVP")
+        );
+
+        let strings = vec![
+            LEGACY,
+            "%g3hPVPDEO1Aj.sha256",
+            "ssb:message/classic/g3hPVPDEO1Aj",
+            "ssb:feed/bendybutt-v1/g3hPVPDEO1Aj",
+            "@g3hPVPDEO1Aj.ed25519",
+            "%.blake3",
+            "%g3hPVPDEO1Aj.",
+            "100% sure. Really",
+        ];
+        strings
+            .into_iter()
+            .for_each(|st| assert_eq!(UnknownHash::parse(st), None, "{}", st));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn unknown_hashes_sort_once_read() {
+        // Stands in for a new algorithm: the hashes in these links are the numbers of messages.
+        let read = |unknown: &UnknownHash| match unknown.format {
+            "fake" => Some(numbered(unknown.data.parse().ok()?)),
+            _ => None,
+        };
+        let msgs = vec![
+            (numbered(2), 2, json!({ "root": "%1.fake" }).to_string()),
+            (
+                numbered(3),
+                3,
+                json!({ "root": "%1.fake", "branch": "ssb:message/fake/2" }).to_string(),
+            ),
+            (
+                numbered(1),
+                1,
+                json!({ "previous": "%1.unknown" }).to_string(),
+            ),
+        ];
+
+        assert_eq!(CausalDag::from_msgs(&msgs).unwrap().edge_count(), 0);
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let builder = SortBuilder::new().backend(*backend).unknown_hashes(read);
+            assert_eq!(builder.sort(&msgs), [3, 2, 1]);
+        });
+    }
+}
@@ -0,0 +1,303 @@
+//! Checking that messages are what their keys say they are.
+use serde::de::{Deserialize, Deserializer, Error, MapAccess, SeqAccess, Visitor};
+use sha2::{Digest, Sha256};
+use ssb_multiformats::multihash::Multihash;
+use std::fmt;
+
+/// The key of the message whose value is `msg`: the SHA-256 of the value in the legacy encoding.
+///
+/// `msg` must be the value exactly as it was signed, ie. `JSON.stringify(value, null, 2)`, as
+/// a message is stored and sent in classic feeds. The legacy encoding hashes the low byte of each
+/// UTF-16 code unit of that text, so characters outside Latin-1 are hashed the way JavaScript
+/// implementations hash them, not as their UTF-8 bytes.
+pub fn message_key(msg: &str) -> Multihash {
+    let mut hasher = Sha256::new();
+    let mut units = [0; 64];
+    let mut len = 0;
+    for unit in msg.encode_utf16() {
+        units[len] = unit as u8;
+        len += 1;
+        if len == units.len() {
+            hasher.update(units);
+            len = 0;
+        }
+    }
+    hasher.update(&units[..len]);
+    Multihash::Message(hasher.finalize().into())
+}
+
+/// `value` as it's signed, ie. as `JSON.stringify(value, null, 2)` writes it, to find the
+/// [`message_key`] of a value that's been stored some other way, eg. on one line.
+///
+/// Fields stay in the order they're in, and numbers and escapes are written the way JavaScript
+/// writes them. A field that's repeated keeps its first place and its last value, as
+/// `JSON.parse` does.
+pub fn legacy_encoding(value: &str) -> serde_json::Result<String> {
+    Ok(serde_json::from_str::<Json>(value)?.legacy_encoding())
+}
+
+/// A JSON value with its object fields in order.
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// See [`legacy_encoding`].
+    pub(crate) fn legacy_encoding(&self) -> String {
+        let mut encoded = String::new();
+        self.write(Some(0), &mut encoded);
+        encoded
+    }
+
+    /// The value on one line, as `JSON.stringify(value)` writes it.
+    pub(crate) fn compact_encoding(&self) -> String {
+        let mut encoded = String::new();
+        self.write(None, &mut encoded);
+        encoded
+    }
+
+    /// Write the value, indented by `indent` spaces or on one line if it's `None`.
+    fn write(&self, indent: Option<usize>, out: &mut String) {
+        let inner = indent.map(|indent| indent + 2);
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => write_number(*n, out),
+            Json::String(st) => write_string(st, out),
+            Json::Array(values) if values.is_empty() => out.push_str("[]"),
+            Json::Array(values) => {
+                out.push('[');
+                for (index, value) in values.iter().enumerate() {
+                    push_separator(index, inner, out);
+                    value.write(inner, out);
+                }
+                push_indent(indent, out);
+                out.push(']');
+            }
+            Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Object(fields) => {
+                out.push('{');
+                for (index, (field, value)) in fields.iter().enumerate() {
+                    push_separator(index, inner, out);
+                    write_string(field, out);
+                    out.push_str(if inner.is_some() { ": " } else { ":" });
+                    value.write(inner, out);
+                }
+                push_indent(indent, out);
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Start the `index`th item of an array or object.
+fn push_separator(index: usize, indent: Option<usize>, out: &mut String) {
+    if index > 0 {
+        out.push(',');
+    }
+    push_indent(indent, out);
+}
+
+/// Start a new line indented by `indent`, unless the value's on one line.
+fn push_indent(indent: Option<usize>, out: &mut String) {
+    if let Some(indent) = indent {
+        out.push('\n');
+        (0..indent).for_each(|_| out.push(' '));
+    }
+}
+
+/// Write `n` as JavaScript's `Number.prototype.toString` does.
+fn write_number(n: f64, out: &mut String) {
+    let abs = n.abs();
+    if n == 0.0 {
+        out.push('0');
+    } else if (1e-6..1e21).contains(&abs) {
+        out.push_str(&n.to_string());
+    } else {
+        let written = format!("{:e}", n);
+        match written.split_once('e') {
+            Some((mantissa, exponent)) if !exponent.starts_with('-') => {
+                out.push_str(mantissa);
+                out.push_str("e+");
+                out.push_str(exponent);
+            }
+            _ => out.push_str(&written),
+        }
+    }
+}
+
+/// Write `st` quoted and escaped as `JSON.stringify` does.
+fn write_string(st: &str, out: &mut String) {
+    out.push('"');
+    for c in st.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl<'de> Deserialize<'de> for Json {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Json, D::Error> {
+        deserializer.deserialize_any(JsonVisitor)
+    }
+}
+
+struct JsonVisitor;
+
+impl<'de> Visitor<'de> for JsonVisitor {
+    type Value = Json;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Json, E> {
+        Ok(Json::Null)
+    }
+
+    fn visit_bool<E: Error>(self, b: bool) -> Result<Json, E> {
+        Ok(Json::Bool(b))
+    }
+
+    fn visit_i64<E: Error>(self, n: i64) -> Result<Json, E> {
+        Ok(Json::Number(n as f64))
+    }
+
+    fn visit_u64<E: Error>(self, n: u64) -> Result<Json, E> {
+        Ok(Json::Number(n as f64))
+    }
+
+    fn visit_f64<E: Error>(self, n: f64) -> Result<Json, E> {
+        Ok(Json::Number(n))
+    }
+
+    fn visit_str<E: Error>(self, st: &str) -> Result<Json, E> {
+        Ok(Json::String(st.to_owned()))
+    }
+
+    fn visit_string<E: Error>(self, st: String) -> Result<Json, E> {
+        Ok(Json::String(st))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Json, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Json::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Json, A::Error> {
+        let mut fields: Vec<(String, Json)> = Vec::new();
+        while let Some((field, value)) = map.next_entry::<String, Json>()? {
+            match fields.iter_mut().find(|(seen, _)| *seen == field) {
+                Some((_, seen)) => *seen = value,
+                None => fields.push((field, value)),
+            }
+        }
+        Ok(Json::Object(fields))
+    }
+}
+
+/// Whether `msg` is the value of the message with `key`. Messages that aren't UTF-8 never are.
+pub(crate) fn key_matches(key: &Multihash, msg: &[u8]) -> bool {
+    match std::str::from_utf8(msg) {
+        Ok(msg) => message_key(msg) == *key,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_matches, legacy_encoding, message_key};
+    use crate::test_utils::{numbered, thread};
+    use crate::{Error, SortBuilder};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use ssb_multiformats::multihash::Multihash;
+
+    fn sha256(bytes: &[u8]) -> Multihash {
+        Multihash::Message(Sha256::digest(bytes).into())
+    }
+
+    #[test]
+    fn keys_hash_the_legacy_encoding() {
+        assert_eq!(message_key("{}"), sha256(b"{}"));
+        assert_eq!(message_key(""), sha256(b""));
+        let long = "a".repeat(1000);
+        assert_eq!(message_key(&long), sha256(long.as_bytes()));
+
+        // `é` is one UTF-16 code unit, `€` another past Latin-1, and `😀` a surrogate pair.
+        assert_eq!(message_key("é"), sha256(&[0xe9]));
+        assert_eq!(message_key("€"), sha256(&[0xac]));
+        assert_eq!(message_key("😀"), sha256(&[0x3d, 0x00]));
+
+        assert!(key_matches(&message_key("{}"), b"{}"));
+        assert!(!key_matches(&message_key("{}"), b"{ }"));
+        assert!(!key_matches(&sha256(b"\xff"), b"\xff"));
+    }
+
+    #[test]
+    fn values_are_encoded_as_javascript_does() {
+        let stored = r#"{"previous":null,"author":"@a","sequence":1,"timestamp":1500000000000.5,
+            "content":{"type":"post","text":"é\n\u0001\"\/","list":[],"obj":{},"flag":true,
+            "n":[1e21,1e-7,-0,0.1,12345678901234567890],"type":"vote"}}"#;
+        let signed = r#"{
+  "previous": null,
+  "author": "@a",
+  "sequence": 1,
+  "timestamp": 1500000000000.5,
+  "content": {
+    "type": "vote",
+    "text": "é\n\u0001\"/",
+    "list": [],
+    "obj": {},
+    "flag": true,
+    "n": [
+      1e+21,
+      1e-7,
+      0,
+      0.1,
+      12345678901234567000
+    ]
+  }
+}"#;
+        assert_eq!(legacy_encoding(stored).unwrap(), signed);
+        assert_eq!(legacy_encoding(signed).unwrap(), signed);
+        assert!(legacy_encoding("{").is_err());
+    }
+
+    #[test]
+    fn sorts_can_reject_forged_keys() {
+        let root = json!({ "content": { "type": "post" } }).to_string();
+        let reply = json!({ "previous": message_key(&root) }).to_string();
+        let mut msgs = vec![
+            (message_key(&reply), 2, reply),
+            (message_key(&root), 1, root),
+        ];
+        let verifying = SortBuilder::new().verify_keys(true);
+        assert_eq!(verifying.try_sort(&msgs).unwrap(), [2, 1]);
+
+        msgs[1].0 = numbered(1);
+        assert!(matches!(
+            verifying.try_sort(&msgs),
+            Err(Error::KeyMismatch { index: 1, key }) if key == numbered(1)
+        ));
+        assert!(SortBuilder::new().try_sort(&msgs).is_ok());
+        assert!(verifying.try_sort(&thread()).is_err());
+    }
+}
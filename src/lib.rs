@@ -1,103 +1,589 @@
 //! Causally sort a collection of messages.
 //!
-//! Returns messages sorted from newest to oldest.
+//! Returns messages sorted from newest to oldest, as [`SortedMessages`].
 //!
 //! If `message b` includes a reference to `message a` then we say that `message b` _must_ have been
 //! published after `message a`, assuming these assumptions hold:
 //! - The hash function is not broken (Two different sets of bytes return the same hash.)
 //! - The person publishing `message b` has not guessed a valid hash of a message before it was
-//! published (extremely unlikely.)
+//!   published (extremely unlikely.)
 //! - The person publishing `message b` is not a time traveller. 
 //!
 //! This function uses [daggy]() to build a [dag]() of references between messages and then
-//! topologically sorts them.
+//! topologically sorts them. The dag itself can be built and looked at with [`CausalDag`].
 //!
 //! If a message is not referenced by any messages you can expect it to be sorted to the start of
 //! the results (it is so new no one has referenced it yet). 
 //!
-use daggy::{Dag, NodeIndex, Walker};
-use petgraph::visit::Topo;
-use serde_json::Value;
+//! Messages whose links form a cycle can't be sorted, and make the sorts panic. When the messages
+//! come from somewhere untrusted, use the `try_` sorts, eg. [`try_causal_sort`], which return an
+//! [`Error`] instead and never panic.
+//!
+//! If you already know each message's links, eg. because you store them alongside the messages or
+//! parse the messages yourself, sort them with [`causal_sort_links`] instead. This doesn't need
+//! the `json` feature.
+//!
+//! Messages as `createHistoryStream` sends them, ie. `{ "key", "value", "timestamp" }`
+//! envelopes, sort with [`causal_sort_envelopes`], which reads their keys from them.
+//!
+//! To merge several whole feeds into one timeline, keeping each feed in order, use
+//! [`interleave_feeds`]. To pick one author's messages out of a thread in order, use
+//! [`author_order`].
+//!
+//! [`fetch_plan`] lists the messages that a set of messages links to but doesn't include, in the
+//! order to fetch them in when replicating, and [`missing_referrers`] which messages link to each.
+//! Links known from elsewhere between messages that are missing can still order the rest, with
+//! [`SortBuilder::known_links`].
+//! [`content_copies`] groups messages with identical content, eg. copies republished under other
+//! keys.
+//!
+//! [`infer_time_bounds`] bounds when each message was published from a few trusted timestamps,
+//! eg. to look into a feed whose claimed timestamps are suspicious.
+//!
+//! [`tangle_completeness`] reports which messages are missing from each thread, eg. to show how
+//! many replies haven't arrived yet, and [`thread_index`] sorts every thread at once, eg. to
+//! index each by its root.
+//!
+//! [`BatchIndexer`] sorts a log in batches, keeping only a little state between them, and a
+//! [`LinkTable`] splices new messages into an order sorted earlier, moving only the newest.
+//!
+//! [`clock`] summarises each tangle by its heads, for deciding what to replicate with a peer the
+//! way EBT's feed clocks do.
+//!
+//! [`extract_links`] finds every link in a message the way the sorts do, telling apart links to
+//! messages, feeds and blobs, and [`find_all_links`] finds the links of chosen [`Sigils`] in a
+//! parsed value.
+//!
+//! The sorts read links to messages in the legacy `%<base64>.sha256` form or as SSB URIs, and
+//! [`normalize_hash`] reads a hash in either form or as raw bytes, so every form of a key is the
+//! same message. Links with hashes of other algorithms can be read with
+//! [`SortBuilder::unknown_hashes`], and links in other forms, eg. with other sigils, with
+//! [`SortBuilder::link_formats`].
+//!
+//! ## Features
+//!
+//! - `bloom`: summarise the hashes a set of messages links to as a [`BloomFilter`], to exchange
+//!   with peers.
+//! - `bumpalo`: parse messages into a [bump arena](https://docs.rs/bumpalo) that is reused from one
+//!   message to the next, rather than allocating the parts of each message that are kept while
+//!   its links are found.
+//! - `conformance`: load and export test vectors shared with other implementations, see
+//!   [`conformance`].
+//! - `corpus`: generate message sets with realistic tangle shapes, see [`corpus`]. The benchmarks
+//!   in `benches/` need this, eg. `cargo bench --features corpus`.
+//! - `db2`: read messages from the log ssb-db2 keeps them in with [`read_db2_log`], to sort
+//!   them by their offsets in the log. Turns on `ndjson`.
+//! - `fingerprint`: hash the canonical order of a set of messages with [`order_fingerprint`], to
+//!   check that two peers' indexes agree.
+//! - `fixtures`: read the logs [ssb-fixtures](https://github.com/ssb-ngi-pointer/ssb-fixtures)
+//!   generates with [`read_fixtures`], to test and benchmark on realistic data, and any
+//!   flumelog-offset log, as ssb-db keeps, with [`read_flume_log`]. Turns on `ndjson`.
+//! - `futures`: sort in the middle of a futures pipeline, sending messages into a `Sink` and
+//!   reading their sorted keys from a `Stream`, with [`sort_channel`], and watch threads change
+//!   as messages arrive with [`LiveSorter`].
+//! - `json` (default): find the links in JSON message bodies, for every sort except
+//!   [`causal_sort_links`] and [`SortBuilder::sort_links`]. Without it, serde_json isn't a
+//!   dependency.
+//! - `margaret`: read messages from the offset logs go-ssb keeps them in with
+//!   [`read_margaret_log`], to index a go-ssb peer's data. Turns on `ndjson`.
+//! - `ndjson`: read messages from a log of newline delimited JSON with [`read_ndjson`], and write
+//!   them back in order with [`write_ndjson`], or archive a thread with an index of its heads and
+//!   missing messages with [`archive_thread`]. Turns on `verify-keys`, to key bare message
+//!   values.
+//! - `testing`: generate fake threads with a controllable shape, see [`testing`].
+//! - `tracing`: record [tracing](https://docs.rs/tracing) spans, with counts, for each stage of a
+//!   sort.
+//! - `prometheus`: record [`Metrics`] as [prometheus](https://docs.rs/prometheus) counters with
+//!   [`PrometheusMetrics`].
+//! - `rayon`: build the dag on many threads with `par_causal_sort` or `SortBuilder::par_sort`,
+//!   and sort large dags in layers on many threads with `TieBreak::Layers`, on a pool of your
+//!   own with `SortBuilder::thread_pool`.
+//! - `tokio`: sort on tokio's blocking threads from async code with [`spawn_sort`] and
+//!   [`SortBuilder::spawn_sort`].
+//! - `unstable-graph`: convert a [`CausalDag`] to a petgraph `Graph`. petgraph's types aren't
+//!   otherwise part of the API, so this is the only feature that can break with a petgraph
+//!   upgrade.
+//! - `shared`: insert messages from many threads into a [`SharedCausalSorter`], while others
+//!   read its latest order without waiting.
+//! - `simd-json`: parse messages with [simd-json](https://docs.rs/simd-json) instead of
+//!   serde_json. The same links are found, and messages are sorted the same way. Takes precedence
+//!   over `bumpalo`.
+//! - `verify-keys`: check that each message's key is the hash of its value with
+//!   [`SortBuilder::verify_keys`], so forged messages can't be sorted, and compute keys with
+//!   [`message_key`], from a value as signed or, with [`legacy_encoding`], as stored.
+//!
 use ssb_multiformats::multihash::Multihash;
+#[cfg(feature = "json")]
 use std::collections::HashMap;
+#[cfg(feature = "json")]
+use std::hash::Hash;
+#[cfg(feature = "json")]
+use std::ops::ControlFlow;
+
+#[cfg(all(feature = "bumpalo", not(feature = "simd-json")))]
+mod arena;
+#[cfg(feature = "ndjson")]
+mod archive;
+#[cfg(feature = "json")]
+mod batch;
+#[cfg(feature = "bloom")]
+mod bloom;
+#[cfg(feature = "json")]
+mod budget;
+mod builder;
+#[cfg(feature = "futures")]
+mod channel;
+#[cfg(feature = "json")]
+pub mod clock;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+#[cfg(feature = "json")]
+mod copies;
+#[cfg(feature = "corpus")]
+pub mod corpus;
+mod csr;
+mod dag;
+#[cfg(feature = "db2")]
+mod db2;
+mod error;
+#[cfg(feature = "json")]
+mod extract;
+#[cfg(feature = "json")]
+mod feeds;
+#[cfg(feature = "json")]
+mod fetch;
+#[cfg(feature = "fingerprint")]
+mod fingerprint;
+#[cfg(feature = "fixtures")]
+mod fixtures;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+mod frontier;
+mod graph;
+mod hashes;
+#[cfg(feature = "verify-keys")]
+mod keys;
+#[cfg(feature = "futures")]
+mod live;
+#[cfg(feature = "margaret")]
+mod margaret;
+mod metrics;
+#[cfg(feature = "ndjson")]
+mod ndjson;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod permutation;
+#[cfg(any(feature = "corpus", feature = "testing"))]
+mod rng;
+#[cfg(feature = "shared")]
+mod shared;
+#[cfg(feature = "simd-json")]
+mod simd;
+mod sorted;
+#[cfg(feature = "tokio")]
+mod spawn;
+#[cfg(feature = "json")]
+mod splice;
+#[cfg(feature = "json")]
+mod stream;
+#[cfg(feature = "json")]
+mod tangles;
+#[cfg(test)]
+mod test_utils;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod trace;
+#[cfg(feature = "json")]
+mod validate;
+#[cfg(feature = "json")]
+mod verify;
 
-pub fn causal_sort<T: AsRef<str>, K: Copy>(msgs: &[(Multihash, K, T)]) -> Vec<K> {
-    // Thought: Can we enumerate the iter and use the index as a key for one or both of the hashes?
-    let (dag, _, node_to_key_id) = msgs
+#[cfg(feature = "ndjson")]
+pub use archive::{archive_thread, ThreadArchive};
+#[cfg(feature = "json")]
+pub use batch::{BatchIndexer, BatchState, IndexedBatch};
+#[cfg(feature = "bloom")]
+pub use bloom::{referenced_hashes_filter, BloomFilter};
+#[cfg(feature = "json")]
+pub use budget::{causal_sort_within, try_causal_sort_within, Budgeted, PartialSort};
+pub use builder::{Backend, Edges, Forks, Profile, SortBuilder, TieBreak, Window};
+#[cfg(feature = "futures")]
+pub use channel::{sort_channel, SortSink, SortStream};
+#[cfg(feature = "json")]
+pub use copies::content_copies;
+pub use dag::{
+    CausalDag, Chunks, ComponentId, EdgeId, Generations, NodeId, Parents, Relation, Role, Sorted,
+    StableId, TimeBounds,
+};
+#[cfg(feature = "db2")]
+pub use db2::read_db2_log;
+pub use error::Error;
+#[cfg(feature = "json")]
+pub use extract::{extract_links, find_all_links, Link, Sigils};
+#[cfg(feature = "json")]
+pub use feeds::{author_order, interleave_feeds, try_author_order, try_interleave_feeds, Feed};
+#[cfg(feature = "json")]
+pub use fetch::{fetch_plan, missing_referrers, try_fetch_plan};
+#[cfg(feature = "fingerprint")]
+pub use fingerprint::{order_fingerprint, try_order_fingerprint};
+#[cfg(feature = "fixtures")]
+pub use fixtures::{read_fixtures, read_flume_log};
+pub use frontier::Frontier;
+pub use hashes::normalize_hash;
+#[cfg(feature = "json")]
+pub use hashes::{LinkFormat, UnknownHash};
+#[cfg(feature = "verify-keys")]
+pub use keys::{legacy_encoding, message_key};
+#[cfg(feature = "futures")]
+pub use live::{LiveSorter, ThreadUpdate, ThreadWatch};
+#[cfg(feature = "margaret")]
+pub use margaret::{read_margaret_entries, read_margaret_log};
+#[cfg(feature = "prometheus")]
+pub use metrics::PrometheusMetrics;
+pub use metrics::{Metrics, SortStats, TypeStats};
+#[cfg(feature = "ndjson")]
+pub use ndjson::{read_ndjson, write_ndjson};
+#[cfg(feature = "rayon")]
+pub use parallel::{par_causal_sort, try_par_causal_sort};
+pub use permutation::apply_permutation;
+#[cfg(feature = "json")]
+pub use permutation::{causal_sort_in_place, causal_sort_permutation};
+#[cfg(feature = "shared")]
+pub use shared::{SharedCausalSorter, Snapshot};
+pub use sorted::{Page, SortedMessages};
+#[cfg(feature = "tokio")]
+pub use spawn::spawn_sort;
+#[cfg(feature = "json")]
+pub use splice::{LinkTable, Spliced};
+#[cfg(feature = "json")]
+pub use tangles::{tangle_completeness, thread_index, try_thread_index, TangleReport};
+#[cfg(feature = "json")]
+pub use validate::{InvalidMessages, Validate};
+#[cfg(feature = "json")]
+pub use verify::{verify_causal_order, OrderError};
+
+#[cfg(feature = "json")]
+use extract::LinkOptions;
+use graph::{BuildGraph, CausalGraph, Checks};
+
+/// Causally sort `msgs`, returning their key ids newest first.
+///
+/// Key ids are cloned into the result. If they are expensive to clone, sort with references to
+/// them instead, eg. `(key, &row_id, msg)`.
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort`] to handle this as an
+/// error, eg. when the messages come from somewhere untrusted.
+#[cfg(feature = "json")]
+pub fn causal_sort<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> SortedMessages<K> {
+    error::unwrap(try_causal_sort(msgs))
+}
+
+/// Like [`causal_sort`], but returns an error rather than panicking. This never panics, whatever
+/// the messages contain.
+#[cfg(feature = "json")]
+pub fn try_causal_sort<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<SortedMessages<K>, Error> {
+    let mut graph = CausalGraph::new();
+    let extracted = msgs
         .iter()
-        .map(|(key, key_id, msg)| {
-            let value: Value = serde_json::from_str(msg.as_ref()).unwrap_or(Value::Null);
-            let mut refs = Vec::new();
-            // Recursively search through the object searching for Multihashes
-            find_all_links(&value, &mut refs);
-            (key, key_id, refs)
-        })
-        .fold(
-            (
-                Dag::<u32, u32, usize>::new(),
-                HashMap::<Multihash, NodeIndex<usize>>::new(),
-                HashMap::<NodeIndex<usize>, K>::new(),
-            ),
-            |(mut dag, mut hash_to_node, mut node_to_key_id), (key, key_id, refs)| {
-                // Check if we've already created a node for key
-                let key_node = hash_to_node
-                    .entry(key.clone())
-                    .or_insert_with(|| dag.add_node(1))
-                    .clone();
-                node_to_key_id.entry(key_node).or_insert(*key_id);
-
-                refs.iter().for_each(|reference| {
-                    let ref_node = hash_to_node
-                        .entry(reference.clone())
-                        .or_insert_with(|| dag.add_node(1));
-                    dag.add_edge(key_node.clone(), *ref_node, 1).expect("The dag has a cycle. This is _VERY_ unexpected. Either the SHA256 hash function is broken, someone is a time traveller, or someone guessed a hash of a message before it was ever created. Most likely this module has a bug :)");
-                });
-
-                (dag, hash_to_node, node_to_key_id)
-            },
-        );
+        .map(|(key, key_id, msg)| (key, key_id.clone(), msg.as_ref().as_bytes()));
+    graph.extend(extracted, Checks::default(), LinkOptions::default())?;
 
     // sort the dag
-    let graph = dag.graph();
-    let topo = Topo::new(graph);
-    topo.iter(graph)
-        // filter_map the sorted nodes into multihashes, taking only the ones that were for the
-        // keys we passed in
-        .filter_map(|node| node_to_key_id.get(&node))
-        .map(|i| *i)
-        .collect()
-}
-
-fn find_all_links(obj: &Value, keys: &mut Vec<Multihash>) {
-    if let Some(st) = obj.as_str() {
-        if let Ok((mh, _)) = Multihash::from_legacy(st.as_bytes()) {
-            keys.push(mh)
-        }
-    }
+    Ok(graph.sorted())
+}
 
-    match obj {
-        Value::Array(arr) => {
-            for val in arr {
-                find_all_links(val, keys);
-            }
-        }
-        Value::Object(kv) => {
-            for val in kv.values() {
-                match val {
-                    Value::Object(_) => find_all_links(val, keys),
-                    Value::Array(_) => find_all_links(val, keys),
-                    Value::String(_) => find_all_links(val, keys),
-                    _ => (),
-                }
-            }
-        }
-        _ => (),
-    }
+/// Like [`causal_sort`], but for message bodies that are raw bytes, eg. straight out of a log.
+///
+/// serde_json validates any UTF-8 it needs to as it parses, so there's no need to convert the
+/// bodies to `str` first.
+#[cfg(feature = "json")]
+pub fn causal_sort_bytes<T: AsRef<[u8]>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> SortedMessages<K> {
+    error::unwrap(try_causal_sort_bytes(msgs))
 }
 
-#[cfg(test)]
+/// Like [`causal_sort_bytes`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_causal_sort_bytes<T: AsRef<[u8]>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<SortedMessages<K>, Error> {
+    let mut graph = CausalGraph::new();
+    let extracted = msgs
+        .iter()
+        .map(|(key, key_id, msg)| (key, key_id.clone(), msg.as_ref()));
+    graph.extend(extracted, Checks::default(), LinkOptions::default())?;
+
+    Ok(graph.sorted())
+}
+
+/// Causally sort `msgs`, returning each key id's rank: its position counting from the oldest
+/// message at 0. See [`SortedMessages::ranks`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort`] and
+/// [`SortedMessages::ranks`] to handle this as an error.
+#[cfg(feature = "json")]
+pub fn causal_ranks<T: AsRef<str>, K: Clone + Hash + Eq>(
+    msgs: &[(Multihash, K, T)],
+) -> HashMap<K, u64> {
+    causal_sort(msgs).ranks()
+}
+
+/// Each message's depth in the dag of `msgs`, which unlike its rank doesn't change as newer
+/// messages are added. See [`CausalDag::depths`].
+#[cfg(feature = "json")]
+pub fn causal_depths<T: AsRef<str>, K: Clone + Hash + Eq>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<HashMap<K, u64>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.depths())
+}
+
+/// Bound when each message in `msgs` was published from the trusted timestamps `anchors` of some
+/// hashes, by the messages they link to and that link to them. See [`CausalDag::time_bounds`].
+#[cfg(feature = "json")]
+pub fn infer_time_bounds<T: AsRef<str>, K: Clone + Hash + Eq>(
+    msgs: &[(Multihash, K, T)],
+    anchors: &[(Multihash, u64)],
+) -> Result<HashMap<K, TimeBounds>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.time_bounds(anchors))
+}
+
+/// Causally sort the messages in `msgs` at most `max_depth` links from a message that nothing
+/// links to, returning their key ids newest first. See [`CausalDag::within_depth`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_prune_depth`] to handle this as an
+/// error.
+#[cfg(feature = "json")]
+pub fn prune_depth<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    max_depth: usize,
+) -> SortedMessages<K> {
+    error::unwrap(try_prune_depth(msgs, max_depth))
+}
+
+/// Like [`prune_depth`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_prune_depth<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    max_depth: usize,
+) -> Result<SortedMessages<K>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.within_depth(max_depth))
+}
+
+/// Causally sort the messages in `msgs` that are causally after the one with `key`, returning
+/// their key ids newest first. With `inclusive`, that message is last. See
+/// [`CausalDag::descendants`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort_after`] to handle this as
+/// an error.
+#[cfg(feature = "json")]
+pub fn causal_sort_after<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    key: &Multihash,
+    inclusive: bool,
+) -> SortedMessages<K> {
+    error::unwrap(try_causal_sort_after(msgs, key, inclusive))
+}
+
+/// Like [`causal_sort_after`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_causal_sort_after<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    key: &Multihash,
+    inclusive: bool,
+) -> Result<SortedMessages<K>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.descendants(key, inclusive))
+}
+
+/// The key ids of the current versions of the message with key `root` in `msgs`, eg. a document
+/// edited by CRDT updates that link back to it, newest first. There's more than one when there
+/// are concurrent versions. See [`CausalDag::concurrent_heads`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_concurrent_heads`] to handle this as an
+/// error.
+#[cfg(feature = "json")]
+pub fn concurrent_heads<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    root: &Multihash,
+) -> SortedMessages<K> {
+    error::unwrap(try_concurrent_heads(msgs, root))
+}
+
+/// Like [`concurrent_heads`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_concurrent_heads<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    root: &Multihash,
+) -> Result<SortedMessages<K>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.concurrent_heads(root))
+}
+
+/// The key ids of the messages in `msgs` that all of `keys` descend from, newest first. See
+/// [`CausalDag::common_ancestors`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_common_ancestors`] to handle this as an
+/// error.
+#[cfg(feature = "json")]
+pub fn common_ancestors<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    keys: &[Multihash],
+) -> SortedMessages<K> {
+    error::unwrap(try_common_ancestors(msgs, keys))
+}
+
+/// Like [`common_ancestors`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_common_ancestors<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+    keys: &[Multihash],
+) -> Result<SortedMessages<K>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.common_ancestors(keys))
+}
+
+/// Causally sort `msgs`, returning their key ids newest first, each with the weakly connected
+/// component it's in, eg. to group unrelated threads. See [`CausalDag::sorted_labeled`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort_labeled`] to handle this as
+/// an error.
+#[cfg(feature = "json")]
+pub fn causal_sort_labeled<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Vec<(K, ComponentId)> {
+    error::unwrap(try_causal_sort_labeled(msgs))
+}
+
+/// Like [`causal_sort_labeled`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_causal_sort_labeled<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<Vec<(K, ComponentId)>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.sorted_labeled())
+}
+
+/// Causally sort `msgs` into a forest, returning each weakly connected component's root and its
+/// key ids newest first, eg. to store an index per thread. See [`CausalDag::sorted_forest`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort_forest`] to handle this as
+/// an error.
+#[cfg(feature = "json")]
+pub fn causal_sort_forest<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Vec<(Multihash, SortedMessages<K>)> {
+    error::unwrap(try_causal_sort_forest(msgs))
+}
+
+/// Like [`causal_sort_forest`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_causal_sort_forest<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<Vec<(Multihash, SortedMessages<K>)>, Error> {
+    Ok(CausalDag::from_msgs(msgs)?.sorted_forest())
+}
+
+/// Causally sort `{ "key", "value", "timestamp" }` envelopes, as `createHistoryStream` sends
+/// them, returning their key ids newest first. See [`SortBuilder::sort_envelopes`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort_envelopes`] to handle this
+/// as an error.
+#[cfg(feature = "json")]
+pub fn causal_sort_envelopes<T: AsRef<str>, K: Clone>(msgs: &[(K, T)]) -> SortedMessages<K> {
+    error::unwrap(try_causal_sort_envelopes(msgs))
+}
+
+/// Like [`causal_sort_envelopes`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_causal_sort_envelopes<T: AsRef<str>, K: Clone>(
+    msgs: &[(K, T)],
+) -> Result<SortedMessages<K>, Error> {
+    SortBuilder::new().try_sort_envelopes(msgs)
+}
+
+/// Causally sort `msgs`, giving each key id to `sink` newest first as it's sorted, until `sink`
+/// returns `ControlFlow::Break`. See [`SortBuilder::sort_into`].
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort_into`] to handle this as an
+/// error.
+#[cfg(feature = "json")]
+pub fn causal_sort_into<T: AsRef<str>, K: Clone, F: FnMut(K) -> ControlFlow<()>>(
+    msgs: &[(Multihash, K, T)],
+    sink: F,
+) {
+    error::unwrap(try_causal_sort_into(msgs, sink))
+}
+
+/// Like [`causal_sort_into`], but returns an error rather than panicking.
+#[cfg(feature = "json")]
+pub fn try_causal_sort_into<T: AsRef<str>, K: Clone, F: FnMut(K) -> ControlFlow<()>>(
+    msgs: &[(Multihash, K, T)],
+    sink: F,
+) -> Result<(), Error> {
+    SortBuilder::new().try_sort_into(msgs, sink)
+}
+
+/// Causally sort messages whose links have already been found, eg. stored alongside them or
+/// found by your own parser, returning their key ids newest first.
+///
+/// The links are the hashes each message references, in the order they appear in it. Concurrent
+/// messages are sorted in the same order as [`causal_sort`] would sort them, given the links it
+/// would have found.
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_causal_sort_links`] to handle this as an
+/// error.
+pub fn causal_sort_links<L: AsRef<[Multihash]>, K: Clone>(
+    msgs: &[(Multihash, K, L)],
+) -> SortedMessages<K> {
+    error::unwrap(try_causal_sort_links(msgs))
+}
+
+/// Like [`causal_sort_links`], but returns an error rather than panicking.
+pub fn try_causal_sort_links<L: AsRef<[Multihash]>, K: Clone>(
+    msgs: &[(Multihash, K, L)],
+) -> Result<SortedMessages<K>, Error> {
+    let mut graph = CausalGraph::new();
+    let links = msgs
+        .iter()
+        .map(|(key, key_id, links)| (key, key_id.clone(), links.as_ref()));
+    graph.extend_links(links, Checks::default())?;
+
+    Ok(graph.sorted())
+}
+
+#[cfg(all(test, feature = "json"))]
 mod tests {
-    use crate::{causal_sort, find_all_links};
+    use crate::extract::find_hashes;
+    use crate::test_utils::{branching, numbered, thread};
+    use crate::{
+        causal_depths, causal_ranks, causal_sort, causal_sort_after, causal_sort_bytes,
+        causal_sort_links, common_ancestors, concurrent_heads, find_all_links, infer_time_bounds,
+        prune_depth, Backend, Sigils, SortBuilder,
+    };
     use serde_json::{json, to_string};
     use ssb_multiformats::multihash::Multihash;
 
@@ -178,6 +664,78 @@ mod tests {
         assert_eq!(sorted.as_slice(), [3,2,1])
     }
 
+    #[test]
+    fn it_works_with_non_copy_key_ids() {
+        let unsorted: Vec<_> = thread()
+            .into_iter()
+            .map(|(key, id, msg)| (key, format!("row-{}", id), msg))
+            .collect();
+        let sorted = causal_sort(&unsorted[..]);
+
+        assert_eq!(sorted, ["row-3", "row-2", "row-1"])
+    }
+
+    #[test]
+    fn it_works_with_byte_bodies() {
+        let unsorted: Vec<_> = thread()
+            .into_iter()
+            .map(|(key, id, msg)| (key, id, msg.into_bytes()))
+            .collect();
+        let sorted = causal_sort_bytes(&unsorted[..]);
+
+        assert_eq!(sorted, [3, 2, 1])
+    }
+
+    #[test]
+    fn it_works_with_precomputed_links() {
+        let unsorted: Vec<_> = thread()
+            .into_iter()
+            .map(|(key, id, msg)| {
+                let mut links = Vec::new();
+                find_hashes(&serde_json::from_str(&msg).unwrap(), &mut links);
+                (key, id, links)
+            })
+            .collect();
+
+        assert_eq!(causal_sort_links(&unsorted), causal_sort(&thread()));
+        assert_eq!(
+            SortBuilder::new()
+                .backend(Backend::Csr)
+                .sort_links(&unsorted),
+            [3, 2, 1]
+        );
+    }
+
+    #[test]
+    fn ranks_count_from_the_oldest() {
+        let ranks = causal_ranks(&thread());
+        assert_eq!((ranks[&1], ranks[&2], ranks[&3]), (0, 1, 2));
+    }
+
+    #[test]
+    fn time_bounds_follow_links() {
+        let msgs = thread();
+        let anchors = [(msgs[1].0.clone(), 10), (msgs[2].0.clone(), 20)];
+        let bounds = infer_time_bounds(&msgs, &anchors).unwrap();
+        let bound = |i| (bounds[&i].earliest, bounds[&i].latest);
+        assert_eq!(bound(1), (Some(10), Some(10)));
+        assert_eq!(bound(2), (Some(10), Some(20)));
+        assert_eq!(bound(3), (Some(20), Some(20)));
+    }
+
+    #[test]
+    fn depths_survive_appends() {
+        let mut msgs = thread();
+        let depths = causal_depths(&msgs).unwrap();
+        assert_eq!((depths[&1], depths[&2], depths[&3]), (0, 1, 2));
+
+        let root = msgs[1].0.clone();
+        msgs.push((numbered(4), 4, json!({ "root": root }).to_string()));
+        let appended = causal_depths(&msgs).unwrap();
+        assert_eq!(appended[&4], 1);
+        assert!(depths.iter().all(|(key_id, depth)| appended[key_id] == *depth));
+    }
+
     #[test]
     fn find_all_links_works() {
         let value = json!({
@@ -188,8 +746,71 @@ mod tests {
             }
         });
 
-        let mut keys = Vec::new();
-        find_all_links(&value, &mut keys);
-        assert_eq!(keys.len(), 4);
+        assert_eq!(find_all_links(&value, Sigils::ALL).len(), 4);
+    }
+
+    #[test]
+    fn prunes_to_the_latest_hops() {
+        // A chain from 5 back to 1, and 6 linking to 2.
+        let link = |i: usize, to: usize| {
+            (numbered(i), i, json!({ "branch": numbered(to) }).to_string())
+        };
+        let mut msgs: Vec<_> = (2..=5).map(|i| link(i, i - 1)).collect();
+        msgs.push((numbered(1), 1, json!({}).to_string()));
+        assert_eq!(prune_depth(&msgs, 0), [5]);
+        assert_eq!(prune_depth(&msgs, 2), [5, 4, 3]);
+        assert_eq!(prune_depth(&msgs, usize::MAX), causal_sort(&msgs));
+
+        msgs.push(link(6, 2));
+        let pruned = prune_depth(&msgs, 1);
+        assert!(pruned.iter().all(|i| [2, 4, 5, 6].contains(i)) && pruned.len() == 4);
+    }
+
+    #[test]
+    fn sorts_only_what_follows_a_message() {
+        let msgs = thread();
+        let (reply1, root) = (&msgs[0].0, &msgs[1].0);
+        assert_eq!(causal_sort_after(&msgs, root, false), [3, 2]);
+        assert_eq!(causal_sort_after(&msgs, root, true), [3, 2, 1]);
+        assert_eq!(causal_sort_after(&msgs, reply1, true), [3, 2]);
+        assert!(causal_sort_after(&msgs, &msgs[2].0, false).is_empty());
+        assert!(causal_sort_after(&msgs, &numbered(9), true).is_empty());
+    }
+
+    #[test]
+    fn finds_what_every_message_descends_from() {
+        // 2 and 3 both edit 1, and 4 merges them.
+        let msgs = [
+            branching(4, &[2, 3]),
+            branching(3, &[1]),
+            branching(2, &[1]),
+            branching(1, &[]),
+        ];
+        assert_eq!(common_ancestors(&msgs, &[numbered(2), numbered(3)]), [1]);
+        assert_eq!(common_ancestors(&msgs, &[numbered(4), numbered(2)]), [2, 1]);
+        assert_eq!(common_ancestors(&msgs, &[numbered(4)]), causal_sort(&msgs));
+        assert!(common_ancestors(&msgs, &[numbered(2), numbered(9)]).is_empty());
+        assert!(common_ancestors(&msgs, &[]).is_empty());
+    }
+
+    #[test]
+    fn heads_are_the_conflicting_versions() {
+        // 2 and 3 both edit 1, and 4 follows on from 2.
+        let mut msgs = vec![
+            branching(4, &[2]),
+            branching(3, &[1]),
+            branching(2, &[1]),
+            branching(1, &[]),
+        ];
+        let heads = concurrent_heads(&msgs, &numbered(1));
+        assert!(heads.len() == 2 && heads.contains(&4) && heads.contains(&3));
+        assert_eq!(concurrent_heads(&msgs, &numbered(2)), [4]);
+        assert_eq!(concurrent_heads(&msgs, &numbered(3)), [3]);
+
+        // Merging the versions leaves one, and an unrelated message doesn't count.
+        msgs.push(branching(5, &[3, 4]));
+        msgs.push(branching(6, &[]));
+        assert_eq!(concurrent_heads(&msgs, &numbered(1)), [5]);
+        assert!(concurrent_heads(&msgs, &numbered(9)).is_empty());
     }
 }
@@ -0,0 +1,362 @@
+//! Keeping a dag up to date as messages arrive, and watching threads in it change.
+use crate::dag::CausalDag;
+use crate::error::Error;
+use crate::graph::CausalGraph;
+use crate::sorted::SortedMessages;
+use futures_core::Stream;
+use ssb_multiformats::multihash::Multihash;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+/// A [`CausalDag`] that messages are inserted into as they arrive, with [`watch`]es on the
+/// threads that need to be shown as they change.
+///
+/// Readers can take a [`snapshot`] of the dag to query while messages carry on being inserted.
+/// A snapshot shares the sorter's dag until the next insert, which copies it, so taking one is
+/// cheap, and only the first insert after a snapshot costs as much as the dag is big.
+///
+/// [`watch`]: LiveSorter::watch
+/// [`snapshot`]: LiveSorter::snapshot
+pub struct LiveSorter<K> {
+    dag: Arc<CausalDag<K>>,
+    watches: Vec<Watch<K>>,
+}
+
+/// How a watched thread changed, from a [`ThreadWatch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadUpdate<K> {
+    /// The root of the thread that changed.
+    pub root: Multihash,
+    /// The thread's key ids now, newest first, as [`CausalDag::descendants`] gives them with the
+    /// root included.
+    pub sorted: SortedMessages<K>,
+    /// The key ids that have joined the thread since the last update, in the order they joined,
+    /// oldest first among any that joined at once.
+    pub added: Vec<K>,
+    /// The key ids that have left the thread since the last update, eg. because they were
+    /// removed, in the order they left.
+    pub removed: Vec<K>,
+}
+
+/// One thread being watched.
+struct Watch<K> {
+    root: Multihash,
+    /// The thread as it was last given to the watch.
+    sorted: SortedMessages<K>,
+    shared: Arc<Mutex<Shared<K>>>,
+}
+
+/// What a [`Watch`] and its [`ThreadWatch`] share.
+struct Shared<K> {
+    /// Every change since the stream last gave an update, as one update.
+    update: Option<ThreadUpdate<K>>,
+    /// Whether the sorter's been dropped, so there'll be no more updates.
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+fn lock<K>(shared: &Mutex<Shared<K>>) -> MutexGuard<'_, Shared<K>> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl<K: Clone + Hash + Eq> LiveSorter<K> {
+    /// A sorter with no messages yet.
+    pub fn new() -> LiveSorter<K> {
+        LiveSorter::from_dag(CausalDag::new(CausalGraph::new()))
+    }
+
+    /// Carry on inserting into `dag`, eg. one built from the messages already stored.
+    pub fn from_dag(dag: CausalDag<K>) -> LiveSorter<K> {
+        LiveSorter {
+            dag: Arc::new(dag),
+            watches: Vec::new(),
+        }
+    }
+
+    pub fn dag(&self) -> &CausalDag<K> {
+        &self.dag
+    }
+
+    pub fn into_dag(self) -> CausalDag<K> {
+        let mut sorter = self;
+        let dag = std::mem::replace(
+            &mut sorter.dag,
+            Arc::new(CausalDag::new(CausalGraph::new())),
+        );
+        Arc::try_unwrap(dag).unwrap_or_else(|dag| (*dag).clone())
+    }
+
+    /// The dag as it is now, unchanged by later inserts, eg. for another thread to sort or walk
+    /// while this one inserts.
+    pub fn snapshot(&self) -> Arc<CausalDag<K>> {
+        self.dag.clone()
+    }
+
+    /// Insert a message, as [`CausalDag::insert`] does, and update the watches on the threads
+    /// whose order it changes.
+    pub fn insert(&mut self, key: &Multihash, key_id: K, msg: &str) -> Result<(), Error> {
+        Arc::make_mut(&mut self.dag).insert(key, key_id, msg)?;
+        self.update_watches();
+        Ok(())
+    }
+
+    /// Remove the message with `key`, as [`CausalDag::remove`] does, eg. after a peer deletes it,
+    /// and update the watches on the threads it leaves. Returns the key ids of the messages that
+    /// now link to a message that isn't in the dag, or `None` if no message has `key`, in which
+    /// case the dag is left as it is.
+    ///
+    /// Snapshots taken before are unchanged, as with inserts.
+    pub fn remove(&mut self, key: &Multihash) -> Option<SortedMessages<K>> {
+        self.dag.node(key).and_then(|node| self.dag.key_id(node))?;
+        let dangling = Arc::make_mut(&mut self.dag).remove(key);
+        self.update_watches();
+        dangling
+    }
+
+    /// Give every watch whose thread's order has changed an update.
+    fn update_watches(&mut self) {
+        // A watch whose stream has been dropped is the only one left holding what they share.
+        self.watches
+            .retain(|watch| Arc::strong_count(&watch.shared) > 1);
+        for watch in &mut self.watches {
+            let sorted = self.dag.descendants(&watch.root, true);
+            if sorted == watch.sorted {
+                continue;
+            }
+            let added: Vec<K> = sorted
+                .iter()
+                .rev()
+                .filter(|key_id| !watch.sorted.contains(key_id))
+                .cloned()
+                .collect();
+            let removed: Vec<K> = watch
+                .sorted
+                .iter()
+                .rev()
+                .filter(|key_id| !sorted.contains(key_id))
+                .cloned()
+                .collect();
+            watch.sorted = sorted.clone();
+
+            let mut shared = lock(&watch.shared);
+            match &mut shared.update {
+                Some(update) => {
+                    update.sorted = sorted;
+                    // A message that comes and goes between updates is left out of both.
+                    for key_id in added {
+                        match update.removed.iter().position(|gone| *gone == key_id) {
+                            Some(gone) => {
+                                update.removed.remove(gone);
+                            }
+                            None => update.added.push(key_id),
+                        }
+                    }
+                    for key_id in removed {
+                        match update.added.iter().position(|new| *new == key_id) {
+                            Some(new) => {
+                                update.added.remove(new);
+                            }
+                            None => update.removed.push(key_id),
+                        }
+                    }
+                }
+                None => {
+                    shared.update = Some(ThreadUpdate {
+                        root: watch.root.clone(),
+                        sorted,
+                        added,
+                        removed,
+                    })
+                }
+            }
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Watch the thread of messages descended from `root`, getting a [`ThreadUpdate`] each time
+    /// an insert or a removal changes its order. Updates that the stream hasn't given yet are merged into one,
+    /// so a slow reader only gets the latest order, with every message added since it last
+    /// looked.
+    ///
+    /// The stream ends when the sorter is dropped.
+    pub fn watch(&mut self, root: &Multihash) -> ThreadWatch<K> {
+        let shared = Arc::new(Mutex::new(Shared {
+            update: None,
+            closed: false,
+            waker: None,
+        }));
+        self.watches.push(Watch {
+            root: root.clone(),
+            sorted: self.dag.descendants(root, true),
+            shared: shared.clone(),
+        });
+        ThreadWatch { shared }
+    }
+}
+
+impl<K: Clone + Hash + Eq> Default for LiveSorter<K> {
+    fn default() -> LiveSorter<K> {
+        LiveSorter::new()
+    }
+}
+
+impl<K> Drop for LiveSorter<K> {
+    fn drop(&mut self) {
+        for watch in &self.watches {
+            let mut shared = lock(&watch.shared);
+            shared.closed = true;
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The [`ThreadUpdate`]s of one thread, from [`LiveSorter::watch`].
+pub struct ThreadWatch<K> {
+    shared: Arc<Mutex<Shared<K>>>,
+}
+
+impl<K> Stream for ThreadWatch<K> {
+    type Item = ThreadUpdate<K>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ThreadUpdate<K>>> {
+        let mut shared = lock(&self.shared);
+        match shared.update.take() {
+            Some(update) => Poll::Ready(Some(update)),
+            None if shared.closed => Poll::Ready(None),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LiveSorter;
+    use crate::test_utils::{feed, numbered};
+    use futures::executor::block_on;
+    use futures::{poll, StreamExt};
+    use serde_json::json;
+
+    #[test]
+    fn watched_threads_are_updated_as_they_change() {
+        let mut sorter = LiveSorter::new();
+        let root = numbered(1);
+        let mut watch = sorter.watch(&root);
+        let mut other = sorter.watch(&numbered(10));
+        block_on(async {
+            assert!(poll!(watch.next()).is_pending());
+
+            // The reply arrives before its root, then brings its own reply into the thread.
+            let reply = json!({ "root": root, "branch": root }).to_string();
+            sorter.insert(&numbered(2), 2, &reply).unwrap();
+            let update = watch.next().await.unwrap();
+            assert_eq!(
+                (update.sorted.as_slice(), &update.added[..]),
+                (&[2][..], &[2][..])
+            );
+
+            let other_reply = json!({ "root": numbered(10) }).to_string();
+            sorter.insert(&numbered(11), 11, &other_reply).unwrap();
+            sorter.insert(&root, 1, &json!({}).to_string()).unwrap();
+            let nested = json!({ "root": root, "branch": numbered(2) }).to_string();
+            sorter.insert(&numbered(3), 3, &nested).unwrap();
+            let update = watch.next().await.unwrap();
+            assert_eq!(update.root, root);
+            assert_eq!(update.sorted, [3, 2, 1]);
+            assert_eq!(update.added, [1, 3]);
+            assert!(poll!(watch.next()).is_pending());
+            assert_eq!(other.next().await.unwrap().added, [11]);
+
+            sorter
+                .insert(&numbered(5), 5, &json!({}).to_string())
+                .unwrap();
+            assert!(poll!(watch.next()).is_pending());
+
+            drop(other);
+            let dag = sorter.into_dag();
+            assert_eq!(watch.next().await, None);
+            assert!(dag.node(&numbered(11)).is_some());
+        });
+    }
+
+    #[test]
+    fn snapshots_stay_as_they_were_taken() {
+        let mut sorter = LiveSorter::new();
+        let msgs = feed(100);
+        let (key, key_id, msg) = &msgs[0];
+        sorter.insert(key, *key_id, msg).unwrap();
+        let snapshot = sorter.snapshot();
+
+        std::thread::scope(|scope| {
+            let reader = scope.spawn(|| (0..50).map(|_| snapshot.sorted()).collect::<Vec<_>>());
+            msgs[1..]
+                .iter()
+                .for_each(|(key, key_id, msg)| sorter.insert(key, *key_id, msg).unwrap());
+            let read = reader.join().unwrap();
+            assert!(read.iter().all(|sorted| *sorted == [1]));
+        });
+        assert_eq!(snapshot.sorted(), [1]);
+        assert_eq!(sorter.snapshot().sorted().len(), 100);
+        assert_eq!(sorter.dag().sorted(), sorter.snapshot().sorted());
+    }
+
+    #[test]
+    fn failed_inserts_leave_the_message_out() {
+        let mut sorter = LiveSorter::new();
+        let first = json!({ "previous": numbered(2) }).to_string();
+        sorter.insert(&numbered(1), 1, &first).unwrap();
+        let cycle = json!({ "previous": numbered(1) }).to_string();
+        assert!(sorter.insert(&numbered(2), 2, &cycle).is_err());
+        assert_eq!(sorter.dag().sorted(), [1]);
+        let dag = sorter.dag();
+        assert_eq!(
+            dag.hash(dag.node(&numbered(2)).unwrap()),
+            Some(&numbered(2))
+        );
+    }
+
+    #[test]
+    fn watches_hear_about_removals() {
+        let mut sorter = LiveSorter::new();
+        let root = numbered(1);
+        sorter.insert(&root, 1, &json!({}).to_string()).unwrap();
+        let reply = json!({ "root": root, "branch": root }).to_string();
+        sorter.insert(&numbered(2), 2, &reply).unwrap();
+        let nested = json!({ "root": root, "branch": numbered(2) }).to_string();
+        sorter.insert(&numbered(3), 3, &nested).unwrap();
+        let snapshot = sorter.snapshot();
+        let mut watch = sorter.watch(&root);
+
+        block_on(async {
+            assert_eq!(sorter.remove(&numbered(2)).unwrap(), [3]);
+            let update = watch.next().await.unwrap();
+            assert_eq!(update.sorted, [3, 1]);
+            assert_eq!(
+                (&update.added[..], &update.removed[..]),
+                (&[][..], &[2][..])
+            );
+            assert!(sorter.remove(&numbered(2)).is_none());
+            assert!(sorter.remove(&numbered(9)).is_none());
+            assert!(poll!(watch.next()).is_pending());
+
+            // A message removed and put back before the stream looks isn't an update to either.
+            sorter.remove(&numbered(3)).unwrap();
+            sorter.insert(&numbered(3), 3, &nested).unwrap();
+            let update = watch.next().await.unwrap();
+            assert_eq!(update.sorted, [3, 1]);
+            assert!(update.added.is_empty() && update.removed.is_empty());
+        });
+        assert_eq!(snapshot.sorted(), [3, 2, 1]);
+        assert_eq!(sorter.dag().sorted(), [3, 1]);
+    }
+}
@@ -0,0 +1,193 @@
+//! Reading messages from go-ssb's [margaret](https://github.com/cryptoscope/margaret) offset logs.
+use crate::error::Error;
+use crate::keys::Json;
+use crate::ndjson::read_json;
+use ssb_multiformats::multihash::Multihash;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read};
+
+/// The longest entry a log is read with, far more than any message. Anything longer is taken to
+/// be a corrupt length.
+const MAX_ENTRY: usize = 64 << 20;
+
+/// Read each entry of the `data` file of a margaret `offset2` log, eg. go-ssb's receive log, as
+/// a message to sort.
+///
+/// An entry is read by the JSON message it holds: a message value, or a `{ "key", "value" }`
+/// envelope, found at the start of the entry or, in go-ssb's own encoding of stored messages,
+/// after the few fields before it. Messages are keyed and given as [`read_ndjson`] gives them, and
+/// numbered by their place in the log. Entries that have been nulled, ie. overwritten with zeros,
+/// are skipped.
+///
+/// An entry with no message in it is an `Error::Parse`, and reading carries on past it. A log
+/// that can't be read, or that's cut short or corrupt, is an `Error::Read`, and ends the
+/// entries. [`read_margaret_entries`] gives the entries undecoded, for other encodings.
+///
+/// [`read_ndjson`]: crate::read_ndjson
+pub fn read_margaret_log<R: Read>(
+    data: R,
+) -> impl Iterator<Item = Result<(Multihash, usize, String), Error>> {
+    read_margaret_entries(data)
+        .enumerate()
+        .filter(|(_, entry)| !matches!(entry, Ok(entry) if entry.iter().all(|byte| *byte == 0)))
+        .map(|(index, entry)| {
+            let entry = entry?;
+            read_entry(&entry)
+                .map(|(key, msg)| (key, index, msg))
+                .map_err(|source| Error::Parse { index, source })
+        })
+}
+
+/// Each entry of the `data` file of a margaret `offset2` log, as the bytes it was appended
+/// with. Each entry is written as its length, a big endian `i64`, then its bytes.
+///
+/// Reading stops after the first `Error::Read`.
+pub fn read_margaret_entries<R: Read>(data: R) -> impl Iterator<Item = Result<Vec<u8>, Error>> {
+    let mut data = Some(data);
+    std::iter::from_fn(move || {
+        let entry = read_frame(data.as_mut()?).transpose()?;
+        if entry.is_err() {
+            data = None;
+        }
+        Some(entry.map_err(|source| Error::Read { source }))
+    })
+}
+
+/// Read one length-prefixed entry, or `None` at the end of the log.
+fn read_frame<R: Read>(data: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 8];
+    let mut filled = 0;
+    while filled < len.len() {
+        match data.read(&mut len[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    let len = match usize::try_from(i64::from_be_bytes(len)) {
+        Ok(len) if len <= MAX_ENTRY => len,
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "bad entry length")),
+    };
+    let mut entry = vec![0; len];
+    data.read_exact(&mut entry)?;
+    Ok(Some(entry))
+}
+
+/// The most places in an entry a message is looked for, so that an entry full of `{` isn't each
+/// parsed from every one of them. go-ssb's fields before a message are a few bytes.
+const MAX_STARTS: usize = 16;
+
+/// Find the message in an entry, from the first `{` that starts a whole JSON object, within the
+/// first `MAX_STARTS` that could.
+fn read_entry(
+    entry: &[u8],
+) -> Result<(Multihash, String), Box<dyn std::error::Error + Send + Sync>> {
+    let starts = entry
+        .iter()
+        .enumerate()
+        .filter(|(start, byte)| **byte == b'{' && opens_object(&entry[start + 1..]))
+        .map(|(start, _)| start)
+        .take(MAX_STARTS);
+    for start in starts {
+        let mut values = serde_json::Deserializer::from_slice(&entry[start..]).into_iter::<Json>();
+        if let Some(Ok(json @ Json::Object(_))) = values.next() {
+            return read_json(json);
+        }
+    }
+    Err("the entry holds no message".into())
+}
+
+/// Whether what follows a `{` could be the rest of an object: a key or its end.
+fn opens_object(rest: &[u8]) -> bool {
+    let next = rest.iter().find(|byte| !byte.is_ascii_whitespace());
+    matches!(next, Some(b'"') | Some(b'}'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_margaret_entries, read_margaret_log};
+    use crate::keys::message_key;
+    use crate::test_utils::numbered;
+    use crate::{Error, SortBuilder};
+    use serde_json::json;
+    use std::io::Cursor;
+
+    /// `entries` as a margaret log, each after its length.
+    fn log(entries: &[&[u8]]) -> Vec<u8> {
+        let mut log = Vec::new();
+        for entry in entries {
+            log.extend_from_slice(&(entry.len() as i64).to_be_bytes());
+            log.extend_from_slice(entry);
+        }
+        log
+    }
+
+    #[test]
+    fn entries_are_read_as_messages() {
+        let root = json!({ "key": numbered(1), "value": { "content": {} } }).to_string();
+        let reply = json!({ "previous": numbered(1), "content": { "type": "post" } }).to_string();
+        // As go-ssb stores a message: binary fields, then the message's JSON, then more fields.
+        let mut stored = b"\x01\x00\x02{\xff".to_vec();
+        stored.extend_from_slice(root.as_bytes());
+        stored.extend_from_slice(b"\x03\x00");
+        let data = log(&[
+            &stored,
+            &[0; 12],
+            b"\x00\x01 not a message",
+            reply.as_bytes(),
+        ]);
+
+        let read: Vec<_> = read_margaret_log(Cursor::new(data)).collect();
+        assert_eq!(read.len(), 3);
+        let (key, index, msg) = read[0].as_ref().unwrap();
+        assert_eq!(
+            (key, *index, msg.as_str()),
+            (&numbered(1), 0, "{\n  \"content\": {}\n}")
+        );
+        assert!(matches!(read[1], Err(Error::Parse { index: 2, .. })));
+        let (key, index, msg) = read[2].as_ref().unwrap();
+        assert_eq!((key, *index), (&message_key(msg), 3));
+
+        let msgs: Vec<_> = read.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(SortBuilder::new().try_sort(&msgs).unwrap(), [3, 0]);
+    }
+
+    #[test]
+    fn messages_are_looked_for_from_the_first_few_objects() {
+        let msg = json!({ "previous": null, "content": {} }).to_string();
+        let after = |junk: &str| [junk.as_bytes(), msg.as_bytes()].concat();
+        let few = after(&"{\"".repeat(super::MAX_STARTS - 1));
+        let many = after(&"{\"".repeat(super::MAX_STARTS));
+        // Not a key or an end, so not the start of an object.
+        let braces = after(&"{".repeat(1_000_000));
+        let data = log(&[&few, &many, &braces]);
+
+        let read: Vec<_> = read_margaret_log(Cursor::new(data)).collect();
+        assert!(matches!(
+            read[..],
+            [Ok(_), Err(Error::Parse { index: 1, .. }), Ok(_)]
+        ));
+    }
+
+    #[test]
+    fn corrupt_logs_end_with_an_error() {
+        let mut cut_short = log(&[b"{}", b"{}"]);
+        cut_short.pop();
+        let mut entries = read_margaret_entries(Cursor::new(cut_short));
+        assert_eq!(entries.next().unwrap().unwrap(), b"{}");
+        assert!(matches!(entries.next(), Some(Err(Error::Read { .. }))));
+        assert!(entries.next().is_none());
+
+        let mut negative = log(&[b"{}"]);
+        negative.extend_from_slice(&(-1i64).to_be_bytes());
+        let read: Vec<_> = read_margaret_log(Cursor::new(negative)).collect();
+        assert!(matches!(read[..], [Ok(_), Err(Error::Read { .. })]));
+
+        let half_a_length = vec![0; 4];
+        let read: Vec<_> = read_margaret_entries(Cursor::new(half_a_length)).collect();
+        assert!(matches!(read[..], [Err(Error::Read { .. })]));
+        assert_eq!(read_margaret_entries(Cursor::new(vec![])).count(), 0);
+    }
+}
@@ -0,0 +1,326 @@
+//! Hooks for counting what a sort does, eg. to export to a monitoring system.
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Called by a [`SortBuilder`](crate::SortBuilder) as it sorts. Every method does nothing by
+/// default, so implement only the ones you need.
+///
+/// The same `Metrics` is shared by every sort the builder does, possibly on many threads at once.
+pub trait Metrics: Send + Sync {
+    /// A sort finished with `count` messages.
+    fn messages_sorted(&self, _count: usize) {}
+
+    /// `count` of the messages in a sort weren't valid JSON, so were sorted as having no links.
+    fn parse_failures(&self, _count: usize) {}
+
+    /// `count` of the messages in a sort had more links than the builder's
+    /// [`max_links`](crate::SortBuilder::max_links), so were sorted by only some of them.
+    fn links_capped(&self, _count: usize) {}
+
+    /// A sort found a cycle in the messages' links.
+    fn cycle(&self) {}
+
+    /// A sort finished in `elapsed`.
+    fn sort_latency(&self, _elapsed: Duration) {}
+
+    /// A sort finished, with what it did and how long each part of it took.
+    fn sort_stats(&self, _stats: &SortStats) {}
+}
+
+/// What one sort did, and how long it spent on each phase, for [`Metrics::sort_stats`], eg. to
+/// include in a report of a slow sort.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SortStats {
+    /// How many messages were given to the sort.
+    pub messages: usize,
+    /// How many of them weren't valid JSON.
+    pub parse_failures: usize,
+    /// How many of them were sorted by only some of their links.
+    pub links_capped: usize,
+    /// Finding the links in the messages, which parses them as it goes. Zero for messages whose
+    /// links were given.
+    pub extract: Duration,
+    /// Adding the messages and their links to the dag.
+    pub build: Duration,
+    /// Sorting the dag.
+    pub sort: Duration,
+    /// The whole sort.
+    pub total: Duration,
+    /// The messages of each `content.type`, and the links found in them, eg. to see which kinds
+    /// of message make up most of the graph. Only messages whose links were found and that have
+    /// a type are counted, so messages that weren't valid JSON, are private or are of
+    /// [excluded types](crate::SortBuilder::exclude_types) aren't.
+    pub types: BTreeMap<String, TypeStats>,
+}
+
+/// How many messages of one type a sort was given, for [`SortStats::types`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TypeStats {
+    /// How many messages had the type.
+    pub messages: usize,
+    /// How many links were found in them, before any were left out for
+    /// [`max_links`](crate::SortBuilder::max_links).
+    pub links: usize,
+}
+
+#[cfg(feature = "prometheus")]
+pub use self::prometheus_metrics::PrometheusMetrics;
+
+#[cfg(feature = "prometheus")]
+mod prometheus_metrics {
+    use super::Metrics;
+    use prometheus::{Histogram, HistogramOpts, IntCounter, Registry};
+    use std::time::Duration;
+
+    /// [`Metrics`] recorded as [prometheus](https://docs.rs/prometheus) counters and a histogram:
+    ///
+    /// - `ssb_causal_sort_messages_total`
+    /// - `ssb_causal_sort_parse_failures_total`
+    /// - `ssb_causal_sort_capped_messages_total`
+    /// - `ssb_causal_sort_cycles_total`
+    /// - `ssb_causal_sort_duration_seconds`
+    #[derive(Clone, Debug)]
+    pub struct PrometheusMetrics {
+        messages: IntCounter,
+        parse_failures: IntCounter,
+        capped: IntCounter,
+        cycles: IntCounter,
+        duration: Histogram,
+    }
+
+    impl PrometheusMetrics {
+        /// Create the metrics and register them with `registry`.
+        pub fn new(registry: &Registry) -> prometheus::Result<PrometheusMetrics> {
+            let metrics = PrometheusMetrics {
+                messages: IntCounter::new("ssb_causal_sort_messages_total", "Messages sorted.")?,
+                parse_failures: IntCounter::new(
+                    "ssb_causal_sort_parse_failures_total",
+                    "Messages sorted that weren't valid JSON.",
+                )?,
+                capped: IntCounter::new(
+                    "ssb_causal_sort_capped_messages_total",
+                    "Messages sorted by only some of their links, for having too many.",
+                )?,
+                cycles: IntCounter::new(
+                    "ssb_causal_sort_cycles_total",
+                    "Sorts that found a cycle.",
+                )?,
+                duration: Histogram::with_opts(HistogramOpts::new(
+                    "ssb_causal_sort_duration_seconds",
+                    "How long each sort took.",
+                ))?,
+            };
+            registry.register(Box::new(metrics.messages.clone()))?;
+            registry.register(Box::new(metrics.parse_failures.clone()))?;
+            registry.register(Box::new(metrics.capped.clone()))?;
+            registry.register(Box::new(metrics.cycles.clone()))?;
+            registry.register(Box::new(metrics.duration.clone()))?;
+            Ok(metrics)
+        }
+    }
+
+    impl Metrics for PrometheusMetrics {
+        fn messages_sorted(&self, count: usize) {
+            self.messages.inc_by(count as u64);
+        }
+
+        fn parse_failures(&self, count: usize) {
+            self.parse_failures.inc_by(count as u64);
+        }
+
+        fn links_capped(&self, count: usize) {
+            self.capped.inc_by(count as u64);
+        }
+
+        fn cycle(&self) {
+            self.cycles.inc();
+        }
+
+        fn sort_latency(&self, elapsed: Duration) {
+            self.duration.observe(elapsed.as_secs_f64());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::{Metrics, SortStats, TypeStats};
+    use crate::test_utils::{numbered, thread};
+    use crate::{Backend, SortBuilder};
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Counts {
+        sorts: AtomicUsize,
+        messages: AtomicUsize,
+        parse_failures: AtomicUsize,
+        capped: AtomicUsize,
+        cycles: AtomicUsize,
+    }
+
+    impl Metrics for Counts {
+        fn messages_sorted(&self, count: usize) {
+            self.messages.fetch_add(count, Ordering::Relaxed);
+        }
+
+        fn parse_failures(&self, count: usize) {
+            self.parse_failures.fetch_add(count, Ordering::Relaxed);
+        }
+
+        fn links_capped(&self, count: usize) {
+            self.capped.fetch_add(count, Ordering::Relaxed);
+        }
+
+        fn cycle(&self) {
+            self.cycles.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn sort_latency(&self, _: Duration) {
+            self.sorts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn sorting_calls_metrics() {
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let counts = Arc::new(Counts::default());
+            let builder = SortBuilder::new().backend(*backend).metrics(counts.clone());
+
+            let mut msgs = thread();
+            builder.sort(&msgs);
+            msgs.push((numbered(4), 4, "{\"not\": json".to_owned()));
+            builder.sort(&msgs);
+            assert_eq!(counts.sorts.load(Ordering::Relaxed), 2);
+            assert_eq!(counts.messages.load(Ordering::Relaxed), 7);
+            assert_eq!(counts.parse_failures.load(Ordering::Relaxed), 1);
+            assert_eq!(counts.capped.load(Ordering::Relaxed), 0);
+
+            let cycle = [
+                (
+                    numbered(1),
+                    1,
+                    json!({ "previous": numbered(2) }).to_string(),
+                ),
+                (
+                    numbered(2),
+                    2,
+                    json!({ "previous": numbered(1) }).to_string(),
+                ),
+            ];
+            assert!(catch_unwind(AssertUnwindSafe(|| builder.sort(&cycle))).is_err());
+            assert_eq!(counts.cycles.load(Ordering::Relaxed), 1);
+            assert_eq!(counts.sorts.load(Ordering::Relaxed), 2);
+
+            // The second reply links to both the root and the first reply.
+            builder.max_links(1).sort(&thread());
+            assert_eq!(counts.capped.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    /// Keeps the stats of every sort.
+    #[derive(Default)]
+    struct Stats(Mutex<Vec<SortStats>>);
+
+    impl Metrics for Stats {
+        fn sort_stats(&self, stats: &SortStats) {
+            self.0.lock().unwrap().push(stats.clone());
+        }
+    }
+
+    #[test]
+    fn sorts_are_timed_by_phase() {
+        let stats = Arc::new(Stats::default());
+        let builder = SortBuilder::new().metrics(stats.clone());
+        let mut msgs = thread();
+        msgs.push((numbered(4), 4, "{\"not\": json".to_owned()));
+        builder.sort(&msgs);
+        builder.clone().backend(Backend::Csr).sort(&msgs);
+        builder.sort_links(&[(numbered(1), 1, vec![numbered(2)])]);
+
+        let stats = stats.0.lock().unwrap();
+        assert_eq!(stats.len(), 3);
+        stats.iter().for_each(|stats| {
+            assert!(stats.extract + stats.build + stats.sort <= stats.total);
+            assert!(stats.sort > Duration::ZERO);
+        });
+        assert_eq!((stats[0].messages, stats[0].parse_failures), (4, 1));
+        assert!(stats[0].extract > Duration::ZERO);
+        assert_eq!((stats[2].messages, stats[2].extract), (1, Duration::ZERO));
+    }
+
+    #[test]
+    fn messages_and_links_are_counted_by_type() {
+        let root = numbered(1);
+        let msgs = vec![
+            (root.clone(), 1, json!({ "content": { "type": "post" } })),
+            (
+                numbered(2),
+                2,
+                json!({ "content": { "type": "post", "root": root } }),
+            ),
+            (
+                numbered(3),
+                3,
+                json!({ "previous": numbered(2), "content": { "type": "vote", "link": root } }),
+            ),
+            (numbered(4), 4, json!({ "content": "c2VjcmV0.box" })),
+        ];
+        let msgs: Vec<_> = msgs
+            .into_iter()
+            .map(|(key, id, msg)| (key, id, msg.to_string()))
+            .collect();
+
+        let stats = Arc::new(Stats::default());
+        let builder = SortBuilder::new().metrics(stats.clone());
+        builder.sort(&msgs);
+        builder.exclude_types(&["vote"]).sort(&msgs);
+
+        let stats = stats.0.lock().unwrap();
+        let counts = |types: &BTreeMap<String, TypeStats>| -> Vec<_> {
+            types
+                .iter()
+                .map(|(msg_type, stats)| (msg_type.clone(), stats.messages, stats.links))
+                .collect()
+        };
+        assert_eq!(
+            counts(&stats[0].types),
+            [("post".to_owned(), 2, 1), ("vote".to_owned(), 1, 2)]
+        );
+        assert_eq!(counts(&stats[1].types), [("post".to_owned(), 2, 1)]);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_metrics_are_registered() {
+        use super::PrometheusMetrics;
+        use prometheus::Registry;
+
+        let registry = Registry::new();
+        let metrics = Arc::new(PrometheusMetrics::new(&registry).unwrap());
+        SortBuilder::new().metrics(metrics).sort(&thread());
+
+        let families = registry.gather();
+        let value = |name: &str| {
+            let family = families.iter().find(|f| f.name() == name).unwrap();
+            let metric = &family.get_metric()[0];
+            match name {
+                "ssb_causal_sort_duration_seconds" => metric.get_histogram().get_sample_count(),
+                _ => metric.get_counter().get_value() as u64,
+            }
+        };
+        assert_eq!(value("ssb_causal_sort_messages_total"), 3);
+        assert_eq!(value("ssb_causal_sort_parse_failures_total"), 0);
+        assert_eq!(value("ssb_causal_sort_capped_messages_total"), 0);
+        assert_eq!(value("ssb_causal_sort_cycles_total"), 0);
+        assert_eq!(value("ssb_causal_sort_duration_seconds"), 1);
+
+        assert!(PrometheusMetrics::new(&registry).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Reading messages from newline delimited JSON, eg. a log file.
+use crate::error::Error;
+use crate::hashes::parse_link;
+use crate::keys::{message_key, Json};
+use ssb_multiformats::multihash::Multihash;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{BufRead, Write};
+
+/// Read a message from each line of `reader`, ready to sort, eg.
+/// `causal_sort(&read_ndjson(file).collect::<Result<Vec<_>, _>>()?)`.
+///
+/// Each line is either a `{ "key": ..., "value": ... }` envelope or a bare message value, which
+/// is keyed with [`message_key`]. Either way the message is its value in its
+/// [`legacy_encoding`], so [`SortBuilder::verify_keys`] accepts genuine messages. Envelopes'
+/// other fields, eg. when they were received, are left out. Messages are numbered from 0
+/// in the order they're read, and blank lines are skipped.
+///
+/// A line that isn't JSON, or an envelope whose key isn't a message hash, is an
+/// `Error::Parse`, and reading carries on past it. Failing to read is an `Error::Read`, and ends
+/// the messages.
+///
+/// [`legacy_encoding`]: crate::legacy_encoding
+/// [`SortBuilder::verify_keys`]: crate::SortBuilder::verify_keys
+pub fn read_ndjson<R: BufRead>(
+    reader: R,
+) -> impl Iterator<Item = Result<(Multihash, usize, String), Error>> {
+    let mut lines = Some(reader.lines());
+    std::iter::from_fn(move || {
+        let line = lines.as_mut()?.next()?;
+        if line.is_err() {
+            lines = None;
+        }
+        Some(line)
+    })
+    .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+    .enumerate()
+    .map(|(index, line)| {
+        let line = line.map_err(|source| Error::Read { source })?;
+        read_line(&line)
+            .map(|(key, msg)| (key, index, msg))
+            .map_err(|source| Error::Parse { index, source })
+    })
+}
+
+/// Write the messages in `sorted`, eg. as a sort gives them, to `writer`, one
+/// `{"key":...,"value":...}` envelope per line in that order, as [`read_ndjson`] reads them.
+///
+/// Each message is found in `msgs` by its `K`, eg. the messages that were sorted, and keys
+/// without a message are left out. Values are written on one line with their fields in order,
+/// so a value's legacy encoding, and so its key, is unchanged. `writer` is written to a line at
+/// a time, so should be buffered, eg. with a `BufWriter`.
+///
+/// A message that isn't JSON is an `Error::Parse`, with its index in `msgs`, and failing to write
+/// is an `Error::Write`. Either stops the writing.
+pub fn write_ndjson<W: Write, K: Hash + Eq, T: AsRef<str>>(
+    mut writer: W,
+    sorted: &[K],
+    msgs: &[(Multihash, K, T)],
+) -> Result<(), Error> {
+    let mut by_id = HashMap::with_capacity(msgs.len());
+    for (index, (key, id, msg)) in msgs.iter().enumerate() {
+        by_id.entry(id).or_insert((index, key, msg.as_ref()));
+    }
+    let mut line = String::new();
+    for (index, key, msg) in sorted.iter().filter_map(|id| by_id.get(id)) {
+        let value: Json = serde_json::from_str(msg).map_err(|source| Error::Parse {
+            index: *index,
+            source: source.into(),
+        })?;
+        line.clear();
+        line.push_str("{\"key\":\"");
+        line.push_str(&key.to_legacy_string());
+        line.push_str("\",\"value\":");
+        line.push_str(&value.compact_encoding());
+        line.push_str("}\n");
+        writer
+            .write_all(line.as_bytes())
+            .map_err(|source| Error::Write { source })?;
+    }
+    writer.flush().map_err(|source| Error::Write { source })
+}
+
+fn read_line(line: &str) -> Result<(Multihash, String), Box<dyn std::error::Error + Send + Sync>> {
+    read_json(serde_json::from_str(line)?)
+}
+
+/// Key a message that's either an envelope or a bare value, and give its value in its legacy
+/// encoding.
+pub(crate) fn read_json(
+    json: Json,
+) -> Result<(Multihash, String), Box<dyn std::error::Error + Send + Sync>> {
+    match json {
+        Json::Object(fields) if is_envelope(&fields) => {
+            let (mut key, mut value) = (None, None);
+            for (field, json) in fields {
+                match (field.as_str(), json) {
+                    ("key", Json::String(st)) => key = parse_link(&st),
+                    ("value", json) => value = Some(json),
+                    _ => (),
+                }
+            }
+            match (key, value) {
+                (Some(key @ Multihash::Message(_)), Some(value)) => {
+                    Ok((key, value.legacy_encoding()))
+                }
+                _ => Err("the key isn't a message hash".into()),
+            }
+        }
+        value => {
+            let value = value.legacy_encoding();
+            Ok((message_key(&value), value))
+        }
+    }
+}
+
+fn is_envelope(fields: &[(String, Json)]) -> bool {
+    let has = |name: &str| fields.iter().any(|(field, _)| field == name);
+    has("key") && has("value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_ndjson, write_ndjson};
+    use crate::keys::{legacy_encoding, message_key};
+    use crate::test_utils::numbered;
+    use crate::{Error, SortBuilder};
+    use serde_json::json;
+    use std::io::{BufReader, Cursor, Read};
+
+    #[test]
+    fn lines_are_read_as_envelopes_or_values() {
+        let root = json!({ "key": numbered(1), "value": { "content": {} } }).to_string();
+        let reply = json!({ "previous": numbered(1), "content": { "type": "post" } }).to_string();
+        let log = format!(
+            "{}\n\n{}\n{{\"not\": json\n{}\n",
+            root,
+            reply,
+            json!({ "key": 1, "value": {} })
+        );
+
+        let read: Vec<_> = read_ndjson(Cursor::new(log)).collect();
+        assert_eq!(read.len(), 4);
+        let (key, index, msg) = read[0].as_ref().unwrap();
+        assert_eq!(
+            (key, *index, msg.as_str()),
+            (&numbered(1), 0, "{\n  \"content\": {}\n}")
+        );
+        let (key, index, msg) = read[1].as_ref().unwrap();
+        assert_eq!((key, *index), (&message_key(msg), 1));
+        assert!(msg.starts_with("{\n  \"content\": {\n"));
+        assert!(matches!(read[2], Err(Error::Parse { index: 2, .. })));
+        assert!(matches!(read[3], Err(Error::Parse { index: 3, .. })));
+
+        let msgs: Vec<_> = read.into_iter().filter_map(Result::ok).collect();
+        let sorted = SortBuilder::new().try_sort(&msgs).unwrap();
+        assert_eq!(sorted, [1, 0]);
+    }
+
+    #[test]
+    fn sorted_messages_are_written_back() {
+        let root = "{\n  \"content\": {\n    \"z\": 1e+21,\n    \"a\": \"\\u0001\"\n  }\n}";
+        let reply = legacy_encoding(&json!({ "previous": message_key(root) }).to_string()).unwrap();
+        let msgs = vec![
+            (message_key(&reply), 2, reply.clone()),
+            (message_key(root), 1, root.to_owned()),
+        ];
+        let sorted = SortBuilder::new().verify_keys(true).sort(&msgs);
+
+        let mut written = Vec::new();
+        write_ndjson(&mut written, &[1, 3, 2], &msgs).unwrap();
+        let lines = String::from_utf8(written).unwrap();
+        let mut lines = lines.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                "{{\"key\":\"{}\",\"value\":{{\"content\":{{\"z\":1e+21,\"a\":\"\\u0001\"}}}}}}",
+                message_key(root).to_legacy_string()
+            )
+        );
+        assert_eq!(lines.count(), 1);
+
+        let mut written = Vec::new();
+        write_ndjson(&mut written, &sorted, &msgs).unwrap();
+        let read: Vec<_> = read_ndjson(Cursor::new(written))
+            .map(|msg| msg.map(|(key, _, msg)| (key, msg)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            read,
+            [
+                (msgs[0].0.clone(), msgs[0].2.clone()),
+                (msgs[1].0.clone(), msgs[1].2.clone())
+            ]
+        );
+        assert_eq!(sorted, [2, 1]);
+
+        let broken = vec![(numbered(1), 1, "{".to_owned())];
+        assert!(matches!(
+            write_ndjson(Vec::new(), &[1], &broken),
+            Err(Error::Parse { index: 0, .. })
+        ));
+    }
+
+    /// Fails every read after the first line.
+    struct Broken(Cursor<&'static [u8]>);
+
+    impl Read for Broken {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(std::io::ErrorKind::BrokenPipe.into()),
+                read => Ok(read),
+            }
+        }
+    }
+
+    #[test]
+    fn read_errors_are_errors() {
+        let reader = BufReader::new(Broken(Cursor::new(b"{}\n")));
+        let mut lines = read_ndjson(reader);
+        assert!(lines.next().unwrap().is_ok());
+        assert!(matches!(lines.next(), Some(Err(Error::Read { .. }))));
+        assert!(lines.next().is_none());
+    }
+}
@@ -0,0 +1,314 @@
+//! Building the dag on many threads with [rayon](https://docs.rs/rayon).
+//!
+//! The sequential build interns hashes and adds edges one message at a time, which caps it at one
+//! core. Here every phase that touches hashes runs in parallel:
+//!
+//! 1. Links are extracted from each message.
+//! 2. Each chunk of messages records where it first saw every hash, split into the interner's
+//!    shards.
+//! 3. Each shard merges the chunks' first sightings. Sorting every hash by where it was first seen
+//!    gives the same node order the sequential build would have produced.
+//! 4. Each message's key and references are resolved to nodes.
+//!
+//! Only adding the already resolved edges to the dag is left to do on one thread. The edges are
+//! added in the same order as the sequential build adds them, so the sort is identical.
+use crate::error::{self, Error};
+use crate::extract::Extractor;
+use crate::graph::{check_count, find_cycle, CausalGraph, Interner, Ix, SHARDS};
+use crate::sorted::SortedMessages;
+use crate::trace::span;
+use daggy::{Dag, NodeIndex};
+use rayon::prelude::*;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Where a hash was seen: the index of the message, then 0 for its key or 1 + the index of the
+/// reference.
+type Position = (usize, usize);
+
+/// How many messages' links are stored in each `LinkArena`.
+const CHUNK: usize = 1024;
+
+/// The links of a run of messages, stored end to end in one allocation rather than in a `Vec` per
+/// message.
+struct LinkArena {
+    links: Vec<Multihash>,
+    ends: Vec<usize>,
+}
+
+impl LinkArena {
+    fn links(&self, index: usize) -> &[Multihash] {
+        let start = index
+            .checked_sub(1)
+            .map_or(0, |previous| self.ends[previous]);
+        &self.links[start..self.ends[index]]
+    }
+}
+
+/// Like [`causal_sort`](crate::causal_sort), but builds the dag on rayon's thread pool.
+///
+/// The result is always the same as `causal_sort`. To build on a pool of your own rather than the
+/// global one, call this from inside [`ThreadPool::install`](rayon::ThreadPool::install).
+pub fn par_causal_sort<T, K>(msgs: &[(Multihash, K, T)]) -> SortedMessages<K>
+where
+    T: AsRef<str> + Sync,
+    K: Clone + Send + Sync,
+{
+    error::unwrap(try_par_causal_sort(msgs))
+}
+
+/// Like [`par_causal_sort`], but returns an error rather than panicking.
+pub fn try_par_causal_sort<T, K>(msgs: &[(Multihash, K, T)]) -> Result<SortedMessages<K>, Error>
+where
+    T: AsRef<str> + Sync,
+    K: Clone + Send + Sync,
+{
+    Ok(par_build(msgs)?.sorted())
+}
+
+pub(crate) fn par_build<T, K>(msgs: &[(Multihash, K, T)]) -> Result<CausalGraph<K>, Error>
+where
+    T: AsRef<str> + Sync,
+    K: Clone + Send + Sync,
+{
+    let span = span!(
+        DEBUG,
+        "build",
+        messages = msgs.len(),
+        links = tracing::field::Empty,
+        nodes = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+
+    let arenas: Vec<LinkArena> = span!(DEBUG, "extract", messages = msgs.len()).in_scope(|| {
+        msgs.par_chunks(CHUNK)
+            .map_init(Extractor::new, |extractor, chunk| {
+                let mut arena = LinkArena {
+                    links: Vec::new(),
+                    ends: Vec::with_capacity(chunk.len()),
+                };
+                chunk.iter().for_each(|(_, _, msg)| {
+                    let links = extractor
+                        .extract(msg.as_ref().as_bytes())
+                        .unwrap_or_default();
+                    arena.links.extend_from_slice(links);
+                    arena.ends.push(arena.links.len());
+                });
+                arena
+            })
+            .collect()
+    });
+    // Every node and edge is numbered by one of these hashes at most.
+    let hash_count = msgs.len() + arenas.iter().map(|arena| arena.links.len()).sum::<usize>();
+    check_count(hash_count.saturating_sub(1))?;
+    let hashes_of = |index: usize| {
+        std::iter::once(&msgs[index].0).chain(arenas[index / CHUNK].links(index % CHUNK))
+    };
+
+    let intern = span!(DEBUG, "intern", nodes = tracing::field::Empty);
+    let (order, interner) = intern.in_scope(|| {
+        // Each chunk finds the first position of every hash it contains.
+        let chunk_size = (msgs.len() / rayon::current_num_threads()).max(1);
+        let chunk_firsts: Vec<Vec<HashMap<&Multihash, Position>>> = (0..msgs.len())
+            .into_par_iter()
+            .with_min_len(chunk_size)
+            .fold(
+                || (0..SHARDS).map(|_| HashMap::new()).collect::<Vec<_>>(),
+                |mut shards, index| {
+                    hashes_of(index).enumerate().for_each(|(offset, hash)| {
+                        shards[Interner::shard_of(hash)]
+                            .entry(hash)
+                            .or_insert((index, offset));
+                    });
+                    shards
+                },
+            )
+            .collect();
+
+        // Each shard merges the first positions found by the chunks.
+        let shard_firsts: Vec<HashMap<&Multihash, Position>> = (0..SHARDS)
+            .into_par_iter()
+            .map(|shard| {
+                let mut firsts: HashMap<&Multihash, Position> = HashMap::new();
+                chunk_firsts.iter().for_each(|chunk| {
+                    chunk[shard].iter().for_each(|(hash, position)| {
+                        let first = firsts.entry(hash).or_insert(*position);
+                        *first = (*first).min(*position);
+                    });
+                });
+                firsts
+            })
+            .collect();
+        drop(chunk_firsts);
+
+        // Nodes are numbered in the order their hashes were first seen.
+        let mut order: Vec<Position> = shard_firsts
+            .par_iter()
+            .flat_map_iter(|firsts| firsts.values().copied())
+            .collect();
+        order.par_sort_unstable();
+
+        let shards: Vec<HashMap<Multihash, NodeIndex<Ix>>> = shard_firsts
+            .into_par_iter()
+            .map(|firsts| {
+                firsts
+                    .into_iter()
+                    .map(|(hash, position)| {
+                        let node = order
+                            .binary_search(&position)
+                            .expect("position was recorded");
+                        (hash.clone(), NodeIndex::new(node))
+                    })
+                    .collect()
+            })
+            .collect();
+        (order, Interner::from_shards(shards))
+    });
+    intern.record("nodes", order.len());
+
+    // Resolve every key and reference to its node.
+    let resolved: Vec<(NodeIndex<Ix>, Vec<NodeIndex<Ix>>)> = (0..msgs.len())
+        .into_par_iter()
+        .map(|index| {
+            let mut nodes = hashes_of(index).map(|hash| node_of(&interner, hash));
+            let key_node = nodes.next().expect("every message has a key");
+            (key_node, nodes.collect())
+        })
+        .collect();
+    // daggy allows an edge from a node to itself, but then leaves the node out of the sort.
+    if resolved
+        .par_iter()
+        .any(|(key_node, ref_nodes)| ref_nodes.contains(key_node))
+    {
+        return Err(cycle_error(order.len(), &resolved, &interner));
+    }
+
+    let edge_count = resolved.iter().map(|(_, r)| r.len()).sum();
+    let mut dag = Dag::with_capacity(order.len(), edge_count);
+    let added = span!(DEBUG, "add_edges", edges = edge_count).in_scope(|| {
+        (0..order.len()).for_each(|_| {
+            dag.add_node(1);
+        });
+        dag.add_edges(resolved.iter().flat_map(|(key_node, ref_nodes)| {
+            ref_nodes
+                .iter()
+                .map(move |ref_node| (*key_node, *ref_node, 1))
+        }))
+        .is_ok()
+    });
+    if !added {
+        return Err(cycle_error(order.len(), &resolved, &interner));
+    }
+    span.record("links", edge_count)
+        .record("nodes", order.len());
+
+    let mut node_to_key_id = vec![None; order.len()];
+    msgs.iter()
+        .zip(resolved.iter())
+        .for_each(|((_, key_id, _), (key_node, _))| {
+            node_to_key_id[key_node.index()].get_or_insert_with(|| key_id.clone());
+        });
+
+    Ok(CausalGraph::from_parts(dag, interner, node_to_key_id))
+}
+
+fn node_of(interner: &Interner, hash: &Multihash) -> NodeIndex<Ix> {
+    interner.get(hash).expect("every hash was interned")
+}
+
+/// The error for a cycle somewhere in the resolved edges.
+fn cycle_error(
+    node_count: usize,
+    resolved: &[(NodeIndex<Ix>, Vec<NodeIndex<Ix>>)],
+    interner: &Interner,
+) -> Error {
+    let mut children = vec![Vec::new(); node_count];
+    resolved.iter().for_each(|(key_node, ref_nodes)| {
+        children[key_node.index()].extend(ref_nodes.iter().map(|node| node.index()))
+    });
+    let nodes = find_cycle(node_count, 0..node_count, |node| {
+        children[node].iter().copied()
+    });
+    Error::Cycle {
+        keys: interner.hashes_of(&nodes.unwrap_or_default()),
+    }
+}
+
+/// The nodes in the layers `layered_order` finds, following each layer's links on many threads.
+/// Each node's last link in is the one that takes its in-degree to 0, so it's added to the next
+/// layer once, and sorting each layer makes the order independent of the threads.
+pub(crate) fn par_layered_order<C, I>(node_count: usize, children: C) -> Vec<usize>
+where
+    C: Fn(usize) -> I + Sync,
+    I: Iterator<Item = usize>,
+{
+    let in_degree: Vec<AtomicUsize> = (0..node_count).map(|_| AtomicUsize::new(0)).collect();
+    (0..node_count).into_par_iter().for_each(|node| {
+        children(node).for_each(|child| {
+            in_degree[child].fetch_add(1, Ordering::Relaxed);
+        })
+    });
+
+    let mut layer: Vec<usize> = (0..node_count)
+        .into_par_iter()
+        .filter(|node| in_degree[*node].load(Ordering::Relaxed) == 0)
+        .collect();
+    let mut order = Vec::with_capacity(node_count);
+    while !layer.is_empty() {
+        order.extend_from_slice(&layer);
+        let mut next: Vec<usize> = layer
+            .par_iter()
+            .flat_map_iter(|node| {
+                children(*node)
+                    .filter(|child| in_degree[*child].fetch_sub(1, Ordering::AcqRel) == 1)
+            })
+            .collect();
+        next.par_sort_unstable();
+        layer = next;
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::par_layered_order;
+    use crate::graph::sequential_layered_order;
+    use crate::test_utils::{numbered, thread};
+    use crate::{causal_sort, par_causal_sort};
+    use serde_json::json;
+
+    #[test]
+    fn matches_causal_sort() {
+        assert_eq!(par_causal_sort(&thread()), causal_sort(&thread()));
+
+        // Lots of concurrent messages, so the result depends on the node order.
+        let unsorted: Vec<_> = (1..500)
+            .filter(|i| i % 3 != 0)
+            .map(|i| {
+                let msg = json!({
+                    "previous": numbered(i / 3),
+                    "other": numbered((i * 7) % 500 + 500),
+                });
+                (numbered(i), i, msg.to_string())
+            })
+            .rev()
+            .collect();
+        assert_eq!(par_causal_sort(&unsorted), causal_sort(&unsorted));
+    }
+
+    #[test]
+    fn layers_match_on_one_thread() {
+        // Each node links to a few others. Links back to lower numbered nodes close cycles.
+        for (node_count, cycles) in [(2000, false), (300, true)] {
+            let children = |node: usize| {
+                vec![node * 7 + 3, node * 13 + 1, node / 3]
+                    .into_iter()
+                    .filter(move |child| *child < node_count && (cycles || *child > node))
+            };
+            let order = sequential_layered_order(node_count, children);
+            assert_eq!(par_layered_order(node_count, children), order);
+            assert_eq!(order.len() == node_count, !cycles);
+        }
+    }
+}
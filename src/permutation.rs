@@ -0,0 +1,115 @@
+//! Sorting as a permutation of the input, for reordering several parallel slices at once.
+#[cfg(feature = "json")]
+use crate::causal_sort;
+#[cfg(feature = "json")]
+use ssb_multiformats::multihash::Multihash;
+
+/// Causally sort `msgs` and return the order as indices into `msgs`, newest first.
+///
+/// If every message has a distinct key the result is a permutation of `0..msgs.len()` which can
+/// be handed to [`apply_permutation`]. When a key appears more than once only its first index is
+/// included.
+#[cfg(feature = "json")]
+pub fn causal_sort_permutation<T: AsRef<str>, K>(msgs: &[(Multihash, K, T)]) -> Vec<usize> {
+    let indexed: Vec<_> = msgs
+        .iter()
+        .enumerate()
+        .map(|(index, (key, _, msg))| (key.clone(), index, msg.as_ref()))
+        .collect();
+    causal_sort(&indexed).into_vec()
+}
+
+/// Causally sort `msgs` in place, newest first.
+///
+/// Messages whose key repeats the key of an earlier message are moved to the end, in their
+/// original order.
+#[cfg(feature = "json")]
+pub fn causal_sort_in_place<T: AsRef<str>, K>(msgs: &mut [(Multihash, K, T)]) {
+    let mut perm = causal_sort_permutation(msgs);
+    if perm.len() < msgs.len() {
+        let mut seen = vec![false; msgs.len()];
+        perm.iter().for_each(|index| seen[*index] = true);
+        perm.extend((0..msgs.len()).filter(|index| !seen[*index]));
+    }
+    apply_permutation(&perm, msgs);
+}
+
+/// Reorder `items` in place so that `items[i]` becomes the element that was at `perm[i]`.
+///
+/// # Panics
+///
+/// Panics if `perm` is not a permutation of `0..items.len()`.
+pub fn apply_permutation<T>(perm: &[usize], items: &mut [T]) {
+    assert_eq!(
+        perm.len(),
+        items.len(),
+        "The permutation and the slice must be the same length"
+    );
+
+    let mut done = vec![false; perm.len()];
+    for start in 0..perm.len() {
+        // Walk each cycle of the permutation once, swapping every element into place.
+        let mut current = start;
+        while !done[current] {
+            done[current] = true;
+            let next = perm[current];
+            assert!(
+                next < perm.len(),
+                "The permutation has an out of range index"
+            );
+            if next == start {
+                break;
+            }
+            assert!(!done[next], "The permutation has a repeated index");
+            items.swap(current, next);
+            current = next;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use crate::test_utils::thread;
+    use crate::{apply_permutation, causal_sort, causal_sort_in_place, causal_sort_permutation};
+
+    #[test]
+    fn permutation_reorders_parallel_slices() {
+        let unsorted = thread();
+        let perm = causal_sort_permutation(&unsorted[..]);
+        assert_eq!(perm, [2, 0, 1]);
+
+        let mut ids = [2, 1, 3];
+        let mut names = ["reply1", "root", "reply2"];
+        apply_permutation(&perm, &mut ids);
+        apply_permutation(&perm, &mut names);
+
+        assert_eq!(ids.to_vec(), causal_sort(&unsorted[..]));
+        assert_eq!(names, ["reply2", "reply1", "root"]);
+    }
+
+    #[test]
+    fn in_place_sorts_newest_first() {
+        let mut msgs = thread();
+        let duplicate = msgs[1].clone();
+        msgs.insert(0, (duplicate.0, 4, duplicate.2));
+
+        causal_sort_in_place(&mut msgs);
+
+        let ids: Vec<_> = msgs.iter().map(|(_, id, _)| *id).collect();
+        assert_eq!(ids, [3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn apply_permutation_handles_long_cycles() {
+        let mut items = ['a', 'b', 'c', 'd', 'e'];
+        apply_permutation(&[4, 0, 1, 2, 3], &mut items);
+        assert_eq!(items, ['e', 'a', 'b', 'c', 'd']);
+    }
+
+    #[test]
+    #[should_panic]
+    fn apply_permutation_rejects_repeats() {
+        let mut items = [1, 2, 3];
+        apply_permutation(&[0, 0, 1], &mut items);
+    }
+}
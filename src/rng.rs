@@ -0,0 +1,70 @@
+//! Random test data.
+use ssb_multiformats::multihash::Multihash;
+
+/// A small, seedable [splitmix64](https://prng.di.unimi.it/splitmix64.c) generator. Good enough
+/// for test data, and it keeps `rand` out of the dependencies.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`. `n` must not be zero.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// True with probability `p`.
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64) < p
+    }
+
+    pub(crate) fn hash(&mut self) -> Multihash {
+        let mut bytes = [0; 32];
+        bytes
+            .chunks_mut(8)
+            .for_each(|chunk| chunk.copy_from_slice(&self.next_u64().to_le_bytes()));
+        Multihash::Message(bytes)
+    }
+
+    pub(crate) fn text(&mut self) -> String {
+        const WORDS: &[&str] = &[
+            "scuttlebutt",
+            "gossip",
+            "pub",
+            "feed",
+            "thread",
+            "reply",
+            "offline",
+            "sync",
+            "peer",
+            "hello",
+            "the",
+            "a",
+            "boat",
+            "island",
+            "solar",
+            "garden",
+        ];
+        let len = self.below(20) + 1;
+        (0..len)
+            .map(|_| WORDS[self.below(WORDS.len())])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub(crate) fn shuffle<T>(&mut self, items: &mut [T]) {
+        (1..items.len())
+            .rev()
+            .for_each(|i| items.swap(i, self.below(i + 1)));
+    }
+}
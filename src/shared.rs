@@ -0,0 +1,200 @@
+//! A sorter that many threads can insert into and read from at once.
+use crate::dag::CausalDag;
+use crate::error::Error;
+use crate::graph::CausalGraph;
+use crate::sorted::SortedMessages;
+use arc_swap::ArcSwap;
+use ssb_multiformats::multihash::Multihash;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// A handle to a [`CausalDag`] that's shared between threads, that any of them can insert
+/// messages into and read a [`Snapshot`] of. Cloning the handle gives another handle to the
+/// same dag.
+///
+/// Inserts take turns, but reads never wait, either for an insert or for each other: each read
+/// gets the snapshot left by the last insert to finish. That makes inserts the expensive side,
+/// as each one copies the dag to leave a new snapshot while readers may still be using the old
+/// one, so [`extend`](SharedCausalSorter::extend) many messages at once where possible.
+pub struct SharedCausalSorter<K> {
+    inner: Arc<Inner<K>>,
+}
+
+struct Inner<K> {
+    /// The dag being inserted into, also held by the latest snapshot.
+    dag: Mutex<Arc<CausalDag<K>>>,
+    latest: ArcSwap<Snapshot<K>>,
+}
+
+/// The dag as it was after one insert into a [`SharedCausalSorter`], with its order found the
+/// first time it's asked for.
+pub struct Snapshot<K> {
+    dag: Arc<CausalDag<K>>,
+    sorted: OnceLock<SortedMessages<K>>,
+}
+
+impl<K: Clone> Snapshot<K> {
+    fn new(dag: Arc<CausalDag<K>>) -> Snapshot<K> {
+        Snapshot {
+            dag,
+            sorted: OnceLock::new(),
+        }
+    }
+
+    pub fn dag(&self) -> &CausalDag<K> {
+        &self.dag
+    }
+
+    /// The key ids of the messages, newest first, as [`CausalDag::sorted`] gives them. Sorted by
+    /// the first reader to ask, and shared with every other reader of the snapshot.
+    pub fn sorted(&self) -> &SortedMessages<K> {
+        self.sorted.get_or_init(|| self.dag.sorted())
+    }
+}
+
+impl<K> Clone for SharedCausalSorter<K> {
+    fn clone(&self) -> SharedCausalSorter<K> {
+        SharedCausalSorter {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K: Clone> SharedCausalSorter<K> {
+    /// A sorter with no messages yet.
+    pub fn new() -> SharedCausalSorter<K> {
+        SharedCausalSorter::from_dag(CausalDag::new(CausalGraph::new()))
+    }
+
+    /// Carry on inserting into `dag`, eg. one built from the messages already stored.
+    pub fn from_dag(dag: CausalDag<K>) -> SharedCausalSorter<K> {
+        let dag = Arc::new(dag);
+        SharedCausalSorter {
+            inner: Arc::new(Inner {
+                latest: ArcSwap::from_pointee(Snapshot::new(dag.clone())),
+                dag: Mutex::new(dag),
+            }),
+        }
+    }
+
+    /// Insert a message, as [`CausalDag::insert`] does, waiting for any other insert to finish
+    /// first. Readers see it from the next snapshot they take.
+    pub fn insert(&self, key: &Multihash, key_id: K, msg: &str) -> Result<(), Error> {
+        self.extend(&[(key.clone(), key_id, msg)])
+    }
+
+    /// Insert every one of `msgs`, in order, leaving one snapshot for them all. If one fails,
+    /// the ones before it are kept, and the snapshot has them.
+    pub fn extend<T: AsRef<str>>(&self, msgs: &[(Multihash, K, T)]) -> Result<(), Error> {
+        let mut dag = self
+            .inner
+            .dag
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let inserted = msgs.iter().try_for_each(|(key, key_id, msg)| {
+            Arc::make_mut(&mut dag).insert(key, key_id.clone(), msg.as_ref())
+        });
+        if !Arc::ptr_eq(&dag, &self.inner.latest.load().dag) {
+            self.inner
+                .latest
+                .store(Arc::new(Snapshot::new(dag.clone())));
+        }
+        inserted
+    }
+
+    /// Remove the message with `key`, as [`CausalDag::remove`] does, waiting for any insert to
+    /// finish first, and leave a snapshot without it. Returns the key ids of the messages that
+    /// now link to a message that isn't in the dag, or `None`, leaving the snapshot as it was, if
+    /// no message has `key`.
+    pub fn remove(&self, key: &Multihash) -> Option<SortedMessages<K>> {
+        let mut dag = self
+            .inner
+            .dag
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        dag.node(key).and_then(|node| dag.key_id(node))?;
+        let dangling = Arc::make_mut(&mut dag).remove(key);
+        self.inner
+            .latest
+            .store(Arc::new(Snapshot::new(dag.clone())));
+        dangling
+    }
+
+    /// The dag as it was after the last insert to finish. This never waits.
+    pub fn snapshot(&self) -> Arc<Snapshot<K>> {
+        self.inner.latest.load_full()
+    }
+}
+
+impl<K: Clone> Default for SharedCausalSorter<K> {
+    fn default() -> SharedCausalSorter<K> {
+        SharedCausalSorter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedCausalSorter;
+    use crate::test_utils::{feed, numbered};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn many_threads_insert_and_read() {
+        let sorter = SharedCausalSorter::new();
+        let msgs = feed(200);
+        std::thread::scope(|scope| {
+            for chunk in msgs.chunks(50) {
+                let sorter = sorter.clone();
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .for_each(|(key, key_id, msg)| sorter.insert(key, *key_id, msg).unwrap())
+                });
+            }
+            let reader = sorter.clone();
+            scope.spawn(move || {
+                let mut seen = 0;
+                while seen < 200 {
+                    let snapshot = reader.snapshot();
+                    let sorted = snapshot.sorted();
+                    assert!(sorted.len() >= seen);
+                    assert!(sorted.windows(2).all(|pair| pair[0] != pair[1]));
+                    seen = sorted.len();
+                }
+            });
+        });
+        let expected: Vec<_> = (1..=200).rev().collect();
+        assert_eq!(*sorter.snapshot().sorted(), expected);
+    }
+
+    #[test]
+    fn snapshots_are_only_left_by_inserts() {
+        let sorter = SharedCausalSorter::new();
+        let before = sorter.snapshot();
+        sorter.extend(&feed(3)).unwrap();
+        assert!(before.sorted().is_empty());
+        assert_eq!(*sorter.snapshot().sorted(), [3, 2, 1]);
+
+        let cycle = (
+            numbered(1),
+            4,
+            json!({ "previous": numbered(3) }).to_string(),
+        );
+        assert!(sorter.extend(&[cycle]).is_err());
+        assert_eq!(*sorter.snapshot().sorted(), [3, 2, 1]);
+    }
+
+    #[test]
+    fn removals_leave_a_snapshot() {
+        let sorter = SharedCausalSorter::new();
+        sorter.extend(&feed(3)).unwrap();
+        let before = sorter.snapshot();
+        assert_eq!(sorter.remove(&numbered(2)).unwrap(), [3]);
+        assert_eq!(*before.sorted(), [3, 2, 1]);
+        assert_eq!(*sorter.snapshot().sorted(), [3, 1]);
+
+        let after = sorter.snapshot();
+        assert!(sorter.remove(&numbered(2)).is_none());
+        assert!(Arc::ptr_eq(&after, &sorter.snapshot()));
+    }
+}
@@ -0,0 +1,132 @@
+//! Link extraction using [simd-json](https://docs.rs/simd-json) instead of serde_json.
+//!
+//! This must find exactly the same links, in exactly the same order, as the serde_json path. The
+//! order links are found in decides the order nodes are added to the dag, which decides where
+//! concurrent messages end up in the sort.
+use crate::extract::{too_deep, Failure, Found, LinkOptions, Part, Scalar, RECURSION_LIMIT};
+use simd_json::BorrowedValue as Value;
+use simd_json::StaticNode;
+use ssb_multiformats::multihash::Multihash;
+use std::convert::TryFrom;
+
+pub(crate) fn extract_refs_into(
+    msg: &[u8],
+    options: &LinkOptions,
+    refs: &mut Vec<Multihash>,
+) -> Result<Found, Failure> {
+    // simd-json parses in place, so it needs its own copy of the message.
+    let mut buf = msg.to_vec();
+    let found = refs.len();
+
+    let parsed = match simd_json::to_borrowed_value(&mut buf) {
+        // serde_json refuses to parse messages nested too deep, so we ignore them too.
+        Ok(value) => {
+            let walked = match options.part(|path| value_at(&value, path).is_some()) {
+                Part::Whole => find_all_links(&value, options, refs, 1),
+                // The rest of the message mustn't be too deep either.
+                _ if too_deep(msg) => None,
+                Part::Nothing => Some(()),
+                Part::At(path) => value_at(&value, path).map_or(Some(()), |part| {
+                    find_all_links(part, options, refs, path.len() + 1)
+                }),
+            };
+            walked
+                .ok_or(Failure::TooDeep)
+                .map(|()| options.find(|path| scalar_at(&value, path)))
+        }
+        Err(error) => Err(Failure::of(msg, error)),
+    };
+    if !matches!(parsed, Ok(Found { kept: true, .. })) {
+        refs.truncate(found);
+    }
+    parsed
+}
+
+fn value_at<'a, 'v>(value: &'a Value<'v>, path: &[&str]) -> Option<&'a Value<'v>> {
+    path.iter().try_fold(value, |value, field| match value {
+        // `get` would find the first of a repeated key, where serde_json keeps the last.
+        Value::Object(kv) => kv
+            .iter()
+            .filter(|&(key, _)| key == field)
+            .map(|(_, value)| value)
+            .last(),
+        _ => None,
+    })
+}
+
+fn scalar_at<'a>(value: &'a Value, path: &[&str]) -> Option<Scalar<'a>> {
+    match value_at(value, path)? {
+        Value::String(st) => Some(Scalar::Str(st)),
+        // simd-json parses integers that fit as an i64, where serde_json would use a u64.
+        Value::Static(StaticNode::I64(n)) => match u64::try_from(*n) {
+            Ok(n) => Some(Scalar::Uint(n)),
+            Err(_) => Some(Scalar::Float(*n as f64)),
+        },
+        Value::Static(StaticNode::U64(n)) => Some(Scalar::Uint(*n)),
+        Value::Static(StaticNode::F64(n)) => Some(Scalar::Float(*n)),
+        _ => None,
+    }
+}
+
+/// Returns `None` if the value is nested too deep.
+fn find_all_links(
+    obj: &Value,
+    options: &LinkOptions,
+    keys: &mut Vec<Multihash>,
+    depth: usize,
+) -> Option<()> {
+    match obj {
+        Value::String(st) => {
+            keys.extend(options.link(st));
+        }
+        Value::Array(arr) => {
+            if depth >= RECURSION_LIMIT {
+                return None;
+            }
+            for val in arr.iter() {
+                find_all_links(val, options, keys, depth + 1)?;
+            }
+        }
+        Value::Object(kv) => {
+            if depth >= RECURSION_LIMIT {
+                return None;
+            }
+            // serde_json's map iterates in key order, keeping the last of any repeated key.
+            // simd-json's iterates in insertion order and keeps all of them.
+            let mut entries: Vec<_> = kv.iter().collect();
+            entries.reverse();
+            entries.sort_by_key(|&(key, _)| key);
+            entries.dedup_by(|(later, _), (kept, _)| later == kept);
+            for (key, val) in entries {
+                if !options.skips(key) {
+                    find_all_links(val, options, keys, depth + 1)?;
+                }
+            }
+        }
+        Value::Static(_) => (),
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_refs_into;
+    use crate::extract::{LinkOptions, RECURSION_LIMIT};
+    use crate::test_utils::assert_extractor_matches_every_case;
+
+    #[test]
+    fn matches_serde_json() {
+        assert_extractor_matches_every_case(extract_refs_into);
+    }
+
+    #[test]
+    fn stops_at_the_recursion_limit() {
+        let link = "\"%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256\"";
+        for depth in RECURSION_LIMIT - 2..RECURSION_LIMIT + 2 {
+            let msg = format!("{}{}{}", "[".repeat(depth), link, "]".repeat(depth));
+            let mut refs = Vec::new();
+            let _ = extract_refs_into(msg.as_bytes(), &LinkOptions::default(), &mut refs);
+            assert_eq!(refs.is_empty(), depth >= RECURSION_LIMIT);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! The result of a sort.
+#[cfg(feature = "json")]
+use crate::dag::CausalDag;
+#[cfg(feature = "json")]
+use crate::extract::Extractor;
+#[cfg(feature = "json")]
+use ssb_multiformats::multihash::Multihash;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::OnceLock;
+
+/// Key ids in causal order, newest first, as returned by the sorts.
+///
+/// Derefs to a slice, so it can be indexed, iterated and compared like the `Vec` it wraps. Use
+/// [`into_vec`](SortedMessages::into_vec) to take the `Vec` back out.
+#[derive(Clone, Default)]
+pub struct SortedMessages<K> {
+    order: Vec<K>,
+    /// The position of each key id, built the first time one is looked up by `position_of`.
+    positions: OnceLock<HashMap<K, usize>>,
+}
+
+impl<K: fmt::Debug> fmt::Debug for SortedMessages<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SortedMessages")
+            .field("order", &self.order)
+            .finish()
+    }
+}
+
+impl<K: PartialEq> PartialEq for SortedMessages<K> {
+    fn eq(&self, other: &SortedMessages<K>) -> bool {
+        self.order == other.order
+    }
+}
+
+impl<K: Eq> Eq for SortedMessages<K> {}
+
+impl<K: Hash> Hash for SortedMessages<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.order.hash(state)
+    }
+}
+
+impl<K> SortedMessages<K> {
+    /// The key ids, newest first. The iterator is double-ended, so `iter().rev().take(n)` gives
+    /// the oldest `n` without copying or reversing the rest.
+    pub fn iter(&self) -> std::slice::Iter<'_, K> {
+        self.order.iter()
+    }
+
+    /// The key ids, oldest first.
+    pub fn rev(&self) -> std::iter::Rev<std::slice::Iter<'_, K>> {
+        self.order.iter().rev()
+    }
+
+    /// Where `key_id` is in the order, counting from the newest message at 0, eg. to scroll to a
+    /// message once it's sorted. It's looked up in a map of every position, built the first time
+    /// it's needed, rather than by searching the order. To look up a message by its key, sort
+    /// with the keys as the key ids, so that this takes a `&Multihash`.
+    pub fn position_of(&self, key_id: &K) -> Option<usize>
+    where
+        K: Hash + Eq + Clone,
+    {
+        self.positions().get(key_id).copied()
+    }
+
+    /// Whether `key_id` is in the order, looked up like
+    /// [`position_of`](SortedMessages::position_of).
+    pub fn contains(&self, key_id: &K) -> bool
+    where
+        K: Hash + Eq + Clone,
+    {
+        self.positions().contains_key(key_id)
+    }
+
+    fn positions(&self) -> &HashMap<K, usize>
+    where
+        K: Hash + Eq + Clone,
+    {
+        self.positions.get_or_init(|| {
+            let mut positions = HashMap::with_capacity(self.order.len());
+            self.order
+                .iter()
+                .enumerate()
+                .for_each(|(position, key_id)| {
+                    positions.entry(key_id.clone()).or_insert(position);
+                });
+            positions
+        })
+    }
+
+    /// Up to `n` key ids, starting at position `cursor`. Empty once `cursor` is past the end.
+    pub fn page(&self, cursor: usize, n: usize) -> &[K] {
+        let start = cursor.min(self.order.len());
+        let end = start.saturating_add(n).min(self.order.len());
+        &self.order[start..end]
+    }
+
+    /// Up to `n` key ids following the one `cursor` names, or from the newest message if `cursor`
+    /// is `None`. `None` if `cursor` isn't in the order.
+    ///
+    /// Pass the returned page's `cursor` to get the next page. Because the cursor names a message
+    /// rather than a position, a page follows on from the last one even if the messages have been
+    /// sorted again with new ones added in between. New messages that sort before the cursor
+    /// aren't returned, so concurrent messages must sort consistently for paging through them to
+    /// see each once.
+    ///
+    /// Cursors are key ids, so to page by key, sort with the keys as the key ids. They're looked
+    /// up like [`position_of`](SortedMessages::position_of). With `n` of 0 the page is empty and its
+    /// cursor is the one passed in.
+    pub fn page_after(&self, cursor: Option<&K>, n: usize) -> Option<Page<'_, K>>
+    where
+        K: Hash + Eq + Clone,
+    {
+        let start = match cursor {
+            Some(cursor) => self.position_of(cursor)? + 1,
+            None => 0,
+        };
+        let messages = self.page(start, n);
+        if n == 0 {
+            return Some(Page {
+                messages,
+                cursor: start.checked_sub(1).map(|at| &self.order[at]),
+            });
+        }
+        Some(Page {
+            messages,
+            cursor: messages
+                .last()
+                .filter(|_| start + messages.len() < self.order.len()),
+        })
+    }
+
+    /// Each key id's rank: its position counting from the oldest message at 0, so that newer
+    /// messages rank higher. Stored as a column, sorting by rank descending gives this order.
+    pub fn ranks(&self) -> HashMap<K, u64>
+    where
+        K: Hash + Eq + Clone,
+    {
+        self.rev()
+            .enumerate()
+            .map(|(rank, key_id)| (key_id.clone(), rank as u64))
+            .collect()
+    }
+
+    /// Where a new message with `key` and body `msg` slots into this order, as the index to insert
+    /// its key id at, without sorting again. `dag` holds the messages that were sorted.
+    ///
+    /// The message goes after every message in the dag that links to it, and before every one it
+    /// links to, as newest as it can. `None` if its links make a cycle, so it has no place.
+    #[cfg(feature = "json")]
+    pub fn insertion_point<T: AsRef<str>>(
+        &self,
+        dag: &CausalDag<K>,
+        key: &Multihash,
+        msg: T,
+    ) -> Option<usize>
+    where
+        K: PartialEq + Clone,
+    {
+        let key_ids = |nodes: &mut dyn Iterator<Item = _>| -> Vec<K> {
+            nodes.filter_map(|node| dag.key_id(node)).cloned().collect()
+        };
+        let newer = match dag.node(key) {
+            Some(node) => key_ids(&mut dag.linked_from(node).map(|(_, from)| from)),
+            None => Vec::new(),
+        };
+        let mut extractor = Extractor::new();
+        let links = extractor
+            .extract(msg.as_ref().as_bytes())
+            .unwrap_or_default();
+        let older = key_ids(&mut links.iter().filter_map(|link| dag.node(link)));
+
+        let mut point = 0;
+        for (index, key_id) in self.order.iter().enumerate() {
+            if older.contains(key_id) {
+                // The message is newer than this one, so nothing linking to it can come after.
+                return Some(point)
+                    .filter(|_| !self.order[index..].iter().any(|k| newer.contains(k)));
+            }
+            if newer.contains(key_id) {
+                point = index + 1;
+            }
+        }
+        Some(point)
+    }
+
+    pub fn as_slice(&self) -> &[K] {
+        &self.order
+    }
+
+    pub fn into_vec(self) -> Vec<K> {
+        self.order
+    }
+}
+
+/// One page of [`SortedMessages::page_after`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Page<'a, K> {
+    /// The key ids on this page, newest first.
+    pub messages: &'a [K],
+    /// The cursor for the next page, or `None` if this is the last one.
+    pub cursor: Option<&'a K>,
+}
+
+impl<K> From<Vec<K>> for SortedMessages<K> {
+    fn from(order: Vec<K>) -> SortedMessages<K> {
+        SortedMessages {
+            order,
+            positions: OnceLock::new(),
+        }
+    }
+}
+
+impl<K> From<SortedMessages<K>> for Vec<K> {
+    fn from(sorted: SortedMessages<K>) -> Vec<K> {
+        sorted.order
+    }
+}
+
+impl<K> Deref for SortedMessages<K> {
+    type Target = [K];
+
+    fn deref(&self) -> &[K] {
+        &self.order
+    }
+}
+
+impl<K> IntoIterator for SortedMessages<K> {
+    type Item = K;
+    type IntoIter = std::vec::IntoIter<K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.order.into_iter()
+    }
+}
+
+impl<'a, K> IntoIterator for &'a SortedMessages<K> {
+    type Item = &'a K;
+    type IntoIter = std::slice::Iter<'a, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.order.iter()
+    }
+}
+
+impl<K: PartialEq<U>, U> PartialEq<Vec<U>> for SortedMessages<K> {
+    fn eq(&self, other: &Vec<U>) -> bool {
+        self.order == *other
+    }
+}
+
+impl<K: PartialEq<U>, U> PartialEq<[U]> for SortedMessages<K> {
+    fn eq(&self, other: &[U]) -> bool {
+        self.order == other
+    }
+}
+
+impl<K: PartialEq<U>, U, const N: usize> PartialEq<[U; N]> for SortedMessages<K> {
+    fn eq(&self, other: &[U; N]) -> bool {
+        self.order == other
+    }
+}
+
+impl<K, U: PartialEq<K>> PartialEq<SortedMessages<K>> for Vec<U> {
+    fn eq(&self, other: &SortedMessages<K>) -> bool {
+        *self == other.order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Page, SortedMessages};
+    #[cfg(feature = "json")]
+    use crate::test_utils::{numbered, thread};
+    #[cfg(feature = "json")]
+    use crate::{causal_sort, verify_causal_order, CausalDag};
+    #[cfg(feature = "json")]
+    use serde_json::json;
+
+    #[test]
+    fn pages_stop_at_the_end() {
+        let sorted = SortedMessages::from(vec![5, 4, 3, 2, 1]);
+        assert_eq!(sorted.page(0, 2), [5, 4]);
+        assert_eq!(sorted.page(4, 2), [1]);
+        assert!(sorted.page(9, 2).is_empty());
+        assert_eq!(sorted.page(1, usize::MAX), [4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn sorted_messages_look_like_a_vec() {
+        let sorted = SortedMessages::from(vec![3, 2, 1]);
+        assert_eq!(sorted.position_of(&2), Some(1));
+        assert_eq!(sorted.position_of(&4), None);
+        assert!(sorted.contains(&3) && !sorted.contains(&4));
+        assert_eq!(sorted.rev().copied().collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(sorted, vec![3, 2, 1]);
+        assert_eq!(vec![3, 2, 1], sorted);
+        assert_eq!(sorted[0], 3);
+        assert_eq!(sorted.ranks()[&3], 2);
+        assert_eq!(sorted.clone().into_vec(), Vec::from(sorted));
+    }
+
+    #[test]
+    fn iterators_take_from_both_ends() {
+        let sorted = SortedMessages::from(vec![5, 4, 3, 2, 1]);
+        let mut iter = sorted.iter();
+        assert_eq!(iter.next(), Some(&5));
+        assert_eq!(iter.next_back(), Some(&1));
+        assert_eq!(iter.len(), 3);
+        assert_eq!(sorted.rev().take(2).collect::<Vec<_>>(), [&1, &2]);
+        let owned: Vec<_> = sorted.into_iter().rev().take(2).collect();
+        assert_eq!(owned, [1, 2]);
+    }
+
+    #[test]
+    fn page_after_follows_the_cursor() {
+        let sorted = SortedMessages::from(vec![5, 4, 3, 2, 1]);
+        let first = sorted.page_after(None, 2).unwrap();
+        assert_eq!(first.messages, [5, 4]);
+        let second = sorted.page_after(first.cursor, 2).unwrap();
+        assert_eq!(second.messages, [3, 2]);
+        let last = sorted.page_after(second.cursor, 2).unwrap();
+        assert_eq!(
+            last,
+            Page {
+                messages: &[1],
+                cursor: None
+            }
+        );
+        assert!(sorted.page_after(Some(&9), 2).is_none());
+
+        // The end of the order exactly.
+        let whole = sorted.page_after(None, 5).unwrap();
+        assert_eq!((whole.messages.len(), whole.cursor), (5, None));
+
+        // Empty pages don't move the cursor, even at the end.
+        let empty = sorted.page_after(first.cursor, 0).unwrap();
+        assert_eq!((empty.messages.len(), empty.cursor), (0, Some(&4)));
+        let empty = sorted.page_after(Some(&1), 0).unwrap();
+        assert_eq!(empty.cursor, Some(&1));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn page_after_survives_new_messages() {
+        use ssb_multiformats::multihash::Multihash;
+
+        let by_key = |msgs: &[(Multihash, u32, String)]| {
+            let keyed: Vec<_> = msgs
+                .iter()
+                .map(|(key, _, msg)| (key.clone(), key.clone(), msg.clone()))
+                .collect();
+            causal_sort(&keyed)
+        };
+        let mut msgs = thread();
+        let sorted = by_key(&msgs);
+        let first = sorted.page_after(None, 1).unwrap();
+        let cursor = first.cursor.cloned();
+
+        let root = &msgs[1].0;
+        let reply = json!({ "root": root }).to_string();
+        msgs.push((numbered(4), 4, reply));
+        let resorted = by_key(&msgs);
+        assert_eq!(resorted.position_of(&msgs[1].0), Some(3));
+        assert_eq!(
+            resorted.page_after(cursor.as_ref(), 5).unwrap().messages,
+            sorted.page_after(cursor.as_ref(), 5).unwrap().messages
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn insertion_points_keep_the_order_causal() {
+        let mut msgs = thread();
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        let sorted = dag.sorted();
+        assert_eq!(sorted, [3, 2, 1]);
+        let (root, reply) = (msgs[1].0.clone(), msgs[0].0.clone());
+
+        // Something new linking to the root goes first, as newest.
+        let new = json!({ "root": root }).to_string();
+        assert_eq!(sorted.insertion_point(&dag, &numbered(4), &new), Some(0));
+
+        // The dag already links a message to a key that hadn't been sorted.
+        msgs.push((numbered(5), 5, json!({ "root": numbered(4) }).to_string()));
+        let dag = CausalDag::from_msgs(&msgs).unwrap();
+        let sorted = dag.sorted();
+        let point = sorted.insertion_point(&dag, &numbered(4), &new).unwrap();
+        let mut inserted = sorted.clone().into_vec();
+        inserted.insert(point, 4);
+        msgs.push((numbered(4), 4, new));
+        assert!(verify_causal_order(&msgs, &inserted).is_ok());
+        assert_eq!(inserted.len(), causal_sort(&msgs).len());
+
+        // Linking to a message that's after one linking to it has no place.
+        let cycle = json!({ "previous": reply }).to_string();
+        assert_eq!(sorted.insertion_point(&dag, &root, &cycle), None);
+    }
+}
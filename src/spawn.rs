@@ -0,0 +1,81 @@
+//! Sorting from async code, on [tokio](https://docs.rs/tokio)'s blocking threads.
+use crate::builder::SortBuilder;
+use crate::error::Error;
+use crate::sorted::SortedMessages;
+use ssb_multiformats::multihash::Multihash;
+use tokio::task::JoinHandle;
+
+/// Causally sort `msgs` on one of tokio's blocking threads, as [`try_causal_sort`] does, so that
+/// a sort doesn't hold up the other tasks on the runtime, eg.
+/// `let sorted = spawn_sort(msgs).await??;`.
+///
+/// With no hooks to call, this sort doesn't panic, so the handle's `JoinError` is only ever for
+/// a runtime that's shutting down. A sort from [`SortBuilder::spawn_sort`] can also end with a
+/// `JoinError` for a panic in one of the builder's hooks, eg. a [`Validate`]. To be able to stop
+/// a sort, use [`SortBuilder::spawn_sort`] with a [`cancellation`](SortBuilder::cancellation).
+///
+/// # Panics
+///
+/// Panics if called outside a tokio runtime.
+///
+/// [`try_causal_sort`]: crate::try_causal_sort
+/// [`Validate`]: crate::Validate
+pub fn spawn_sort<T, K>(
+    msgs: Vec<(Multihash, K, T)>,
+) -> JoinHandle<Result<SortedMessages<K>, Error>>
+where
+    T: AsRef<str> + Send + 'static,
+    K: Clone + Send + 'static,
+{
+    SortBuilder::new().spawn_sort(msgs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spawn_sort;
+    use crate::test_utils::thread;
+    use crate::{causal_sort, Error, InvalidMessages, SortBuilder, Validate};
+    use ssb_multiformats::multihash::Multihash;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    struct Panics;
+
+    impl Validate for Panics {
+        fn validate(
+            &self,
+            _key: &Multihash,
+            _msg: &[u8],
+        ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            panic!("validate hook panicked")
+        }
+    }
+
+    #[test]
+    fn sorts_run_on_blocking_threads() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let sorted = runtime
+            .block_on(async { spawn_sort(thread()).await })
+            .unwrap()
+            .unwrap();
+        assert_eq!(sorted, causal_sort(&thread()));
+
+        let cancelled = SortBuilder::new().cancellation(Arc::new(AtomicBool::new(true)));
+        let sorted = runtime
+            .block_on(async { cancelled.spawn_sort(thread()).await })
+            .unwrap();
+        assert!(matches!(sorted, Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn panicking_hooks_are_join_errors() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let builder = SortBuilder::new().validate(Arc::new(Panics), InvalidMessages::Exclude);
+        let joined = runtime.block_on(async { builder.spawn_sort(thread()).await });
+        assert!(joined.unwrap_err().is_panic());
+    }
+}
@@ -0,0 +1,339 @@
+//! Splicing new messages into an order that was sorted before, without sorting it all again.
+use crate::error::Error;
+use crate::extract::Extractor;
+use crate::sorted::SortedMessages;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// An order of messages with their links, kept so that new messages can be spliced into it with
+/// [`extend_sorted`](LinkTable::extend_sorted) rather than sorting every message again.
+///
+/// Build one from an order sorted earlier with [`new`](LinkTable::new), eg. from the order and
+/// links persisted yesterday, then extend it with each batch of new messages.
+#[derive(Clone, Debug)]
+pub struct LinkTable<K> {
+    /// The messages, oldest first.
+    entries: Vec<Entry<K>>,
+    /// Each message's index in `entries`.
+    ranks: HashMap<Multihash, usize>,
+    /// The hashes that messages link to but that aren't in the table, with the keys of the
+    /// messages that link to them.
+    waiting: HashMap<Multihash, Vec<Multihash>>,
+}
+
+/// The part of a [`LinkTable`]'s order that [`extend_sorted`](LinkTable::extend_sorted) changed.
+///
+/// Only the newest messages are spliced or moved, so the rest of the order is left as it was:
+/// the new order is `newest` followed by the oldest `kept` messages of the order before.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spliced<K> {
+    /// The key ids of the messages that are new or have moved, newest first.
+    pub newest: Vec<K>,
+    /// How many of the oldest messages kept their places.
+    pub kept: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Entry<K> {
+    key: Multihash,
+    key_id: K,
+    links: Vec<Multihash>,
+}
+
+impl<K: Clone> LinkTable<K> {
+    /// The table of `msgs`, whose links have already been found, in the order `sorted`, newest
+    /// first, eg. as [`causal_sort_links`](crate::causal_sort_links) returned it. `sorted` isn't
+    /// checked, so it must be a causal order of the messages. Messages that aren't in it are
+    /// left out.
+    pub fn new<L: AsRef<[Multihash]>>(msgs: &[(Multihash, K, L)], sorted: &[K]) -> LinkTable<K>
+    where
+        K: Hash + Eq,
+    {
+        let mut by_id = HashMap::with_capacity(msgs.len());
+        for (key, key_id, links) in msgs {
+            by_id.entry(key_id).or_insert((key, links.as_ref()));
+        }
+        let entries = sorted
+            .iter()
+            .rev()
+            .filter_map(|key_id| {
+                let (key, links) = by_id.remove(key_id)?;
+                Some(Entry {
+                    key: key.clone(),
+                    key_id: key_id.clone(),
+                    links: links.to_vec(),
+                })
+            })
+            .collect();
+        LinkTable::from_entries(entries)
+    }
+
+    fn from_entries(entries: Vec<Entry<K>>) -> LinkTable<K> {
+        let ranks: HashMap<_, _> = entries
+            .iter()
+            .enumerate()
+            .map(|(rank, entry)| (entry.key.clone(), rank))
+            .collect();
+        let mut waiting: HashMap<_, Vec<_>> = HashMap::new();
+        for entry in &entries {
+            for link in entry.links.iter().filter(|link| !ranks.contains_key(*link)) {
+                waiting
+                    .entry(link.clone())
+                    .or_default()
+                    .push(entry.key.clone());
+            }
+        }
+        LinkTable {
+            entries,
+            ranks,
+            waiting,
+        }
+    }
+
+    /// The key ids, newest first. This copies the whole order, so to look through it after each
+    /// [`extend_sorted`](LinkTable::extend_sorted), use [`iter`](LinkTable::iter).
+    pub fn sorted(&self) -> SortedMessages<K> {
+        self.iter().cloned().collect::<Vec<_>>().into()
+    }
+
+    /// The key ids, newest first, without copying them.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &K> + ExactSizeIterator + '_ {
+        self.entries.iter().rev().map(|entry| &entry.key_id)
+    }
+
+    /// What's changed in the order if every message from `first` in `entries` is new or moved.
+    fn spliced(&self, first: usize) -> Spliced<K> {
+        Spliced {
+            newest: self.iter().take(self.len() - first).cloned().collect(),
+            kept: first,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Splice `msgs` into the order, finding their links in their JSON bodies as
+    /// [`causal_sort`](crate::causal_sort) does, and return the part of the order that changed.
+    ///
+    /// Each new message goes as new in the order as it can: just older than the oldest message
+    /// that links to it, or newest of all if nothing does. Besides copying out the new order,
+    /// only the messages newer than where the oldest of them goes are moved, so adding a day's
+    /// messages to a long history doesn't sort, look through or copy the rest of it. Messages
+    /// with keys already in the table are left out.
+    ///
+    /// A message can only be spliced in like this if the messages it links to are all older than
+    /// the ones that link to it. If not, eg. when a message that was missing arrives linking to
+    /// newer ones, every message is sorted again from the table's links, and none keep their
+    /// places. The table is left as it was if this fails, eg. with `Error::Cycle`.
+    pub fn extend_sorted<T: AsRef<str>>(
+        &mut self,
+        msgs: &[(Multihash, K, T)],
+    ) -> Result<Spliced<K>, Error> {
+        let mut extractor = Extractor::new();
+        let mut seen = HashSet::new();
+        let mut new = Vec::new();
+        for (key, key_id, msg) in msgs {
+            if self.ranks.contains_key(key) || !seen.insert(key) {
+                continue;
+            }
+            let links = extractor
+                .extract(msg.as_ref().as_bytes())
+                .unwrap_or_default();
+            new.push(Entry {
+                key: key.clone(),
+                key_id: key_id.clone(),
+                links: links.to_vec(),
+            });
+        }
+        if new.is_empty() {
+            return Ok(self.spliced(self.len()));
+        }
+
+        let links: Vec<_> = new
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.key.clone(), index, entry.links.as_slice()))
+            .collect();
+        let order = crate::try_causal_sort_links(&links)?;
+
+        // How many of the table's messages are older than each new one, newest first so that
+        // every message that links to one is placed before it.
+        let index_of: HashMap<Multihash, usize> = new
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.key.clone(), index))
+            .collect();
+        let mut slots = vec![self.len(); new.len()];
+        for &index in order.iter() {
+            let entry = &new[index];
+            let linking = self.waiting.get(&entry.key).into_iter().flatten();
+            let slot = linking
+                .map(|key| self.ranks[key])
+                .fold(slots[index], usize::min);
+            let oldest = entry
+                .links
+                .iter()
+                .filter_map(|link| self.ranks.get(link))
+                .map(|rank| rank + 1)
+                .max()
+                .unwrap_or(0);
+            if oldest > slot {
+                return self.sort_again(new);
+            }
+            slots[index] = slot;
+            for linked in entry.links.iter().filter_map(|link| index_of.get(link)) {
+                slots[*linked] = slots[*linked].min(slot);
+            }
+        }
+
+        new.iter().for_each(|entry| {
+            self.waiting.remove(&entry.key);
+        });
+        let mut placed: Vec<_> = order
+            .iter()
+            .rev()
+            .map(|index| (slots[*index], *index))
+            .collect();
+        placed.sort_by_key(|(slot, _)| *slot);
+        let (first, len) = (placed[0].0, self.len());
+        let mut placed = placed.into_iter().peekable();
+        let mut new: Vec<_> = new.into_iter().map(Some).collect();
+        let mut moved = self.entries.split_off(first).into_iter();
+        for slot in first..=len {
+            while let Some((_, index)) = placed.next_if(|(placed, _)| *placed == slot) {
+                self.entries.extend(new[index].take());
+            }
+            self.entries.extend(moved.next());
+        }
+
+        for (rank, entry) in self.entries.iter().enumerate().skip(first) {
+            self.ranks.insert(entry.key.clone(), rank);
+        }
+        let (ranks, waiting) = (&self.ranks, &mut self.waiting);
+        for entry in self.entries[first..]
+            .iter()
+            .filter(|entry| index_of.contains_key(&entry.key))
+        {
+            for link in entry.links.iter().filter(|link| !ranks.contains_key(*link)) {
+                waiting
+                    .entry(link.clone())
+                    .or_default()
+                    .push(entry.key.clone());
+            }
+        }
+        Ok(self.spliced(first))
+    }
+
+    /// Sort the table's messages and `new` together from their links.
+    fn sort_again(&mut self, new: Vec<Entry<K>>) -> Result<Spliced<K>, Error> {
+        let all: Vec<_> = self.entries.iter().cloned().chain(new).collect();
+        let links: Vec<_> = all
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.key.clone(), index, entry.links.as_slice()))
+            .collect();
+        let order = crate::try_causal_sort_links(&links)?;
+        let mut all: Vec<_> = all.into_iter().map(Some).collect();
+        let entries = order.iter().rev().filter_map(|index| all[*index].take());
+        *self = LinkTable::from_entries(entries.collect());
+        Ok(self.spliced(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LinkTable;
+    use crate::extract::Extractor;
+    use crate::test_utils::branching;
+    use crate::{causal_sort_links, verify_causal_order, Error};
+    use ssb_multiformats::multihash::Multihash;
+
+    /// The table of `msgs` in the order `sorted`, or as they sort if that's empty.
+    fn table(msgs: &[(Multihash, usize, String)], sorted: &[usize]) -> LinkTable<usize> {
+        let links: Vec<_> = msgs
+            .iter()
+            .map(|(key, key_id, msg)| {
+                (
+                    key.clone(),
+                    *key_id,
+                    Extractor::new().extract(msg.as_bytes()).unwrap().to_vec(),
+                )
+            })
+            .collect();
+        match sorted {
+            [] => LinkTable::new(&links, &causal_sort_links(&links)),
+            sorted => LinkTable::new(&links, sorted),
+        }
+    }
+
+    #[test]
+    fn new_messages_are_spliced_in() {
+        let mut msgs: Vec<_> = (1..=20).map(|i| branching(i, &[i - 1])).collect();
+        let mut table = table(&msgs, &[]);
+        assert_eq!(table.len(), 20);
+
+        let new = vec![
+            branching(21, &[20]),
+            branching(22, &[5]),
+            branching(23, &[21, 22]),
+        ];
+        let before = table.sorted();
+        let spliced = table.extend_sorted(&new).unwrap();
+        assert_eq!((&spliced.newest[..], spliced.kept), (&[23, 21, 22][..], 20));
+        let sorted = table.sorted();
+        assert_eq!(sorted, [&spliced.newest[..], &before[..]].concat());
+        assert!(table.iter().eq(sorted.iter()));
+        msgs.extend(new);
+        assert_eq!(verify_causal_order(&msgs, &sorted), Ok(()));
+
+        // One more, and one that's already in the table.
+        let new = vec![branching(24, &[23]), branching(3, &[])];
+        let spliced = table.extend_sorted(&new).unwrap();
+        assert_eq!((&spliced.newest[..], spliced.kept), (&[24][..], 23));
+        let sorted = table.sorted();
+        assert_eq!((sorted.len(), sorted[0]), (24, 24));
+        msgs.push(new[0].clone());
+        assert_eq!(verify_causal_order(&msgs, &sorted), Ok(()));
+        let spliced = table.extend_sorted(&new[1..]).unwrap();
+        assert_eq!((spliced.newest.len(), spliced.kept), (0, 24));
+    }
+
+    #[test]
+    fn messages_that_were_missing_go_before_their_replies() {
+        // 2 links to 9, which hasn't arrived.
+        let mut msgs = vec![branching(1, &[]), branching(2, &[9]), branching(3, &[2])];
+        let mut spliced = table(&msgs, &[3, 2, 1]);
+        let changed = spliced.extend_sorted(&[branching(9, &[1])]).unwrap();
+        assert_eq!((&changed.newest[..], changed.kept), (&[3, 2, 9][..], 1));
+        assert_eq!(spliced.sorted(), [3, 2, 9, 1]);
+
+        // With 1 ordered newer than 2, 9 can only go between them by moving 1, so they're all
+        // sorted again.
+        let mut sorted_again = table(&msgs, &[1, 3, 2]);
+        let changed = sorted_again.extend_sorted(&[branching(9, &[1])]).unwrap();
+        assert_eq!((changed.newest.len(), changed.kept), (4, 0));
+        msgs.push(branching(9, &[1]));
+        assert_eq!(verify_causal_order(&msgs, &changed.newest), Ok(()));
+        sorted_again.extend_sorted(&[branching(4, &[3])]).unwrap();
+        let sorted = sorted_again.sorted();
+        assert_eq!(sorted.len(), 5);
+        assert_eq!(sorted[0], 4);
+    }
+
+    #[test]
+    fn cycles_leave_the_table_as_it_was() {
+        let msgs = vec![branching(1, &[]), branching(2, &[9]), branching(3, &[2])];
+        let mut table = table(&msgs, &[3, 2, 1]);
+        assert!(matches!(
+            table.extend_sorted(&[branching(9, &[3])]),
+            Err(Error::Cycle { .. })
+        ));
+        assert_eq!(table.sorted(), [3, 2, 1]);
+    }
+}
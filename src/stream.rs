@@ -0,0 +1,311 @@
+//! Finding the links in a message as it's parsed, without building a `serde_json::Value`.
+//!
+//! A `Value` holds every string in a message, including the text of posts, which is usually most
+//! of it, only for them all to be freed once the links have been found. Here serde_json's
+//! deserializer is driven with visitors that keep only what the options can look at: the top few
+//! levels of objects, where the type, author, sequence number, timestamp, content and `previous`
+//! link are, and the links anywhere in the message. Every other string is dropped as soon as it's
+//! been checked for being a link, so a message never takes much more memory than its links.
+//!
+//! The links found must be the same, in the same order, as when parsing into a `Value`. As in the
+//! arena backend, each object's fields are sorted by key, keeping the last of any repeated key,
+//! before the links in them are collected.
+use crate::extract::{Found, LinkOptions, Part, Scalar};
+use serde::de::{DeserializeSeed, Deserializer, Error, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use ssb_multiformats::multihash::Multihash;
+use std::borrow::Cow;
+use std::fmt;
+
+/// How many levels of objects to keep, enough for the longest path the options look up,
+/// `value.content.type`.
+const LEVELS: usize = 3;
+
+/// The fields whose strings the options can look up, rather than only the links in them.
+const STRING_FIELDS: [&str; 2] = ["type", "author"];
+
+/// What's kept of a value near the top of a message.
+enum Shape {
+    /// An object, its fields in key order.
+    Object(Vec<(String, Shape)>),
+    /// A string in one of `STRING_FIELDS`.
+    String(String),
+    Uint(u64),
+    Float(f64),
+    /// Anything else, as the links in it, in order.
+    Links(Vec<Multihash>),
+}
+
+#[cfg_attr(any(feature = "simd-json", feature = "bumpalo"), allow(dead_code))]
+pub(crate) fn extract_refs_into(
+    msg: &[u8],
+    options: &LinkOptions,
+    refs: &mut Vec<Multihash>,
+) -> serde_json::Result<Found> {
+    let mut deserializer = serde_json::Deserializer::from_slice(msg);
+    let seed = ShapeSeed {
+        options,
+        levels: LEVELS,
+        string: false,
+    };
+    let parsed = seed.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+
+    let found = options.find(|path| parsed.scalar_at(path));
+    if found.kept {
+        match options.part(|path| parsed.shape_at(path).is_some()) {
+            Part::Whole => parsed.links_into(options, refs),
+            Part::Nothing => (),
+            Part::At(path) => parsed
+                .shape_at(path)
+                .into_iter()
+                .for_each(|part| part.links_into(options, refs)),
+        }
+    }
+    Ok(found)
+}
+
+impl Shape {
+    fn shape_at(&self, path: &[&str]) -> Option<&Shape> {
+        path.iter().try_fold(self, |shape, field| match shape {
+            Shape::Object(fields) => {
+                let index = fields
+                    .binary_search_by(|(key, _)| key.as_str().cmp(field))
+                    .ok()?;
+                Some(&fields[index].1)
+            }
+            _ => None,
+        })
+    }
+
+    fn scalar_at(&self, path: &[&str]) -> Option<Scalar<'_>> {
+        match self.shape_at(path)? {
+            Shape::String(st) => Some(Scalar::Str(st)),
+            Shape::Uint(n) => Some(Scalar::Uint(*n)),
+            Shape::Float(n) => Some(Scalar::Float(*n)),
+            _ => None,
+        }
+    }
+
+    /// Add the links in this to `refs`. The fields the options skip were never kept.
+    fn links_into(&self, options: &LinkOptions, refs: &mut Vec<Multihash>) {
+        match self {
+            Shape::Object(fields) => fields
+                .iter()
+                .for_each(|(_, shape)| shape.links_into(options, refs)),
+            Shape::String(st) => refs.extend(options.link(st)),
+            Shape::Uint(_) | Shape::Float(_) => (),
+            Shape::Links(links) => refs.extend_from_slice(links),
+        }
+    }
+}
+
+/// Sort `fields` by key, keeping the last of any repeated key, as `serde_json::Map` does.
+fn sort_fields<K: Ord, V>(fields: &mut Vec<(K, V)>) {
+    // The sort is stable, so reversed first, the last of each key comes first and is kept.
+    fields.reverse();
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    fields.dedup_by(|(later, _), (kept, _)| later == kept);
+}
+
+/// Keeps the shape of a value `levels` objects from the bottom of what's kept.
+#[derive(Clone, Copy)]
+struct ShapeSeed<'o> {
+    options: &'o LinkOptions,
+    levels: usize,
+    /// Whether the value is in one of `STRING_FIELDS`.
+    string: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for ShapeSeed<'_> {
+    type Value = Shape;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Shape, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ShapeSeed<'_> {
+    type Value = Shape;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any valid JSON value")
+    }
+
+    fn visit_bool<E: Error>(self, _: bool) -> Result<Shape, E> {
+        Ok(Shape::Links(Vec::new()))
+    }
+
+    fn visit_i64<E: Error>(self, v: i64) -> Result<Shape, E> {
+        // serde_json only visits negative integers as i64s.
+        Ok(Shape::Float(v as f64))
+    }
+
+    fn visit_u64<E: Error>(self, v: u64) -> Result<Shape, E> {
+        Ok(Shape::Uint(v))
+    }
+
+    fn visit_f64<E: Error>(self, v: f64) -> Result<Shape, E> {
+        Ok(Shape::Float(v))
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Shape, E> {
+        Ok(Shape::Links(Vec::new()))
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Shape, E> {
+        if self.string {
+            Ok(Shape::String(v.to_owned()))
+        } else {
+            Ok(Shape::Links(self.options.link(v).into_iter().collect()))
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Shape, A::Error> {
+        let mut links = Vec::new();
+        LinksSeed::new(self.options, &mut links).visit_seq(seq)?;
+        Ok(Shape::Links(links))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Shape, A::Error> {
+        if self.levels == 0 {
+            let mut links = Vec::new();
+            LinksSeed::new(self.options, &mut links).visit_map(map)?;
+            return Ok(Shape::Links(links));
+        }
+
+        let mut fields = Vec::new();
+        while let Some(key) = map.next_key_seed(KeySeed)? {
+            if self.options.skips(&key) {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            let seed = ShapeSeed {
+                options: self.options,
+                levels: self.levels - 1,
+                string: STRING_FIELDS.contains(&key.as_ref()),
+            };
+            let value = map.next_value_seed(seed)?;
+            fields.push((key.into_owned(), value));
+        }
+        sort_fields(&mut fields);
+        Ok(Shape::Object(fields))
+    }
+}
+
+/// Adds the links in a value to `links`, keeping nothing else.
+struct LinksSeed<'o, 'l> {
+    options: &'o LinkOptions,
+    links: &'l mut Vec<Multihash>,
+}
+
+impl<'o, 'l> LinksSeed<'o, 'l> {
+    fn new(options: &'o LinkOptions, links: &'l mut Vec<Multihash>) -> LinksSeed<'o, 'l> {
+        LinksSeed { options, links }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for LinksSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for LinksSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any valid JSON value")
+    }
+
+    fn visit_bool<E: Error>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E: Error>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E: Error>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E: Error>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<(), E> {
+        self.links.extend(self.options.link(v));
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq
+            .next_element_seed(LinksSeed::new(self.options, self.links))?
+            .is_some()
+        {}
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        // Most fields have no links, and an empty `Vec` doesn't allocate.
+        let mut fields: Vec<(Cow<str>, Vec<Multihash>)> = Vec::new();
+        while let Some(key) = map.next_key_seed(KeySeed)? {
+            if self.options.skips(&key) {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            let mut links = Vec::new();
+            map.next_value_seed(LinksSeed::new(self.options, &mut links))?;
+            fields.push((key, links));
+        }
+        sort_fields(&mut fields);
+        fields
+            .into_iter()
+            .for_each(|(_, links)| self.links.extend(links));
+        Ok(())
+    }
+}
+
+/// Reads an object key, borrowing it from the message when it has no escapes.
+struct KeySeed;
+
+impl<'de> DeserializeSeed<'de> for KeySeed {
+    type Value = Cow<'de, str>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Cow<'de, str>, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeySeed {
+    type Value = Cow<'de, str>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string key")
+    }
+
+    fn visit_borrowed_str<E: Error>(self, v: &'de str) -> Result<Cow<'de, str>, E> {
+        Ok(Cow::Borrowed(v))
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Cow<'de, str>, E> {
+        Ok(Cow::Owned(v.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_refs_into;
+    use crate::test_utils::assert_extractor_matches_every_case;
+
+    #[test]
+    fn matches_serde_json() {
+        assert_extractor_matches_every_case(extract_refs_into);
+    }
+}
@@ -0,0 +1,211 @@
+//! Looking at each tangle in a set of messages.
+use crate::error::{self, Error};
+use crate::hashes::parse_link;
+use crate::sorted::SortedMessages;
+use crate::try_causal_sort;
+use serde_json::Value;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::{HashMap, HashSet};
+
+/// Where a message's `root` and `branch` fields can be, depending on whether it's the content of
+/// a message, a message value or a message with its key.
+const CONTENT_PATHS: [&[&str]; 3] = [&[], &["content"], &["value", "content"]];
+
+/// How much of one tangle is present, from [`tangle_completeness`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TangleReport {
+    /// The key of the tangle's root message, which its other messages link to in their `root`.
+    pub root: Multihash,
+    /// How many of the tangle's messages are present, counting the root.
+    pub messages: usize,
+    /// The messages the tangle links to that aren't present, in the order they're first linked
+    /// to. The root is first if it's missing.
+    pub missing: Vec<Multihash>,
+}
+
+impl TangleReport {
+    /// Whether every message the tangle links to is present.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Report how complete each tangle in `msgs` is, in the order their roots are first linked to.
+///
+/// A message is in the tangle of the message its `root` field links to. A tangle is missing its
+/// root if that isn't in `msgs`, and any message its other messages link to in their `branch`
+/// fields that isn't either. Messages that aren't valid JSON aren't in any tangle.
+pub fn tangle_completeness<T: AsRef<str>, K>(msgs: &[(Multihash, K, T)]) -> Vec<TangleReport> {
+    let present: HashSet<&Multihash> = msgs.iter().map(|(key, _, _)| key).collect();
+    let mut reports: Vec<TangleReport> = Vec::new();
+    let mut report_of: HashMap<Multihash, usize> = HashMap::new();
+    for (_, _, msg) in msgs {
+        let value: Value = match serde_json::from_str(msg.as_ref()) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let (content, root) = match content_and_root(&value) {
+            Some(content) => content,
+            None => continue,
+        };
+
+        let index = *report_of.entry(root.clone()).or_insert_with(|| {
+            let root_present = present.contains(&root);
+            reports.push(TangleReport {
+                root: root.clone(),
+                messages: root_present as usize,
+                missing: if root_present {
+                    vec![]
+                } else {
+                    vec![root.clone()]
+                },
+            });
+            reports.len() - 1
+        });
+        let report = &mut reports[index];
+        report.messages += 1;
+        for branch in branches(content) {
+            if !present.contains(&branch) && !report.missing.contains(&branch) {
+                report.missing.push(branch);
+            }
+        }
+    }
+    reports
+}
+
+/// Causally sort each tangle in `msgs`, returning the key ids of each tangle's messages newest
+/// first, by its root. The root message, if it's in `msgs`, is last in its own tangle.
+///
+/// The messages are sorted once, together, so each tangle is in the order
+/// [`causal_sort`](crate::causal_sort) would put it in. Messages are in tangles as for
+/// [`tangle_completeness`], and a message that's the root of one tangle and in another is in
+/// both.
+///
+/// # Panics
+///
+/// Panics if the messages' links form a cycle. Use [`try_thread_index`] to handle this as an
+/// error.
+pub fn thread_index<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> HashMap<Multihash, SortedMessages<K>> {
+    error::unwrap(try_thread_index(msgs))
+}
+
+/// Like [`thread_index`], but returns an error rather than panicking.
+pub fn try_thread_index<T: AsRef<str>, K: Clone>(
+    msgs: &[(Multihash, K, T)],
+) -> Result<HashMap<Multihash, SortedMessages<K>>, Error> {
+    let roots: Vec<Option<Multihash>> = msgs
+        .iter()
+        .map(|(_, _, msg)| {
+            let value = serde_json::from_str(msg.as_ref()).ok()?;
+            Some(content_and_root(&value)?.1)
+        })
+        .collect();
+    let tangles: HashSet<&Multihash> = roots.iter().flatten().collect();
+
+    let indexed: Vec<_> = msgs
+        .iter()
+        .enumerate()
+        .map(|(index, (key, _, msg))| (key.clone(), index, msg.as_ref()))
+        .collect();
+    let mut index: HashMap<Multihash, Vec<K>> = HashMap::new();
+    for i in try_causal_sort(&indexed)? {
+        let (key, key_id, _) = &msgs[i];
+        if let Some(root) = &roots[i] {
+            index.entry(root.clone()).or_default().push(key_id.clone());
+        }
+        if tangles.contains(key) {
+            index.entry(key.clone()).or_default().push(key_id.clone());
+        }
+    }
+    Ok(index
+        .into_iter()
+        .map(|(root, key_ids)| (root, key_ids.into()))
+        .collect())
+}
+
+/// The content of a message that's in a tangle, and the tangle's root.
+pub(crate) fn content_and_root(value: &Value) -> Option<(&Value, Multihash)> {
+    CONTENT_PATHS.iter().find_map(|path| {
+        let content = path
+            .iter()
+            .try_fold(value, |value, field| value.as_object()?.get(*field))?;
+        Some((content, hash(content.get("root")?)?))
+    })
+}
+
+/// The messages the content of a message in a tangle links to in its `branch`.
+pub(crate) fn branches(content: &Value) -> Vec<Multihash> {
+    match content.get("branch") {
+        Some(Value::Array(branches)) => branches.iter().filter_map(hash).collect(),
+        Some(branch) => hash(branch).into_iter().collect(),
+        None => vec![],
+    }
+}
+
+fn hash(value: &Value) -> Option<Multihash> {
+    match parse_link(value.as_str()?) {
+        Some(hash @ Multihash::Message(_)) => Some(hash),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tangle_completeness, thread_index};
+    use crate::test_utils::{numbered, thread};
+    use serde_json::json;
+
+    #[test]
+    fn reports_missing_roots_and_branches() {
+        let complete = tangle_completeness(&thread());
+        assert!(complete.iter().all(|report| report.is_complete()));
+
+        // 1 is present, with 3 missing from its tangle. 20 is missing as a root.
+        let msgs = [
+            (numbered(1), 1, json!({ "type": "post" }).to_string()),
+            (
+                numbered(2),
+                2,
+                json!({ "root": numbered(1), "branch": numbered(1) }).to_string(),
+            ),
+            (
+                numbered(4),
+                4,
+                json!({ "root": numbered(1), "branch": [numbered(2), numbered(3)] }).to_string(),
+            ),
+            (
+                numbered(21),
+                21,
+                json!({ "value": { "content": { "root": numbered(20) } } }).to_string(),
+            ),
+        ];
+        let reports = tangle_completeness(&msgs);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            (reports[0].root.clone(), reports[0].messages),
+            (numbered(1), 3)
+        );
+        assert_eq!(reports[0].missing, [numbered(3)]);
+        assert_eq!(reports[1].root, numbered(20));
+        assert_eq!((reports[1].messages, reports[1].missing.len()), (1, 1));
+        assert!(!reports[1].is_complete());
+    }
+
+    #[test]
+    fn indexes_each_thread_in_causal_order() {
+        let msgs = thread();
+        let index = thread_index(&msgs);
+        // The thread's root doesn't link to anything, so it's in no other tangle.
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[&msgs[1].0], [3, 2, 1]);
+
+        let mut msgs = msgs;
+        let other = json!({ "root": msgs[2].0, "branch": msgs[2].0 });
+        msgs.push((numbered(4), 4, other.to_string()));
+        let index = thread_index(&msgs);
+        assert_eq!(index[&msgs[1].0], [3, 2, 1]);
+        assert_eq!(index[&msgs[2].0], [4, 3]);
+    }
+}
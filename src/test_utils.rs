@@ -0,0 +1,165 @@
+//! Fixtures shared between the tests of different modules.
+#![allow(dead_code)]
+#[cfg(feature = "json")]
+use crate::extract::{serde_extract_refs_into, Found, LinkOptions, RECURSION_LIMIT};
+#[cfg(feature = "json")]
+use crate::{Edges, Window};
+#[cfg(feature = "json")]
+use serde_json::{json, to_string};
+use ssb_multiformats::multihash::Multihash;
+
+pub(crate) fn hash(legacy: &str) -> Multihash {
+    Multihash::from_legacy(legacy.as_bytes()).unwrap().0
+}
+
+/// A distinct, valid message hash for every `n`, with varied leading bytes.
+pub(crate) fn numbered(n: usize) -> Multihash {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    hash(&format!("%{}{:0>41}0=.sha256", ALPHABET[n % 64] as char, n))
+}
+
+/// A root message with two replies, the second reply also referencing the first. Given out of
+/// order, keyed `2, 1, 3`; sorts to `3, 2, 1`.
+#[cfg(feature = "json")]
+pub(crate) fn thread() -> Vec<(Multihash, u32, String)> {
+    let k1 = hash("%rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256");
+    let v1 = to_string(&json!({})).unwrap();
+    let k2 = hash("%reply1K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256");
+    let v2 = to_string(&json!({
+        "root":  "%rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+    }))
+    .unwrap();
+    let k3 = hash("%reply2K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256");
+    let v3 = to_string(&json!({
+        "root":  "%rootBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "previous": "%reply1K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"
+    }))
+    .unwrap();
+
+    vec![(k2, 2, v2), (k1, 1, v1), (k3, 3, v3)]
+}
+
+/// Message `i`, keyed `numbered(i)` and `i`, linking in `branch` to the messages numbered
+/// `links`.
+#[cfg(feature = "json")]
+pub(crate) fn branching(i: usize, links: &[usize]) -> (Multihash, usize, String) {
+    let branch: Vec<_> = links.iter().map(|link| numbered(*link)).collect();
+    (numbered(i), i, json!({ "branch": branch }).to_string())
+}
+
+/// A feed of `n` messages, oldest first, each linking to the one before it in `previous`.
+/// Message `i` is keyed `numbered(i)` and `i`.
+#[cfg(feature = "json")]
+pub(crate) fn feed(n: usize) -> Vec<(Multihash, usize, String)> {
+    (1..=n)
+        .map(|i| {
+            let msg = json!({ "previous": numbered(i - 1) });
+            (numbered(i), i, msg.to_string())
+        })
+        .collect()
+}
+
+/// Messages that every extractor must find the same links in as the serde_json one: repeated
+/// and unusual keys, forks, excluded types, feed positions, timestamps, envelopes, and messages
+/// that aren't JSON or that have more after their value.
+#[cfg(feature = "json")]
+const EXTRACTOR_CASES: &[&str] = &[
+    r#"{
+        "z": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "a": ["&2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256", {"m": "%3AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}],
+        "z": "%4AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "%": "%5AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "n": [null, true, 1, -1, 1.5]
+    }"#,
+    r#"{
+        "root": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "fork": "%2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "nested": {"fork": ["%3AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"]}
+    }"#,
+    "\"%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256\"",
+    r#"{"type": "vote", "vote": {"link": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}}"#,
+    r#"{"value": {"content": {"type": "vote", "vote": {"link": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}}}}"#,
+    r#"{"type": ["vote"], "root": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}"#,
+    r#"{"type": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256", "author": "&2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}"#,
+    r#"{"author": "@a", "sequence": 2, "previous": null}"#,
+    r#"{"value": {"author": "@a", "sequence": -2}}"#,
+    r#"{"value": {"author": "@a", "sequence": 2.5}}"#,
+    r#"{"value": {"author": "@a", "sequence": "2"}}"#,
+    r#"{"value": {"timestamp": 1500.5}, "timestamp": 3000}"#,
+    r#"{"timestamp": 1999}"#,
+    r#"{"timestamp": -1500}"#,
+    r#"{
+        "key": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "value": {
+            "previous": "%2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+            "content": {"root": "%3AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}
+        }
+    }"#,
+    r#"{
+        "key": "%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+        "value": {
+            "previous": "%2AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256",
+            "content": {"vote": {"link": "%3AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}, "type": "post"},
+            "content": {"a": {"b": {"c": {"fork": "%4AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256", "d": "%5AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256"}}}}
+        }
+    }"#,
+    r#"{"previous": null, "content": "encrypted", "n": [[[]]]}"#,
+    "{\"not\": json",
+    r#"{"a": [1, 2"#,
+    "{} trailing",
+    r#"{"a": 1} {"b": 2}"#,
+];
+
+/// Check [`assert_extractor_matches`] for `extract` with every message in `EXTRACTOR_CASES`, the
+/// messages of [`thread`], and links nested either side of `RECURSION_LIMIT` and far past it.
+#[cfg(feature = "json")]
+pub(crate) fn assert_extractor_matches_every_case<F, E>(extract: F)
+where
+    F: Fn(&[u8], &LinkOptions, &mut Vec<Multihash>) -> Result<Found, E>,
+{
+    let link = "\"%1AfrBOK7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256\"";
+    let nested = |depth: usize| format!("{}{}{}", "[".repeat(depth), link, "]".repeat(depth));
+    let deep: Vec<String> = (RECURSION_LIMIT - 2..RECURSION_LIMIT + 2)
+        .chain(std::iter::once(200))
+        .map(nested)
+        .collect();
+    thread()
+        .iter()
+        .map(|(_, _, msg)| msg.as_str())
+        .chain(EXTRACTOR_CASES.iter().copied())
+        .chain(deep.iter().map(String::as_str))
+        .for_each(|msg| assert_extractor_matches(msg, &extract));
+}
+
+/// Check that `extract` finds the same links in `msg`, and the same about it, as the serde_json
+/// extractor does, with each combination of the options that change what's found.
+#[cfg(feature = "json")]
+pub(crate) fn assert_extractor_matches<F, E>(msg: &str, extract: F)
+where
+    F: Fn(&[u8], &LinkOptions, &mut Vec<Multihash>) -> Result<Found, E>,
+{
+    let edges = [Edges::All, Edges::Feed, Edges::Content];
+    [false, true].iter().for_each(|ignore_forks| {
+        edges.iter().for_each(|edges| {
+            let options = LinkOptions {
+                ignore_forks: *ignore_forks,
+                exclude_types: vec!["vote".to_owned()],
+                exclude_authors: vec!["@spam".to_owned()],
+                sequence_edges: *ignore_forks,
+                edges: *edges,
+                window: Some((1000..2000, Window::Inside)),
+                link_formats: None,
+                unknown_hashes: None,
+                envelopes: false,
+                types: *ignore_forks,
+                trust: None,
+            };
+            let mut refs = Vec::new();
+            let found = extract(msg.as_bytes(), &options, &mut refs).ok();
+            let mut serde_refs = Vec::new();
+            let serde_found =
+                serde_extract_refs_into(msg.as_bytes(), &options, &mut serde_refs).ok();
+            assert_eq!((found, refs), (serde_found, serde_refs), "{}", msg);
+        });
+    });
+}
@@ -0,0 +1,183 @@
+//! Fake ssb threads with a controllable shape, for testing code that consumes causally sorted
+//! messages.
+//!
+//! ```
+//! use ssb_causal_sort::testing::TangleGenerator;
+//!
+//! let tangle = TangleGenerator::new(42)
+//!     .depth(3)
+//!     .branching(2)
+//!     .concurrency(2)
+//!     .missing(0.1)
+//!     .generate();
+//! assert_eq!(tangle.msgs.len() + tangle.missing.len(), 1 + 2 + 4 + 8);
+//! ```
+use crate::rng::Rng;
+use serde_json::json;
+use ssb_multiformats::multihash::Multihash;
+
+/// Generates a thread: a root message, then `depth` layers of replies.
+///
+/// Every message in a layer gets `branching` replies in the next layer, so there are
+/// `branching.pow(depth)` messages in the last layer. Each reply links to the root and, in its
+/// `branch`, to its parent plus up to `concurrency - 1` other messages from the parent's layer, as
+/// if it had seen several concurrent heads. Messages can then be dropped at random to simulate an
+/// incompletely replicated thread.
+#[derive(Clone, Debug)]
+pub struct TangleGenerator {
+    seed: u64,
+    depth: usize,
+    branching: usize,
+    concurrency: usize,
+    missing: f64,
+}
+
+/// A generated thread.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tangle {
+    /// The root message's key. The root may itself be missing.
+    pub root: Multihash,
+    /// The messages that weren't dropped, shuffled. Key ids count up in the order the messages
+    /// were generated, so a message's id is always larger than the ids of everything it links to.
+    pub msgs: Vec<(Multihash, usize, String)>,
+    /// The keys of the dropped messages.
+    pub missing: Vec<Multihash>,
+}
+
+impl TangleGenerator {
+    /// A generator for a root with two layers of two replies each, with nothing missing.
+    pub fn new(seed: u64) -> TangleGenerator {
+        TangleGenerator {
+            seed,
+            depth: 2,
+            branching: 2,
+            concurrency: 1,
+            missing: 0.0,
+        }
+    }
+
+    /// How many layers of replies follow the root.
+    pub fn depth(mut self, depth: usize) -> TangleGenerator {
+        self.depth = depth;
+        self
+    }
+
+    /// How many replies each message gets in the next layer.
+    pub fn branching(mut self, branching: usize) -> TangleGenerator {
+        self.branching = branching;
+        self
+    }
+
+    /// How many messages from the previous layer each reply links to. At least one.
+    pub fn concurrency(mut self, concurrency: usize) -> TangleGenerator {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// The fraction of messages, between 0 and 1, to drop from the result.
+    pub fn missing(mut self, fraction: f64) -> TangleGenerator {
+        self.missing = fraction;
+        self
+    }
+
+    pub fn generate(&self) -> Tangle {
+        let mut rng = Rng::new(self.seed);
+        let root = rng.hash();
+        let mut all = vec![(root.clone(), json!({ "type": "post", "text": rng.text() }))];
+
+        let mut layer = 0..1;
+        for _ in 0..self.depth {
+            let next_layer = all.len()..all.len() + layer.len() * self.branching;
+            for parent in layer.clone() {
+                for _ in 0..self.branching {
+                    let mut branch = vec![all[parent].0.clone()];
+                    for _ in 1..self.concurrency.min(layer.len()) {
+                        let other = &all[layer.start + rng.below(layer.len())].0;
+                        if !branch.contains(other) {
+                            branch.push(other.clone());
+                        }
+                    }
+                    let content = json!({
+                        "type": "post",
+                        "text": rng.text(),
+                        "root": root,
+                        "branch": branch,
+                    });
+                    all.push((rng.hash(), content));
+                }
+            }
+            layer = next_layer;
+        }
+
+        let mut msgs = Vec::new();
+        let mut missing = Vec::new();
+        for (id, (key, content)) in all.into_iter().enumerate() {
+            if rng.chance(self.missing) {
+                missing.push(key);
+            } else {
+                msgs.push((key, id, content.to_string()));
+            }
+        }
+        rng.shuffle(&mut msgs);
+
+        Tangle {
+            root,
+            msgs,
+            missing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TangleGenerator;
+    use crate::causal_sort;
+    use serde_json::Value;
+    use ssb_multiformats::multihash::Multihash;
+
+    #[test]
+    fn generates_the_requested_shape() {
+        let tangle = TangleGenerator::new(1).depth(3).branching(3).generate();
+        assert_eq!(tangle.msgs.len(), 1 + 3 + 9 + 27);
+        assert!(tangle.missing.is_empty());
+        assert!(tangle.msgs.iter().any(|(key, _, _)| *key == tangle.root));
+
+        // Every message must sort before the messages in its branch.
+        let sorted = causal_sort(&tangle.msgs);
+        let position = |key: &Multihash| {
+            let (_, id, _) = tangle.msgs.iter().find(|(k, _, _)| k == key).unwrap();
+            sorted.iter().position(|i| i == id).unwrap()
+        };
+        assert_eq!(sorted.last(), Some(&0));
+        tangle.msgs.iter().for_each(|(key, _, msg)| {
+            let content: Value = serde_json::from_str(msg).unwrap();
+            content["branch"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .for_each(|link| {
+                    let link = Multihash::from_legacy(link.as_str().unwrap().as_bytes())
+                        .unwrap()
+                        .0;
+                    assert!(position(key) < position(&link));
+                });
+        });
+    }
+
+    #[test]
+    fn drops_missing_messages() {
+        let tangle = TangleGenerator::new(2)
+            .depth(4)
+            .branching(3)
+            .concurrency(3)
+            .missing(0.25)
+            .generate();
+        assert_eq!(tangle.msgs.len() + tangle.missing.len(), 121);
+        assert!(!tangle.missing.is_empty());
+        assert!(tangle.missing.len() < 60);
+        assert!(tangle
+            .msgs
+            .iter()
+            .all(|(key, _, _)| !tangle.missing.contains(key)));
+    }
+}
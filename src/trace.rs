@@ -0,0 +1,344 @@
+//! [tracing](https://docs.rs/tracing) spans around the stages of a sort.
+//!
+//! With the `tracing` feature a sort records:
+//!
+//! - a `build` span at `DEBUG` level for adding messages to the dag, with `messages`, `links` and
+//!   `nodes` counts. Links are extracted as the dag is built, so this includes parsing.
+//! - an `extract` span at `TRACE` level for parsing each message, with its `bytes` and `links`.
+//! - a `topo_sort` span at `DEBUG` level for sorting the finished dag, with `nodes` and `sorted`
+//!   counts.
+//!
+//! The parallel build has its own `extract`, `intern` and `add_edges` spans inside its `build`
+//! span, since it does each of those stages in one go.
+//!
+//! Links that the options leave out are recorded as `DEBUG` events with the target
+//! `ssb_causal_sort::ignored`, to audit what a configuration skips. Each has the message's `key`,
+//! a `reason`, and the `link` and its JSON `path` where there's one link to tell of:
+//!
+//! - `"excluded type"` for a message of a type [`SortBuilder::exclude_types`] leaves out.
+//! - `"excluded author"` for a message by an author [`SortBuilder::exclude_authors`] leaves out.
+//! - `"excluded key"` for a message [`SortBuilder::exclude_keys`] leaves out.
+//! - `"outside time window"` for a message only its links are kept of.
+//! - `"fork"` and `"edges"` for links left out by [`SortBuilder::forks`] and
+//!   [`SortBuilder::edges`]. Finding their paths parses the message again, which is only done
+//!   when the subscriber wants the events.
+//! - `"max_links"` for links past [`SortBuilder::max_links`].
+//! - `"not a sorted message"` for links [`SortBuilder::internal_links_only`] leaves out.
+//! - `"invalid"` for a message [`SortBuilder::validate`] leaves out.
+//!
+//! [`SortBuilder::exclude_types`]: crate::SortBuilder::exclude_types
+//! [`SortBuilder::exclude_authors`]: crate::SortBuilder::exclude_authors
+//! [`SortBuilder::exclude_keys`]: crate::SortBuilder::exclude_keys
+//! [`SortBuilder::forks`]: crate::SortBuilder::forks
+//! [`SortBuilder::edges`]: crate::SortBuilder::edges
+//! [`SortBuilder::max_links`]: crate::SortBuilder::max_links
+//! [`SortBuilder::internal_links_only`]: crate::SortBuilder::internal_links_only
+//! [`SortBuilder::validate`]: crate::SortBuilder::validate
+//!
+//! Timings come from the subscriber, eg. `tracing_subscriber::fmt` with `FmtSpan::CLOSE`. Without
+//! the feature the spans compile away to nothing.
+
+#[cfg(all(feature = "tracing", feature = "json"))]
+use crate::builder::Edges;
+#[cfg(all(feature = "tracing", feature = "json"))]
+use crate::extract::LinkOptions;
+#[cfg(all(feature = "tracing", feature = "json"))]
+use serde_json::Value;
+#[cfg(all(feature = "tracing", feature = "json"))]
+use ssb_multiformats::multihash::Multihash;
+
+/// Create a span, eg. `span!(DEBUG, "build", messages = msgs.len())`. Field values aren't
+/// evaluated without the `tracing` feature.
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($level:ident, $name:expr $(, $field:ident = $value:expr)* $(,)?) => {
+        tracing::span!(tracing::Level::$level, $name $(, $field = $value)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($tokens:tt)*) => {
+        $crate::trace::Span
+    };
+}
+
+pub(crate) use span;
+
+/// Record that something was left out of a sort, eg.
+/// `ignored!(key, Some(link), None, "max_links")`, as described in the module docs.
+#[cfg(feature = "tracing")]
+macro_rules! ignored {
+    ($key:expr, $link:expr, $path:expr, $reason:expr $(,)?) => {{
+        let link: Option<&ssb_multiformats::multihash::Multihash> = $link;
+        let link = link.map(ssb_multiformats::multihash::Multihash::to_legacy_string);
+        let path: Option<&str> = $path;
+        tracing::debug!(
+            target: "ssb_causal_sort::ignored",
+            key = %$key.to_legacy_string(),
+            link = link.as_deref(),
+            path = path,
+            reason = $reason,
+        )
+    }};
+}
+
+#[cfg(all(feature = "json", not(feature = "tracing")))]
+macro_rules! ignored {
+    ($($tokens:tt)*) => {
+        ()
+    };
+}
+
+#[cfg(any(feature = "json", feature = "tracing"))]
+pub(crate) use ignored;
+
+/// Record the links in `msg` that `options` left out of `kept`, with their paths, if the
+/// subscriber wants them. Only `fork` fields and classes of edges leave out single links.
+#[cfg(all(feature = "tracing", feature = "json"))]
+pub(crate) fn filtered_links(
+    key: &Multihash,
+    msg: &[u8],
+    kept: &[Multihash],
+    options: &LinkOptions,
+) {
+    let filters = options.ignore_forks || options.edges != Edges::All;
+    if !filters || !tracing::enabled!(target: "ssb_causal_sort::ignored", tracing::Level::DEBUG) {
+        return;
+    }
+    let value: Value = match serde_json::from_slice(msg) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    // Each value to visit, with its path and whether it's in a `fork` field.
+    let mut to_visit = vec![(&value, String::new(), false)];
+    while let Some((value, path, fork)) = to_visit.pop() {
+        match value {
+            Value::String(st) => match options.link(st) {
+                Some(link) if !kept.contains(&link) => {
+                    let reason = if fork { "fork" } else { "edges" };
+                    ignored!(key, Some(&link), Some(path.as_str()), reason);
+                }
+                _ => (),
+            },
+            Value::Array(arr) => to_visit.extend(
+                arr.iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, value)| (value, join(&path, &index.to_string()), fork)),
+            ),
+            Value::Object(kv) => to_visit.extend(kv.iter().rev().map(|(field, value)| {
+                let fork = fork || options.skips(field);
+                (value, join(&path, field), fork)
+            })),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(all(feature = "tracing", feature = "json"))]
+fn join(path: &str, field: &str) -> String {
+    match path {
+        "" => field.to_owned(),
+        path => format!("{}.{}", path, field),
+    }
+}
+
+/// Stands in for `tracing::Span` when there's no `tracing` to record to.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn enter(&self) -> Entered {
+        Entered
+    }
+
+    pub(crate) fn record<V>(&self, _field: &str, _value: V) -> &Span {
+        self
+    }
+
+    #[cfg_attr(not(feature = "rayon"), allow(dead_code))]
+    pub(crate) fn in_scope<F: FnOnce() -> R, R>(&self, f: F) -> R {
+        f()
+    }
+}
+
+#[cfg(all(test, feature = "json", feature = "tracing"))]
+mod tests {
+    use crate::test_utils::{numbered, thread};
+    use crate::{causal_sort, Backend, Edges, Forks, SortBuilder};
+    use serde_json::json;
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Collects the name and fields of every span, with fields recorded after creation appended,
+    /// and the target and fields of every event.
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<(&'static str, Fields)>>>);
+
+    #[derive(Default)]
+    struct Fields(Vec<(String, String)>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .push((field.name().to_owned(), format!("{:?}", value)));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push((field.name().to_owned(), value.to_owned()));
+        }
+    }
+
+    impl Subscriber for Spans {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            let mut spans = self.0.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record) {
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut spans[span.into_u64() as usize - 1].1);
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let mut spans = self.0.lock().unwrap();
+            spans.push((event.metadata().target(), fields));
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    impl Spans {
+        fn field(&self, name: &str, field: &str) -> Vec<String> {
+            let spans = self.0.lock().unwrap();
+            spans
+                .iter()
+                .filter(|(span, _)| *span == name)
+                .flat_map(|(_, fields)| fields.0.iter().filter(|(f, _)| f == field))
+                .map(|(_, value)| value.clone())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn sorting_records_spans_with_counts() {
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let spans = Spans::default();
+            tracing::subscriber::with_default(spans.clone(), || {
+                SortBuilder::new().backend(*backend).sort(&thread());
+            });
+
+            assert_eq!(spans.field("build", "messages"), ["3"]);
+            assert_eq!(spans.field("build", "links"), ["3"]);
+            assert_eq!(spans.field("build", "nodes"), ["3"]);
+            assert_eq!(spans.field("extract", "links"), ["1", "0", "2"]);
+            assert_eq!(spans.field("topo_sort", "sorted"), ["3"]);
+        });
+
+        let spans = Spans::default();
+        tracing::subscriber::with_default(spans.clone(), || causal_sort(&thread()));
+        assert_eq!(spans.field("build", "links"), ["3"]);
+        assert_eq!(spans.field("topo_sort", "nodes"), ["3"]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_build_records_each_stage() {
+        let spans = Spans::default();
+        tracing::subscriber::with_default(spans.clone(), || crate::par_causal_sort(&thread()));
+        assert_eq!(spans.field("build", "links"), ["3"]);
+        assert_eq!(spans.field("intern", "nodes"), ["3"]);
+        assert_eq!(spans.field("add_edges", "edges"), ["3"]);
+        assert_eq!(spans.field("topo_sort", "sorted"), ["3"]);
+    }
+
+    #[test]
+    fn ignored_links_are_recorded() {
+        let msg = |n, value: serde_json::Value| (numbered(n), n, value.to_string());
+        let msgs = [
+            msg(1, json!({})),
+            msg(2, json!({})),
+            msg(
+                3,
+                json!({ "previous": numbered(1), "content": { "root": numbered(2) } }),
+            ),
+            msg(
+                4,
+                json!({ "previous": numbered(3), "content": { "fork": numbered(2) } }),
+            ),
+            msg(5, json!({ "content": { "type": "vote" } })),
+            msg(6, json!({ "author": "@blocked", "content": {} })),
+            msg(7, json!({})),
+        ];
+        let ignored = "ssb_causal_sort::ignored";
+
+        [Backend::Daggy, Backend::Csr].iter().for_each(|backend| {
+            let spans = Spans::default();
+            tracing::subscriber::with_default(spans.clone(), || {
+                SortBuilder::new()
+                    .backend(*backend)
+                    .forks(Forks::Ignore)
+                    .edges(Edges::Feed)
+                    .exclude_types(&["vote"])
+                    .exclude_authors(&["@blocked"])
+                    .exclude_keys(&[numbered(7)])
+                    .max_links(0)
+                    .sort(&msgs);
+            });
+
+            let reasons = [
+                "edges",
+                "max_links",
+                "fork",
+                "max_links",
+                "excluded type",
+                "excluded author",
+                "excluded key",
+            ];
+            assert_eq!(spans.field(ignored, "reason"), reasons);
+            assert_eq!(
+                spans.field(ignored, "path"),
+                ["content.root", "content.fork"]
+            );
+            let keys: Vec<_> = [3, 3, 4, 4, 5, 6, 7].iter().map(|n| numbered(*n)).collect();
+            let keys: Vec<_> = keys.iter().map(|key| key.to_legacy_string()).collect();
+            assert_eq!(spans.field(ignored, "key"), keys);
+            assert_eq!(
+                spans.field(ignored, "link")[2],
+                numbered(2).to_legacy_string()
+            );
+        });
+
+        let spans = Spans::default();
+        tracing::subscriber::with_default(spans.clone(), || {
+            SortBuilder::new()
+                .internal_links_only(true)
+                .sort(&msgs[2..3]);
+        });
+        assert_eq!(
+            spans.field(ignored, "reason"),
+            ["not a sorted message", "not a sorted message"]
+        );
+    }
+}
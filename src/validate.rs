@@ -0,0 +1,81 @@
+//! Validating messages as they're sorted.
+use ssb_multiformats::multihash::Multihash;
+use std::error::Error;
+
+/// Called by a [`SortBuilder`](crate::SortBuilder) on each message before its links are added,
+/// eg. to check its signature and its place in its feed with `ssb-validate`, so that one pass
+/// both validates and sorts incoming messages.
+///
+/// The same `Validate` is shared by every sort the builder does, possibly on many threads at
+/// once.
+pub trait Validate: Send + Sync {
+    /// Check the message with `key` and the value `msg`, returning why it's invalid if it is.
+    fn validate(&self, key: &Multihash, msg: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// What a sort does with the messages its [`Validate`] finds invalid.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidMessages {
+    /// Leave them out of the sort, as if they hadn't been given. The default.
+    #[default]
+    Exclude,
+    /// Sort them as having no links, so they can't put anything out of order.
+    Orphan,
+    /// Fail the sort with `Error::Invalid`.
+    Error,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InvalidMessages, Validate};
+    use crate::test_utils::{numbered, thread};
+    use crate::{Error, SortBuilder};
+    use serde_json::json;
+    use ssb_multiformats::multihash::Multihash;
+    use std::sync::Arc;
+
+    /// Finds messages that say they're forged invalid.
+    struct Honesty;
+
+    impl Validate for Honesty {
+        fn validate(
+            &self,
+            _: &Multihash,
+            msg: &[u8],
+        ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            match serde_json::from_slice::<serde_json::Value>(msg)?.get("forged") {
+                Some(_) => Err("forged".into()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_messages_are_dealt_with_as_asked() {
+        let mut msgs = thread();
+        // The forged message's link makes a cycle, so the sort only works without it.
+        let forged = json!({ "forged": true, "previous": numbered(4) });
+        msgs.push((
+            numbered(4),
+            4,
+            json!({ "previous": numbered(5) }).to_string(),
+        ));
+        msgs.push((numbered(5), 5, forged.to_string()));
+        let builder = |invalid| SortBuilder::new().validate(Arc::new(Honesty), invalid);
+
+        let excluded = builder(InvalidMessages::Exclude).sort(&msgs);
+        assert_eq!(excluded.len(), 4);
+        assert!(!excluded.contains(&5));
+        let orphaned = builder(InvalidMessages::Orphan).sort(&msgs);
+        assert_eq!(orphaned.len(), 5);
+        assert!(orphaned.position_of(&4) < orphaned.position_of(&5));
+        assert!(matches!(
+            builder(InvalidMessages::Error).try_sort(&msgs),
+            Err(Error::Invalid { index: 4, .. })
+        ));
+        assert!(matches!(
+            SortBuilder::new().try_sort(&msgs),
+            Err(Error::Cycle { .. })
+        ));
+    }
+}
@@ -0,0 +1,188 @@
+//! Checking that an order is causally consistent.
+use crate::extract::Extractor;
+use ssb_multiformats::multihash::Multihash;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+
+/// Why an order isn't a valid causal order of a set of messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OrderError<K> {
+    /// A message is missing from the order.
+    Missing(K),
+    /// The order has a key id that isn't one of the messages. When several messages share a key
+    /// only the first of them is expected in the order.
+    Unexpected(K),
+    /// A key id appears in the order more than once.
+    Repeated(K),
+    /// `newer` links to `older` with `link`, the key of `older`, but comes after it in the
+    /// order.
+    Violation { newer: K, older: K, link: Multihash },
+}
+
+impl<K: fmt::Debug> fmt::Display for OrderError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderError::Missing(key) => write!(f, "{:?} is missing from the order", key),
+            OrderError::Unexpected(key) => write!(f, "{:?} is not one of the messages", key),
+            OrderError::Repeated(key) => write!(f, "{:?} is in the order more than once", key),
+            OrderError::Violation { newer, older, link } => write!(
+                f,
+                "{:?} links to {:?} with {} but is ordered after it",
+                newer,
+                older,
+                link.to_legacy_string()
+            ),
+        }
+    }
+}
+
+impl<K: fmt::Debug> Error for OrderError<K> {}
+
+/// Check that `order`, newest first, is consistent with every link between `msgs`.
+///
+/// This accepts any valid causal order, not just the one [`causal_sort`](crate::causal_sort)
+/// picks, so it can check orders produced elsewhere. Links are found the same way the sort finds
+/// them, and links to messages that aren't in `msgs` are ignored. The first problem found is
+/// returned, with the link that a `Violation` breaks, eg. to cross-check the orders of another
+/// implementation. Check an order that's oldest first by reversing it.
+pub fn verify_causal_order<T, K>(
+    msgs: &[(Multihash, K, T)],
+    order: &[K],
+) -> Result<(), OrderError<K>>
+where
+    T: AsRef<str>,
+    K: Clone + Eq + Hash,
+{
+    // Only the first message with each key counts, just like when sorting.
+    let mut key_to_id: HashMap<&Multihash, &K> = HashMap::new();
+    msgs.iter().for_each(|(key, key_id, _)| {
+        key_to_id.entry(key).or_insert(key_id);
+    });
+    let expected: HashSet<&K> = key_to_id.values().copied().collect();
+
+    let mut positions: HashMap<&K, usize> = HashMap::with_capacity(order.len());
+    for (position, key_id) in order.iter().enumerate() {
+        if !expected.contains(key_id) {
+            return Err(OrderError::Unexpected(key_id.clone()));
+        }
+        if positions.insert(key_id, position).is_some() {
+            return Err(OrderError::Repeated(key_id.clone()));
+        }
+    }
+
+    let mut extractor = Extractor::new();
+    for (key, key_id, msg) in msgs {
+        if key_to_id[key] != key_id {
+            continue;
+        }
+        let newer = *positions
+            .get(key_id)
+            .ok_or_else(|| OrderError::Missing(key_id.clone()))?;
+
+        for reference in extractor
+            .extract(msg.as_ref().as_bytes())
+            .unwrap_or_default()
+        {
+            if let Some(older_id) = key_to_id.get(reference) {
+                let older = *positions
+                    .get(older_id)
+                    .ok_or_else(|| OrderError::Missing((*older_id).clone()))?;
+                if newer > older {
+                    return Err(OrderError::Violation {
+                        newer: key_id.clone(),
+                        older: (*older_id).clone(),
+                        link: reference.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_causal_order, OrderError};
+    use crate::test_utils::{numbered, thread};
+    use crate::{causal_sort, Backend, SortBuilder};
+    use proptest::prelude::*;
+    use serde_json::json;
+    use ssb_multiformats::multihash::Multihash;
+
+    #[test]
+    fn accepts_valid_orders() {
+        let msgs = thread();
+        assert_eq!(verify_causal_order(&msgs, &causal_sort(&msgs)), Ok(()));
+        assert_eq!(verify_causal_order(&msgs, &[3, 2, 1]), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_orders() {
+        let msgs = thread();
+        let error = verify_causal_order(&msgs, &[2, 3, 1]).unwrap_err();
+        assert_eq!(
+            error,
+            OrderError::Violation {
+                newer: 3,
+                older: 2,
+                link: msgs[0].0.clone()
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "3 links to 2 with %reply1K7pZikWM6aupei3PuE5ghRtFM44nrsX0FuBWY=.sha256 but is ordered \
+             after it"
+        );
+        assert_eq!(
+            verify_causal_order(&msgs, &[3, 2]),
+            Err(OrderError::Missing(1))
+        );
+        assert_eq!(
+            verify_causal_order(&msgs, &[3, 2, 1, 4]),
+            Err(OrderError::Unexpected(4))
+        );
+        assert_eq!(
+            verify_causal_order(&msgs, &[3, 2, 2, 1]),
+            Err(OrderError::Repeated(2))
+        );
+    }
+
+    /// Shuffled messages `0..n`, each with a few links to older messages or to messages that
+    /// aren't in the set.
+    fn dag() -> impl Strategy<Value = Vec<(Multihash, usize, String)>> {
+        let links = prop::collection::vec(0..1000_usize, 0..4);
+        prop::collection::vec(links, 1..60).prop_flat_map(|links| {
+            let msgs: Vec<_> = links
+                .into_iter()
+                .enumerate()
+                .map(|(i, links)| {
+                    let branch: Vec<_> = links
+                        .into_iter()
+                        .map(|l| match l {
+                            l if l < 500 && i > 0 => numbered(l % i),
+                            l => numbered(l + 1000),
+                        })
+                        .collect();
+                    (numbered(i), i, json!({ "branch": branch }).to_string())
+                })
+                .collect();
+            Just(msgs).prop_shuffle()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn causal_sort_is_always_valid(msgs in dag()) {
+            prop_assert_eq!(verify_causal_order(&msgs, &causal_sort(&msgs)), Ok(()));
+        }
+
+        #[test]
+        fn csr_backend_is_always_valid(msgs in dag()) {
+            let sorted = SortBuilder::new().backend(Backend::Csr).sort(&msgs);
+            prop_assert_eq!(verify_causal_order(&msgs, &sorted), Ok(()));
+        }
+    }
+}